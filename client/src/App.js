@@ -9,9 +9,10 @@ class App extends React.Component {
     super();
     this.state = {
       realtimeData: [],
-      route: [],
+      route: null,
       passengerData: { passengers: 0, capacity: 0 },
       vehiclesLoaded: false,
+      feedStatuses: {},
     };
     this.ws = null;
     this.wsSend = this.wsSend.bind(this);
@@ -24,13 +25,29 @@ class App extends React.Component {
     }
   }
 
+  // Returns the worst status of all operators' realtime feeds.
+  worstFeedStatus() {
+    let statuses = Object.values(this.state.feedStatuses);
+
+    if (statuses.includes("stale")) return "stale";
+    if (statuses.includes("degraded")) return "degraded";
+    return "ok";
+  }
+
   handleReceivedMessage(message) {
     if (message.type === "vehicle-positions") {
       this.setState({ vehiclesLoaded: true });
       this.setState({ realtimeData: message.payload.vehicles });
     } else if (message.type === "route-info") {
       console.log(message);
-      this.setState({ route: message.payload.route });
+      this.setState({ route: message.payload });
+    } else if (message.type === "feed-status") {
+      this.setState({
+        feedStatuses: {
+          ...this.state.feedStatuses,
+          [message.payload.operator]: message.payload.status,
+        },
+      });
     } else if (message.type === "passenger-info") {
       this.setState({
         passengerData: {
@@ -77,6 +94,13 @@ class App extends React.Component {
           <LoadingScreen />
         ) : (
           <div className="App-header">
+            {this.worstFeedStatus() !== "ok" && (
+              <div id="feedStatus">
+                {this.worstFeedStatus() === "stale"
+                  ? "Live data unavailable"
+                  : "Live data may be delayed"}
+              </div>
+            )}
             <Map
               wsSend={this.wsSend}
               realtimeData={this.state.realtimeData}
@@ -40,6 +40,18 @@ const mapContainerStyle = {
   width: "100vw",
 };
 
+// Returns every shape variant of a route, where "relativeShare" is 1 for the
+// most used variant, so that rare branches can be drawn faintly
+const routeVariants = (route) => {
+  const variants = route.directions.flatMap((direction) => direction.variants);
+  const mainShare = Math.max(...variants.map((variant) => variant.share));
+
+  return variants.map((variant) => ({
+    ...variant,
+    relativeShare: variant.share / mainShare,
+  }));
+};
+
 // Styling for the polyline that is shown when drawing
 // a route
 const polyLineOptions = {
@@ -292,8 +304,11 @@ function Map(props) {
     }
   };
 
+  // The zoom level of the map, which the shapes of routes are simplified for.
+  const getZoom = () => Math.round(mapRef.current.getZoom());
+
   const onVehicleSelect = (vehicleId, vehicle) => {
-    props.wsSend(JSON.stringify(routeRequest(vehicle.trip)));
+    props.wsSend(JSON.stringify(routeRequest(vehicle.trip, "trip_id", getZoom())));
     props.wsSend(JSON.stringify(getPassengerInfo(vehicleId)));
 
     vehicleDataDispatch({
@@ -317,13 +332,13 @@ function Map(props) {
         line: null,
       },
     });
-    setRoute([]);
+    setRoute(null);
   };
 
   // this functions purpose is to be passed as a
   // callback to the searchbar component.
   const onBuslineSearch = useCallback((line) => {
-    props.wsSend(JSON.stringify(routeRequest(line)));
+    props.wsSend(JSON.stringify(routeRequest(line, "line", getZoom())));
 
     vehicleDataDispatch({
       type: "setSelectedVehicle",
@@ -488,17 +503,41 @@ function Map(props) {
           }}
         />
 
-        {currentRoute && (
-          <Polyline
-            path={currentRoute.map((obj) => {
-              return {
-                lat: parseFloat(obj.lat),
-                lng: parseFloat(obj.lng),
-              };
-            })}
-            options={polyLineOptions}
-          />
-        )}
+        {currentRoute &&
+          routeVariants(currentRoute).map((variant) => (
+            <Polyline
+              key={variant.shapeId}
+              path={variant.route.coordinates.map(([lng, lat]) => {
+                return { lat, lng };
+              })}
+              options={{
+                ...polyLineOptions,
+                strokeOpacity: 0.3 + 0.5 * variant.relativeShare,
+                strokeWeight: 1 + 3 * variant.relativeShare,
+              }}
+            />
+          ))}
+
+        {currentRoute &&
+          currentRoute.directions.flatMap((direction) =>
+            direction.variants.slice(0, 1).flatMap((variant) =>
+              variant.stops.map((stop) => (
+                <Marker
+                  key={`${variant.shapeId}-${stop.stopId}-${stop.distance}`}
+                  position={{ lat: stop.lat, lng: stop.lng }}
+                  title={stop.platform ? `${stop.name} (${stop.platform})` : stop.name}
+                  icon={{
+                    path: window.google.maps.SymbolPath.CIRCLE,
+                    scale: 4,
+                    fillColor: "#FFFFFF",
+                    fillOpacity: 1,
+                    strokeColor: polyLineOptions.strokeColor,
+                    strokeWeight: 2,
+                  }}
+                />
+              ))
+            )
+          )}
       </GoogleMap>
       <Fab
         id="locationButton"
@@ -1,8 +1,11 @@
-export function routeRequest(id) {
+export function routeRequest(id, kind, zoom) {
   return {
     type: "get-route-info",
     payload: {
       id: id,
+      kind: kind,
+      format: "geojson",
+      zoom: zoom,
     },
   };
 }
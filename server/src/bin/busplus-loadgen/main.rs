@@ -0,0 +1,304 @@
+//! Load generator that simulates riders to find out how many a server can handle.
+//!
+//! Every simulated rider opens a WebSocket connection to the server, moves around the region
+//! and sends the same messages as the app (see `rider`). When the run is over, latency
+//! percentiles for every kind of request, the throughput and the number of disconnects are
+//! printed.
+//!
+//! Example, against a server that runs locally:
+//!
+//!     cargo run --release --bin busplus-loadgen -- --riders 2000 --duration 120
+
+mod report;
+mod rider;
+
+use std::cell::RefCell;
+use std::env;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use tokio::time::delay_for;
+
+use report::Stats;
+use rider::{ride, RiderSettings};
+
+/// How often progress is printed while the riders are riding.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a rider waits for an answer before it is counted as unanswered.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A rectangular area in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl Region {
+    /// Uppsala, which is where UL's buses drive the most.
+    const UPPSALA: Region = Region {
+        west: 17.55,
+        south: 59.80,
+        east: 17.75,
+        north: 59.90,
+    };
+
+    /// Parses a region from a comma separated string like "17.55,59.80,17.75,59.90".
+    fn parse(input: &str) -> Result<Self, String> {
+        let values = input
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| format!("'{}' contains a value that is not a number", input))?;
+
+        match values.as_slice() {
+            [west, south, east, north] if west < east && south < north => Ok(Region {
+                west: *west,
+                south: *south,
+                east: *east,
+                north: *north,
+            }),
+            _ => Err(format!(
+                "'{}' must be four values: west, south, east and north",
+                input
+            )),
+        }
+    }
+}
+
+/// Options given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+struct LoadArgs {
+    url: String,
+    riders: usize,
+    duration: Duration,
+    ramp_up: Duration,
+    interval: Duration,
+    region: Region,
+    lines: Vec<String>,
+    view_distance: f32,
+    route_chance: f64,
+    reserve_chance: f64,
+    help: bool,
+}
+
+impl Default for LoadArgs {
+    fn default() -> Self {
+        LoadArgs {
+            url: "ws://127.0.0.1:8080/ws".to_owned(),
+            riders: 100,
+            duration: Duration::from_secs(60),
+            ramp_up: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            region: Region::UPPSALA,
+            lines: (1..=12).map(|line| line.to_string()).collect(),
+            view_distance: 2000.0,
+            route_chance: 0.05,
+            reserve_chance: 0.02,
+            help: false,
+        }
+    }
+}
+
+impl LoadArgs {
+    /// Usage description for the command line arguments.
+    const USAGE: &'static str = "\
+Usage: busplus-loadgen [OPTIONS]
+
+Options:
+  -u, --url <URL>             WebSocket endpoint of the server (default: ws://127.0.0.1:8080/ws)
+  -n, --riders <N>            Number of simulated riders (default: 100)
+  -d, --duration <SECONDS>    How long every rider stays connected (default: 60)
+      --ramp-up <SECONDS>     Time over which the riders connect (default: 10)
+      --interval <SECONDS>    Time between two moves of a rider (default: 1)
+      --region <W,S,E,N>      Area that the riders move around in (default: Uppsala)
+      --lines <LIST>          Comma separated lines that riders ask about when they cannot
+                              see any vehicles (default: 1,2,...,12)
+      --view-distance <METRES>
+                              How far from a rider vehicles are shown (default: 2000)
+      --route-chance <P>      Probability that a rider asks about a route after a move
+                              (default: 0.05)
+      --reserve-chance <P>    Probability that a rider reserves or gives up a seat after a
+                              move (default: 0.02)
+  -h, --help                  Prints this message";
+
+    /// Parses a list of command line arguments (without the name of the program).
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut result = LoadArgs::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for argument '{}'.", name))
+            };
+
+            match arg.as_str() {
+                "-u" | "--url" => result.url = value(&arg)?,
+                "-n" | "--riders" => result.riders = parse_number(&arg, &value(&arg)?)?,
+                "-d" | "--duration" => result.duration = parse_seconds(&arg, &value(&arg)?)?,
+                "--ramp-up" => result.ramp_up = parse_seconds(&arg, &value(&arg)?)?,
+                "--interval" => result.interval = parse_seconds(&arg, &value(&arg)?)?,
+                "--region" => result.region = Region::parse(&value(&arg)?)?,
+                "--lines" => {
+                    result.lines = value(&arg)?
+                        .split(',')
+                        .map(|line| line.trim().to_owned())
+                        .filter(|line| !line.is_empty())
+                        .collect()
+                }
+                "--view-distance" => result.view_distance = parse_number(&arg, &value(&arg)?)?,
+                "--route-chance" => result.route_chance = parse_chance(&arg, &value(&arg)?)?,
+                "--reserve-chance" => result.reserve_chance = parse_chance(&arg, &value(&arg)?)?,
+                "-h" | "--help" => result.help = true,
+                _ => return Err(format!("Unknown argument '{}'.", arg)),
+            }
+        }
+
+        if result.interval.as_secs_f64() == 0.0 {
+            return Err("--interval must be positive.".to_owned());
+        }
+
+        Ok(result)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid value for '{}'.", value, name))
+}
+
+fn parse_seconds(name: &str, value: &str) -> Result<Duration, String> {
+    let seconds = parse_number::<f64>(name, value)?;
+
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(format!("'{}' must be a number of seconds.", name));
+    }
+
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_chance(name: &str, value: &str) -> Result<f64, String> {
+    let chance = parse_number::<f64>(name, value)?;
+
+    if !(0.0..=1.0).contains(&chance) {
+        return Err(format!("'{}' must be between 0 and 1.", name));
+    }
+
+    Ok(chance)
+}
+
+#[actix_web::main]
+async fn main() {
+    let args = LoadArgs::parse(env::args().skip(1)).unwrap_or_else(|reason| {
+        println!("{}\n\n{}", reason, LoadArgs::USAGE);
+
+        std::process::exit(2);
+    });
+
+    if args.help {
+        println!("{}", LoadArgs::USAGE);
+
+        return;
+    }
+
+    let settings = Rc::new(RiderSettings {
+        url: args.url.clone(),
+        region: args.region,
+        lines: args.lines.clone(),
+        interval: args.interval,
+        view_distance: args.view_distance,
+        route_chance: args.route_chance,
+        reserve_chance: args.reserve_chance,
+        response_timeout: RESPONSE_TIMEOUT,
+    });
+    let stats = Rc::new(RefCell::new(Stats::default()));
+
+    println!(
+        "Connecting {} riders to {} over {:?}.",
+        args.riders, args.url, args.ramp_up
+    );
+
+    let started = Instant::now();
+
+    // The riders connect one after another during the ramp-up, and every rider stays for the
+    // whole duration.
+    let riders = (0..args.riders).map(|index| {
+        let delay = args.ramp_up.mul_f64(index as f64 / args.riders as f64);
+
+        ride(
+            settings.clone(),
+            stats.clone(),
+            delay,
+            started + delay + args.duration,
+        )
+    });
+
+    let riders = Box::pin(future::join_all(riders));
+    let progress = Box::pin(print_progress(stats.clone(), started));
+
+    if let Either::Right(_) = future::select(riders, progress).await {
+        unreachable!("Progress is printed until the riders are done");
+    }
+
+    println!("\n{}", stats.borrow().report(started.elapsed()));
+}
+
+/// Prints how the run is going every `PROGRESS_INTERVAL`, forever.
+async fn print_progress(stats: Rc<RefCell<Stats>>, started: Instant) {
+    loop {
+        delay_for(PROGRESS_INTERVAL).await;
+
+        let stats = stats.borrow();
+
+        println!(
+            "[{:>4}s] {} riders connected, {} messages sent, {} frames received, {} disconnects",
+            started.elapsed().as_secs(),
+            stats.connected,
+            stats.total_sent(),
+            stats.total_received(),
+            stats.disconnects
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<LoadArgs, String> {
+        LoadArgs::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(args(&[]), Ok(LoadArgs::default()));
+
+        let parsed = args(&[
+            "-n",
+            "2000",
+            "--duration",
+            "1.5",
+            "--region",
+            "18.0,59.3,18.1,59.4",
+            "--lines",
+            "5, 5X",
+        ])
+        .unwrap();
+        assert_eq!(parsed.riders, 2000);
+        assert_eq!(parsed.duration, Duration::from_millis(1500));
+        assert_eq!(parsed.region.north, 59.4);
+        assert_eq!(parsed.lines, vec!["5", "5X"]);
+
+        assert!(args(&["--riders", "many"]).is_err());
+        assert!(args(&["--route-chance", "2"]).is_err());
+        assert!(args(&["--region", "18.1,59.3,18.0,59.4"]).is_err());
+        assert!(args(&["--interval", "0"]).is_err());
+        assert!(args(&["--riders"]).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Statistics that the riders collect, and the report that is printed from them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Everything that has happened to the riders so far. The riders run on the same thread, so
+/// they share a single instance.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// The number of riders that are connected right now.
+    pub connected: usize,
+
+    /// The largest number of riders that have been connected at the same time.
+    pub peak_connected: usize,
+
+    /// The number of riders that could not connect.
+    pub connect_failures: u64,
+
+    /// The number of riders whose connection was closed or broken before they were done.
+    pub disconnects: u64,
+
+    /// The number of messages sent, by message type.
+    pub sent: BTreeMap<&'static str, u64>,
+
+    /// The number of frames received, by message type.
+    pub received: BTreeMap<String, u64>,
+
+    /// The total size of the received frames.
+    pub received_bytes: u64,
+
+    /// How long it took to get an answer, by the type of the request.
+    pub latencies: BTreeMap<&'static str, Vec<Duration>>,
+
+    /// The number of requests that were not answered in time.
+    pub timeouts: u64,
+}
+
+impl Stats {
+    pub fn rider_connected(&mut self) {
+        self.connected += 1;
+        self.peak_connected = self.peak_connected.max(self.connected);
+    }
+
+    pub fn rider_left(&mut self) {
+        self.connected -= 1;
+    }
+
+    pub fn record_sent(&mut self, message_type: &'static str) {
+        *self.sent.entry(message_type).or_default() += 1;
+    }
+
+    pub fn record_received(&mut self, message_type: &str, bytes: usize) {
+        *self.received.entry(message_type.to_owned()).or_default() += 1;
+        self.received_bytes += bytes as u64;
+    }
+
+    pub fn record_latency(&mut self, request_type: &'static str, latency: Duration) {
+        self.latencies
+            .entry(request_type)
+            .or_default()
+            .push(latency);
+    }
+
+    /// Returns the total number of messages sent.
+    pub fn total_sent(&self) -> u64 {
+        self.sent.values().sum()
+    }
+
+    /// Returns the total number of frames received.
+    pub fn total_received(&self) -> u64 {
+        self.received.values().sum()
+    }
+
+    /// Creates the report that is printed when the run is over, where `elapsed` is how long
+    /// the run took.
+    pub fn report(&self, elapsed: Duration) -> String {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let mut report = String::new();
+
+        // Writing to a string cannot fail.
+        let _ = writeln!(
+            report,
+            "Riders: {} connected at most, {} could not connect, {} were disconnected",
+            self.peak_connected, self.connect_failures, self.disconnects
+        );
+
+        let _ = writeln!(
+            report,
+            "Sent: {} messages ({:.1}/s)",
+            self.total_sent(),
+            self.total_sent() as f64 / seconds
+        );
+        for (message_type, count) in &self.sent {
+            let _ = writeln!(report, "  {:<22} {:>10}", message_type, count);
+        }
+
+        let _ = writeln!(
+            report,
+            "Received: {} frames ({:.1}/s), {:.1} MB",
+            self.total_received(),
+            self.total_received() as f64 / seconds,
+            self.received_bytes as f64 / 1_000_000.0
+        );
+        for (message_type, count) in &self.received {
+            let _ = writeln!(report, "  {:<22} {:>10}", message_type, count);
+        }
+
+        let _ = writeln!(
+            report,
+            "Latency (ms):            {:>10} {:>8} {:>8} {:>8} {:>8}",
+            "count", "p50", "p90", "p99", "max"
+        );
+        for (request_type, latencies) in &self.latencies {
+            let mut sorted = latencies.clone();
+            sorted.sort();
+
+            let millis = |percentile| {
+                percentile_of(&sorted, percentile)
+                    .map(|latency| latency.as_secs_f64() * 1000.0)
+                    .unwrap_or_default()
+            };
+
+            let _ = writeln!(
+                report,
+                "  {:<22} {:>10} {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+                request_type,
+                sorted.len(),
+                millis(50.0),
+                millis(90.0),
+                millis(99.0),
+                millis(100.0)
+            );
+        }
+
+        let _ = write!(report, "Unanswered requests: {}", self.timeouts);
+
+        report
+    }
+}
+
+/// Returns the value that `percentile` percent of the `sorted` values are less than or equal
+/// to (the nearest-rank method), or None if there are no values.
+pub fn percentile_of(sorted: &[Duration], percentile: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let sorted = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+
+        assert_eq!(
+            percentile_of(&sorted, 50.0),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            percentile_of(&sorted, 99.0),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(
+            percentile_of(&sorted, 100.0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(percentile_of(&sorted, 0.0), Some(Duration::from_millis(1)));
+        assert_eq!(
+            percentile_of(&sorted[..1], 90.0),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(percentile_of(&[], 50.0), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut stats = Stats::default();
+        stats.record_sent("geo-position-update");
+        stats.record_sent("reserve-seat");
+        stats.record_received("passenger-info", 500_000);
+        stats.record_latency("reserve-seat", Duration::from_millis(12));
+
+        let report = stats.report(Duration::from_secs(2));
+
+        assert!(report.contains("Sent: 2 messages (1.0/s)"), "{}", report);
+        assert!(
+            report.contains("Received: 1 frames (0.5/s), 0.5 MB"),
+            "{}",
+            report
+        );
+        assert!(report.contains("reserve-seat"), "{}", report);
+        assert!(report.contains("12.0"), "{}", report);
+    }
+}
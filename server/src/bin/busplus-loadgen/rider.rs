@@ -0,0 +1,474 @@
+//! A simulated rider that moves around the region and uses the app the way a real rider does.
+//!
+//! Every rider has a WebSocket connection of its own. After every move it tells the server
+//! where it is, and now and then it asks about the route of a vehicle it can see or reserves a
+//! seat on one. A rider has at most one request waiting for an answer, so that answers can be
+//! told apart by their type and the vehicle they are about.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix_codec::Framed;
+use awc::ws::{Codec, Frame, Message};
+use awc::BoxedSocket;
+use bus_plus::protocol::client_protocol::{
+    ClientInput, GeoPosition, GeoPositionPoint, GeometryFormat, IdentifierKind,
+    RouteInformationRequest, VehicleDescriptor,
+};
+use futures::{SinkExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use tokio::time::{delay_for, timeout};
+
+use crate::report::Stats;
+use crate::Region;
+
+/// How fast (in metres per second) riders move.
+const SPEED: f64 = 10.0;
+
+/// The number of metres in a degree of latitude.
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+/// The largest WebSocket frame that is accepted from the server, which is reached when a rider
+/// can see a lot of vehicles.
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// How the riders behave.
+#[derive(Debug, Clone)]
+pub struct RiderSettings {
+    /// The WebSocket endpoint of the server.
+    pub url: String,
+
+    /// The area that riders move around in.
+    pub region: Region,
+
+    /// The time between two moves of a rider.
+    pub interval: Duration,
+
+    /// The lines that riders ask about when they cannot see any vehicles.
+    pub lines: Vec<String>,
+
+    /// How far (in metres) from the rider vehicles are shown.
+    pub view_distance: f32,
+
+    /// The probability that a rider asks about a route after a move.
+    pub route_chance: f64,
+
+    /// The probability that a rider reserves or gives up a seat after a move.
+    pub reserve_chance: f64,
+
+    /// How long a rider waits for an answer before it is counted as unanswered.
+    pub response_timeout: Duration,
+}
+
+/// A request that is waiting for an answer, with the vehicle that it is about.
+#[derive(Debug, Clone, PartialEq)]
+enum Request {
+    RouteInfo,
+    PassengerInfo(String),
+    ReserveSeat(String),
+    UnreserveSeat(String),
+}
+
+impl Request {
+    /// Returns the message type of the request.
+    fn message_type(&self) -> &'static str {
+        match self {
+            Request::RouteInfo => "get-route-info",
+            Request::PassengerInfo(_) => "get-passenger-info",
+            Request::ReserveSeat(_) => "reserve-seat",
+            Request::UnreserveSeat(_) => "unreserve-seat",
+        }
+    }
+
+    /// Returns the descriptor id of the vehicle that the request is about, if any.
+    fn descriptor_id(&self) -> Option<&str> {
+        match self {
+            Request::RouteInfo => None,
+            Request::PassengerInfo(descriptor_id)
+            | Request::ReserveSeat(descriptor_id)
+            | Request::UnreserveSeat(descriptor_id) => Some(descriptor_id),
+        }
+    }
+
+    /// Returns true if `output` answers the request.
+    ///
+    /// Passenger information is also sent whenever someone reserves a seat on the vehicle that
+    /// the rider last asked about, so it only answers requests about the same vehicle.
+    fn is_answered_by(&self, output: &Value) -> bool {
+        let error_type = output["payload"]["errorType"].as_str();
+
+        match (self, output["type"].as_str().unwrap_or("invalid")) {
+            (Request::RouteInfo, "route-info") => true,
+            (Request::RouteInfo, "error") => error_type == Some("ROUTE_INFO"),
+            (_, "passenger-info") => {
+                self.descriptor_id().is_some()
+                    && output["payload"]["descriptorId"].as_str() == self.descriptor_id()
+            }
+            (Request::ReserveSeat(_), "error") => error_type == Some("RESERVE"),
+            (Request::UnreserveSeat(_), "error") => error_type == Some("UNRESERVE"),
+            _ => false,
+        }
+    }
+}
+
+/// A vehicle that the rider can see on their map.
+#[derive(Debug, Clone)]
+struct SeenVehicle {
+    descriptor_id: String,
+    line: Option<String>,
+}
+
+/// The state of a single rider.
+struct Rider {
+    settings: Rc<RiderSettings>,
+    stats: Rc<RefCell<Stats>>,
+    rng: StdRng,
+
+    /// Where the rider is, as (latitude, longitude).
+    position: (f64, f64),
+
+    /// The direction the rider is moving in, in radians from north.
+    heading: f64,
+
+    /// The vehicles in the latest vehicle positions.
+    seen: Vec<SeenVehicle>,
+
+    /// The request that is waiting for an answer, and when it was sent.
+    pending: Option<(Request, Instant)>,
+
+    /// The vehicle that the rider is about to reserve a seat on, once it knows about it.
+    reserving: Option<String>,
+
+    /// The vehicle that the rider has reserved a seat on.
+    reserved: Option<String>,
+}
+
+/// Connects a rider after `delay` and lets it ride until `until`.
+pub async fn ride(
+    settings: Rc<RiderSettings>,
+    stats: Rc<RefCell<Stats>>,
+    delay: Duration,
+    until: Instant,
+) {
+    delay_for(delay).await;
+
+    let connection = awc::Client::new()
+        .ws(&settings.url)
+        .max_frame_size(MAX_FRAME_SIZE)
+        .connect()
+        .await;
+
+    let mut connection = match connection {
+        Ok((_, connection)) => connection,
+        Err(_) => {
+            stats.borrow_mut().connect_failures += 1;
+            return;
+        }
+    };
+
+    stats.borrow_mut().rider_connected();
+
+    let mut rng = StdRng::from_entropy();
+    let region = settings.region;
+
+    let mut rider = Rider {
+        position: (
+            rng.gen_range(region.south..region.north),
+            rng.gen_range(region.west..region.east),
+        ),
+        heading: rng.gen_range(0.0..std::f64::consts::TAU),
+        rng,
+        settings,
+        stats: stats.clone(),
+        seen: Vec::new(),
+        pending: None,
+        reserving: None,
+        reserved: None,
+    };
+
+    let completed = rider.run(&mut connection, until).await;
+
+    stats.borrow_mut().rider_left();
+
+    match completed {
+        Ok(()) => {
+            let _ = connection.send(Message::Close(None)).await;
+        }
+        Err(()) => stats.borrow_mut().disconnects += 1,
+    }
+}
+
+impl Rider {
+    /// Moves around and reads frames until `until`. Fails if the connection is lost.
+    async fn run(
+        &mut self,
+        connection: &mut Framed<BoxedSocket, Codec>,
+        until: Instant,
+    ) -> Result<(), ()> {
+        // Riders do not move in step with each other.
+        let mut next_move = Instant::now() + self.settings.interval.mul_f64(self.rng.gen());
+
+        loop {
+            let now = Instant::now();
+
+            if now >= until {
+                return Ok(());
+            }
+
+            if now >= next_move {
+                self.act(connection).await?;
+                next_move += self.settings.interval;
+                continue;
+            }
+
+            let frame = match timeout(next_move.min(until) - now, connection.next()).await {
+                Ok(Some(Ok(frame))) => frame,
+                Ok(_) => return Err(()),
+                Err(_) => continue,
+            };
+
+            match frame {
+                Frame::Text(text) => self.handle_text(connection, &text).await?,
+                Frame::Ping(message) => {
+                    // The server disconnects clients that do not answer its pings.
+                    send(connection, Message::Pong(message)).await?;
+                }
+                Frame::Close(_) => return Err(()),
+                _ => (),
+            }
+        }
+    }
+
+    /// Moves the rider, tells the server, and maybe makes a request.
+    async fn act(&mut self, connection: &mut Framed<BoxedSocket, Codec>) -> Result<(), ()> {
+        if let Some((_, sent)) = &self.pending {
+            if sent.elapsed() > self.settings.response_timeout {
+                self.stats.borrow_mut().timeouts += 1;
+                self.pending = None;
+                self.reserving = None;
+            }
+        }
+
+        self.step();
+
+        let (lat, lng) = self.position;
+        let update = ClientInput::GeoPositionUpdate(GeoPosition {
+            max_distance: self.settings.view_distance,
+            position: GeoPositionPoint {
+                position_type: "Point".to_owned(),
+                coordinates: vec![lat as f32, lng as f32],
+            },
+        });
+        self.send(connection, update).await?;
+
+        if self.pending.is_some() {
+            return Ok(());
+        }
+
+        if self.rng.gen_bool(self.settings.route_chance) {
+            if let Some(request) = self.route_request() {
+                self.request(connection, Request::RouteInfo, request)
+                    .await?;
+            }
+        } else if self.rng.gen_bool(self.settings.reserve_chance) {
+            match self.reserved.take() {
+                Some(descriptor_id) => {
+                    self.request(
+                        connection,
+                        Request::UnreserveSeat(descriptor_id),
+                        ClientInput::UnreserveSeat,
+                    )
+                    .await?
+                }
+                None => {
+                    // Riders look at the passenger information before they reserve a seat,
+                    // which also makes the server send them updates about the vehicle.
+                    if let Some(vehicle) = self.seen.choose(&mut self.rng) {
+                        let descriptor_id = vehicle.descriptor_id.clone();
+                        self.reserving = Some(descriptor_id.clone());
+
+                        let request = ClientInput::GetPassengerInformation(VehicleDescriptor {
+                            descriptor_id: descriptor_id.clone(),
+                        });
+                        self.request(connection, Request::PassengerInfo(descriptor_id), request)
+                            .await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the rider as far as it gets in one interval, turning back at the edges of the
+    /// region.
+    fn step(&mut self) {
+        let region = self.settings.region;
+        let distance = SPEED * self.settings.interval.as_secs_f64();
+        let (lat, lng) = self.position;
+
+        self.heading += self.rng.gen_range(-0.5..0.5);
+
+        let mut next_lat = lat + distance * self.heading.cos() / METRES_PER_DEGREE;
+        let mut next_lng =
+            lng + distance * self.heading.sin() / (METRES_PER_DEGREE * lat.to_radians().cos());
+
+        if !(region.south..=region.north).contains(&next_lat)
+            || !(region.west..=region.east).contains(&next_lng)
+        {
+            self.heading += std::f64::consts::PI;
+            next_lat = lat;
+            next_lng = lng;
+        }
+
+        self.position = (next_lat, next_lng);
+    }
+
+    /// Picks a route to ask about: the line of a vehicle that the rider can see, or the
+    /// vehicle itself if its line is unknown. Riders that cannot see any vehicles ask about
+    /// one of the lines in the settings.
+    fn route_request(&mut self) -> Option<ClientInput> {
+        let (id, kind) = match self.seen.choose(&mut self.rng) {
+            Some(SeenVehicle {
+                line: Some(line), ..
+            }) => (line.clone(), IdentifierKind::Line),
+            Some(vehicle) => (vehicle.descriptor_id.clone(), IdentifierKind::DescriptorId),
+            None => (
+                self.settings.lines.choose(&mut self.rng)?.clone(),
+                IdentifierKind::Line,
+            ),
+        };
+
+        Some(ClientInput::GetRouteInformation(RouteInformationRequest {
+            id,
+            kind: Some(kind),
+            format: GeometryFormat::Polyline,
+            zoom: Some(self.rng.gen_range(10..17)),
+        }))
+    }
+
+    /// Sends a request and waits for its answer in `handle_text`.
+    async fn request(
+        &mut self,
+        connection: &mut Framed<BoxedSocket, Codec>,
+        request: Request,
+        input: ClientInput,
+    ) -> Result<(), ()> {
+        self.pending = Some((request, Instant::now()));
+        self.send(connection, input).await
+    }
+
+    async fn send(
+        &mut self,
+        connection: &mut Framed<BoxedSocket, Codec>,
+        input: ClientInput,
+    ) -> Result<(), ()> {
+        self.stats.borrow_mut().record_sent(input.message_type());
+
+        let text = serde_json::to_string(&input).unwrap();
+        send(connection, Message::Text(text)).await
+    }
+
+    /// Handles a message from the server.
+    async fn handle_text(
+        &mut self,
+        connection: &mut Framed<BoxedSocket, Codec>,
+        text: &[u8],
+    ) -> Result<(), ()> {
+        let output: Value = serde_json::from_slice(text).unwrap_or_default();
+        let message_type = output["type"].as_str().unwrap_or("invalid");
+
+        self.stats
+            .borrow_mut()
+            .record_received(message_type, text.len());
+
+        if message_type == "vehicle-positions" {
+            self.seen = seen_vehicles(&output);
+        }
+
+        let (request, sent) = match self.pending.take() {
+            Some((request, sent)) if request.is_answered_by(&output) => (request, sent),
+            pending => {
+                self.pending = pending;
+                return Ok(());
+            }
+        };
+
+        self.stats
+            .borrow_mut()
+            .record_latency(request.message_type(), sent.elapsed());
+
+        match (request, message_type) {
+            (Request::PassengerInfo(_), _) => {
+                if let Some(descriptor_id) = self.reserving.clone() {
+                    let input = ClientInput::ReserveSeat(VehicleDescriptor {
+                        descriptor_id: descriptor_id.clone(),
+                    });
+
+                    self.request(connection, Request::ReserveSeat(descriptor_id), input)
+                        .await?;
+                }
+            }
+            (Request::ReserveSeat(_), "passenger-info") => self.reserved = self.reserving.take(),
+            (Request::ReserveSeat(_), _) => self.reserving = None,
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the vehicles in a "vehicle-positions" message.
+fn seen_vehicles(output: &Value) -> Vec<SeenVehicle> {
+    output["payload"]["vehicles"]
+        .as_array()
+        .map(|vehicles| {
+            vehicles
+                .iter()
+                .filter_map(|vehicle| {
+                    Some(SeenVehicle {
+                        descriptor_id: vehicle["descriptorId"].as_str()?.to_owned(),
+                        line: vehicle["line"].as_str().map(str::to_owned),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+async fn send(connection: &mut Framed<BoxedSocket, Codec>, message: Message) -> Result<(), ()> {
+    connection.send(message).await.map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_answered_by() {
+        let passenger_info = |descriptor_id: &str| {
+            json!({
+                "type": "passenger-info",
+                "payload": {"descriptorId": descriptor_id, "passengers": 10, "capacity": 30},
+            })
+        };
+        let error = |error_type: &str| json!({"type": "error", "payload": {"errorType": error_type, "errorMessage": ""}});
+
+        let request = Request::PassengerInfo("ul:9031003".to_owned());
+        assert!(request.is_answered_by(&passenger_info("ul:9031003")));
+
+        // Updates about other vehicles that the rider has watched are not answers.
+        assert!(!request.is_answered_by(&passenger_info("ul:9031004")));
+        assert!(!Request::RouteInfo.is_answered_by(&passenger_info("ul:9031003")));
+
+        let request = Request::ReserveSeat("ul:9031003".to_owned());
+        assert!(request.is_answered_by(&error("RESERVE")));
+        assert!(!request.is_answered_by(&error("UNRESERVE")));
+        assert!(Request::RouteInfo.is_answered_by(&error("ROUTE_INFO")));
+    }
+}
@@ -1,41 +1,53 @@
 //! Information about individual clients connected to the lobby.
+//!
+//! Messages are sent to a client without waiting for its mailbox to have room, so that a
+//! client on a slow connection cannot hold up everyone else. When the mailbox is full, which
+//! happens when the client's connection cannot keep up, messages are dropped, except for
+//! the latest vehicle positions which are kept until there is room for them.
 
-use uuid::Uuid;
+use std::time::{Duration, Instant};
+
+use actix::prelude::{Recipient, SendError};
 
 use crate::lobby::Socket;
+use crate::messages::{CloseConnection, WsMessage};
+use crate::metrics::DROPPED_MESSAGES;
 use crate::protocol::client_protocol::GeoPosition;
 
 /// State for a WebsocketClient. Holds information specific to each connection.
 #[derive(Debug)]
 pub struct ClientData {
-    /// Unique id.
-    pub id: Uuid,
-
     /// An address to communicate with the client actor.
     pub addr: Socket,
 
+    /// An address to tell the client actor to close the connection.
+    pub close: Recipient<CloseConnection>,
+
     /// Where the client is currently positioned on their map. Used to send
     /// relevant data to each individual client.
     pub position: Option<GeoPosition>,
 
-    /// None if the client has not reserved a seat on a bus, Some with a descriptor_id
-    /// if the client has reserved a seat.
-    pub reserved_seat: Option<String>,
-
     /// Keeps track of the last descriptor id that the client has made any request for/on.
     /// This is used to be able to send the client updates to the descriptor id when they happen.
     pub last_descriptor_request: Option<String>,
+
+    /// The latest vehicle positions, if they could not be sent because the mailbox was full.
+    pending_positions: Option<String>,
+
+    /// When the client's mailbox became full, if it still is.
+    congested_since: Option<Instant>,
 }
 
 impl ClientData {
-    /// Constructs a new client with no position and no reserved seat.
-    pub fn new(id: Uuid, addr: Socket) -> Self {
+    /// Constructs a new client with no position.
+    pub fn new(addr: Socket, close: Recipient<CloseConnection>) -> Self {
         ClientData {
-            id,
             addr,
+            close,
             position: None,
-            reserved_seat: None,
             last_descriptor_request: None,
+            pending_positions: None,
+            congested_since: None,
         }
     }
 
@@ -48,4 +60,115 @@ impl ClientData {
     pub fn update_last_descriptor(&mut self, descriptor_id: String) {
         self.last_descriptor_request = Some(descriptor_id);
     }
+
+    /// Sends a message to the client, or drops it if the client's mailbox is full.
+    pub fn send(&mut self, message: String) {
+        if let Err(WsMessage(_)) = self.try_send(message) {
+            DROPPED_MESSAGES.with_label_values(&["mailbox_full"]).inc();
+        }
+    }
+
+    /// Sends the latest vehicle positions to the client. If the client's mailbox is full they
+    /// replace any positions that are waiting to be sent, and are sent by `flush()`.
+    pub fn send_vehicle_positions(&mut self, message: String) {
+        if self.pending_positions.replace(message).is_some() {
+            DROPPED_MESSAGES.with_label_values(&["coalesced"]).inc();
+        }
+
+        self.flush();
+    }
+
+    /// Sends the vehicle positions that are waiting to be sent, if there is room for them.
+    pub fn flush(&mut self) {
+        if let Some(message) = self.pending_positions.take() {
+            if let Err(WsMessage(message)) = self.try_send(message) {
+                self.pending_positions = Some(message);
+            }
+        }
+    }
+
+    /// Returns true if the client's mailbox has been full for longer than `timeout`.
+    pub fn is_slow(&self, timeout: Duration) -> bool {
+        self.congested_since
+            .is_some_and(|since| since.elapsed() > timeout)
+    }
+
+    /// Tries to put a message in the client's mailbox, and gives it back if it is full.
+    fn try_send(&mut self, message: String) -> Result<(), WsMessage> {
+        match self.addr.try_send(WsMessage(message)) {
+            Ok(()) => {
+                self.congested_since = None;
+                Ok(())
+            }
+            Err(SendError::Full(message)) => {
+                self.congested_since.get_or_insert_with(Instant::now);
+                Err(message)
+            }
+            // The client has disconnected and is about to be unsubscribed.
+            Err(SendError::Closed(_)) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use actix::prelude::{Actor, Context, Handler};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    /// Stands in for a WebsocketClient and forwards every message it receives to a channel.
+    pub struct TestClient(pub UnboundedSender<String>);
+
+    impl Actor for TestClient {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for TestClient {
+        type Result = ();
+
+        fn handle(&mut self, msg: WsMessage, _: &mut Context<Self>) {
+            let _ = self.0.send(msg.0);
+        }
+    }
+
+    impl Handler<CloseConnection> for TestClient {
+        type Result = ();
+
+        fn handle(&mut self, _: CloseConnection, _: &mut Context<Self>) {}
+    }
+
+    #[actix_rt::test]
+    async fn test_only_latest_positions_are_kept() {
+        let (sender, mut messages) = unbounded_channel();
+
+        let addr = TestClient::create(|ctx| {
+            ctx.set_mailbox_capacity(2);
+            TestClient(sender)
+        });
+
+        let mut client = ClientData::new(addr.clone().recipient(), addr.recipient());
+
+        // The client does not get to read its mailbox until the test waits for something, so
+        // it fills up.
+        for i in 0..10 {
+            client.send_vehicle_positions(i.to_string());
+        }
+        client.send("passenger-info".to_owned());
+
+        assert_eq!(client.pending_positions.as_deref(), Some("9"));
+        assert!(client.is_slow(Duration::from_secs(0)));
+        assert!(!client.is_slow(Duration::from_secs(60)));
+
+        // Once the client has caught up the latest positions are sent, and nothing in between.
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            received.push(messages.recv().await.unwrap());
+        }
+        client.flush();
+        received.push(messages.recv().await.unwrap());
+
+        assert_eq!(received, vec!["0", "1", "9"]);
+        assert!(client.pending_positions.is_none());
+        assert!(!client.is_slow(Duration::from_secs(0)));
+    }
 }
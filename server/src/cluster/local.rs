@@ -0,0 +1,121 @@
+//! An event bus and leases that live in memory.
+//!
+//! These are used when a single instance is running, and stand in for the database in tests.
+//! Clones share the same channel and leases, so several lobbies in the same process behave
+//! like separate instances.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::stream::StreamExt;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::cluster::{ClusterEvent, EventBus, EventStream, LeaseFuture, Leases};
+
+/// The number of events a subscriber can fall behind before it starts missing events.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// An event bus within a single process.
+#[derive(Clone)]
+pub struct LocalBus {
+    sender: broadcast::Sender<ClusterEvent>,
+}
+
+impl LocalBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        LocalBus { sender }
+    }
+}
+
+impl EventBus for LocalBus {
+    fn publish(&self, event: ClusterEvent) {
+        // Sending only fails if nobody is subscribed, in which case nobody cares.
+        let _ = self.sender.send(event);
+    }
+
+    fn subscribe(&self) -> EventStream {
+        Box::pin(
+            self.sender
+                .subscribe()
+                .into_stream()
+                .filter_map(|event| match event {
+                    Ok(event) => Some(event),
+                    Err(err) => {
+                        warn!("Missed events from the event bus. Reason: {:?}", err);
+                        None
+                    }
+                }),
+        )
+    }
+}
+
+/// Leases within a single process.
+#[derive(Clone, Default)]
+pub struct LocalLeases {
+    /// Maps lease names to their holder and when the lease expires.
+    leases: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+}
+
+impl LocalLeases {
+    pub fn new() -> Self {
+        LocalLeases::default()
+    }
+}
+
+impl Leases for LocalLeases {
+    fn acquire(&self, name: &str, holder: &str, ttl: Duration) -> LeaseFuture {
+        let now = Instant::now();
+        let mut leases = self.leases.lock().unwrap();
+
+        let acquired = match leases.get(name) {
+            Some((current_holder, expires)) => current_holder == holder || *expires <= now,
+            None => true,
+        };
+
+        if acquired {
+            leases.insert(name.to_owned(), (holder.to_owned(), now + ttl));
+        }
+
+        Box::pin(async move { acquired })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_leases() {
+        let leases = LocalLeases::new();
+        let ttl = Duration::from_millis(50);
+
+        assert!(leases.acquire("feed", "a", ttl).await);
+        assert!(!leases.acquire("feed", "b", ttl).await);
+        assert!(leases.acquire("other", "b", ttl).await);
+
+        // The holder can renew the lease, but once it expires someone else can take it.
+        assert!(leases.acquire("feed", "a", ttl).await);
+        tokio::time::delay_for(ttl * 2).await;
+        assert!(leases.acquire("feed", "b", ttl).await);
+        assert!(!leases.acquire("feed", "a", ttl).await);
+    }
+
+    #[actix_rt::test]
+    async fn test_every_subscriber_gets_every_event() {
+        let bus = LocalBus::new();
+        let mut first = bus.subscribe();
+        let mut second = bus.clone().subscribe();
+
+        let event = ClusterEvent::PassengerInfoChanged {
+            descriptor_id: "ul:9031003".to_owned(),
+        };
+        bus.publish(event.clone());
+
+        assert_eq!(first.next().await, Some(event.clone()));
+        assert_eq!(second.next().await, Some(event));
+    }
+}
@@ -0,0 +1,127 @@
+//! State that is shared between server instances.
+//!
+//! Several instances can run behind a load balancer, so a rider's seat reservation has to
+//! reach riders on every instance. Passenger information and reservations are kept in a
+//! reservation store that every instance shares (see `store`), and are changed there with
+//! atomic updates. Afterwards a `ClusterEvent` is published on an event bus, which tells every
+//! instance (including the one that published it) to send the new passenger information to
+//! its clients. Events only tell about changes, so an instance that misses one still serves
+//! the right seat counts.
+//!
+//! Realtime feeds are only fetched by the instance that holds the feed's lease. The fetched
+//! feed is published on the bus, so every instance serves the same vehicles and reports the
+//! same feed status to its clients.
+//!
+//! With the "local" backend the bus and the leases live in memory, which is what a single
+//! instance (and the tests) use. The "mongodb" backend shares them through the database.
+
+mod local;
+mod mongo;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::stream::Stream;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::config::{ClusterBackend, ClusterSettings};
+use crate::database::DbConnection;
+use crate::gtfs::trafiklab::RealtimeFeed;
+
+pub use local::{LocalBus, LocalLeases};
+pub use mongo::{MongoBus, MongoLeases};
+
+/// A stream of every event that is published after subscribing.
+pub type EventStream = Pin<Box<dyn Stream<Item = ClusterEvent>>>;
+
+/// Resolves to true if the lease was acquired (or renewed).
+pub type LeaseFuture = Pin<Box<dyn Future<Output = bool>>>;
+
+/// Something that happened on one instance that every instance needs to know about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClusterEvent {
+    /// An operator's realtime feed has been fetched, or the fetch failed for the given reason.
+    RealtimeFeed {
+        operator: String,
+        result: Result<RealtimeFeed, String>,
+    },
+
+    /// An operator's TripUpdates feed has been fetched.
+    TripUpdates {
+        operator: String,
+        feed: RealtimeFeed,
+    },
+
+    /// The passenger information of a vehicle has changed in the reservation store.
+    PassengerInfoChanged { descriptor_id: String },
+}
+
+/// Sends events between every instance.
+pub trait EventBus {
+    /// Publishes an event to every instance, including this one.
+    fn publish(&self, event: ClusterEvent);
+
+    /// Returns every event that is published from now on, in the same order on every
+    /// instance.
+    fn subscribe(&self) -> EventStream;
+}
+
+/// Leases that make sure that only one instance does something at a time.
+pub trait Leases {
+    /// Acquires the lease `name` for `holder`, or renews it if `holder` already holds it.
+    /// Another holder can only acquire the lease once it has not been renewed for `ttl`.
+    fn acquire(&self, name: &str, holder: &str, ttl: Duration) -> LeaseFuture;
+}
+
+/// Everything an actor needs to share state with the other instances.
+#[derive(Clone)]
+pub struct Cluster {
+    /// Identifies this instance.
+    pub instance_id: String,
+
+    pub bus: Arc<dyn EventBus>,
+    pub leases: Arc<dyn Leases>,
+}
+
+impl Cluster {
+    /// Sets up the backend that is chosen in the settings. `db_connection` is only used (and
+    /// must only be given) by the "mongodb" backend.
+    pub async fn from_settings(
+        settings: &ClusterSettings,
+        db_connection: Option<&DbConnection>,
+    ) -> Self {
+        let instance_id = settings
+            .instance_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        info!(%instance_id, backend = ?settings.backend, "Starting cluster backend.");
+
+        match settings.backend {
+            ClusterBackend::Local => Cluster::local(&instance_id),
+            ClusterBackend::Mongodb => {
+                let database = db_connection
+                    .expect("The mongodb backend needs a database connection")
+                    .database(&settings.database);
+
+                Cluster {
+                    instance_id,
+                    bus: Arc::new(MongoBus::new(&database, settings.event_log_size).await),
+                    leases: Arc::new(MongoLeases::new(&database)),
+                }
+            }
+        }
+    }
+
+    /// A cluster with a single instance, where everything is kept in memory.
+    pub fn local(instance_id: &str) -> Self {
+        Cluster {
+            instance_id: instance_id.to_owned(),
+            bus: Arc::new(LocalBus::new()),
+            leases: Arc::new(LocalLeases::new()),
+        }
+    }
+}
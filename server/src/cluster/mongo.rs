@@ -0,0 +1,413 @@
+//! An event bus and leases that are shared through MongoDB.
+//!
+//! The MongoDB driver that is used does not support change streams, so events are sent
+//! through a capped collection instead, which every instance reads with a tailable cursor.
+//! A capped collection keeps its documents in the order they were inserted, which means that
+//! every instance reads the events in the same order. It also removes the oldest documents
+//! when it is full, so the collection never has to be cleaned up.
+//!
+//! Events are kept small so that the capped collection holds many of them. Realtime feeds can
+//! be several megabytes, so only the latest feeds of every operator are kept in a separate
+//! collection, and the event refers to it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{doc, Binary, Document};
+use mongodb::options::{
+    CreateCollectionOptions, CursorType, FindOneAndUpdateOptions, FindOptions, ReplaceOptions,
+};
+use mongodb::{Collection, Database};
+use tokio::stream::StreamExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::delay_for;
+use tracing::{debug, error, warn};
+
+use crate::cluster::{ClusterEvent, EventBus, EventStream, LeaseFuture, Leases};
+use crate::gtfs::trafiklab::RealtimeFeed;
+
+/// The capped collection that events are sent through.
+const EVENTS_COLLECTION: &str = "events";
+
+/// The collection with the latest realtime feeds of every operator, by `feed_key()`.
+const FEEDS_COLLECTION: &str = "feeds";
+
+/// The collection that contains a document for every lease.
+const LEASES_COLLECTION: &str = "leases";
+
+/// How long to wait before reading events again after the cursor has been interrupted.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// An event bus that is shared by every instance that uses the same database.
+pub struct MongoBus {
+    events: Collection,
+    feeds: Collection,
+
+    /// Events are inserted one at a time by a background task, so that the events from this
+    /// instance are stored in the order they were published.
+    outbox: UnboundedSender<ClusterEvent>,
+}
+
+impl MongoBus {
+    /// Creates the events collection with room for `size` bytes, unless it already exists.
+    pub async fn new(database: &Database, size: u64) -> Self {
+        let options = CreateCollectionOptions::builder()
+            .capped(true)
+            .size(size as i64)
+            .build();
+
+        // This fails if the collection already exists, which is expected for every instance
+        // but the first one.
+        if let Err(err) = database.create_collection(EVENTS_COLLECTION, options).await {
+            debug!("Did not create the events collection. Reason: {}", err);
+        }
+
+        let events = database.collection(EVENTS_COLLECTION);
+        let feeds = database.collection(FEEDS_COLLECTION);
+        let (outbox, mut queue) = unbounded_channel::<ClusterEvent>();
+
+        let (collection, feed_collection) = (events.clone(), feeds.clone());
+        actix::spawn(async move {
+            while let Some(event) = queue.recv().await {
+                let (document, feed) = encode(&event);
+
+                // The feed has to be stored before the event that refers to it.
+                if let Some(feed) = feed {
+                    let filter = doc! {"_id": feed.get_str("_id").unwrap_or_default()};
+                    let options = ReplaceOptions::builder().upsert(true).build();
+
+                    if let Err(err) = feed_collection.replace_one(filter, feed, options).await {
+                        error!("Could not publish a realtime feed. Reason: {}", err);
+                        continue;
+                    }
+                }
+
+                if let Err(err) = collection.insert_one(document, None).await {
+                    error!("Could not publish an event. Reason: {}", err);
+                }
+            }
+        });
+
+        MongoBus {
+            events,
+            feeds,
+            outbox,
+        }
+    }
+}
+
+impl EventBus for MongoBus {
+    fn publish(&self, event: ClusterEvent) {
+        let _ = self.outbox.send(event);
+    }
+
+    fn subscribe(&self) -> EventStream {
+        let (sender, receiver) = unbounded_channel();
+
+        actix::spawn(tail(self.events.clone(), self.feeds.clone(), sender));
+
+        Box::pin(receiver)
+    }
+}
+
+/// Reads events from the events collection and sends them to `sender` until it is closed.
+async fn tail(events: Collection, feeds: Collection, sender: UnboundedSender<ClusterEvent>) {
+    // The last document that was read, where reading continues if the cursor is interrupted.
+    let mut last_read: Option<ObjectId> = None;
+
+    loop {
+        // The last document is gone if the collection has been full since it was read.
+        if let Some(id) = &last_read {
+            if let Ok(None) = events.find_one(doc! {"_id": id.clone()}, None).await {
+                warn!("Events were removed before they could be read, some were missed.");
+                last_read = None;
+            }
+        }
+
+        // A tailable cursor starts at the oldest document, so a marker is inserted to know
+        // where the events that are published after subscribing begin.
+        let marker = match &last_read {
+            Some(id) => id.clone(),
+            None => {
+                let marker = ObjectId::new();
+
+                if let Err(err) = events
+                    .insert_one(doc! {"_id": marker.clone(), "type": "subscribed"}, None)
+                    .await
+                {
+                    warn!("Could not subscribe to the event bus. Reason: {}", err);
+                    delay_for(RESUBSCRIBE_DELAY).await;
+                    continue;
+                }
+
+                marker
+            }
+        };
+        last_read = Some(marker.clone());
+
+        let options = FindOptions::builder()
+            .cursor_type(CursorType::TailableAwait)
+            .build();
+
+        let mut cursor = match events.find(None, options).await {
+            Ok(cursor) => cursor,
+            Err(err) => {
+                warn!("Could not read from the event bus. Reason: {}", err);
+                delay_for(RESUBSCRIBE_DELAY).await;
+                continue;
+            }
+        };
+
+        let mut after_marker = false;
+
+        while let Some(document) = cursor.next().await {
+            let document = match document {
+                Ok(document) => document,
+                Err(err) => {
+                    warn!("Could not read from the event bus. Reason: {}", err);
+                    break;
+                }
+            };
+
+            if !after_marker {
+                after_marker = document.get_object_id("_id").ok() == Some(&marker);
+                continue;
+            }
+
+            if let Ok(id) = document.get_object_id("_id") {
+                last_read = Some(id.clone());
+            }
+
+            // Events about fetched feeds refer to the feed in the feeds collection.
+            let feed = match (
+                document.get_str("feed_key"),
+                document.get_object_id("feed_id"),
+            ) {
+                (Ok(feed_key), Ok(_)) => feeds
+                    .find_one(doc! {"_id": feed_key}, None)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("Could not read a realtime feed. Reason: {}", err);
+                        None
+                    }),
+                _ => None,
+            };
+
+            if let Some(event) = decode(&document, feed.as_ref()) {
+                if sender.send(event).is_err() {
+                    // Nobody is listening anymore.
+                    return;
+                }
+            }
+        }
+
+        warn!("Reading from the event bus was interrupted, continuing where it stopped.");
+        delay_for(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+/// Converts an event to the document that is stored in the events collection. A fetched feed
+/// is returned as a separate document for the feeds collection, which the event refers to.
+fn encode(event: &ClusterEvent) -> (Document, Option<Document>) {
+    match event {
+        ClusterEvent::RealtimeFeed {
+            operator,
+            result: Ok(feed),
+        } => encode_feed("realtime-feed", operator, feed),
+        ClusterEvent::RealtimeFeed {
+            operator,
+            result: Err(reason),
+        } => (
+            doc! {
+                "type": "realtime-feed",
+                "operator": operator,
+                "error": reason,
+            },
+            None,
+        ),
+        ClusterEvent::TripUpdates { operator, feed } => encode_feed("trip-updates", operator, feed),
+        ClusterEvent::PassengerInfoChanged { descriptor_id } => (
+            doc! {
+                "type": "passenger-info-changed",
+                "descriptor_id": descriptor_id,
+            },
+            None,
+        ),
+    }
+}
+
+/// Converts an event of type `event_type` about a fetched feed to an event that refers to a
+/// document in the feeds collection, and that document.
+fn encode_feed(
+    event_type: &str,
+    operator: &str,
+    feed: &RealtimeFeed,
+) -> (Document, Option<Document>) {
+    let feed_id = ObjectId::new();
+    let feed_key = feed_key(event_type, operator);
+
+    let event = doc! {
+        "type": event_type,
+        "operator": operator,
+        "feed_key": &feed_key,
+        "feed_id": feed_id.clone(),
+    };
+    let feed = doc! {
+        "_id": feed_key,
+        "feed_id": feed_id,
+        "feed": Binary { subtype: BinarySubtype::Generic, bytes: feed.as_bytes().to_vec() },
+    };
+
+    (event, Some(feed))
+}
+
+/// Returns the id of the document in the feeds collection that keeps an operator's latest
+/// feed for events of type `event_type`. The VehiclePositions feed is kept by the name of the
+/// operator.
+fn feed_key(event_type: &str, operator: &str) -> String {
+    match event_type {
+        "realtime-feed" => operator.to_owned(),
+        _ => format!("{}/{}", operator, event_type),
+    }
+}
+
+/// Converts a document from the events collection to an event, where `feed` is the document
+/// in the feeds collection that the event refers to. Returns None for documents that are not
+/// events, such as the markers inserted by `tail()`, and for feeds that have already been
+/// replaced by a newer one, which has an event of its own.
+fn decode(document: &Document, feed: Option<&Document>) -> Option<ClusterEvent> {
+    let string = |key: &str| document.get_str(key).ok().map(str::to_owned);
+
+    // Only the feed that the event refers to is decoded, and not a newer one.
+    let decode_feed = |feed_id| {
+        let feed = feed.filter(|feed| feed.get_object_id("feed_id") == Ok(feed_id))?;
+        let bytes = feed.get_binary_generic("feed").ok()?;
+
+        Some(RealtimeFeed::from_bytes(bytes.clone()).map_err(|err| err.to_string()))
+    };
+
+    let event = match document.get_str("type").ok()? {
+        "realtime-feed" => ClusterEvent::RealtimeFeed {
+            operator: string("operator")?,
+            result: match document.get_object_id("feed_id") {
+                Ok(feed_id) => decode_feed(feed_id)?,
+                Err(_) => Err(string("error")?),
+            },
+        },
+        "trip-updates" => ClusterEvent::TripUpdates {
+            operator: string("operator")?,
+            feed: decode_feed(document.get_object_id("feed_id").ok()?)?.ok()?,
+        },
+        "passenger-info-changed" => ClusterEvent::PassengerInfoChanged {
+            descriptor_id: string("descriptor_id")?,
+        },
+        _ => return None,
+    };
+
+    Some(event)
+}
+
+/// Leases that are shared by every instance that uses the same database.
+pub struct MongoLeases {
+    leases: Collection,
+}
+
+impl MongoLeases {
+    pub fn new(database: &Database) -> Self {
+        MongoLeases {
+            leases: database.collection(LEASES_COLLECTION),
+        }
+    }
+}
+
+impl Leases for MongoLeases {
+    fn acquire(&self, name: &str, holder: &str, ttl: Duration) -> LeaseFuture {
+        let leases = self.leases.clone();
+        let name = name.to_owned();
+        let holder = holder.to_owned();
+
+        Box::pin(async move {
+            // Expiry times are stored as milliseconds since the epoch, which assumes that the
+            // clocks of the instances roughly agree.
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64;
+
+            let filter = doc! {
+                "_id": &name,
+                "$or": [{"holder": &holder}, {"expires_at": {"$lte": now}}],
+            };
+            let update = doc! {
+                "$set": {"holder": &holder, "expires_at": now + ttl.as_millis() as i64},
+            };
+            let options = FindOneAndUpdateOptions::builder().upsert(true).build();
+
+            // If someone else holds the lease the filter matches nothing, and the upsert fails
+            // since a lease with the same name already exists.
+            leases
+                .find_one_and_update(filter, update, options)
+                .await
+                .is_ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::transit_realtime::FeedMessage;
+
+    #[test]
+    fn test_encode_and_decode() {
+        let mut message = FeedMessage::default();
+        message.header.timestamp = Some(1_600_000_000);
+        let feed = RealtimeFeed::from_message(&message);
+
+        let events = vec![
+            ClusterEvent::RealtimeFeed {
+                operator: "ul".to_owned(),
+                result: Err("Request timed out.".to_owned()),
+            },
+            ClusterEvent::PassengerInfoChanged {
+                descriptor_id: "ul:9031003".to_owned(),
+            },
+        ];
+
+        for event in events {
+            let (document, feed) = encode(&event);
+            assert_eq!(feed, None);
+            assert_eq!(decode(&document, None), Some(event));
+        }
+
+        // Fetched feeds are kept out of the events.
+        let event = ClusterEvent::RealtimeFeed {
+            operator: "ul".to_owned(),
+            result: Ok(feed),
+        };
+        let (document, feed) = encode(&event);
+        let feed = feed.unwrap();
+        assert!(!document.contains_key("feed"));
+        assert_eq!(feed.get_str("_id"), Ok("ul"));
+        assert_eq!(decode(&document, Some(&feed)), Some(event.clone()));
+
+        // Events about feeds that have been replaced by a newer one are skipped.
+        let (_, newer) = encode(&event);
+        assert_eq!(decode(&document, newer.as_ref()), None);
+        assert_eq!(decode(&document, None), None);
+
+        // Trip updates are kept apart from the vehicle positions of the same operator.
+        let event = ClusterEvent::TripUpdates {
+            operator: "ul".to_owned(),
+            feed: RealtimeFeed::from_message(&message),
+        };
+        let (document, feed) = encode(&event);
+        let feed = feed.unwrap();
+        assert_eq!(feed.get_str("_id"), Ok("ul/trip-updates"));
+        assert_eq!(decode(&document, Some(&feed)), Some(event));
+
+        // The markers that are inserted when subscribing are not events.
+        assert_eq!(decode(&doc! {"type": "subscribed"}, None), None);
+    }
+}
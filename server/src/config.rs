@@ -1,105 +1,1174 @@
-//! Utility for reading values from a config file.
+//! Typed settings for the server, read from a YAML config file.
+//!
+//! Any value in the config file can be overridden by an environment variable or a command line
+//! argument, in that order of precedence:
+//!
+//!     # Environment variable: BUSPLUS__<SECTION>__<KEY>
+//!     BUSPLUS__SERVER__BIND_ADDRESS=127.0.0.1:9000
+//!
+//!     # Command line argument: --set <section>.<key>=<value>
+//!     cargo run -- --set server.bind_address=127.0.0.1:9000
+//!
+//! The settings are validated when they are loaded, and every missing or invalid key is
+//! reported at once so that a broken config file can be fixed in one go.
 
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 
-use yaml_rust::{Yaml, YamlLoader};
+use chrono::NaiveDateTime;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use tracing_subscriber::filter::EnvFilter;
 
-/// The file path to the config file.
-pub const CONFIG_FILE_PATH: &str = "../config.yml";
+use crate::gtfs::operator::Operator;
 
-/// The YAML key where all trafiklab data is stored.
-/// Example:
-///
-///     trafiklab_api: <-- this is the key
-///         api_key: a12b34c567d89
-///
+/// The file path to the config file if no other path is given.
+const DEFAULT_CONFIG_FILE_PATH: &str = "../config.yml";
+
+/// Environment variable that can be used to set the path to the config file.
+const CONFIG_PATH_ENV_VAR: &str = "BUSPLUS_CONFIG";
+
+/// Prefix for environment variables that override values in the config file.
+const ENV_VAR_PREFIX: &str = "BUSPLUS__";
+
+/// Separates sections and keys in environment variables that override values.
+const ENV_VAR_SEPARATOR: &str = "__";
+
+/// The operator that is served when no operators are listed in the config file.
+const DEFAULT_OPERATOR: &str = "ul";
 
-const TRAFIKLAB_YAML_KEY: &str = "trafiklab_api";
-const DATABASE_YAML_KEY: &str = "database";
+/// The database containing static data for the default operator.
+const LEGACY_STATIC_DATABASE: &str = "trafiklab-static-data";
 
-/// Stores the parsed contents of a YAML config file.
-pub struct Config {
-    documents: Vec<Yaml>,
+/// Settings for the HTTP server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// The address the server listens on. "0.0.0.0" means that the server accepts requests
+    /// from any host (127.0.0.1, 192.168.x.x, etc..)
+    pub bind_address: String,
+
+    /// The number of worker threads. Defaults to the number of logical CPU cores.
+    pub workers: Option<usize>,
+
+    /// Which messages are logged. Either a level ("error", "warn", "info", "debug" or "trace")
+    /// or a list of filters such as "info,bus_plus::lobby=debug".
+    pub log_level: String,
+
+    /// How log messages are written.
+    pub log_format: LogFormat,
 }
 
-impl Config {
-    pub fn new() -> Self {
-        Config {
-            documents: Vec::new(),
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind_address: "0.0.0.0:8080".to_owned(),
+            workers: None,
+            log_level: "info".to_owned(),
+            log_format: LogFormat::Human,
         }
     }
+}
 
-    /// Loads the config from a file path and tries to parse it from YAML.
-    pub fn load_config(&mut self, config_path: &str) -> Result<(), String> {
-        // Read the contents of the config file into a string.
-        let config_file_contents = fs::read_to_string(Path::new(config_path));
+/// Formats for log messages.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Human,
+
+    /// One JSON object per line.
+    Json,
+}
+
+/// Settings for a single operator in the config file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperatorSettings {
+    pub name: String,
+    pub realtime_key: Option<String>,
+    pub static_key: Option<String>,
+    pub realtime_url: Option<String>,
+
+    /// URL to the operator's TripUpdates feed. Trafiklab's feed is used by default, unless
+    /// `realtime_url` points somewhere else, in which case no trip updates are fetched unless
+    /// this is set as well.
+    pub trip_updates_url: Option<String>,
+
+    pub static_url: Option<String>,
+    pub database: Option<String>,
+
+    /// Path to a GTFS zip with the operator's static data. The "memory" storage backend reads
+    /// it instead of the database, and the "sqlite" backend imports it when it has changed.
+    pub gtfs_path: Option<String>,
+}
+
+/// Settings for fetching data from Trafiklab's API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafiklabSettings {
+    pub realtime_key: Option<String>,
+    pub static_key: Option<String>,
+
+    /// How often (in seconds) vehicle positions are fetched and sent to all clients.
+    pub echo_interval: f64,
+
+    /// The operators to serve data for. Only UL is served if this is left out.
+    pub operators: Option<Vec<OperatorSettings>>,
+
+    /// How long (in seconds) we wait for a connection to the API to be established.
+    pub connect_timeout: f64,
+
+    /// How long (in seconds) we wait for a complete response once a request has been sent.
+    pub request_timeout: f64,
+
+    /// The delay (in seconds) before the first retry after a failed request.
+    pub backoff_base: f64,
+
+    /// The maximum delay (in seconds) between retries.
+    pub backoff_max: f64,
+
+    /// How many consecutive failures are allowed before requests are paused.
+    pub circuit_failure_threshold: u32,
+
+    /// How long (in seconds) requests are paused.
+    pub circuit_cooldown: f64,
+}
+
+impl Default for TrafiklabSettings {
+    fn default() -> Self {
+        TrafiklabSettings {
+            realtime_key: None,
+            static_key: None,
+            echo_interval: 2.0,
+            operators: None,
+            connect_timeout: 5.0,
+            request_timeout: 10.0,
+            backoff_base: 1.0,
+            backoff_max: 60.0,
+            circuit_failure_threshold: 5,
+            circuit_cooldown: 30.0,
+        }
+    }
+}
+
+impl TrafiklabSettings {
+    pub fn echo_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.echo_interval)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.connect_timeout)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.request_timeout)
+    }
+
+    pub fn backoff_base(&self) -> Duration {
+        Duration::from_secs_f64(self.backoff_base)
+    }
+
+    pub fn backoff_max(&self) -> Duration {
+        Duration::from_secs_f64(self.backoff_max)
+    }
+
+    pub fn circuit_cooldown(&self) -> Duration {
+        Duration::from_secs_f64(self.circuit_cooldown)
+    }
+}
+
+/// Settings for the database.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings {
+    pub uri: Option<String>,
+}
+
+/// Settings for the heartbeat between the server and each client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSettings {
+    /// How often (in seconds) heartbeat pings are sent.
+    pub interval: f64,
+
+    /// How long (in seconds) before lack of client response causes a timeout.
+    pub client_timeout: f64,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        HeartbeatSettings {
+            interval: 5.0,
+            client_timeout: 10.0,
+        }
+    }
+}
+
+impl HeartbeatSettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(self.interval)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.client_timeout)
+    }
+}
+
+/// Limits that protect the server from misbehaving clients and APIs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// The maximum distance (in metres) a client can request vehicles within.
+    pub max_view_distance: f32,
+
+    /// The maximum size (in bytes) of a WebSocket message from a client.
+    pub max_message_size: usize,
+
+    /// The maximum size (in bytes) of a response from the realtime API.
+    pub max_feed_size: usize,
+
+    /// The maximum number of messages that can wait to be sent to a client. Messages are
+    /// dropped when a client falls this far behind, except for the latest vehicle positions.
+    pub max_queued_messages: usize,
+
+    /// How long (in seconds) a client can be too slow to receive its messages before it is
+    /// disconnected.
+    pub slow_client_timeout: f64,
+}
+
+/// The largest feed (in bytes) that the "mongodb" cluster backend can share, since a feed is
+/// stored in a single document and MongoDB documents are at most 16 MiB including the ids
+/// that are stored with the feed.
+pub const MAX_SHARED_FEED_SIZE: usize = 16 * 1024 * 1024 - 64 * 1024;
+
+impl LimitSettings {
+    pub fn slow_client_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.slow_client_timeout)
+    }
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings {
+            max_view_distance: 20_000.0,
+            max_message_size: 64 * 1024,
+            max_feed_size: 15 * 1024 * 1024,
+            max_queued_messages: 32,
+            slow_client_timeout: 30.0,
+        }
+    }
+}
+
+/// How server instances share state with each other.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusterBackend {
+    /// Nothing is shared, which only works when a single instance is running.
+    Local,
+
+    /// State is shared through the MongoDB database, so several instances can run behind a
+    /// load balancer.
+    Mongodb,
+}
+
+/// Settings for running several instances of the server side by side.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterSettings {
+    /// How instances share state.
+    pub backend: ClusterBackend,
+
+    /// Identifies this instance in logs and leases. A random id is used if this is left out.
+    pub instance_id: Option<String>,
+
+    /// The database that shared state is stored in when the backend is "mongodb".
+    pub database: String,
+
+    /// The size (in bytes) of the collection that events between instances are sent through.
+    /// Old events are removed when it is full. Realtime feeds are kept in a collection of their
+    /// own, so events are small.
+    pub event_log_size: u64,
+
+    /// How long (in seconds) an instance may fetch a realtime feed without renewing its lease.
+    /// Another instance takes over if the lease is not renewed in time.
+    pub lease_ttl: f64,
+}
+
+impl Default for ClusterSettings {
+    fn default() -> Self {
+        ClusterSettings {
+            backend: ClusterBackend::Local,
+            instance_id: None,
+            database: "busplus".to_owned(),
+            event_log_size: 64 * 1024 * 1024,
+            lease_ttl: 10.0,
+        }
+    }
+}
+
+impl ClusterSettings {
+    pub fn lease_ttl(&self) -> Duration {
+        Duration::from_secs_f64(self.lease_ttl)
+    }
+}
+
+/// Where static data and reservations are kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Static data is read from each operator's MongoDB database.
+    #[default]
+    Mongodb,
+
+    /// Static data is read from each operator's GTFS zip when the server starts, and nothing
+    /// is stored outside the server.
+    Memory,
+
+    /// Static data and reservations are kept in a SQLite file, which each operator's GTFS zip
+    /// is imported into.
+    Sqlite,
+}
 
-        if config_file_contents.is_err() {
-            return Err(format!(
+/// Settings for storing data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+
+    /// The file that is used when the backend is "sqlite". It is created if it does not exist.
+    pub sqlite_path: String,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        StorageSettings {
+            backend: StorageBackend::Mongodb,
+            sqlite_path: "busplus.sqlite3".to_owned(),
+        }
+    }
+}
+
+/// Settings for the simulator, which serves feeds with simulated vehicles that drive
+/// according to the timetables in the operators' GTFS zips (see `gtfs::simulator`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulatorSettings {
+    /// Whether the simulated feeds are served at "/simulator/<operator>/VehiclePositions.pb".
+    pub enabled: bool,
+
+    /// How many times faster than real time the simulated clock runs.
+    pub speed: f64,
+
+    /// The simulated time when the server starts, as "YYYY-MM-DD HH:MM:SS" in the timezone of
+    /// the timetables. Defaults to the current local time.
+    pub start_time: Option<String>,
+
+    /// How late (in seconds) vehicles are on average. Negative values make them early.
+    pub delay: f64,
+
+    /// How much (in seconds) the delay of a trip may differ from `delay`, in either direction.
+    pub delay_variation: f64,
+}
+
+impl Default for SimulatorSettings {
+    fn default() -> Self {
+        SimulatorSettings {
+            enabled: false,
+            speed: 1.0,
+            start_time: None,
+            delay: 0.0,
+            delay_variation: 0.0,
+        }
+    }
+}
+
+impl SimulatorSettings {
+    /// The format of `start_time`.
+    pub const START_TIME_FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+
+    /// Returns the simulated time when the server starts, if it is set and valid.
+    pub fn start_time(&self) -> Option<NaiveDateTime> {
+        self.start_time.as_ref().and_then(|start_time| {
+            NaiveDateTime::parse_from_str(start_time, SimulatorSettings::START_TIME_FORMAT).ok()
+        })
+    }
+}
+
+/// Settings for the punctuality history, which records when trips actually arrive at their
+/// stops (see `lobby::history`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistorySettings {
+    /// Whether arrivals are recorded and on-time performance is served at "/api/punctuality".
+    pub enabled: bool,
+
+    /// How many days of arrivals are kept.
+    pub retention_days: u32,
+
+    /// How early and how late (in seconds) a trip can arrive at a stop and still be on time.
+    pub early_tolerance: u32,
+    pub late_tolerance: u32,
+
+    /// The database that arrivals are stored in when the storage backend is "mongodb".
+    pub database: String,
+
+    /// Whether crowding forecasts are made from the occupancy of the recorded arrivals.
+    pub forecasts: bool,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        HistorySettings {
+            enabled: false,
+            retention_days: 90,
+            early_tolerance: 60,
+            late_tolerance: 300,
+            database: "busplus".to_owned(),
+            forecasts: false,
+        }
+    }
+}
+
+/// All settings for the server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub trafiklab_api: TrafiklabSettings,
+    pub database: DatabaseSettings,
+    pub heartbeat: HeartbeatSettings,
+    pub limits: LimitSettings,
+    pub cluster: ClusterSettings,
+    pub storage: StorageSettings,
+    pub simulator: SimulatorSettings,
+    pub history: HistorySettings,
+}
+
+/// A list of everything that is wrong with the settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingsError(pub Vec<String>);
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "The configuration is invalid:")?;
+
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Options given on the command line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CliArgs {
+    /// Path to the config file.
+    pub config_path: Option<String>,
+
+    /// Overrides in the format "<section>.<key>=<value>".
+    pub overrides: Vec<String>,
+
+    /// Whether the usage should be printed instead of starting the server.
+    pub help: bool,
+}
+
+impl CliArgs {
+    /// Usage description for the command line arguments.
+    pub const USAGE: &'static str = "\
+Usage: bus_plus [OPTIONS]
+
+Options:
+  -c, --config <PATH>         Path to the config file (default: ../config.yml)
+  -s, --set <SECTION.KEY=VALUE>
+                              Overrides a value in the config file
+  -b, --bind <ADDRESS>        Shorthand for --set server.bind_address=<ADDRESS>
+  -h, --help                  Prints this message";
+
+    /// Returns the path to the config file, which is given either as an argument, by an
+    /// environment variable or falls back to `DEFAULT_CONFIG_FILE_PATH`.
+    pub fn config_path(&self) -> String {
+        self.config_path
+            .clone()
+            .or_else(|| env::var(CONFIG_PATH_ENV_VAR).ok())
+            .unwrap_or_else(|| DEFAULT_CONFIG_FILE_PATH.to_owned())
+    }
+
+    /// Parses the command line arguments of the process.
+    pub fn from_env() -> Result<Self, String> {
+        CliArgs::parse(env::args().skip(1))
+    }
+
+    /// Parses a list of command line arguments (without the name of the program).
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut result = CliArgs::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for argument '{}'.", name))
+            };
+
+            match arg.as_str() {
+                "-c" | "--config" => result.config_path = Some(value(&arg)?),
+                "-s" | "--set" => result.overrides.push(value(&arg)?),
+                "-b" | "--bind" => result
+                    .overrides
+                    .push(format!("server.bind_address={}", value(&arg)?)),
+                "-h" | "--help" => result.help = true,
+                _ => return Err(format!("Unknown argument '{}'.", arg)),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+impl Settings {
+    /// Loads the settings from the config file, applies overrides from environment variables
+    /// and command line arguments, and validates the result.
+    pub fn load(args: &CliArgs) -> Result<Settings, SettingsError> {
+        Settings::load_from(&args.config_path(), env::vars(), &args.overrides)
+    }
+
+    /// Loads the settings from `config_path` and applies the overrides in `env_vars` (which
+    /// only are used if they start with `ENV_VAR_PREFIX`) followed by `overrides`.
+    pub fn load_from<I>(
+        config_path: &str,
+        env_vars: I,
+        overrides: &[String],
+    ) -> Result<Settings, SettingsError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        // Read the contents of the config file into a string.
+        let contents = fs::read_to_string(Path::new(config_path)).map_err(|_| {
+            SettingsError(vec![format!(
                 "Could not load config file from {:?}.",
                 config_path
-            ));
+            )])
+        })?;
+
+        Settings::from_yaml(&contents, env_vars, overrides)
+    }
+
+    /// Parses settings from the contents of a YAML config file and applies overrides.
+    pub fn from_yaml<I>(
+        contents: &str,
+        env_vars: I,
+        overrides: &[String],
+    ) -> Result<Settings, SettingsError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut errors = Vec::new();
+
+        // An empty file is parsed as null, which is treated as a file without any keys.
+        let mut document = match serde_yaml::from_str::<Value>(contents) {
+            Ok(Value::Null) => Value::Mapping(Mapping::new()),
+            Ok(value) => value,
+            Err(err) => {
+                return Err(SettingsError(vec![format!(
+                    "Could not parse contents of the config file into YAML: {}",
+                    err
+                )]))
+            }
+        };
+
+        for (name, value) in env_vars {
+            if let Some(path) = name.strip_prefix(ENV_VAR_PREFIX) {
+                let path = path
+                    .split(ENV_VAR_SEPARATOR)
+                    .map(str::to_lowercase)
+                    .collect::<Vec<String>>();
+
+                if let Err(err) = set_value(&mut document, &path, &value) {
+                    errors.push(format!("{}: {}", name, err));
+                }
+            }
         }
 
-        // Parse the contents of the config file to YAML.
-        let config = YamlLoader::load_from_str(&config_file_contents.unwrap());
+        for entry in overrides {
+            match entry.split_once('=') {
+                Some((path, value)) => {
+                    let path = path.split('.').map(str::to_owned).collect::<Vec<String>>();
 
-        if config.is_err() {
-            return Err("Could not parse contents of the config file into YAML.".to_owned());
+                    if let Err(err) = set_value(&mut document, &path, value) {
+                        errors.push(format!("--set {}: {}", entry, err));
+                    }
+                }
+                None => errors.push(format!(
+                    "--set {}: expected the format <section>.<key>=<value>",
+                    entry
+                )),
+            }
         }
 
-        // Store the parsed YAML contents.
-        self.documents = config.unwrap();
+        let settings = Settings::deserialize_reporting_every_key(&document, &mut errors);
 
-        Ok(())
+        errors.extend(settings.validate());
+
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(SettingsError(errors))
+        }
     }
 
-    /// Returns a reference to the Yaml document that contains relevant data
-    fn get_config_docuemnt(&self) -> Option<&Yaml> {
-        // If the documents vector is empty that means we haven't loaded in any config
-        // file yet, so None is returned.
-        if self.documents.is_empty() {
-            return None;
+    /// Deserializes the settings and pushes an error for every key that is invalid.
+    ///
+    /// Serde stops at the first error, so if the settings cannot be deserialized each key is
+    /// deserialized on its own. Invalid keys are then removed, which means that the valid keys
+    /// can still be deserialized and validated since every field has a default value.
+    fn deserialize_reporting_every_key(document: &Value, errors: &mut Vec<String>) -> Settings {
+        if let Ok(settings) = serde_yaml::from_value::<Settings>(document.clone()) {
+            return settings;
+        }
+
+        let sections = match document.as_mapping() {
+            Some(sections) => sections,
+            None => {
+                errors.push("The config file must contain a mapping of sections.".to_owned());
+                return Settings::default();
+            }
+        };
+
+        let mut valid_sections = Mapping::new();
+
+        for (section, value) in sections {
+            let name = section.as_str().unwrap_or("<invalid key>");
+
+            let valid_keys = match name {
+                "server" => check_section::<ServerSettings>(name, value, errors),
+                "trafiklab_api" => check_section::<TrafiklabSettings>(name, value, errors),
+                "database" => check_section::<DatabaseSettings>(name, value, errors),
+                "heartbeat" => check_section::<HeartbeatSettings>(name, value, errors),
+                "limits" => check_section::<LimitSettings>(name, value, errors),
+                "cluster" => check_section::<ClusterSettings>(name, value, errors),
+                "storage" => check_section::<StorageSettings>(name, value, errors),
+                "simulator" => check_section::<SimulatorSettings>(name, value, errors),
+                "history" => check_section::<HistorySettings>(name, value, errors),
+                _ => {
+                    errors.push(format!("{}: unknown section", name));
+                    continue;
+                }
+            };
+
+            valid_sections.insert(section.clone(), Value::Mapping(valid_keys));
         }
 
-        Some(&self.documents[0])
+        serde_yaml::from_value(Value::Mapping(valid_sections)).unwrap_or_default()
     }
 
-    /// Gets a key value from the config section in the config file as a `&str`
-    pub fn get_config_value_str(&self, field: &str, key: &str) -> Option<&str> {
-        let document = match self.get_config_docuemnt() {
-            Some(doc) => doc,
-            None => return None,
+    /// Returns a list of every missing or invalid value.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self
+            .server
+            .bind_address
+            .parse::<std::net::SocketAddr>()
+            .is_err()
+        {
+            errors.push(format!(
+                "server.bind_address: '{}' is not a valid socket address",
+                self.server.bind_address
+            ));
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.server.log_level) {
+            errors.push(format!(
+                "server.log_level: '{}' is not a valid filter ({})",
+                self.server.log_level, err
+            ));
+        }
+
+        if self.server.workers == Some(0) {
+            errors.push("server.workers: must be at least 1".to_owned());
+        }
+
+        if self.uses_database() && self.database.uri.is_none() {
+            errors.push("database.uri: missing".to_owned());
+        }
+
+        let trafiklab = &self.trafiklab_api;
+
+        let durations = [
+            ("trafiklab_api.echo_interval", trafiklab.echo_interval),
+            ("trafiklab_api.connect_timeout", trafiklab.connect_timeout),
+            ("trafiklab_api.request_timeout", trafiklab.request_timeout),
+            ("trafiklab_api.backoff_base", trafiklab.backoff_base),
+            ("trafiklab_api.backoff_max", trafiklab.backoff_max),
+            ("trafiklab_api.circuit_cooldown", trafiklab.circuit_cooldown),
+            ("heartbeat.interval", self.heartbeat.interval),
+            ("heartbeat.client_timeout", self.heartbeat.client_timeout),
+            (
+                "limits.slow_client_timeout",
+                self.limits.slow_client_timeout,
+            ),
+            ("cluster.lease_ttl", self.cluster.lease_ttl),
+        ];
+
+        for (key, seconds) in durations.iter() {
+            if !seconds.is_finite() || *seconds <= 0.0 {
+                errors.push(format!("{}: must be a positive number of seconds", key));
+            }
+        }
+
+        if trafiklab.backoff_max < trafiklab.backoff_base {
+            errors.push("trafiklab_api.backoff_max: must not be less than backoff_base".to_owned());
+        }
+
+        if trafiklab.circuit_failure_threshold == 0 {
+            errors.push("trafiklab_api.circuit_failure_threshold: must be at least 1".to_owned());
+        }
+
+        if self.heartbeat.client_timeout <= self.heartbeat.interval {
+            errors.push(
+                "heartbeat.client_timeout: must be longer than heartbeat.interval".to_owned(),
+            );
+        }
+
+        if !self.limits.max_view_distance.is_finite() || self.limits.max_view_distance <= 0.0 {
+            errors.push("limits.max_view_distance: must be positive".to_owned());
+        }
+
+        if self.limits.max_message_size == 0 {
+            errors.push("limits.max_message_size: must be positive".to_owned());
+        }
+
+        if self.limits.max_feed_size == 0 {
+            errors.push("limits.max_feed_size: must be positive".to_owned());
+        }
+
+        if self.cluster.backend == ClusterBackend::Mongodb
+            && self.limits.max_feed_size > MAX_SHARED_FEED_SIZE
+        {
+            errors.push(format!(
+                "limits.max_feed_size: must be at most {} with the mongodb cluster backend",
+                MAX_SHARED_FEED_SIZE
+            ));
+        }
+
+        if self.limits.max_queued_messages == 0 {
+            errors.push("limits.max_queued_messages: must be at least 1".to_owned());
+        }
+
+        // Leases are only used when several instances share state.
+        if self.cluster.backend == ClusterBackend::Mongodb
+            && self.cluster.lease_ttl <= trafiklab.echo_interval
+        {
+            errors.push(
+                "cluster.lease_ttl: must be longer than trafiklab_api.echo_interval".to_owned(),
+            );
+        }
+
+        if self.cluster.instance_id.as_deref() == Some("") {
+            errors.push("cluster.instance_id: must not be empty".to_owned());
+        }
+
+        if self.storage.backend == StorageBackend::Sqlite && self.storage.sqlite_path.is_empty() {
+            errors.push("storage.sqlite_path: must not be empty".to_owned());
+        }
+
+        let simulator = &self.simulator;
+
+        if !simulator.speed.is_finite() || simulator.speed <= 0.0 {
+            errors.push("simulator.speed: must be positive".to_owned());
+        }
+
+        if simulator.start_time.is_some() && simulator.start_time().is_none() {
+            errors.push(format!(
+                "simulator.start_time: '{}' is not a time in the format YYYY-MM-DD HH:MM:SS",
+                simulator.start_time.as_deref().unwrap_or_default()
+            ));
+        }
+
+        if !simulator.delay.is_finite() {
+            errors.push("simulator.delay: must be a number of seconds".to_owned());
+        }
+
+        if !simulator.delay_variation.is_finite() || simulator.delay_variation < 0.0 {
+            errors.push("simulator.delay_variation: must not be negative".to_owned());
+        }
+
+        if self.history.retention_days == 0 {
+            errors.push("history.retention_days: must be at least 1".to_owned());
+        }
+
+        if self.history.database.is_empty() {
+            errors.push("history.database: must not be empty".to_owned());
+        }
+
+        match self.operators() {
+            Ok(operators) => {
+                if simulator.enabled && operators.iter().all(|op| op.gtfs_path.is_none()) {
+                    errors.push(
+                        "simulator.enabled: no operator has a gtfs_path to simulate".to_owned(),
+                    );
+                }
+            }
+            Err(operator_errors) => errors.extend(operator_errors),
+        }
+
+        errors
+    }
+
+    /// Returns true if the storage or cluster backend keeps its data in MongoDB, in which case
+    /// the database's URI is needed.
+    pub fn uses_database(&self) -> bool {
+        self.storage.backend == StorageBackend::Mongodb
+            || self.cluster.backend == ClusterBackend::Mongodb
+    }
+
+    /// Returns all operators that data should be served for.
+    ///
+    /// Every operator uses the API keys in the trafiklab section unless it has keys of its own.
+    /// If no operators are listed, the server only serves UL (Uppsala). UL's static data is
+    /// always in the database that was used before multiple operators were supported, unless
+    /// another database is given.
+    pub fn operators(&self) -> Result<Vec<Operator>, Vec<String>> {
+        let trafiklab = &self.trafiklab_api;
+        let mut errors = Vec::new();
+
+        let operators = match &trafiklab.operators {
+            Some(operators) => operators.clone(),
+            None => vec![OperatorSettings {
+                name: DEFAULT_OPERATOR.to_owned(),
+                realtime_key: None,
+                static_key: None,
+                realtime_url: None,
+                trip_updates_url: None,
+                static_url: None,
+                database: None,
+                gtfs_path: None,
+            }],
         };
 
-        document[field][key].as_str()
+        let memory = self.storage.backend == StorageBackend::Memory;
+
+        if memory && trafiklab.operators.is_none() {
+            errors.push(
+                "trafiklab_api.operators: missing (the memory storage backend needs the \
+                 gtfs_path of every operator)"
+                    .to_owned(),
+            );
+        }
+
+        if operators.is_empty() {
+            errors.push("trafiklab_api.operators: at least one operator must be listed".to_owned());
+        }
+
+        let mut result: Vec<Operator> = Vec::new();
+
+        for (index, settings) in operators.iter().enumerate() {
+            let key = format!("trafiklab_api.operators[{}]", index);
+
+            if settings.name.is_empty() {
+                errors.push(format!("{}.name: must not be empty", key));
+                continue;
+            }
+
+            if result.iter().any(|operator| operator.name == settings.name) {
+                errors.push(format!(
+                    "{}.name: '{}' is listed more than once",
+                    key, settings.name
+                ));
+                continue;
+            }
+
+            let realtime_key = settings
+                .realtime_key
+                .as_ref()
+                .or(trafiklab.realtime_key.as_ref());
+            let static_key = settings
+                .static_key
+                .as_ref()
+                .or(trafiklab.static_key.as_ref());
+
+            if realtime_key.is_none() {
+                errors.push(format!(
+                    "trafiklab_api.realtime_key: missing (needed by operator '{}')",
+                    settings.name
+                ));
+            }
+
+            if static_key.is_none() {
+                errors.push(format!(
+                    "trafiklab_api.static_key: missing (needed by operator '{}')",
+                    settings.name
+                ));
+            }
+
+            if memory && trafiklab.operators.is_some() && settings.gtfs_path.is_none() {
+                errors.push(format!(
+                    "{}.gtfs_path: missing (needed by the memory storage backend)",
+                    key
+                ));
+            }
+
+            let (realtime_key, static_key) = match (realtime_key, static_key) {
+                (Some(realtime_key), Some(static_key)) => (realtime_key, static_key),
+                _ => continue,
+            };
+
+            let mut operator = Operator::new(&settings.name, realtime_key, static_key);
+
+            if let Some(url) = &settings.realtime_url {
+                operator.realtime_url = url.clone();
+
+                // Trip updates from Trafiklab would not match vehicles from somewhere else.
+                operator.trip_updates_url = None;
+            }
+            if let Some(url) = &settings.trip_updates_url {
+                operator.trip_updates_url = Some(url.clone());
+            }
+            if let Some(url) = &settings.static_url {
+                operator.static_url = url.clone();
+            }
+            if let Some(database) = &settings.database {
+                operator.database = database.clone();
+            } else if settings.name == DEFAULT_OPERATOR {
+                // UL's static data was served before multiple operators were supported, so it
+                // stays in the same database when operators are listed.
+                operator.database = LEGACY_STATIC_DATABASE.to_owned();
+            }
+            operator.gtfs_path = settings.gtfs_path.clone();
+
+            result.push(operator);
+        }
+
+        if errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns these settings with the keys in `changes.reloadable` taken from `new`, which
+    /// are the settings that are in use once `new` has been applied to the running server.
+    pub fn with_reloaded(&self, new: &Settings, changes: &SettingsChanges) -> Settings {
+        // Serializing plain structs into YAML cannot fail.
+        let mut document = serde_yaml::to_value(self).unwrap_or(Value::Null);
+        let new_document = serde_yaml::to_value(new).unwrap_or(Value::Null);
+
+        for key in &changes.reloadable {
+            if let Some((section, key)) = key.split_once('.') {
+                let value = new_document[section][key].clone();
+
+                if let Some(keys) = document
+                    .get_mut(section)
+                    .and_then(|keys| keys.as_mapping_mut())
+                {
+                    keys.insert(Value::String(key.to_owned()), value);
+                }
+            }
+        }
+
+        serde_yaml::from_value(document).unwrap_or_else(|_| self.clone())
     }
 
-    /// Gets a key value from the config section in the config file as a `f64`
-    pub fn get_config_value_f64(&self, field: &str, key: &str) -> Option<f64> {
-        let document = match self.get_config_docuemnt() {
-            Some(doc) => doc,
-            None => return None,
+    /// Compares these settings with `new` and lists every key that has changed, split into
+    /// keys that can be applied to the running server and keys that require a restart.
+    pub fn changes(&self, new: &Settings) -> SettingsChanges {
+        let mut changes = SettingsChanges::default();
+
+        let old_keys = flatten(self);
+        let new_keys = flatten(new);
+
+        for (key, old_value) in &old_keys {
+            if new_keys.get(key) == Some(old_value) {
+                continue;
+            }
+
+            if RESTART_REQUIRED_KEYS.contains(&key.as_str()) {
+                changes.restart_required.push(key.clone());
+            } else {
+                changes.reloadable.push(key.clone());
+            }
+        }
+
+        // Adding, removing or moving operators changes which fetchers and static data are
+        // used, but their keys and URLs can be changed while the server is running.
+        let static_data = |settings: &Settings| {
+            settings.operators().map(|operators| {
+                operators
+                    .into_iter()
+                    .map(|operator| (operator.name, operator.database, operator.gtfs_path))
+                    .collect::<Vec<_>>()
+            })
         };
 
-        document[field][key].as_f64()
+        if static_data(self) != static_data(new) {
+            changes
+                .restart_required
+                .push("trafiklab_api.operators".to_owned());
+            changes
+                .reloadable
+                .retain(|key| key != "trafiklab_api.operators");
+        }
+
+        changes.reloadable.sort();
+        changes.restart_required.sort();
+
+        changes
     }
+}
+
+/// Keys that only are read when the server starts.
+const RESTART_REQUIRED_KEYS: &[&str] = &[
+    "server.bind_address",
+    "server.workers",
+    "server.log_format",
+    "database.uri",
+    "heartbeat.interval",
+    "heartbeat.client_timeout",
+    "limits.max_message_size",
+    "limits.max_queued_messages",
+    "cluster.backend",
+    "cluster.instance_id",
+    "cluster.database",
+    "cluster.event_log_size",
+    "storage.backend",
+    "storage.sqlite_path",
+    "simulator.enabled",
+    "simulator.speed",
+    "simulator.start_time",
+    "simulator.delay",
+    "simulator.delay_variation",
+    "history.enabled",
+    "history.retention_days",
+    "history.database",
+];
+
+/// Keys that have changed between two versions of the settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettingsChanges {
+    /// Keys that are applied to the running server.
+    pub reloadable: Vec<String>,
 
-    /// Returns a value from the trafiklab section in the config file as a `f64`
-    pub fn get_trafiklab_value_f64(&self, key: &str) -> Option<f64> {
-        self.get_config_value_f64(TRAFIKLAB_YAML_KEY, key)
+    /// Keys that only take effect once the server is restarted.
+    pub restart_required: Vec<String>,
+}
+
+impl SettingsChanges {
+    pub fn is_empty(&self) -> bool {
+        self.reloadable.is_empty() && self.restart_required.is_empty()
     }
+}
 
-    /// Returns a value from the trafiklab section in the config file as a `&str`
-    pub fn get_trafiklab_value_str(&self, key: &str) -> Option<&str> {
-        self.get_config_value_str(TRAFIKLAB_YAML_KEY, key)
+/// Maps "<section>.<key>" to the value of every key in the settings.
+fn flatten(settings: &Settings) -> HashMap<String, Value> {
+    let mut keys = HashMap::new();
+
+    // Serializing plain structs into YAML cannot fail.
+    let document = serde_yaml::to_value(settings).unwrap_or(Value::Null);
+
+    for (section, values) in document.as_mapping().into_iter().flatten() {
+        for (key, value) in values.as_mapping().into_iter().flatten() {
+            if let (Some(section), Some(key)) = (section.as_str(), key.as_str()) {
+                keys.insert(format!("{}.{}", section, key), value.clone());
+            }
+        }
     }
 
-    /// Returns a value from the database section in the config file as a `&str`
-    pub fn get_database_value(&self, key: &str) -> Option<&str> {
-        self.get_config_value_str(DATABASE_YAML_KEY, key)
+    keys
+}
+
+/// Deserializes every key in a section on its own, pushes an error for each invalid key and
+/// returns the valid keys.
+fn check_section<T: DeserializeOwned>(
+    section: &str,
+    value: &Value,
+    errors: &mut Vec<String>,
+) -> Mapping {
+    let mut valid_keys = Mapping::new();
+
+    let keys = match value.as_mapping() {
+        Some(keys) => keys,
+        None => {
+            errors.push(format!("{}: must be a mapping of keys", section));
+            return valid_keys;
+        }
+    };
+
+    for (key, value) in keys {
+        let mut single = Mapping::new();
+        single.insert(key.clone(), value.clone());
+
+        match serde_yaml::from_value::<T>(Value::Mapping(single)) {
+            Ok(_) => {
+                valid_keys.insert(key.clone(), value.clone());
+            }
+            Err(err) => errors.push(format!(
+                "{}.{}: {}",
+                section,
+                key.as_str().unwrap_or("<invalid key>"),
+                err
+            )),
+        }
     }
+
+    valid_keys
+}
+
+/// Sets the value at `path` in a YAML document, creating mappings along the way.
+///
+/// The value is parsed as YAML so that numbers and booleans get the correct type, unless the
+/// key is a string, in which case the raw value is used so that e.g. an API key that looks
+/// like a number stays the same.
+fn set_value(document: &mut Value, path: &[String], raw_value: &str) -> Result<(), String> {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return Err("empty key".to_owned()),
+    };
+
+    let mut current = document;
+
+    for key in parents {
+        let mapping = current
+            .as_mapping_mut()
+            .ok_or_else(|| format!("'{}' is not a section", key))?;
+
+        current = mapping
+            .entry(Value::String(key.clone()))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+    }
+
+    let value = match serde_yaml::from_str::<Value>(raw_value) {
+        Ok(Value::Null) => Value::Null,
+        Ok(value) if !is_string_key(path, raw_value) => value,
+        _ => Value::String(raw_value.to_owned()),
+    };
+
+    current
+        .as_mapping_mut()
+        .ok_or_else(|| format!("cannot set '{}' in a value that is not a section", last))?
+        .insert(Value::String(last.clone()), value);
+
+    Ok(())
+}
+
+/// Returns true if the key at `path` is a string, which is the case if the settings can be
+/// deserialized with `raw_value` as a string at `path` and nothing else.
+fn is_string_key(path: &[String], raw_value: &str) -> bool {
+    let document = path
+        .iter()
+        .rev()
+        .fold(Value::String(raw_value.to_owned()), |value, key| {
+            let mut mapping = Mapping::new();
+            mapping.insert(Value::String(key.clone()), value);
+            Value::Mapping(mapping)
+        });
+
+    serde_yaml::from_value::<Settings>(document).is_ok()
 }
 
 #[cfg(test)]
@@ -111,68 +1180,335 @@ mod tests {
 
     const TEST_DIRECTORY_NAME: &str = "config_test_dir";
     const TEST_FILE_NAME: &str = "test_config.yml";
-    const TEST_API_KEY: &str = "api_key";
-    const TEST_API_KEY_VALUE: &str = "a12b34c567d89";
 
-    const TEST_DATABASE_KEY: &str = "test_uri";
-    const TEST_DATABASE_KEY_VALUE: &str = "testconnectionstring";
+    const TEST_CONFIG: &str = "
+trafiklab_api:
+  realtime_key: a12b34c567d89
+  static_key: b12b34c567d89
+  echo_interval: 2.5
+database:
+  uri: testconnectionstring
+";
+
+    fn no_env() -> Vec<(String, String)> {
+        Vec::new()
+    }
 
     #[test]
     fn test_config() -> std::io::Result<()> {
-        let yaml_content: String = format!(
-            "
-{}:
-  {}: {}
-{}:
-  {}: {}
-",
-            TRAFIKLAB_YAML_KEY,
-            TEST_API_KEY,
-            TEST_API_KEY_VALUE,
-            DATABASE_YAML_KEY,
-            TEST_DATABASE_KEY,
-            TEST_DATABASE_KEY_VALUE
-        );
-
         // Create a temporary directory to create the config file in.
         let dir = TempDir::new(TEST_DIRECTORY_NAME)?;
         let file_path = dir.path().join(TEST_FILE_NAME);
-        eprintln!("FILE PATH: {:?}", file_path);
 
         // Create the file containing the yaml content.
         let mut test_file = File::create(&file_path)?;
-        test_file.write_all(yaml_content.as_bytes())?;
+        test_file.write_all(TEST_CONFIG.as_bytes())?;
 
-        let mut config_handler = Config::new();
+        let settings = Settings::load_from(file_path.to_str().unwrap(), no_env(), &[]).unwrap();
 
-        // Make sure that the config is properly loaded in.
+        // Make sure that the values are read from the file and that defaults are used for
+        // everything else.
         assert_eq!(
-            config_handler
-                .load_config(file_path.to_str().unwrap())
-                .is_ok(),
-            true
+            settings.trafiklab_api.realtime_key.as_deref(),
+            Some("a12b34c567d89")
         );
-
-        // Make sure that some bad key returns None
         assert_eq!(
-            config_handler.get_trafiklab_value_str("bad_key").is_none(),
-            true
+            settings.database.uri.as_deref(),
+            Some("testconnectionstring")
         );
-        assert_eq!(config_handler.get_database_value("bad_key").is_none(), true);
-
-        let get_key_result_database = config_handler.get_database_value(TEST_DATABASE_KEY);
-        let get_key_result_trafik = config_handler.get_trafiklab_value_str(TEST_API_KEY);
+        assert_eq!(
+            settings.trafiklab_api.echo_interval(),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(settings.server, ServerSettings::default());
 
-        // Make sure that a correct key is returned as Some and the correct value.
-        assert_eq!(get_key_result_trafik.is_some(), true);
-        assert_eq!(get_key_result_trafik.unwrap(), TEST_API_KEY_VALUE);
+        // Without a list of operators only the default operator is served.
+        let operators = settings.operators().unwrap();
+        assert_eq!(operators.len(), 1);
+        assert_eq!(operators[0].database, LEGACY_STATIC_DATABASE);
 
-        assert_eq!(get_key_result_database.is_some(), true);
-        assert_eq!(get_key_result_database.unwrap(), TEST_DATABASE_KEY_VALUE);
+        // A file that doesn't exist should be reported.
+        assert!(Settings::load_from("does_not_exist.yml", no_env(), &[]).is_err());
 
         // Close the temporary directory.
         dir.close()?;
 
         Ok(())
     }
+
+    #[test]
+    fn test_overrides() {
+        let env = vec![
+            (
+                "BUSPLUS__SERVER__BIND_ADDRESS".to_owned(),
+                "127.0.0.1:9000".to_owned(),
+            ),
+            ("BUSPLUS__HEARTBEAT__INTERVAL".to_owned(), "2".to_owned()),
+            (
+                "BUSPLUS__TRAFIKLAB_API__STATIC_KEY".to_owned(),
+                "00123".to_owned(),
+            ),
+            ("UNRELATED".to_owned(), "value".to_owned()),
+        ];
+
+        // Command line arguments take precedence over environment variables.
+        let args = CliArgs::parse(
+            vec![
+                "--bind",
+                "127.0.0.1:9001",
+                "-s",
+                "database.uri=override",
+                "-s",
+                "trafiklab_api.realtime_key=12345",
+            ]
+            .into_iter()
+            .map(str::to_owned),
+        )
+        .unwrap();
+
+        let settings = Settings::from_yaml(TEST_CONFIG, env, &args.overrides).unwrap();
+
+        assert_eq!(settings.server.bind_address, "127.0.0.1:9001");
+        assert_eq!(settings.heartbeat.interval, 2.0);
+        assert_eq!(settings.database.uri.as_deref(), Some("override"));
+
+        // String keys get the raw value, even if it looks like a number.
+        assert_eq!(
+            settings.trafiklab_api.realtime_key.as_deref(),
+            Some("12345")
+        );
+        assert_eq!(settings.trafiklab_api.static_key.as_deref(), Some("00123"));
+
+        assert!(CliArgs::parse(vec!["--unknown".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_every_error_is_reported() {
+        let yaml_content = "
+server:
+  bind_address: not an address
+trafiklab_api:
+  echo_interval: often
+  circuit_failure_threshold: -1
+heartbeat:
+  interval: 0
+history:
+  retention_days: 0
+unknown_section:
+  key: value
+";
+
+        let errors = Settings::from_yaml(yaml_content, no_env(), &[])
+            .unwrap_err()
+            .0;
+        let reported = |key: &str| errors.iter().any(|error| error.starts_with(key));
+
+        assert!(reported("server.bind_address"));
+        assert!(reported("trafiklab_api.echo_interval"));
+        assert!(reported("trafiklab_api.circuit_failure_threshold"));
+        assert!(reported("unknown_section"));
+        assert!(reported("database.uri"));
+        assert!(reported("trafiklab_api.realtime_key"));
+        assert!(reported("trafiklab_api.static_key"));
+        assert!(reported("history.retention_days"));
+
+        // The lease has to outlive the time between two fetches, but only instances that
+        // share state use it.
+        let overrides = vec!["cluster.lease_ttl=1".to_owned()];
+        assert!(Settings::from_yaml(TEST_CONFIG, no_env(), &overrides).is_ok());
+
+        let overrides = vec![overrides[0].clone(), "cluster.backend=mongodb".to_owned()];
+        let errors = Settings::from_yaml(TEST_CONFIG, no_env(), &overrides)
+            .unwrap_err()
+            .0;
+        assert_eq!(
+            errors,
+            vec!["cluster.lease_ttl: must be longer than trafiklab_api.echo_interval"]
+        );
+
+        // Feeds are shared in a single MongoDB document, which has a size limit.
+        let overrides = vec![
+            "cluster.backend=mongodb".to_owned(),
+            "limits.max_feed_size=16777216".to_owned(),
+        ];
+        let errors = Settings::from_yaml(TEST_CONFIG, no_env(), &overrides)
+            .unwrap_err()
+            .0;
+        assert_eq!(
+            errors,
+            vec!["limits.max_feed_size: must be at most 16711680 with the mongodb cluster backend"]
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = Settings::from_yaml(TEST_CONFIG, no_env(), &[]).unwrap();
+        assert!(old.changes(&old).is_empty());
+
+        let overrides = vec![
+            "trafiklab_api.echo_interval=1".to_owned(),
+            "trafiklab_api.realtime_key=new_key".to_owned(),
+            "server.bind_address=127.0.0.1:9000".to_owned(),
+            "history.enabled=true".to_owned(),
+            "history.late_tolerance=180".to_owned(),
+            "history.forecasts=true".to_owned(),
+        ];
+        let new = Settings::from_yaml(TEST_CONFIG, no_env(), &overrides).unwrap();
+
+        let changes = old.changes(&new);
+        assert_eq!(
+            changes.reloadable,
+            vec![
+                "history.forecasts",
+                "history.late_tolerance",
+                "trafiklab_api.echo_interval",
+                "trafiklab_api.realtime_key"
+            ]
+        );
+        assert_eq!(
+            changes.restart_required,
+            vec!["history.enabled", "server.bind_address"]
+        );
+
+        // Only the keys that were applied are in use after the reload, so changes that need a
+        // restart are still reported by the next one.
+        let current = old.with_reloaded(&new, &changes);
+        assert_eq!(current.history.late_tolerance, 180);
+        assert!(current.history.forecasts);
+        assert!(!current.history.enabled);
+        assert_eq!(
+            current.trafiklab_api.realtime_key.as_deref(),
+            Some("new_key")
+        );
+        assert_eq!(current.server.bind_address, old.server.bind_address);
+        assert_eq!(
+            current.changes(&new).restart_required,
+            changes.restart_required
+        );
+        assert!(current.changes(&new).reloadable.is_empty());
+
+        // Serving another operator requires a restart.
+        let overrides = vec!["trafiklab_api.operators=[{name: ul}, {name: sl}]".to_owned()];
+        let new = Settings::from_yaml(TEST_CONFIG, no_env(), &overrides).unwrap();
+
+        assert_eq!(
+            old.changes(&new).restart_required,
+            vec!["trafiklab_api.operators"]
+        );
+    }
+
+    #[test]
+    fn test_operators() {
+        let yaml_content = "
+trafiklab_api:
+  realtime_key: shared_realtime
+  static_key: shared_static
+  operators:
+    - name: ul
+    - name: sl
+      realtime_key: sl_realtime
+      database: sl-data
+    - name: otraf
+      realtime_url: http://127.0.0.1:8080/simulator/otraf/VehiclePositions.pb
+database:
+  uri: testconnectionstring
+";
+
+        let settings = Settings::from_yaml(yaml_content, no_env(), &[]).unwrap();
+        let operators = settings.operators().unwrap();
+        assert_eq!(operators.len(), 3);
+
+        // Operators use the shared keys unless they have keys of their own.
+        assert_eq!(operators[0].name, "ul");
+        assert_eq!(operators[0].realtime_key, "shared_realtime");
+        assert_eq!(operators[0].database, LEGACY_STATIC_DATABASE);
+
+        assert_eq!(operators[1].realtime_key, "sl_realtime");
+        assert_eq!(operators[1].static_key, "shared_static");
+        assert_eq!(operators[1].database, "sl-data");
+
+        // Trafiklab's trip updates are only fetched along with Trafiklab's vehicle positions.
+        assert_eq!(
+            operators[1].trip_updates_url.as_deref(),
+            Some("https://opendata.samtrafiken.se/gtfs-rt/sl/TripUpdates.pb")
+        );
+        assert_eq!(operators[2].trip_updates_url, None);
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let yaml_content = "
+trafiklab_api:
+  realtime_key: shared_realtime
+  static_key: shared_static
+  operators:
+    - name: ul
+      gtfs_path: ul.zip
+    - name: sl
+storage:
+  backend: memory
+";
+
+        // The database is not needed, but every operator needs a GTFS zip.
+        let errors = Settings::from_yaml(yaml_content, no_env(), &[])
+            .unwrap_err()
+            .0;
+        assert_eq!(
+            errors,
+            vec!["trafiklab_api.operators[1].gtfs_path: missing (needed by the memory storage backend)"]
+        );
+
+        let overrides = vec!["trafiklab_api.operators=[{name: ul, gtfs_path: ul.zip}]".to_owned()];
+        let settings = Settings::from_yaml(yaml_content, no_env(), &overrides).unwrap();
+        assert!(!settings.uses_database());
+        assert_eq!(
+            settings.operators().unwrap()[0].gtfs_path.as_deref(),
+            Some("ul.zip")
+        );
+
+        // Sharing state between instances still needs the database.
+        let overrides = vec![overrides[0].clone(), "cluster.backend=mongodb".to_owned()];
+        let errors = Settings::from_yaml(yaml_content, no_env(), &overrides)
+            .unwrap_err()
+            .0;
+        assert_eq!(errors, vec!["database.uri: missing"]);
+    }
+
+    #[test]
+    fn test_simulator() {
+        let settings = Settings::from_yaml(TEST_CONFIG, no_env(), &[]).unwrap();
+        assert!(!settings.simulator.enabled);
+        assert_eq!(settings.simulator.start_time(), None);
+
+        // Only operators with a GTFS zip can be simulated.
+        let overrides = vec![
+            "simulator.enabled=true".to_owned(),
+            "simulator.speed=0".to_owned(),
+            "simulator.start_time=08:00".to_owned(),
+        ];
+        let errors = Settings::from_yaml(TEST_CONFIG, no_env(), &overrides)
+            .unwrap_err()
+            .0;
+        assert_eq!(
+            errors,
+            vec![
+                "simulator.speed: must be positive",
+                "simulator.start_time: '08:00' is not a time in the format YYYY-MM-DD HH:MM:SS",
+                "simulator.enabled: no operator has a gtfs_path to simulate",
+            ]
+        );
+
+        let overrides = vec![
+            "simulator.enabled=true".to_owned(),
+            "simulator.start_time=2021-05-10 08:00:00".to_owned(),
+            "trafiklab_api.operators=[{name: ul, gtfs_path: ul.zip}]".to_owned(),
+        ];
+        let settings = Settings::from_yaml(TEST_CONFIG, no_env(), &overrides).unwrap();
+        assert_eq!(
+            settings.simulator.start_time(),
+            Some(
+                NaiveDateTime::parse_from_str("2021-05-10 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+            )
+        );
+    }
 }
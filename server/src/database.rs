@@ -1,19 +1,22 @@
 //! Module for handling operations and connection to a external MongoDB database
 
-use mongodb::bson::{from_bson, Bson, Document};
+use mongodb::bson::{doc, from_bson, Bson, Document};
 use mongodb::{error::Result, options::ClientOptions, Client, Database};
+use serde::de::DeserializeOwned;
 use tokio::stream::StreamExt;
 
-use crate::gtfs::transit_static::{Route, Shape, Trip};
-use crate::protocol::server_protocol::RouteNode;
-
-/// Database name for the database containing static data.
-const STATIC_DATABASE: &str = "trafiklab-static-data";
+use crate::geometry::Point;
+use crate::gtfs::transit_static::{Route, Shape, Stop, StopTime, Trip};
+use crate::metrics::DB_QUERY_SECONDS;
+use crate::store::shape_points;
 
 /// Our abstraction for the db, we can use method syntax for operation ex: conn.updateGeoPosition(id, value)
 #[derive(Clone)]
 pub struct DbConnection {
     client: Client,
+
+    /// Name of the database containing static data.
+    static_database: String,
 }
 
 /// Inititalise a connection with uri_str
@@ -23,80 +26,147 @@ pub async fn init_db_connection(uri_str: &str) -> Result<DbConnection> {
 
     Ok(DbConnection {
         client: result_client,
+        static_database: String::new(),
     })
 }
 
 impl DbConnection {
+    /// Returns a handle that reads static data from the database `static_database`. The
+    /// underlying connection is shared between all handles.
+    pub fn with_static_database(&self, static_database: &str) -> DbConnection {
+        DbConnection {
+            client: self.client.clone(),
+            static_database: static_database.to_owned(),
+        }
+    }
+
+    /// Returns a handle to the database `name` on the same server.
+    pub fn database(&self, name: &str) -> Database {
+        self.client.database(name)
+    }
+
     fn static_db(&self) -> Database {
-        self.client.database(STATIC_DATABASE)
+        self.client.database(&self.static_database)
+    }
+
+    /// Returns true if the database server responds to a ping.
+    pub async fn ping(&self) -> bool {
+        self.client
+            .database("admin")
+            .run_command(doc! {"ping": 1}, None)
+            .await
+            .is_ok()
+    }
+
+    /// Returns true if the static database contains routes, trips and shapes.
+    pub async fn has_static_data(&self) -> bool {
+        for collection in &["routes", "trips", "shapes"] {
+            match self
+                .static_db()
+                .collection(collection)
+                .find_one(None, None)
+                .await
+            {
+                Ok(Some(_)) => (),
+                _ => return false,
+            }
+        }
+
+        true
     }
 }
 
 impl DbConnection {
     /// Query the database for a "route".
     pub async fn get_route(&self, query: Document) -> Option<Route> {
+        let _timer = DB_QUERY_SECONDS
+            .with_label_values(&["routes"])
+            .start_timer();
+
         match self
             .static_db()
             .collection("routes")
             .find_one(query, None)
             .await
         {
-            Ok(value_option) => match value_option {
-                Some(doc) => Some(from_bson(Bson::Document(doc)).unwrap()),
-                None => None,
-            },
+            Ok(value_option) => value_option.map(|doc| from_bson(Bson::Document(doc)).unwrap()),
             Err(_) => None,
         }
     }
 
     /// Query the database for a "trip".
     pub async fn get_trip(&self, query: Document) -> Option<Trip> {
+        let _timer = DB_QUERY_SECONDS.with_label_values(&["trips"]).start_timer();
+
         match self
             .static_db()
             .collection("trips")
             .find_one(query, None)
             .await
         {
-            Ok(value_option) => match value_option {
-                Some(doc) => Some(from_bson(Bson::Document(doc)).unwrap()),
-                None => None,
-            },
+            Ok(value_option) => value_option.map(|doc| from_bson(Bson::Document(doc)).unwrap()),
             Err(_) => None,
         }
     }
 
-    /// Query the database for a list of "shapes".
-    pub async fn get_shapes(&self, query: Document) -> Option<Vec<RouteNode>> {
+    /// Query the database for every "trip" that matches the query.
+    pub async fn get_trips(&self, query: Document) -> Option<Vec<Trip>> {
+        self.find_all("trips", query).await
+    }
+
+    /// Query the database for the "stop times" that match the query, in the order of their
+    /// sequence.
+    pub async fn get_stop_times(&self, query: Document) -> Option<Vec<StopTime>> {
+        let mut stop_times = self.find_all::<StopTime>("stop_times", query).await?;
+        stop_times.sort_by_key(|stop_time| stop_time.stop_sequence);
+
+        Some(stop_times)
+    }
+
+    /// Query the database for every "stop" that matches the query.
+    pub async fn get_stops(&self, query: Document) -> Option<Vec<Stop>> {
+        self.find_all("stops", query).await
+    }
+
+    /// Query a collection of static data for every document that matches the query. Documents
+    /// that cannot be parsed are skipped.
+    async fn find_all<T: DeserializeOwned>(
+        &self,
+        collection: &str,
+        query: Document,
+    ) -> Option<Vec<T>> {
+        let _timer = DB_QUERY_SECONDS
+            .with_label_values(&[collection])
+            .start_timer();
+
         match self
             .static_db()
-            .collection("shapes")
+            .collection(collection)
             .find(query, None)
             .await
         {
             Ok(mut cursor) => {
-                // Create a vector to store all the nodes in.
-                let mut nodes = Vec::new();
+                let mut documents = Vec::new();
 
                 while let Some(result) = cursor.next().await {
-                    // Each "result" in the cursor iterator is a result from a mongodb
-                    // query since not all documents might be fetched at the same time.
-                    if let Ok(document) = result {
-                        // Type annotate.
-                        let shape: Shape = from_bson(Bson::Document(document)).unwrap();
-
-                        // Store a RouteNode representation in the shapes list.
-                        nodes.push(RouteNode {
-                            lat: shape.shape_pt_lat,
-                            lng: shape.shape_pt_lon,
-                            sequence: shape.shape_pt_sequence.parse().unwrap(),
-                        });
+                    if let Ok(parsed) = result.and_then(|document| {
+                        from_bson(Bson::Document(document)).map_err(Into::into)
+                    }) {
+                        documents.push(parsed);
                     }
                 }
 
-                // Return all the shapes.
-                Some(nodes)
+                Some(documents)
             }
             Err(_) => None,
         }
     }
+
+    /// Query the database for a list of "shapes", and returns their points in the order they
+    /// are driven. Points that are not numbers are skipped.
+    pub async fn get_shapes(&self, query: Document) -> Option<Vec<Point>> {
+        let rows = self.find_all::<Shape>("shapes", query).await?;
+
+        Some(shape_points(&rows))
+    }
 }
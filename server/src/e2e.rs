@@ -0,0 +1,684 @@
+//! End-to-end tests that start the whole server and talk to it the way clients do.
+//!
+//! The server is started with the "memory" storage backend and a small GTFS feed with line 5,
+//! and fetches its realtime data from a stand-in for Trafiklab's API that replays recorded
+//! feeds. Everything listens on ports that are picked by the operating system, so the tests
+//! can run at the same time.
+//!
+//! Clients are scripted: they send `ClientInput` messages over a WebSocket connection and
+//! assert on every `ServerOutput` frame they receive, in the order they receive them.
+
+use std::borrow::Cow;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::Addr;
+use actix_codec::Framed;
+use actix_web::dev::Server;
+use actix_web::web::{self, Data};
+use actix_web::{App, HttpResponse, HttpServer};
+use awc::ws::{Codec, Frame, Message};
+use awc::BoxedSocket;
+use futures::{SinkExt, StreamExt};
+use tempdir::TempDir;
+use tokio::time::{delay_for, timeout};
+
+use crate::config::Settings;
+use crate::gtfs::trafiklab::RealtimeFeed;
+use crate::gtfs::transit_realtime::{
+    FeedEntity, FeedMessage, Position, TripDescriptor, VehicleDescriptor, VehiclePosition,
+};
+use crate::lobby::Lobby;
+use crate::messages::ReadinessRequest;
+use crate::protocol::client_protocol::{
+    ClientInput, GeoPosition, GeoPositionPoint, GeometryFormat, LineInformation,
+    RouteInformationRequest, VehicleDescriptor as DescriptorInput,
+};
+use crate::protocol::server_protocol::{
+    DirectionHealth, EntityCounts, ErrorOutput, ErrorType, FeedStatus, FeedStatusOutput,
+    LineHealthOutput, PassengerInformationOutput, RouteDirection, RouteGeometry,
+    RouteInformationOutput, RouteStop, RouteVariant, ServerOutput, Vehicle,
+    VehiclePassengerInformationOutput, VehiclePositionsOutput,
+};
+use crate::startup;
+use crate::store::line_5;
+
+/// How long a client waits for a frame, and the server for its first feed, before the test
+/// fails.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often (in seconds) the server fetches the replayed feed.
+const FETCH_INTERVAL: f64 = 0.1;
+
+/// The vehicle that drives line 5, and the passenger information it has when the server
+/// starts, which is set up front so that the tests do not depend on made-up numbers.
+const BUS: &str = "9031003";
+const BUS_PASSENGERS: PassengerInformationOutput = PassengerInformationOutput {
+    capacity: 30,
+    passengers: 10,
+    forecast: None,
+};
+
+/// The recorded feeds and the one that is currently served.
+struct Recording {
+    feeds: Vec<RealtimeFeed>,
+    current: AtomicUsize,
+}
+
+/// Serves recorded realtime feeds in place of Trafiklab's API.
+///
+/// The same feed is served until the test moves on to the next one. Every feed is stamped with
+/// the current time when it is served, like a live feed, so that it is never considered stale.
+struct ReplayedFeed {
+    recording: Arc<Recording>,
+    server: Server,
+    address: SocketAddr,
+}
+
+impl ReplayedFeed {
+    fn start(feeds: Vec<RealtimeFeed>) -> Self {
+        let recording = Arc::new(Recording {
+            feeds,
+            current: AtomicUsize::new(0),
+        });
+
+        let data = Data::from(recording.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/{operator}/VehiclePositions.pb", web::get().to(serve_feed))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+
+        let address = server.addrs()[0];
+
+        ReplayedFeed {
+            recording,
+            server: server.run(),
+            address,
+        }
+    }
+
+    /// Moves on to the next recorded feed.
+    fn next(&self) {
+        let next = self.recording.current.fetch_add(1, Ordering::SeqCst) + 1;
+
+        assert!(next < self.recording.feeds.len(), "No more recorded feeds");
+    }
+}
+
+async fn serve_feed(recording: Data<Recording>) -> HttpResponse {
+    let feed = &recording.feeds[recording.current.load(Ordering::SeqCst)];
+
+    let mut message = feed.message();
+    message.header.timestamp = Some(Lobby::get_current_timestamp());
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(RealtimeFeed::from_message(&message).as_bytes().to_vec())
+}
+
+/// The whole server for UL, with line 5 as static data.
+struct TestServer {
+    feed: ReplayedFeed,
+    server: Server,
+    address: SocketAddr,
+
+    /// Holds the GTFS zip, which is removed when the server is dropped.
+    _static_data: TempDir,
+}
+
+impl TestServer {
+    /// Starts the server and waits until it has fetched the first of the recorded `feeds`.
+    async fn start(feeds: Vec<RealtimeFeed>) -> Self {
+        let feed = ReplayedFeed::start(feeds);
+
+        let static_data = TempDir::new("busplus-e2e").unwrap();
+        let gtfs_path = static_data.path().join("ul.zip");
+        std::fs::File::create(&gtfs_path)
+            .unwrap()
+            .write_all(line_5().get_ref())
+            .unwrap();
+
+        let config = format!(
+            "
+server:
+  bind_address: 127.0.0.1:0
+  workers: 1
+trafiklab_api:
+  realtime_key: replay
+  static_key: replay
+  echo_interval: {}
+  operators:
+    - name: ul
+      realtime_url: http://{}/ul/VehiclePositions.pb
+      gtfs_path: {}
+storage:
+  backend: memory
+",
+            FETCH_INTERVAL,
+            feed.address,
+            gtfs_path.display()
+        );
+
+        let settings = Settings::from_yaml(&config, std::iter::empty(), &[]).unwrap();
+        let operators = settings.operators().unwrap();
+
+        let (storage, cluster) = startup::connect(&settings, &operators).await.unwrap();
+        storage
+            .reservations
+            .create_passenger_info(&format!("ul:{}", BUS), BUS_PASSENGERS)
+            .await;
+
+        let (lobby, _) = startup::start_services(&settings, operators, storage, cluster);
+        let (server, addresses) = startup::run_http_server(&settings, lobby.clone(), None).unwrap();
+
+        wait_until_ready(&lobby).await;
+
+        TestServer {
+            feed,
+            server,
+            address: addresses[0],
+            _static_data: static_data,
+        }
+    }
+
+    /// Connects a client, which is told about the status of the feed right away.
+    async fn connect(&self) -> TestClient {
+        let (_, connection) = awc::Client::new()
+            .ws(format!("http://{}/ws", self.address))
+            .connect()
+            .await
+            .unwrap();
+
+        let mut client = TestClient {
+            connection,
+            last_positions: None,
+        };
+
+        // Nothing is fetched before the first client connects, so that client is told that
+        // there is no data yet and is told again once the first feed has been fetched.
+        match client.receive().await {
+            status if status == feed_status_stale() => client.expect(feed_status_ok(2)).await,
+            status => assert_eq!(status, feed_status_ok(2)),
+        }
+
+        client
+    }
+
+    /// Stops the server without waiting for the clients to disconnect.
+    async fn stop(self) {
+        self.server.stop(false).await;
+        self.feed.server.stop(false).await;
+    }
+}
+
+/// Waits until the server has static data.
+async fn wait_until_ready(lobby: &Addr<Lobby>) {
+    let ready = async {
+        while !lobby.send(ReadinessRequest).await.unwrap().ready {
+            delay_for(Duration::from_millis(20)).await;
+        }
+    };
+
+    timeout(TIMEOUT, ready)
+        .await
+        .expect("The server did not get ready in time");
+}
+
+/// A scripted client that is connected over a WebSocket.
+struct TestClient {
+    connection: Framed<BoxedSocket, Codec>,
+
+    /// The latest vehicle positions that the client received.
+    last_positions: Option<ServerOutput>,
+}
+
+impl TestClient {
+    async fn send(&mut self, input: ClientInput) {
+        let text = serde_json::to_string(&input).unwrap();
+
+        self.connection.send(Message::Text(text)).await.unwrap();
+    }
+
+    /// Waits for the next frame, with every field that depends on the clock set to zero.
+    ///
+    /// Vehicle positions are sent every time the feed is fetched, so positions that are the
+    /// same as the ones the client already has are skipped.
+    async fn receive(&mut self) -> ServerOutput {
+        loop {
+            let frame = timeout(TIMEOUT, self.connection.next())
+                .await
+                .expect("Timed out waiting for a frame")
+                .expect("The connection is still open")
+                .unwrap();
+
+            let text = match frame {
+                Frame::Text(text) => text,
+                Frame::Ping(message) => {
+                    self.connection.send(Message::Pong(message)).await.unwrap();
+                    continue;
+                }
+                Frame::Close(reason) => panic!("The server closed the connection: {:?}", reason),
+                _ => continue,
+            };
+
+            let output = without_clock(serde_json::from_slice(&text).unwrap());
+
+            if let ServerOutput::VehiclePositions(_) = output {
+                if self.last_positions.as_ref() == Some(&output) {
+                    continue;
+                }
+
+                self.last_positions = Some(output.clone());
+            }
+
+            return output;
+        }
+    }
+
+    /// Asserts that the next frame is `expected`.
+    async fn expect(&mut self, expected: ServerOutput) {
+        assert_eq!(self.receive().await, expected);
+    }
+
+    /// Asserts that nothing but the same vehicle positions is received for a few fetches.
+    async fn expect_nothing(&mut self) {
+        let wait = Duration::from_secs_f64(FETCH_INTERVAL * 5.0);
+
+        if let Ok(output) = timeout(wait, self.receive()).await {
+            panic!("Expected nothing, but received {:?}", output);
+        }
+    }
+}
+
+/// Sets every field that depends on the clock to zero, so that frames can be compared exactly.
+fn without_clock(mut output: ServerOutput) -> ServerOutput {
+    match &mut output {
+        ServerOutput::VehiclePositions(positions) => positions.timestamp = 0,
+        ServerOutput::RouteInformation(route) => route.timestamp = 0,
+        ServerOutput::LineHealth(health) => health.timestamp = 0,
+        ServerOutput::FeedStatus(status) => {
+            status.timestamp = 0;
+            status.last_update = status.last_update.map(|_| 0);
+            status.feed_timestamp = status.feed_timestamp.map(|_| 0);
+            status.data_age = status.data_age.map(|_| 0);
+        }
+        ServerOutput::Error(_) | ServerOutput::PassengerInformation(_) => (),
+    }
+
+    output
+}
+
+/// A feed with the bus on line 5 at `(latitude, longitude)`, and a vehicle in Stockholm on a
+/// trip that is not in the static data.
+fn feed_with_bus_at(latitude: f32, longitude: f32) -> RealtimeFeed {
+    let vehicle = |id: &'static str, trip_id: &'static str, latitude, longitude| FeedEntity {
+        id: Cow::Borrowed(id),
+        vehicle: Some(VehiclePosition {
+            trip: Some(TripDescriptor {
+                trip_id: Some(Cow::Borrowed(trip_id)),
+                ..TripDescriptor::default()
+            }),
+            vehicle: Some(VehicleDescriptor {
+                id: Some(Cow::Borrowed(id)),
+                ..VehicleDescriptor::default()
+            }),
+            position: Some(Position {
+                latitude,
+                longitude,
+                ..Position::default()
+            }),
+            ..VehiclePosition::default()
+        }),
+        ..FeedEntity::default()
+    };
+
+    let mut message = FeedMessage::default();
+    message.header.gtfs_realtime_version = Cow::Borrowed("2.0");
+    message.entity = vec![
+        vehicle(BUS, "141010000123456789", latitude, longitude),
+        vehicle("9031008", "141010000999999999", 59.33, 18.06),
+    ];
+
+    RealtimeFeed::from_message(&message)
+}
+
+/// The status of a feed that has not been fetched yet.
+fn feed_status_stale() -> ServerOutput {
+    ServerOutput::FeedStatus(FeedStatusOutput {
+        timestamp: 0,
+        operator: "ul".to_owned(),
+        status: FeedStatus::Stale,
+        last_update: None,
+        feed_timestamp: None,
+        data_age: None,
+        entities: EntityCounts::default(),
+        total_invalid_entities: 0,
+        consecutive_failures: 0,
+        message: None,
+    })
+}
+
+/// The status of a feed that was fetched without problems and has `vehicles` vehicles.
+fn feed_status_ok(vehicles: usize) -> ServerOutput {
+    ServerOutput::FeedStatus(FeedStatusOutput {
+        timestamp: 0,
+        operator: "ul".to_owned(),
+        status: FeedStatus::Ok,
+        last_update: Some(0),
+        feed_timestamp: Some(0),
+        data_age: Some(0),
+        entities: EntityCounts {
+            vehicles,
+            ..EntityCounts::default()
+        },
+        total_invalid_entities: 0,
+        consecutive_failures: 0,
+        message: None,
+    })
+}
+
+/// The bus on line 5 at `(latitude, longitude)`.
+fn bus_positions(latitude: f32, longitude: f32) -> ServerOutput {
+    ServerOutput::VehiclePositions(VehiclePositionsOutput {
+        timestamp: 0,
+        vehicles: vec![Vehicle {
+            descriptor_id: format!("ul:{}", BUS),
+            line: Some("5".to_owned()),
+            trip_id: Some("ul:141010000123456789".to_owned()),
+            position: Position {
+                latitude,
+                longitude,
+                ..Position::default()
+            },
+        }],
+    })
+}
+
+/// The route of line 5, which has a single trip from Centralstationen to Stora torget.
+fn line_5_route() -> ServerOutput {
+    let shape = RouteGeometry::EncodedPolyline {
+        polyline: "o_jlJo|sjBgE?".to_owned(),
+    };
+
+    let stop = |stop_id: &str, name: &str, lat, platform: Option<&str>, distance| RouteStop {
+        stop_id: format!("ul:{}", stop_id),
+        name: name.to_owned(),
+        lat,
+        lng: 17.638,
+        platform: platform.map(str::to_owned),
+        distance,
+        arrival_time: None,
+        departure_time: None,
+        forecast: None,
+    };
+
+    ServerOutput::RouteInformation(RouteInformationOutput {
+        timestamp: 0,
+        route: shape.clone(),
+        directions: vec![RouteDirection {
+            direction_id: Some(0),
+            headsign: Some("Gottsunda".to_owned()),
+            share: 1.0,
+            variants: vec![RouteVariant {
+                shape_id: "ul:1".to_owned(),
+                trips: 1,
+                share: 1.0,
+                route: shape,
+                stops: vec![
+                    stop(
+                        "9022003700021001",
+                        "Centralstationen",
+                        59.858,
+                        Some("A"),
+                        0.0,
+                    ),
+                    stop(
+                        "9022003700021002",
+                        "Stora torget",
+                        59.859,
+                        None,
+                        111.19492664508968,
+                    ),
+                ],
+            }],
+        }],
+    })
+}
+
+fn passenger_info(passengers: i32) -> ServerOutput {
+    ServerOutput::PassengerInformation(VehiclePassengerInformationOutput {
+        descriptor_id: format!("ul:{}", BUS),
+        passenger_info: PassengerInformationOutput {
+            passengers,
+            ..BUS_PASSENGERS
+        },
+    })
+}
+
+fn error(error_type: ErrorType, error_message: &str) -> ServerOutput {
+    ServerOutput::Error(ErrorOutput {
+        error_type,
+        error_message: error_message.to_owned(),
+    })
+}
+
+fn descriptor(descriptor_id: &str) -> DescriptorInput {
+    DescriptorInput {
+        descriptor_id: descriptor_id.to_owned(),
+    }
+}
+
+#[actix_rt::test]
+async fn test_vehicles_and_routes() {
+    let server = TestServer::start(vec![
+        feed_with_bus_at(59.858, 17.638),
+        feed_with_bus_at(59.859, 17.638),
+    ])
+    .await;
+    let mut client = server.connect().await;
+
+    // Only vehicles that are close to the client are sent, which leaves out the one in
+    // Stockholm.
+    client
+        .send(ClientInput::GeoPositionUpdate(GeoPosition {
+            max_distance: 1000.0,
+            position: GeoPositionPoint {
+                position_type: "Point".to_owned(),
+                coordinates: vec![59.858, 17.638],
+            },
+        }))
+        .await;
+    client.expect(bus_positions(59.858, 17.638)).await;
+
+    server.feed.next();
+    client.expect(bus_positions(59.859, 17.638)).await;
+
+    client
+        .send(ClientInput::GetRouteInformation(RouteInformationRequest {
+            id: "5".to_owned(),
+            kind: None,
+            format: GeometryFormat::Polyline,
+            zoom: None,
+        }))
+        .await;
+    client.expect(line_5_route()).await;
+
+    // A single bus has no headways, but it is placed at the end of its trip.
+    client
+        .send(ClientInput::GetLineHealth(LineInformation {
+            line: "5".to_owned(),
+        }))
+        .await;
+    client
+        .expect(ServerOutput::LineHealth(LineHealthOutput {
+            timestamp: 0,
+            line: "ul:5".to_owned(),
+            route_id: "ul:9011003000500000".to_owned(),
+            directions: vec![DirectionHealth {
+                direction_id: Some(0),
+                headsign: Some("Gottsunda".to_owned()),
+                vehicles: vec![format!("ul:{}", BUS)],
+                headways: Vec::new(),
+                bunched: 0,
+                gaps: 0,
+            }],
+            untracked: 0,
+        }))
+        .await;
+
+    client
+        .send(ClientInput::GetRouteInformation(RouteInformationRequest {
+            id: "6".to_owned(),
+            kind: None,
+            format: GeometryFormat::Geojson,
+            zoom: None,
+        }))
+        .await;
+    client
+        .expect(error(
+            ErrorType::RouteInfo,
+            "'6' is not a valid line number",
+        ))
+        .await;
+
+    client.expect_nothing().await;
+    server.stop().await;
+}
+
+#[actix_rt::test]
+async fn test_seat_reservations() {
+    let server = TestServer::start(vec![feed_with_bus_at(59.858, 17.638)]).await;
+    let mut first = server.connect().await;
+    let mut second = server.connect().await;
+    let bus = format!("ul:{}", BUS);
+
+    // Both clients watch the same bus, whether or not they namespace its id...
+    first
+        .send(ClientInput::GetPassengerInformation(descriptor(&bus)))
+        .await;
+    first.expect(passenger_info(10)).await;
+
+    second
+        .send(ClientInput::GetPassengerInformation(descriptor(BUS)))
+        .await;
+    second.expect(passenger_info(10)).await;
+
+    // ...so both are told when a seat on it is reserved...
+    first.send(ClientInput::ReserveSeat(descriptor(&bus))).await;
+    first.expect(passenger_info(11)).await;
+    second.expect(passenger_info(11)).await;
+
+    // ...but only the client that made a request is told about errors...
+    second.send(ClientInput::UnreserveSeat).await;
+    second
+        .expect(error(
+            ErrorType::Unreserve,
+            "Cannot unreserve since there is no active reservation.",
+        ))
+        .await;
+
+    second
+        .send(ClientInput::ReserveSeat(descriptor("9031009")))
+        .await;
+    second
+        .expect(error(
+            ErrorType::Reserve,
+            "A bus with descriptor id 'ul:9031009' does not exist.",
+        ))
+        .await;
+
+    // ...and when the seat is given up.
+    first.send(ClientInput::UnreserveSeat).await;
+    first.expect(passenger_info(10)).await;
+    second.expect(passenger_info(10)).await;
+
+    first.expect_nothing().await;
+    second.expect_nothing().await;
+    server.stop().await;
+}
+
+#[actix_rt::test]
+async fn test_passengers_endpoint() {
+    let server = TestServer::start(vec![feed_with_bus_at(59.858, 17.638)]).await;
+    let mut client = server.connect().await;
+
+    // The client is sent the bus once it is in traffic.
+    client
+        .send(ClientInput::GeoPositionUpdate(GeoPosition {
+            max_distance: 1000.0,
+            position: GeoPositionPoint {
+                position_type: "Point".to_owned(),
+                coordinates: vec![59.858, 17.638],
+            },
+        }))
+        .await;
+    client.expect(bus_positions(59.858, 17.638)).await;
+
+    let http = awc::Client::new();
+    let url = |descriptor_id: &str| {
+        format!(
+            "http://{}/api/vehicles/{}/passengers",
+            server.address, descriptor_id
+        )
+    };
+
+    let mut response = http.get(url(&format!("ul:{}", BUS))).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.json::<PassengerInformationOutput>().await.unwrap(),
+        BUS_PASSENGERS
+    );
+
+    // A seat that is reserved without the namespace is counted for the namespaced id too.
+    client
+        .send(ClientInput::GetPassengerInformation(descriptor(BUS)))
+        .await;
+    client.expect(passenger_info(10)).await;
+    client.send(ClientInput::ReserveSeat(descriptor(BUS))).await;
+    client.expect(passenger_info(11)).await;
+
+    for descriptor_id in &[BUS.to_owned(), format!("ul:{}", BUS)] {
+        let mut response = http.get(url(descriptor_id)).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response
+                .json::<PassengerInformationOutput>()
+                .await
+                .unwrap()
+                .passengers,
+            11
+        );
+    }
+
+    let mut response = http.get(url("ul:9031009")).send().await.unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(
+        response.json::<ErrorOutput>().await.unwrap(),
+        ErrorOutput {
+            error_type: ErrorType::PassengerInfo,
+            error_message: "'ul:9031009' is not a vehicle in traffic".to_owned(),
+        }
+    );
+
+    // Asking about a vehicle that is not in traffic does not create passenger information
+    // for it.
+    client
+        .send(ClientInput::ReserveSeat(descriptor("ul:9031009")))
+        .await;
+    client
+        .expect(error(
+            ErrorType::Reserve,
+            "A bus with descriptor id 'ul:9031009' does not exist.",
+        ))
+        .await;
+
+    client.expect_nothing().await;
+    server.stop().await;
+}
@@ -1,10 +1,34 @@
 //! All endpoints that are exposed through the webserver.
+//!
+//! Besides the WebSocket endpoint there is a REST API under "/api", which returns the same
+//! JSON types as the WebSocket protocol (see `server_protocol`). Errors are returned as an
+//! `ErrorOutput` together with a matching HTTP status code.
 
-use actix::Addr;
-use actix_web::{get, web::Data, web::Payload, Error, HttpRequest, HttpResponse};
+use actix::{Addr, MailboxError};
+use actix_http::ws::Codec;
+use actix_web::{
+    get,
+    web::{Data, Path, Payload, Query},
+    Error, HttpRequest, HttpResponse,
+};
 use actix_web_actors::ws;
+use chrono::{Local, NaiveDate, TimeDelta};
+use serde::Deserialize;
 
+use crate::config::Settings;
+use crate::gtfs::simulator::Simulator;
+use crate::gtfs::trafiklab::RealtimeFeed;
 use crate::lobby::Lobby;
+use crate::messages::{
+    LineHealthRequest, LineRequest, PassengerInfoRequest, PunctualityRequest, ReadinessRequest,
+    RouteShapeRequest, TripUpdatesFeedRequest, VehiclePositionsFeedRequest, VehiclesRequest,
+};
+use crate::metrics;
+use crate::protocol::client_protocol::{GeometryFormat, IdentifierKind};
+use crate::protocol::server_protocol::{
+    ErrorOutput, ErrorType, PunctualityGrouping, VehiclePositionsOutput,
+};
+use crate::util::BoundingBox;
 use crate::ws::WebsocketClient;
 
 /// Endpoint for creating a WebSocket connection from a HTTP request.
@@ -13,11 +37,307 @@ pub async fn ws_endpoint(
     req: HttpRequest,
     stream: Payload,
     srv: Data<Addr<Lobby>>,
+    settings: Data<Settings>,
 ) -> Result<HttpResponse, Error> {
     // Create a new WebsocketClient with an address to the lobby.
-    let ws = WebsocketClient::new(srv.get_ref().clone());
+    let ws = WebsocketClient::new(
+        srv.get_ref().clone(),
+        settings.heartbeat.clone(),
+        settings.limits.max_queued_messages,
+    );
+
+    // Messages larger than the limit are rejected by the codec before they reach the client.
+    let codec = Codec::new().max_size(settings.limits.max_message_size);
 
     // Start the websocket connection and return the result.
-    let resp = ws::start(ws, &req, stream)?;
-    Ok(resp)
+    let mut resp = ws::handshake(&req)?;
+    Ok(resp.streaming(ws::WebsocketContext::with_codec(ws, stream, codec)))
+}
+
+/// Endpoint that tells whether the process is up.
+#[get("/healthz")]
+pub async fn health_endpoint() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Endpoint that tells whether the server is ready to serve clients, which is the case when
+/// the database responds and every operator has live and static data.
+#[get("/readyz")]
+pub async fn readiness_endpoint(srv: Data<Addr<Lobby>>) -> HttpResponse {
+    match srv.send(ReadinessRequest).await {
+        Ok(output) if output.ready => HttpResponse::Ok().json(output),
+        Ok(output) => HttpResponse::ServiceUnavailable().json(output),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Endpoint for Prometheus metrics.
+#[get("/metrics")]
+pub async fn metrics_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::gather())
+}
+
+/// Query parameters for `vehicles_endpoint`.
+#[derive(Debug, Deserialize)]
+pub struct VehiclesQuery {
+    /// Only vehicles within this area are returned, given as "west,south,east,north".
+    bbox: Option<String>,
+}
+
+/// Endpoint for the latest positions of all vehicles, e.g. "/api/vehicles?bbox=17.5,59.8,17.8,59.9".
+#[get("/api/vehicles")]
+pub async fn vehicles_endpoint(
+    query: Query<VehiclesQuery>,
+    srv: Data<Addr<Lobby>>,
+) -> HttpResponse {
+    let bbox = match query.bbox.as_deref().map(BoundingBox::parse).transpose() {
+        Ok(bbox) => bbox,
+        Err(reason) => return error_response(ErrorType::BadData, reason),
+    };
+
+    match srv.send(VehiclesRequest { bbox }).await {
+        Ok(vehicles) => HttpResponse::Ok().json(VehiclePositionsOutput {
+            timestamp: Lobby::get_current_timestamp(),
+            vehicles,
+        }),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Endpoint for information about a line and the vehicles on it, e.g. "/api/routes/ul:5".
+#[get("/api/routes/{line}")]
+pub async fn line_endpoint(line: Path<String>, srv: Data<Addr<Lobby>>) -> HttpResponse {
+    match srv.send(LineRequest { line: line.0 }).await {
+        Ok(Ok(output)) => HttpResponse::Ok().json(output),
+        Ok(Err(err)) => output_error_response(err),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Endpoint for the headways between the vehicles on a line, and where they are bunched or far
+/// apart, e.g. "/api/routes/ul:5/health".
+#[get("/api/routes/{line}/health")]
+pub async fn line_health_endpoint(line: Path<String>, srv: Data<Addr<Lobby>>) -> HttpResponse {
+    match srv.send(LineHealthRequest { line: line.0 }).await {
+        Ok(Ok(output)) => HttpResponse::Ok().json(output),
+        Ok(Err(err)) => output_error_response(err),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Query parameters for `punctuality_endpoint`.
+#[derive(Debug, Deserialize)]
+pub struct PunctualityQuery {
+    /// The operator, which can be left out if the line or the stop is namespaced.
+    operator: Option<String>,
+
+    /// Only arrivals on this line or at this stop, if given.
+    line: Option<String>,
+    stop: Option<String>,
+
+    /// The first and the last service date ("YYYY-MM-DD"). The default is the last seven days.
+    from: Option<String>,
+    to: Option<String>,
+
+    /// "line", "stop", "hour" or "weekday", to measure each of them separately.
+    group_by: Option<PunctualityGrouping>,
+}
+
+/// Endpoint for the on-time performance of the arrivals in the punctuality history, e.g.
+/// "/api/punctuality?line=ul:5&from=2021-05-01&to=2021-05-31&group_by=hour".
+#[get("/api/punctuality")]
+pub async fn punctuality_endpoint(
+    query: Query<PunctualityQuery>,
+    srv: Data<Addr<Lobby>>,
+) -> HttpResponse {
+    let query = query.into_inner();
+
+    let parse = |date: Option<String>| {
+        date.map(|date| {
+            NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                .map_err(|_| format!("'{}' is not a date in the format YYYY-MM-DD", date))
+        })
+        .transpose()
+    };
+
+    let (from, to) = match (parse(query.from), parse(query.to)) {
+        (Ok(from), Ok(to)) => {
+            let to = to.unwrap_or_else(|| Local::now().date_naive());
+            (from.unwrap_or(to - TimeDelta::days(6)), to)
+        }
+        (Err(reason), _) | (_, Err(reason)) => return error_response(ErrorType::BadData, reason),
+    };
+
+    if from > to {
+        return error_response(
+            ErrorType::BadData,
+            format!("'from' ({}) is after 'to' ({})", from, to),
+        );
+    }
+
+    let request = PunctualityRequest {
+        operator: query.operator,
+        line: query.line,
+        stop_id: query.stop,
+        from,
+        to,
+        group_by: query.group_by,
+    };
+
+    match srv.send(request).await {
+        Ok(Ok(output)) => HttpResponse::Ok().json(output),
+        Ok(Err(err)) => output_error_response(err),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Query parameters for the shape endpoint.
+#[derive(Debug, Deserialize)]
+pub struct RouteShapeQuery {
+    /// What kind of identifier the route is looked up by, which is inferred if left out.
+    kind: Option<IdentifierKind>,
+
+    /// "geojson" (the default) for a GeoJSON LineString, or "polyline" for an encoded polyline.
+    #[serde(default)]
+    format: GeometryFormat,
+
+    /// The zoom level of the map the shape is shown on. The full shape is returned without it.
+    zoom: Option<u8>,
+}
+
+/// Endpoint for the shape of a route, where the identifier is e.g. a line number or a trip id,
+/// e.g. "/api/routes/ul:5/shape?kind=line&format=polyline&zoom=13".
+#[get("/api/routes/{line}/shape")]
+pub async fn route_shape_endpoint(
+    line: Path<String>,
+    query: Query<RouteShapeQuery>,
+    srv: Data<Addr<Lobby>>,
+) -> HttpResponse {
+    let msg = RouteShapeRequest {
+        identifier: line.0,
+        kind: query.kind,
+        format: query.format,
+        zoom: query.zoom,
+    };
+
+    match srv.send(msg).await {
+        Ok(Ok(output)) => HttpResponse::Ok().json(output),
+        Ok(Err(err)) => output_error_response(err),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Endpoint for passenger information for a bus, e.g. "/api/vehicles/ul:9031003/passengers".
+/// Vehicles that are not in traffic are not found.
+#[get("/api/vehicles/{descriptor_id}/passengers")]
+pub async fn passengers_endpoint(
+    descriptor_id: Path<String>,
+    srv: Data<Addr<Lobby>>,
+) -> HttpResponse {
+    let msg = PassengerInfoRequest {
+        descriptor_id: descriptor_id.0,
+    };
+
+    match srv.send(msg).await {
+        Ok(Ok(output)) => HttpResponse::Ok().json(output),
+        Ok(Err(err)) => output_error_response(err),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Query parameters for the GTFS Realtime endpoints.
+#[derive(Debug, Deserialize)]
+pub struct GtfsRealtimeQuery {
+    /// "protobuf" (the default) for the binary format, or "json" to make debugging easier.
+    format: Option<String>,
+}
+
+/// Endpoint for the vehicles of every operator as a GTFS Realtime feed, with occupancy based
+/// on the passenger counts.
+#[get("/gtfs-rt/VehiclePositions.pb")]
+pub async fn vehicle_positions_feed_endpoint(
+    query: Query<GtfsRealtimeQuery>,
+    srv: Data<Addr<Lobby>>,
+) -> HttpResponse {
+    match srv.send(VehiclePositionsFeedRequest).await {
+        Ok(feed) => gtfs_realtime_response(feed, query.format.as_deref()),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Endpoint for the trip updates of every operator as a GTFS Realtime feed.
+#[get("/gtfs-rt/TripUpdates.pb")]
+pub async fn trip_updates_feed_endpoint(
+    query: Query<GtfsRealtimeQuery>,
+    srv: Data<Addr<Lobby>>,
+) -> HttpResponse {
+    match srv.send(TripUpdatesFeedRequest).await {
+        Ok(feed) => gtfs_realtime_response(feed, query.format.as_deref()),
+        Err(err) => mailbox_error_response(err),
+    }
+}
+
+/// Endpoint for the simulated feed of an operator, which the operator's `realtime_url` can
+/// point at instead of Trafiklab's API. Only served when the simulator is enabled.
+#[get("/simulator/{operator}/VehiclePositions.pb")]
+pub async fn simulated_feed_endpoint(
+    operator: Path<String>,
+    query: Query<GtfsRealtimeQuery>,
+    simulator: Data<Simulator>,
+) -> HttpResponse {
+    match simulator.feed(&operator, Lobby::get_current_timestamp()) {
+        Some(feed) => gtfs_realtime_response(feed, query.format.as_deref()),
+        None => HttpResponse::NotFound().body(format!(
+            "Operator '{}' is not simulated, since it has no gtfs_path.",
+            operator.as_str()
+        )),
+    }
+}
+
+/// Creates a response with a GTFS Realtime feed in the requested format.
+fn gtfs_realtime_response(feed: RealtimeFeed, format: Option<&str>) -> HttpResponse {
+    match format {
+        None | Some("protobuf") => HttpResponse::Ok()
+            .content_type("application/x-protobuf")
+            .body(feed.as_bytes().to_vec()),
+        Some("json") => HttpResponse::Ok().json(feed.message()),
+        Some(other) => error_response(
+            ErrorType::BadData,
+            format!(
+                "'{}' is not a supported format, use 'protobuf' or 'json'",
+                other
+            ),
+        ),
+    }
+}
+
+/// Creates a response with an `ErrorOutput` and a status code that matches the type of error.
+fn output_error_response(err: ErrorOutput) -> HttpResponse {
+    let mut response = match err.error_type {
+        ErrorType::BadData | ErrorType::UnknownMessage | ErrorType::Position => {
+            HttpResponse::BadRequest()
+        }
+        ErrorType::LineInfo | ErrorType::RouteInfo | ErrorType::PassengerInfo => {
+            HttpResponse::NotFound()
+        }
+        ErrorType::Reserve | ErrorType::Unreserve => HttpResponse::Conflict(),
+        ErrorType::ServerError => HttpResponse::InternalServerError(),
+    };
+
+    response.json(err)
+}
+
+/// Creates an error response from an error type and a message.
+fn error_response(error_type: ErrorType, error_message: String) -> HttpResponse {
+    output_error_response(ErrorOutput {
+        error_type,
+        error_message,
+    })
+}
+
+/// Creates an error response for when the lobby could not be reached.
+fn mailbox_error_response(err: MailboxError) -> HttpResponse {
+    error_response(ErrorType::ServerError, err.to_string())
 }
@@ -0,0 +1,383 @@
+//! Geometry for the routes that are sent to clients.
+//!
+//! Shapes from the static data have a point every few metres, which is far more than a map
+//! can show unless it is zoomed in all the way. Shapes are therefore simplified with the
+//! Douglas–Peucker algorithm to what is visible at the zoom level of the client's map.
+//!
+//! The simulator (see `gtfs::simulator`) also uses the distances along shapes to move its
+//! vehicles.
+
+use crate::protocol::client_protocol::GeometryFormat;
+use crate::protocol::server_protocol::RouteGeometry;
+
+/// The size (in metres) of a pixel at the equator on a web map at zoom level 0.
+const METRES_PER_PIXEL_AT_ZOOM_0: f64 = 156_543.03;
+
+/// The radius of the earth in metres.
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// The number of decimals that are kept in encoded polylines, which is what Google Maps uses.
+const POLYLINE_PRECISION: f64 = 1e5;
+
+/// A coordinate on a route.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// Returns the largest distance (in metres) a simplified shape may deviate from the original
+/// at a zoom level, which is the size of a pixel.
+///
+/// Pixels are smaller away from the equator, so the simplification is finer than it has to be
+/// in e.g. Sweden, but never coarser.
+pub fn tolerance_for_zoom(zoom: u8) -> f64 {
+    METRES_PER_PIXEL_AT_ZOOM_0 / 2f64.powi(zoom as i32)
+}
+
+/// Simplifies a line with the Douglas–Peucker algorithm, so that no point of the original
+/// line is further than `tolerance` metres from the simplified line. The first and last
+/// points are always kept.
+pub fn simplify(points: &[Point], tolerance: f64) -> Vec<Point> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    // The distances are small enough for the earth to be treated as flat around the line.
+    let projected = project(points, points[0]);
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // Sections of the line that are left to simplify, as the indices of their end points.
+    let mut sections = vec![(0, points.len() - 1)];
+
+    while let Some((first, last)) = sections.pop() {
+        let farthest = (first + 1..last)
+            .map(|i| {
+                let (_, distance) =
+                    closest_on_segment(projected[i], projected[first], projected[last], 0.0);
+                (i, distance)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        if let Some((i, distance)) = farthest {
+            if distance > tolerance {
+                keep[i] = true;
+                sections.push((first, i));
+                sections.push((i, last));
+            }
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(point, _)| *point)
+        .collect()
+}
+
+/// Returns how far (in metres) along `line` each of `stops` is, where the stops are in the
+/// order they are passed. Every stop is placed on the closest part of the line that comes
+/// after the previous stop, so that a line that passes a stop twice (like a loop) still gets
+/// its stops in order.
+pub fn distances_along(line: &[Point], stops: &[Point]) -> Vec<f64> {
+    if line.len() < 2 {
+        return vec![0.0; stops.len()];
+    }
+
+    let projected_line = project(line, line[0]);
+    let projected_stops = project(stops, line[0]);
+
+    // The distance along the line to the start of every segment.
+    let segment_starts = cumulative_lengths(&projected_line);
+
+    let mut previous = (0, 0.0);
+
+    projected_stops
+        .iter()
+        .map(|stop| {
+            let (segment, t, _) = (previous.0..projected_line.len() - 1)
+                .map(|i| {
+                    // A stop on the same segment as the previous one can not be before it.
+                    let min_t = if i == previous.0 { previous.1 } else { 0.0 };
+                    let (t, distance) =
+                        closest_on_segment(*stop, projected_line[i], projected_line[i + 1], min_t);
+
+                    (i, t, distance)
+                })
+                .min_by(|a, b| a.2.total_cmp(&b.2))
+                .unwrap();
+
+            previous = (segment, t);
+
+            let length = segment_starts[segment + 1] - segment_starts[segment];
+            segment_starts[segment] + t * length
+        })
+        .collect()
+}
+
+/// Returns the distance (in metres) along `line` to each of its points.
+pub fn distances_to_points(line: &[Point]) -> Vec<f64> {
+    match line.first() {
+        Some(origin) => cumulative_lengths(&project(line, *origin)),
+        None => Vec::new(),
+    }
+}
+
+/// Returns the point that is `distance` metres along `line` and the direction of the line at
+/// that point, in degrees clockwise from north. `distances` are the distances to the points of
+/// the line (see `distances_to_points`). Distances before the start or beyond the end of the
+/// line give its first or last point.
+///
+/// Panics if the line is empty.
+pub fn point_along(line: &[Point], distances: &[f64], distance: f64) -> (Point, f64) {
+    if line.len() < 2 {
+        return (line[0], 0.0);
+    }
+
+    // The segment that the point is on, which is the last one that starts before it.
+    let segment = distances.partition_point(|start| *start <= distance);
+    let segment = segment.clamp(1, line.len() - 1) - 1;
+
+    let (start, end) = (line[segment], line[segment + 1]);
+    let length = distances[segment + 1] - distances[segment];
+
+    let t = if length > 0.0 {
+        ((distance - distances[segment]) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    let point = Point {
+        lat: start.lat + t * (end.lat - start.lat),
+        lng: start.lng + t * (end.lng - start.lng),
+    };
+
+    let (dx, dy) = project(&[end], start)[0];
+    let bearing = dx.atan2(dy).to_degrees().rem_euclid(360.0);
+
+    (point, bearing)
+}
+
+/// Returns the length of a projected line up to each of its points.
+fn cumulative_lengths(projected: &[(f64, f64)]) -> Vec<f64> {
+    let mut lengths = Vec::with_capacity(projected.len());
+    let mut total = 0.0;
+
+    for (index, point) in projected.iter().enumerate() {
+        if index > 0 {
+            let previous = projected[index - 1];
+            total += (point.0 - previous.0).hypot(point.1 - previous.1);
+        }
+
+        lengths.push(total);
+    }
+
+    lengths
+}
+
+/// Projects points to metres east and north of `origin`.
+fn project(points: &[Point], origin: Point) -> Vec<(f64, f64)> {
+    let lng_scale = origin.lat.to_radians().cos();
+
+    points
+        .iter()
+        .map(|point| {
+            let x = (point.lng - origin.lng).to_radians() * lng_scale * EARTH_RADIUS;
+            let y = (point.lat - origin.lat).to_radians() * EARTH_RADIUS;
+
+            (x, y)
+        })
+        .collect()
+}
+
+/// Returns how far along the segment between `start` and `end` the closest point to `point`
+/// is, from `min_t` to 1 (end) where 0 is the start, and the distance to it.
+fn closest_on_segment(
+    point: (f64, f64),
+    start: (f64, f64),
+    end: (f64, f64),
+    min_t: f64,
+) -> (f64, f64) {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let length_squared = dx * dx + dy * dy;
+
+    let t = if length_squared == 0.0 {
+        min_t
+    } else {
+        (((point.0 - start.0) * dx + (point.1 - start.1) * dy) / length_squared).clamp(min_t, 1.0)
+    };
+
+    let (x, y) = (start.0 + t * dx, start.1 + t * dy);
+
+    (t, (point.0 - x).hypot(point.1 - y))
+}
+
+/// Encodes points with Google's encoded polyline algorithm.
+pub fn encode_polyline(points: &[Point]) -> String {
+    let mut encoded = String::new();
+    let (mut previous_lat, mut previous_lng) = (0, 0);
+
+    for point in points {
+        let lat = (point.lat * POLYLINE_PRECISION).round() as i64;
+        let lng = (point.lng * POLYLINE_PRECISION).round() as i64;
+
+        encode_value(lat - previous_lat, &mut encoded);
+        encode_value(lng - previous_lng, &mut encoded);
+
+        previous_lat = lat;
+        previous_lng = lng;
+    }
+
+    encoded
+}
+
+/// Encodes a single (delta) value of a polyline.
+fn encode_value(value: i64, encoded: &mut String) {
+    let mut value = if value < 0 { !(value << 1) } else { value << 1 };
+
+    while value >= 0x20 {
+        encoded.push((((value & 0x1f) | 0x20) as u8 + 63) as char);
+        value >>= 5;
+    }
+
+    encoded.push((value as u8 + 63) as char);
+}
+
+/// Converts points to the geometry that is sent to clients.
+pub fn to_geometry(points: &[Point], format: GeometryFormat) -> RouteGeometry {
+    match format {
+        GeometryFormat::Geojson => RouteGeometry::LineString {
+            // GeoJSON positions are longitude first.
+            coordinates: points.iter().map(|point| [point.lng, point.lat]).collect(),
+        },
+        GeometryFormat::Polyline => RouteGeometry::EncodedPolyline {
+            polyline: encode_polyline(points),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(lat: f64, lng: f64) -> Point {
+        Point { lat, lng }
+    }
+
+    #[test]
+    fn test_encode_polyline() {
+        // The example from Google's documentation of the format.
+        let points = vec![
+            point(38.5, -120.2),
+            point(40.7, -120.95),
+            point(43.252, -126.453),
+        ];
+
+        assert_eq!(encode_polyline(&points), "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+        assert_eq!(encode_polyline(&[]), "");
+    }
+
+    #[test]
+    fn test_simplify() {
+        // A straight line along a street in Uppsala, with a small wiggle in the middle and a
+        // turn at the end.
+        let points = vec![
+            point(59.8580, 17.6380),
+            point(59.8585, 17.6380),
+            point(59.8590, 17.63801),
+            point(59.8595, 17.6380),
+            point(59.8600, 17.6380),
+            point(59.8600, 17.6400),
+        ];
+
+        // The wiggle is about half a metre, so it disappears unless the map is zoomed in.
+        assert_eq!(
+            simplify(&points, tolerance_for_zoom(15)),
+            vec![points[0], points[4], points[5]]
+        );
+        assert_eq!(simplify(&points, 0.1), points);
+
+        // Lines that are too short to simplify are left as they are.
+        assert_eq!(simplify(&points[..2], 1000.0), points[..2].to_vec());
+    }
+
+    #[test]
+    fn test_distances_along() {
+        // An L-shaped line of about 111 + 56 metres.
+        let line = vec![
+            point(59.858, 17.638),
+            point(59.859, 17.638),
+            point(59.859, 17.639),
+        ];
+        let stops = vec![
+            point(59.858, 17.638),
+            // Next to the line, halfway along the first segment.
+            point(59.8585, 17.6381),
+            point(59.859, 17.639),
+        ];
+
+        let distances = distances_along(&line, &stops);
+
+        assert_eq!(distances[0], 0.0);
+        assert!((distances[1] - 55.6).abs() < 0.5, "{}", distances[1]);
+        assert!((distances[2] - 167.0).abs() < 0.5, "{}", distances[2]);
+
+        // A line that goes back the same way passes the first stop twice, but the last stop
+        // is at the end.
+        let there_and_back = vec![line[0], line[1], line[0]];
+        let distances = distances_along(&there_and_back, &[line[0], line[1], line[0]]);
+        assert!((distances[2] - 222.4).abs() < 0.5, "{}", distances[2]);
+    }
+
+    #[test]
+    fn test_point_along() {
+        let line = vec![
+            point(59.858, 17.638),
+            point(59.859, 17.638),
+            point(59.859, 17.639),
+        ];
+        let distances = distances_to_points(&line);
+
+        assert_eq!(distances[0], 0.0);
+        assert!((distances[1] - 111.2).abs() < 0.5, "{}", distances[1]);
+        assert!((distances[2] - 167.0).abs() < 0.5, "{}", distances[2]);
+
+        // Halfway along the first segment, going north.
+        let (halfway, bearing) = point_along(&line, &distances, distances[1] / 2.0);
+        assert!((halfway.lat - 59.8585).abs() < 1e-9, "{:?}", halfway);
+        assert_eq!(bearing, 0.0);
+
+        // On the second segment, going east.
+        let (_, bearing) = point_along(&line, &distances, 150.0);
+        assert!((bearing - 90.0).abs() < 1e-6, "{}", bearing);
+
+        // Beyond the ends of the line.
+        assert_eq!(point_along(&line, &distances, -10.0).0, line[0]);
+        assert_eq!(point_along(&line, &distances, 1000.0).0, line[2]);
+        assert_eq!(point_along(&line[..1], &distances[..1], 10.0).0, line[0]);
+    }
+
+    #[test]
+    fn test_to_geometry() {
+        let points = vec![point(59.858, 17.638), point(59.86, 17.64)];
+
+        assert_eq!(
+            serde_json::to_value(to_geometry(&points, GeometryFormat::Geojson)).unwrap(),
+            serde_json::json!({
+                "type": "LineString",
+                "coordinates": [[17.638, 59.858], [17.64, 59.86]],
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(to_geometry(&points, GeometryFormat::Polyline)).unwrap(),
+            serde_json::json!({
+                "type": "EncodedPolyline",
+                "polyline": encode_polyline(&points),
+            })
+        );
+    }
+}
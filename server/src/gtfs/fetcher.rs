@@ -0,0 +1,255 @@
+//! Actor that periodically fetches realtime data without blocking anyone else.
+//!
+//! Requests are made asynchronously, failed requests are retried with exponential backoff and
+//! a circuit breaker stops requests for a while if the API keeps failing. The outcome of every
+//! fetch is published on the cluster's event bus.
+//!
+//! Operators with a TripUpdates feed have it fetched along with their vehicle positions. Only
+//! vehicle positions count towards the health of the API, a failed fetch of trip updates just
+//! means that the previous ones keep being served.
+//!
+//! Nothing is fetched while no clients are connected, since nobody would see the data.
+//!
+//! When several instances of the server are running, every instance has a fetcher for each
+//! operator but only the one that holds the operator's lease makes requests. The others keep
+//! trying to acquire the lease, so one of them takes over if that instance goes away or has no
+//! clients left.
+
+use std::time::{Duration, Instant};
+
+use actix::prelude::{Actor, ActorFuture, AsyncContext, Context, Handler, Recipient, WrapFuture};
+use tracing::{info, warn};
+
+use crate::cluster::{Cluster, ClusterEvent};
+use crate::config::Settings;
+use crate::gtfs::operator::Operator;
+use crate::gtfs::retry::{Backoff, CircuitBreaker};
+use crate::gtfs::trafiklab::TrafiklabApi;
+use crate::messages::{ClientCountRequest, ReloadSettings};
+use crate::metrics::{FEED_FETCH_FAILURES, FEED_FETCH_SECONDS};
+
+/// Fetches vehicle positions and trip updates for a single operator from Trafiklab's API and
+/// publishes them.
+pub struct RealtimeFetcher {
+    /// The name of the operator that data is fetched for.
+    operator: String,
+
+    /// Handle to communicate with Trafiklab's API.
+    trafiklab: TrafiklabApi,
+
+    /// How often data is fetched when the API is working as expected.
+    interval: Duration,
+
+    /// Delays retries after failed requests.
+    backoff: Backoff,
+
+    /// Stops requests while the API is considered to be down.
+    breaker: CircuitBreaker,
+
+    /// Where fetched feeds are published, and the leases that decide who fetches them.
+    cluster: Cluster,
+
+    /// How long the lease lasts without being renewed.
+    lease_ttl: Duration,
+
+    /// Whether this instance held the lease at the last attempt to fetch.
+    holds_lease: bool,
+
+    /// Where to find out how many clients are connected to this instance.
+    clients: Recipient<ClientCountRequest>,
+}
+
+impl RealtimeFetcher {
+    pub fn new(
+        operator: Operator,
+        settings: &Settings,
+        cluster: Cluster,
+        clients: Recipient<ClientCountRequest>,
+    ) -> Self {
+        let trafiklab = &settings.trafiklab_api;
+
+        RealtimeFetcher {
+            operator: operator.name.clone(),
+            trafiklab: TrafiklabApi::new(operator, settings),
+            interval: trafiklab.echo_interval(),
+            backoff: Backoff::new(trafiklab.backoff_base(), trafiklab.backoff_max()),
+            breaker: CircuitBreaker::new(
+                trafiklab.circuit_failure_threshold,
+                trafiklab.circuit_cooldown(),
+            ),
+            cluster,
+            lease_ttl: settings.cluster.lease_ttl(),
+            holds_lease: false,
+            clients,
+        }
+    }
+
+    /// Schedules a fetch after `delay`.
+    ///
+    /// Instead of using a fixed interval, every fetch schedules the next one when it has
+    /// completed. This way requests never overlap and the delay can depend on the outcome.
+    fn schedule_fetch(&self, ctx: &mut <Self as Actor>::Context, delay: Duration) {
+        ctx.run_later(delay, |act, ctx| act.fetch(ctx));
+    }
+
+    /// Fetches vehicle positions if any clients are connected to this instance.
+    fn fetch(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let clients = self.clients.send(ClientCountRequest);
+
+        ctx.spawn(clients.into_actor(self).map(|clients, act, ctx| {
+            // If no clients are connected there is no point in fetching any data. The lease is
+            // not renewed either, so that an instance with clients can take over.
+            match clients {
+                Ok(0) | Err(_) => act.schedule_fetch(ctx, act.interval),
+                Ok(_) => act.fetch_with_clients(ctx),
+            }
+        }));
+    }
+
+    /// Fetches vehicle positions if this instance holds the lease for the operator's feed.
+    fn fetch_with_clients(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let lease = self.cluster.leases.acquire(
+            &format!("realtime-feed:{}", self.operator),
+            &self.cluster.instance_id,
+            self.lease_ttl,
+        );
+
+        ctx.spawn(lease.into_actor(self).map(|acquired, act, ctx| {
+            if acquired != act.holds_lease {
+                info!(
+                    operator = %act.operator,
+                    instance_id = %act.cluster.instance_id,
+                    "{} the realtime feed.",
+                    if acquired { "Started fetching" } else { "Stopped fetching" }
+                );
+
+                act.holds_lease = acquired;
+            }
+
+            if acquired {
+                act.fetch_with_lease(ctx);
+            } else {
+                act.schedule_fetch(ctx, act.interval);
+            }
+        }));
+    }
+
+    /// Fetches vehicle positions unless the circuit breaker is open.
+    fn fetch_with_lease(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Err(remaining) = self.breaker.try_acquire(Instant::now()) {
+            self.schedule_fetch(ctx, remaining);
+            return;
+        }
+
+        if let Some(request) = self.trafiklab.fetch_trip_updates() {
+            ctx.spawn(request.into_actor(self).map(|result, act, _| match result {
+                Ok(feed) => act.cluster.bus.publish(ClusterEvent::TripUpdates {
+                    operator: act.operator.clone(),
+                    feed,
+                }),
+                Err(reason) => warn!(
+                    operator = %act.operator,
+                    "Failed to retrieve trip updates from Trafiklab Realtime API. Reason: {}",
+                    reason
+                ),
+            }));
+        }
+
+        let request = self.trafiklab.fetch_vehicle_positions();
+        let started = Instant::now();
+
+        ctx.spawn(request.into_actor(self).map(move |result, act, ctx| {
+            let outcome = if result.is_ok() { "success" } else { "failure" };
+
+            FEED_FETCH_SECONDS
+                .with_label_values(&[&act.operator, outcome])
+                .observe(started.elapsed().as_secs_f64());
+
+            let delay = match &result {
+                Ok(_) => {
+                    act.backoff.reset();
+                    act.breaker.record_success();
+
+                    act.interval
+                }
+                Err(reason) => {
+                    act.breaker.record_failure(Instant::now());
+
+                    FEED_FETCH_FAILURES
+                        .with_label_values(&[&act.operator, reason.kind()])
+                        .inc();
+
+                    let delay = act.backoff.next_delay();
+
+                    warn!(
+                        operator = %act.operator,
+                        retry_in = ?delay,
+                        "Failed to retrieve data from Trafiklab Realtime API. Reason: {}",
+                        reason
+                    );
+
+                    delay
+                }
+            };
+
+            // Failures are published as well so that every instance can keep track of the
+            // health of the feed.
+            act.cluster.bus.publish(ClusterEvent::RealtimeFeed {
+                operator: act.operator.clone(),
+                result: result.map_err(|reason| reason.to_string()),
+            });
+
+            act.schedule_fetch(ctx, delay);
+        }));
+    }
+}
+
+impl Actor for RealtimeFetcher {
+    type Context = Context<Self>;
+
+    // This method is called when the fetcher is started.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // Fetch the initial data right away if anyone is connected.
+        self.fetch(ctx);
+    }
+}
+
+impl Handler<ReloadSettings> for RealtimeFetcher {
+    type Result = ();
+
+    // This method is called when the settings have been reloaded. The new settings are used
+    // from the next fetch and onwards.
+    fn handle(&mut self, msg: ReloadSettings, _: &mut Context<Self>) {
+        let settings = msg.0;
+
+        let operator = settings.operators().ok().and_then(|operators| {
+            operators
+                .into_iter()
+                .find(|operator| operator.name == self.operator)
+        });
+
+        // An operator that has been removed keeps being fetched until the server is
+        // restarted, since the lobby still serves its data.
+        match operator {
+            Some(operator) => self.trafiklab.reconfigure(operator, &settings),
+            None => {
+                info!(
+                    operator = %self.operator,
+                    "Operator is no longer in the settings, keeping its old settings."
+                );
+                return;
+            }
+        }
+
+        let trafiklab = &settings.trafiklab_api;
+
+        self.interval = trafiklab.echo_interval();
+        self.lease_ttl = settings.cluster.lease_ttl();
+        self.backoff
+            .set_bounds(trafiklab.backoff_base(), trafiklab.backoff_max());
+        self.breaker.set_policy(
+            trafiklab.circuit_failure_threshold,
+            trafiklab.circuit_cooldown(),
+        );
+    }
+}
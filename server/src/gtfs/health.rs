@@ -0,0 +1,144 @@
+//! Keeps track of how healthy the realtime feed is.
+//!
+//! The health is derived from how old the latest data is and how many fetches in a row have
+//! failed. Clients are told about the health so that they can show that live data is
+//! unavailable instead of showing buses that are frozen in place.
+
+use crate::protocol::server_protocol::{EntityCounts, FeedStatus, FeedStatusOutput};
+
+/// Data older than this (in seconds) is considered delayed.
+const DEGRADED_DATA_AGE: u64 = 45;
+
+/// Data older than this (in seconds) is considered too old to be shown as live data.
+const STALE_DATA_AGE: u64 = 120;
+
+/// The number of consecutive failed fetches before the feed is considered degraded, even if
+/// the latest data is still fresh.
+const DEGRADED_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Statistics about the realtime feed.
+#[derive(Debug, Clone, Default)]
+pub struct FeedHealth {
+    /// POSIX timestamp of the last successful fetch.
+    last_success: Option<u64>,
+
+    /// The `FeedHeader::timestamp` of the last successfully fetched feed, i.e. when the data
+    /// was created by the provider.
+    feed_timestamp: Option<u64>,
+
+    /// The number of entities of each kind in the last successfully fetched feed.
+    entity_counts: EntityCounts,
+
+    /// The total number of invalid entities that have been skipped.
+    total_invalid_entities: u64,
+
+    /// The number of failed fetches since the last successful one.
+    consecutive_failures: u32,
+
+    /// The reason for the latest failed fetch, if the latest fetch failed.
+    last_error: Option<String>,
+}
+
+impl FeedHealth {
+    pub fn new() -> Self {
+        FeedHealth::default()
+    }
+
+    /// Registers a successfully fetched feed.
+    pub fn record_success(
+        &mut self,
+        now: u64,
+        feed_timestamp: Option<u64>,
+        entity_counts: EntityCounts,
+    ) {
+        self.total_invalid_entities += entity_counts.invalid as u64;
+
+        self.last_success = Some(now);
+        self.feed_timestamp = feed_timestamp;
+        self.entity_counts = entity_counts;
+        self.consecutive_failures = 0;
+        self.last_error = None;
+    }
+
+    /// Registers a failed fetch.
+    pub fn record_failure(&mut self, reason: String) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.last_error = Some(reason);
+    }
+
+    /// Returns how old (in seconds) the latest data is at `now`, or None if no data has been
+    /// fetched yet.
+    ///
+    /// The feed's own timestamp is preferred over the time of the fetch, since a provider might
+    /// keep serving the same old data even though the requests succeed.
+    pub fn data_age(&self, now: u64) -> Option<u64> {
+        self.feed_timestamp
+            .or(self.last_success)
+            .map(|timestamp| now.saturating_sub(timestamp))
+    }
+
+    /// Determines the status of the feed at `now`.
+    pub fn status(&self, now: u64) -> FeedStatus {
+        match self.data_age(now) {
+            None => FeedStatus::Stale,
+            Some(age) if age > STALE_DATA_AGE => FeedStatus::Stale,
+            Some(age)
+                if age > DEGRADED_DATA_AGE
+                    || self.consecutive_failures >= DEGRADED_CONSECUTIVE_FAILURES =>
+            {
+                FeedStatus::Degraded
+            }
+            Some(_) => FeedStatus::Ok,
+        }
+    }
+
+    /// Creates the output that is sent to clients for the feed of `operator`.
+    pub fn to_output(&self, operator: &str, now: u64) -> FeedStatusOutput {
+        FeedStatusOutput {
+            timestamp: now,
+            operator: operator.to_owned(),
+            status: self.status(now),
+            last_update: self.last_success,
+            feed_timestamp: self.feed_timestamp,
+            data_age: self.data_age(now),
+            entities: self.entity_counts.clone(),
+            total_invalid_entities: self.total_invalid_entities,
+            consecutive_failures: self.consecutive_failures,
+            message: self.last_error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+
+    #[test]
+    fn test_feed_status() {
+        let mut health = FeedHealth::new();
+
+        // Without any data the feed cannot be live.
+        assert_eq!(health.status(NOW), FeedStatus::Stale);
+
+        health.record_success(NOW, Some(NOW - 5), EntityCounts::default());
+        assert_eq!(health.status(NOW), FeedStatus::Ok);
+
+        // A few failures in a row degrades the feed even if the data is still fresh.
+        for _ in 0..DEGRADED_CONSECUTIVE_FAILURES {
+            health.record_failure("Request timed out.".to_owned());
+        }
+        assert_eq!(health.status(NOW), FeedStatus::Degraded);
+
+        // The age of the data is based on the feed's timestamp rather than the fetch time.
+        health.record_success(
+            NOW,
+            Some(NOW - DEGRADED_DATA_AGE - 1),
+            EntityCounts::default(),
+        );
+        assert_eq!(health.status(NOW), FeedStatus::Degraded);
+
+        assert_eq!(health.status(NOW + STALE_DATA_AGE + 1), FeedStatus::Stale);
+    }
+}
@@ -1,5 +1,14 @@
 //! Interface for receiving and parsing GTFS (General Transit Feed Specification) data.
 
+pub mod fetcher;
+pub mod health;
+pub mod operator;
+pub mod republish;
+pub mod retry;
+pub mod simulator;
 pub mod trafiklab;
-pub mod transit_realtime;
 pub mod transit_static;
+pub mod validation;
+
+// The realtime messages are part of the protocol, which other programs use as well.
+pub use bus_plus::gtfs::transit_realtime;
@@ -0,0 +1,120 @@
+//! Transit operators (regional public transport authorities) that the server serves data for.
+//!
+//! Every operator has its own realtime and static feeds and its own database with static data.
+//! Since ids are only unique within an operator, ids that are sent to clients are namespaced
+//! with the operator's name, e.g. "ul:9031003" or "sl:9031003".
+
+pub use crate::protocol::NAMESPACE_SEPARATOR;
+
+/// The URL for Trafiklab's Vehicle Positions API, where "{operator}" is replaced by the name of
+/// an operator.
+const TRAFIKLAB_VEH_POS_API_URL: &str =
+    "https://opendata.samtrafiken.se/gtfs-rt/{operator}/VehiclePositions.pb";
+
+/// The URL for Trafiklab's Trip Updates API, where "{operator}" is replaced by the name of an
+/// operator.
+const TRAFIKLAB_TRIP_UPDATES_API_URL: &str =
+    "https://opendata.samtrafiken.se/gtfs-rt/{operator}/TripUpdates.pb";
+
+/// The URL for Trafiklab's Static Data API, where "{operator}" is replaced by the name of
+/// an operator.
+const TRAFIKLAB_STATIC_API_URL: &str =
+    "https://opendata.samtrafiken.se/gtfs/{operator}/{operator}.zip";
+
+/// Settings for a single operator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operator {
+    /// Trafiklab's abbreviation for the operator, e.g. "ul", "sl" or "otraf". This is also the
+    /// namespace for all ids that belong to the operator.
+    pub name: String,
+
+    /// URL to the operator's GTFS Realtime Vehicle Positions feed (without an API key).
+    pub realtime_url: String,
+
+    /// URL to the operator's GTFS Realtime Trip Updates feed (without an API key), if it has
+    /// one. It uses the same API key as the Vehicle Positions feed.
+    pub trip_updates_url: Option<String>,
+
+    /// URL to the operator's GTFS static data (without an API key).
+    pub static_url: String,
+
+    pub realtime_key: String,
+    pub static_key: String,
+
+    /// Name of the database that contains the operator's static data.
+    pub database: String,
+
+    /// Path to a GTFS zip with the operator's static data, for the "memory" storage backend.
+    pub gtfs_path: Option<String>,
+}
+
+impl Operator {
+    /// Creates an operator that uses Trafiklab's default feeds and a database named after it.
+    pub fn new(name: &str, realtime_key: &str, static_key: &str) -> Self {
+        Operator {
+            name: name.to_owned(),
+            realtime_url: TRAFIKLAB_VEH_POS_API_URL.replace("{operator}", name),
+            trip_updates_url: Some(TRAFIKLAB_TRIP_UPDATES_API_URL.replace("{operator}", name)),
+            static_url: TRAFIKLAB_STATIC_API_URL.replace("{operator}", name),
+            realtime_key: realtime_key.to_owned(),
+            static_key: static_key.to_owned(),
+            database: format!("trafiklab-static-data-{}", name),
+            gtfs_path: None,
+        }
+    }
+
+    /// Returns `id` namespaced with the name of this operator.
+    pub fn namespace_id(&self, id: &str) -> String {
+        format!("{}{}{}", self.name, NAMESPACE_SEPARATOR, id)
+    }
+}
+
+/// Splits a (possibly) namespaced id into the name of an operator and an id.
+///
+/// Ids are only split if they start with the name of one of `operators`, since ids without a
+/// namespace can contain the separator as well. Ids without a namespace are returned with
+/// `None` as the operator, which means that the default operator should be used.
+pub fn split_namespaced_id<I>(id: &str, operators: I) -> (Option<&str>, &str)
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    if let Some((operator, rest)) = id.split_once(NAMESPACE_SEPARATOR) {
+        if operators.into_iter().any(|name| name.as_ref() == operator) {
+            return (Some(operator), rest);
+        }
+    }
+
+    (None, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespaced_ids() {
+        let operator = Operator::new("ul", "realtime", "static");
+
+        assert_eq!(
+            operator.realtime_url,
+            "https://opendata.samtrafiken.se/gtfs-rt/ul/VehiclePositions.pb"
+        );
+        assert_eq!(
+            operator.trip_updates_url.as_deref(),
+            Some("https://opendata.samtrafiken.se/gtfs-rt/ul/TripUpdates.pb")
+        );
+
+        let id = operator.namespace_id("9031003");
+        assert_eq!(id, "ul:9031003");
+        assert_eq!(split_namespaced_id(&id, &["ul"]), (Some("ul"), "9031003"));
+        assert_eq!(split_namespaced_id("5", &["ul"]), (None, "5"));
+
+        // Only configured operators are namespaces.
+        assert_eq!(split_namespaced_id("sl:5", &["ul"]), (None, "sl:5"));
+        assert_eq!(
+            split_namespaced_id("ul:sl:5", &["ul"]),
+            (Some("ul"), "sl:5")
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Builds the GTFS Realtime feeds that the server republishes to other systems.
+//!
+//! The republished feeds contain the vehicles of every operator, with ids namespaced in the
+//! same way as in the rest of the API. Vehicle positions are enriched with the routes that the
+//! vehicles are running on and with occupancy based on the passenger counts that riders'
+//! reservations keep up to date, which the feeds from the realtime API do not have. Trip
+//! updates are passed on from the operators' TripUpdates feeds as they were received.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::gtfs::operator::Operator;
+use crate::gtfs::trafiklab::RealtimeFeed;
+use crate::gtfs::transit_realtime::mod_VehiclePosition::OccupancyStatus;
+use crate::gtfs::transit_realtime::{
+    FeedEntity, FeedHeader, FeedMessage, TripDescriptor, VehicleDescriptor, VehiclePosition,
+};
+use crate::protocol::server_protocol::{PassengerInformationOutput, Vehicle};
+
+/// The version of the GTFS Realtime specification that the feeds follow.
+const GTFS_REALTIME_VERSION: &str = "2.0";
+
+/// A vehicle together with the (namespaced) id of the route it is running on, if known.
+pub struct RepublishedVehicle<'a> {
+    pub vehicle: &'a Vehicle,
+    pub route_id: Option<&'a str>,
+}
+
+/// Returns how crowded a vehicle is, and how many percent of its seats are taken.
+///
+/// A vehicle is full when every seat is taken, which is the same limit that reservations use.
+pub fn occupancy(passenger_info: &PassengerInformationOutput) -> (OccupancyStatus, u32) {
+    let passengers = passenger_info.passengers.max(0);
+    let capacity = passenger_info.capacity.max(1);
+
+    let status = if passengers == 0 {
+        OccupancyStatus::EMPTY
+    } else if passengers >= capacity {
+        OccupancyStatus::FULL
+    } else if passengers * 2 < capacity {
+        OccupancyStatus::MANY_SEATS_AVAILABLE
+    } else {
+        OccupancyStatus::FEW_SEATS_AVAILABLE
+    };
+
+    let percentage = (passengers as f64 / capacity as f64 * 100.0).round() as u32;
+
+    (status, percentage)
+}
+
+/// Builds a VehiclePositions feed from enriched vehicles, where `passenger_info` maps
+/// descriptor ids to the passenger information of the vehicles that riders have asked about.
+pub fn vehicle_positions(
+    vehicles: &[RepublishedVehicle],
+    passenger_info: &HashMap<String, PassengerInformationOutput>,
+    timestamp: u64,
+) -> RealtimeFeed {
+    let entity = vehicles
+        .iter()
+        .map(|republished| {
+            let vehicle = republished.vehicle;
+            let occupancy = passenger_info.get(&vehicle.descriptor_id).map(occupancy);
+
+            FeedEntity {
+                id: Cow::Borrowed(&vehicle.descriptor_id),
+                vehicle: Some(VehiclePosition {
+                    trip: vehicle.trip_id.as_ref().map(|trip_id| TripDescriptor {
+                        trip_id: Some(Cow::Borrowed(trip_id)),
+                        route_id: republished.route_id.map(Cow::Borrowed),
+                        ..TripDescriptor::default()
+                    }),
+                    vehicle: Some(VehicleDescriptor {
+                        id: Some(Cow::Borrowed(&vehicle.descriptor_id)),
+                        ..VehicleDescriptor::default()
+                    }),
+                    position: Some(vehicle.position.clone()),
+                    occupancy_status: occupancy.map(|(status, _)| status),
+                    occupancy_percentage: occupancy.map(|(_, percentage)| percentage),
+                    ..VehiclePosition::default()
+                }),
+                ..FeedEntity::default()
+            }
+        })
+        .collect();
+
+    RealtimeFeed::from_message(&FeedMessage {
+        header: header(timestamp),
+        entity,
+    })
+}
+
+/// Builds a TripUpdates feed from the latest TripUpdates feed of every operator.
+pub fn trip_updates(feeds: &[(&Operator, &RealtimeFeed)], timestamp: u64) -> RealtimeFeed {
+    let messages = feeds
+        .iter()
+        .map(|(operator, feed)| (*operator, feed.message()))
+        .collect::<Vec<_>>();
+
+    let mut entity = Vec::new();
+
+    for (operator, message) in &messages {
+        let namespace = |id: &Cow<str>| Cow::Owned(operator.namespace_id(id));
+
+        for feed_entity in &message.entity {
+            let mut trip_update = match &feed_entity.trip_update {
+                Some(trip_update) if !feed_entity.is_deleted => trip_update.clone(),
+                _ => continue,
+            };
+
+            trip_update.trip.trip_id = trip_update.trip.trip_id.as_ref().map(namespace);
+            trip_update.trip.route_id = trip_update.trip.route_id.as_ref().map(namespace);
+
+            if let Some(vehicle) = trip_update.vehicle.as_mut() {
+                vehicle.id = vehicle.id.as_ref().map(namespace);
+            }
+
+            for stop_time_update in trip_update.stop_time_update.iter_mut() {
+                stop_time_update.stop_id = stop_time_update.stop_id.as_ref().map(namespace);
+            }
+
+            entity.push(FeedEntity {
+                id: namespace(&feed_entity.id),
+                trip_update: Some(trip_update),
+                ..FeedEntity::default()
+            });
+        }
+    }
+
+    RealtimeFeed::from_message(&FeedMessage {
+        header: header(timestamp),
+        entity,
+    })
+}
+
+/// Creates the header of a feed that contains every entity.
+pub fn header(timestamp: u64) -> FeedHeader<'static> {
+    FeedHeader {
+        gtfs_realtime_version: Cow::Borrowed(GTFS_REALTIME_VERSION),
+        timestamp: Some(timestamp),
+        ..FeedHeader::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::transit_realtime::mod_TripUpdate::StopTimeUpdate;
+    use crate::gtfs::transit_realtime::{Position, TripUpdate};
+
+    fn passenger_info(passengers: i32, capacity: i32) -> PassengerInformationOutput {
+        PassengerInformationOutput {
+            passengers,
+            capacity,
+            forecast: None,
+        }
+    }
+
+    #[test]
+    fn test_occupancy() {
+        assert_eq!(
+            occupancy(&passenger_info(0, 20)),
+            (OccupancyStatus::EMPTY, 0)
+        );
+        assert_eq!(
+            occupancy(&passenger_info(5, 20)),
+            (OccupancyStatus::MANY_SEATS_AVAILABLE, 25)
+        );
+        assert_eq!(
+            occupancy(&passenger_info(10, 20)),
+            (OccupancyStatus::FEW_SEATS_AVAILABLE, 50)
+        );
+        assert_eq!(
+            occupancy(&passenger_info(19, 20)),
+            (OccupancyStatus::FEW_SEATS_AVAILABLE, 95)
+        );
+        assert_eq!(
+            occupancy(&passenger_info(20, 20)),
+            (OccupancyStatus::FULL, 100)
+        );
+    }
+
+    #[test]
+    fn test_vehicle_positions() {
+        let vehicles = [
+            Vehicle {
+                descriptor_id: "ul:9031003".to_owned(),
+                line: Some("5".to_owned()),
+                trip_id: Some("ul:141010000123456789".to_owned()),
+                position: Position {
+                    latitude: 59.85,
+                    longitude: 17.63,
+                    ..Position::default()
+                },
+            },
+            Vehicle {
+                descriptor_id: "sl:9031004".to_owned(),
+                line: None,
+                trip_id: None,
+                position: Position::default(),
+            },
+        ];
+        let republished = vec![
+            RepublishedVehicle {
+                vehicle: &vehicles[0],
+                route_id: Some("ul:9011003000500000"),
+            },
+            RepublishedVehicle {
+                vehicle: &vehicles[1],
+                route_id: None,
+            },
+        ];
+
+        let mut passengers = HashMap::new();
+        passengers.insert("ul:9031003".to_owned(), passenger_info(5, 20));
+
+        let feed = vehicle_positions(&republished, &passengers, 1_600_000_000);
+        let message = feed.message();
+
+        assert_eq!(message.header.gtfs_realtime_version, "2.0");
+        assert_eq!(message.header.timestamp, Some(1_600_000_000));
+        assert_eq!(message.entity.len(), 2);
+
+        let position = message.entity[0].vehicle.as_ref().unwrap();
+        let trip = position.trip.as_ref().unwrap();
+        assert_eq!(trip.trip_id.as_deref(), Some("ul:141010000123456789"));
+        assert_eq!(trip.route_id.as_deref(), Some("ul:9011003000500000"));
+        assert_eq!(
+            position.occupancy_status,
+            Some(OccupancyStatus::MANY_SEATS_AVAILABLE)
+        );
+        assert_eq!(position.occupancy_percentage, Some(25));
+
+        // Nobody has asked about the second vehicle, so its occupancy is unknown.
+        let position = message.entity[1].vehicle.as_ref().unwrap();
+        assert!(position.trip.is_none());
+        assert_eq!(position.occupancy_status, None);
+        assert_eq!(position.occupancy_percentage, None);
+    }
+
+    #[test]
+    fn test_trip_updates() {
+        let operator = Operator::new("ul", "realtime", "static");
+
+        let mut message = FeedMessage::default();
+        message.entity.push(FeedEntity {
+            id: Cow::Borrowed("1"),
+            trip_update: Some(TripUpdate {
+                trip: TripDescriptor {
+                    trip_id: Some(Cow::Borrowed("141010000123456789")),
+                    ..TripDescriptor::default()
+                },
+                stop_time_update: vec![StopTimeUpdate {
+                    stop_id: Some(Cow::Borrowed("9022003700021001")),
+                    ..StopTimeUpdate::default()
+                }],
+                delay: Some(120),
+                ..TripUpdate::default()
+            }),
+            ..FeedEntity::default()
+        });
+        // Vehicle positions are not trip updates.
+        message.entity.push(FeedEntity {
+            id: Cow::Borrowed("2"),
+            vehicle: Some(VehiclePosition::default()),
+            ..FeedEntity::default()
+        });
+        let feed = RealtimeFeed::from_message(&message);
+
+        let republished = trip_updates(&[(&operator, &feed)], 1_600_000_000);
+        let message = republished.message();
+
+        assert_eq!(message.entity.len(), 1);
+        assert_eq!(message.entity[0].id, "ul:1");
+
+        let trip_update = message.entity[0].trip_update.as_ref().unwrap();
+        assert_eq!(
+            trip_update.trip.trip_id.as_deref(),
+            Some("ul:141010000123456789")
+        );
+        assert_eq!(
+            trip_update.stop_time_update[0].stop_id.as_deref(),
+            Some("ul:9022003700021001")
+        );
+        assert_eq!(trip_update.delay, Some(120));
+    }
+}
@@ -0,0 +1,191 @@
+//! Retry policies for requests to external APIs.
+//!
+//! `Backoff` decides how long to wait before retrying a failed request, and `CircuitBreaker`
+//! stops requests altogether for a while when an API has failed too many times in a row, so
+//! that we don't hammer an API that is already struggling.
+
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// Exponential backoff with jitter.
+///
+/// The delay doubles for every consecutive failure, starting at `base` and never exceeding
+/// `max`. Half of the delay is randomized ("equal jitter") so that several servers that fail
+/// at the same time do not retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+
+    /// Number of consecutive failures since the last success.
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Returns the delay before the next retry and increases the delay for the retry after that.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current_max_delay();
+
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::from_secs(0)..=half)
+    }
+
+    /// Changes the bounds of the delay without resetting the number of failures.
+    pub fn set_bounds(&mut self, base: Duration, max: Duration) {
+        self.base = base;
+        self.max = max;
+    }
+
+    /// Resets the backoff after a successful request.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// The upper bound of the delay for the current attempt (before jitter is applied).
+    fn current_max_delay(&self) -> Duration {
+        // Cap the exponent so that the multiplication cannot overflow.
+        let factor = 2u32.saturating_pow(self.attempt.min(16));
+
+        self.base
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+/// The states a `CircuitBreaker` can be in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    /// Requests are allowed.
+    Closed,
+
+    /// Requests are not allowed until the cooldown has passed.
+    Open { until: Instant },
+
+    /// The cooldown has passed and a single trial request is allowed. If it succeeds the
+    /// circuit is closed again, otherwise it is reopened.
+    HalfOpen,
+}
+
+/// Stops requests to an external API after `failure_threshold` consecutive failures.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    state: CircuitState,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            consecutive_failures: 0,
+            state: CircuitState::Closed,
+        }
+    }
+
+    /// Changes when the circuit opens and for how long, without changing the current state.
+    pub fn set_policy(&mut self, failure_threshold: u32, cooldown: Duration) {
+        self.failure_threshold = failure_threshold;
+        self.cooldown = cooldown;
+    }
+
+    /// Returns `Ok` if a request may be made at `now`, otherwise `Err` with the time left
+    /// until the circuit allows a trial request.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.state {
+            CircuitState::Closed | CircuitState::HalfOpen => Ok(()),
+            CircuitState::Open { until } if now >= until => {
+                self.state = CircuitState::HalfOpen;
+                Ok(())
+            }
+            CircuitState::Open { until } => Err(until - now),
+        }
+    }
+
+    /// Registers a successful request, which closes the circuit.
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.state = CircuitState::Closed;
+    }
+
+    /// Registers a failed request. Opens the circuit if the trial request failed or if the
+    /// threshold of consecutive failures has been reached.
+    pub fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+        if self.state == CircuitState::HalfOpen
+            || self.consecutive_failures >= self.failure_threshold
+        {
+            self.state = CircuitState::Open {
+                until: now + self.cooldown,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_resets() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(10);
+        let mut backoff = Backoff::new(base, max);
+
+        // Every delay should be within [upper bound / 2, upper bound] where the upper bound
+        // doubles each attempt until it reaches the maximum.
+        for upper in &[1, 2, 4, 8, 10, 10] {
+            let upper = Duration::from_secs(*upper);
+            let delay = backoff.next_delay();
+
+            assert!(delay >= upper / 2 && delay <= upper);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= base);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let cooldown = Duration::from_secs(30);
+        let mut breaker = CircuitBreaker::new(3, cooldown);
+        let now = Instant::now();
+
+        // The circuit stays closed until the threshold is reached.
+        breaker.record_failure(now);
+        breaker.record_failure(now);
+        assert!(breaker.try_acquire(now).is_ok());
+
+        breaker.record_failure(now);
+        assert_eq!(breaker.try_acquire(now), Err(cooldown));
+
+        // After the cooldown a single trial request is allowed, and if it fails the circuit
+        // is opened again immediately.
+        let later = now + cooldown;
+        assert!(breaker.try_acquire(later).is_ok());
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+
+        breaker.record_failure(later);
+        assert!(breaker.try_acquire(later).is_err());
+
+        // A successful request closes the circuit.
+        let even_later = later + cooldown;
+        assert!(breaker.try_acquire(even_later).is_ok());
+        breaker.record_success();
+        assert_eq!(breaker.state, CircuitState::Closed);
+    }
+}
@@ -0,0 +1,766 @@
+//! Simulated realtime feeds for demos and offline development.
+//!
+//! The simulator reads the trips, stop times, shapes and calendars in every operator's GTFS
+//! zip and places a vehicle on every trip that is running at the simulated time. Vehicles move
+//! along the shapes of their trips at the pace of the timetable, optionally delayed, and the
+//! simulated clock can run faster than real time.
+//!
+//! The feeds are served at "/simulator/<operator>/VehiclePositions.pb", so an operator's
+//! `realtime_url` and `trip_updates_url` can point at the server itself instead of at
+//! Trafiklab's API. Every feed contains a vehicle position and a trip update with the delay
+//! for each simulated vehicle.
+
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+
+use crate::config::SimulatorSettings;
+use crate::geometry::{self, Point};
+use crate::gtfs::operator::Operator;
+use crate::gtfs::republish;
+use crate::gtfs::trafiklab::RealtimeFeed;
+use crate::gtfs::transit_realtime::mod_TripUpdate::{StopTimeEvent, StopTimeUpdate};
+use crate::gtfs::transit_realtime::mod_VehiclePosition::VehicleStopStatus;
+use crate::gtfs::transit_realtime::{
+    FeedEntity, FeedMessage, Position, TripDescriptor, TripUpdate, VehicleDescriptor,
+    VehiclePosition,
+};
+use crate::gtfs::transit_static::{Calendar, CalendarDates, Shape, StopTime};
+use crate::store::{shape_points, StaticTables};
+use crate::util::{parse_gtfs_date, parse_gtfs_time, GTFS_DATE_FORMAT};
+
+/// Prefix for the ids of simulated vehicles, so that they are not mistaken for real ones.
+const VEHICLE_ID_PREFIX: &str = "sim-";
+
+/// Serves simulated feeds for the operators that have a GTFS zip. Clones share the same
+/// timetables and clock.
+#[derive(Clone)]
+pub struct Simulator {
+    timetables: Arc<HashMap<String, Timetable>>,
+    clock: SimulatedClock,
+    delays: Delays,
+}
+
+impl Simulator {
+    /// Reads the timetable of every operator that has a GTFS zip.
+    pub fn from_settings(
+        settings: &SimulatorSettings,
+        operators: &[Operator],
+    ) -> Result<Self, String> {
+        let mut timetables = HashMap::new();
+
+        for operator in operators {
+            let path = match &operator.gtfs_path {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let tables = StaticTables::open(path).map_err(|reason| {
+                format!(
+                    "Could not load the timetable of operator '{}' from '{}'. Reason: {}",
+                    operator.name, path, reason
+                )
+            })?;
+
+            timetables.insert(operator.name.clone(), Timetable::new(tables));
+        }
+
+        let start = settings
+            .start_time()
+            .unwrap_or_else(|| Local::now().naive_local());
+
+        Ok(Simulator {
+            timetables: Arc::new(timetables),
+            clock: SimulatedClock::new(start, settings.speed),
+            delays: Delays {
+                mean: settings.delay,
+                variation: settings.delay_variation,
+            },
+        })
+    }
+
+    /// Returns the names of the operators that are simulated.
+    pub fn operators(&self) -> impl Iterator<Item = &str> {
+        self.timetables.keys().map(String::as_str)
+    }
+
+    /// Returns the feed of an operator at the current simulated time, or None if the operator
+    /// is not simulated. `timestamp` is the real time that the feed is created at.
+    pub fn feed(&self, operator: &str, timestamp: u64) -> Option<RealtimeFeed> {
+        let timetable = self.timetables.get(operator)?;
+        let vehicles = timetable.vehicles_at(self.clock.now(), self.delays);
+
+        Some(feed(&vehicles, timestamp))
+    }
+}
+
+/// A clock that starts at a simulated time and runs `speed` times faster than real time.
+#[derive(Debug, Clone, Copy)]
+struct SimulatedClock {
+    started: Instant,
+    start: NaiveDateTime,
+    speed: f64,
+}
+
+impl SimulatedClock {
+    fn new(start: NaiveDateTime, speed: f64) -> Self {
+        SimulatedClock {
+            started: Instant::now(),
+            start,
+            speed,
+        }
+    }
+
+    /// Returns the current simulated time.
+    fn now(&self) -> NaiveDateTime {
+        let elapsed = self.started.elapsed().as_secs_f64() * self.speed;
+
+        self.start + TimeDelta::milliseconds((elapsed * 1000.0) as i64)
+    }
+}
+
+/// How late trips are. Every trip has its own delay, which stays the same for as long as it
+/// runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Delays {
+    /// The average delay in seconds.
+    mean: f64,
+
+    /// How much the delay of a trip may differ from the average, in seconds.
+    variation: f64,
+}
+
+impl Delays {
+    /// Returns the delay of a trip in whole seconds.
+    fn of(&self, trip_id: &str) -> i64 {
+        // The trip id is hashed instead of picking a random delay, so that the delay is the
+        // same every time the feed is created.
+        let mut hasher = DefaultHasher::new();
+        trip_id.hash(&mut hasher);
+        let spread = hasher.finish() as f64 / u64::MAX as f64 * 2.0 - 1.0;
+
+        (self.mean + spread * self.variation).round() as i64
+    }
+}
+
+/// The trips of an operator and the days that they run on.
+struct Timetable {
+    trips: Vec<ScheduledTrip>,
+    calendar: ServiceCalendar,
+}
+
+/// A trip with its stops and the shape that it drives along.
+struct ScheduledTrip {
+    trip_id: String,
+    route_id: String,
+    service_id: String,
+    direction_id: Option<u32>,
+
+    /// The stops in the order they are visited, with at least two stops.
+    stops: Vec<ScheduledStop>,
+
+    /// The points of the shape, with at least two points, and the distances to them.
+    shape: Arc<(Vec<Point>, Vec<f64>)>,
+}
+
+/// A stop on a trip.
+struct ScheduledStop {
+    stop_id: String,
+    stop_sequence: u32,
+
+    /// Times in seconds after midnight at the start of the service day, which can be more
+    /// than a day for trips that run past midnight.
+    arrival: i64,
+    departure: i64,
+
+    /// How far (in metres) along the shape the stop is.
+    distance: f64,
+}
+
+/// Where a vehicle is on its trip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Progress {
+    /// The stop that the vehicle is at or on its way to, as an index into the trip's stops.
+    stop: usize,
+
+    /// Whether the vehicle is standing at the stop.
+    stopped: bool,
+
+    /// How far (in metres) along the shape the vehicle is.
+    distance: f64,
+
+    /// The speed (in metres per second) that the vehicle needs to keep to be on time.
+    speed: f64,
+}
+
+/// A vehicle on a trip at a point in time.
+struct SimulatedVehicle<'a> {
+    trip: &'a ScheduledTrip,
+    service_date: NaiveDate,
+    progress: Progress,
+    position: Point,
+    bearing: f64,
+    delay: i64,
+}
+
+impl Timetable {
+    /// Creates a timetable from the tables of a GTFS feed. Trips without a shape or with
+    /// fewer than two stops that have times are left out, since they cannot be simulated.
+    fn new(tables: StaticTables) -> Self {
+        let mut shape_rows: HashMap<String, Vec<Shape>> = HashMap::new();
+        for row in tables.shapes {
+            shape_rows
+                .entry(row.shape_id.clone())
+                .or_default()
+                .push(row);
+        }
+
+        let shapes = shape_rows
+            .into_iter()
+            .filter_map(|(shape_id, rows)| {
+                let points = shape_points(&rows);
+                let distances = geometry::distances_to_points(&points);
+
+                match points.len() {
+                    0 | 1 => None,
+                    _ => Some((shape_id, Arc::new((points, distances)))),
+                }
+            })
+            .collect::<HashMap<_, _>>();
+
+        let stop_positions = tables
+            .stops
+            .iter()
+            .filter_map(|stop| {
+                let lat = stop.stop_lat.parse().ok()?;
+                let lng = stop.stop_lon.parse().ok()?;
+
+                Some((stop.stop_id.as_str(), Point { lat, lng }))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut stop_times: HashMap<String, Vec<StopTime>> = HashMap::new();
+        for stop_time in tables.stop_times {
+            stop_times
+                .entry(stop_time.trip_id.clone())
+                .or_default()
+                .push(stop_time);
+        }
+
+        let trips = tables
+            .trips
+            .into_iter()
+            .filter_map(|trip| {
+                let shape = shapes.get(&trip.shape_id)?.clone();
+                let mut trip_stop_times = stop_times.remove(&trip.trip_id)?;
+                trip_stop_times.sort_by_key(|stop_time| stop_time.stop_sequence);
+
+                let stops = scheduled_stops(&trip_stop_times, &shape, &stop_positions);
+
+                if stops.len() < 2 {
+                    return None;
+                }
+
+                Some(ScheduledTrip {
+                    direction_id: trip.direction_id.parse().ok(),
+                    trip_id: trip.trip_id,
+                    route_id: trip.route_id,
+                    service_id: trip.service_id,
+                    stops,
+                    shape,
+                })
+            })
+            .collect();
+
+        Timetable {
+            trips,
+            calendar: ServiceCalendar::new(&tables.calendar, &tables.calendar_dates),
+        }
+    }
+
+    /// Returns every vehicle that is on a trip at the simulated time `now`.
+    fn vehicles_at(&self, now: NaiveDateTime, delays: Delays) -> Vec<SimulatedVehicle<'_>> {
+        let mut vehicles = Vec::new();
+
+        for trip in &self.trips {
+            let delay = delays.of(&trip.trip_id);
+
+            // Trips that run past midnight belong to the service day before.
+            let service_dates = [Some(now.date()), now.date().pred_opt()];
+
+            for service_date in service_dates.iter().flatten() {
+                let midnight = NaiveDateTime::new(*service_date, NaiveTime::MIN);
+                let seconds = (now - midnight).num_seconds() - delay;
+
+                let progress = match trip.progress_at(seconds) {
+                    Some(progress) if self.calendar.runs_on(&trip.service_id, *service_date) => {
+                        progress
+                    }
+                    _ => continue,
+                };
+
+                let (points, distances) = trip.shape.as_ref();
+                let (position, bearing) =
+                    geometry::point_along(points, distances, progress.distance);
+
+                vehicles.push(SimulatedVehicle {
+                    trip,
+                    service_date: *service_date,
+                    progress,
+                    position,
+                    bearing,
+                    delay,
+                });
+                break;
+            }
+        }
+
+        vehicles
+    }
+}
+
+impl ScheduledTrip {
+    /// Returns where on the trip a vehicle that follows the timetable is `seconds` after
+    /// midnight at the start of the service day, or None if the trip is not running then.
+    fn progress_at(&self, seconds: i64) -> Option<Progress> {
+        // The last stop that the vehicle has arrived at.
+        let arrived = self
+            .stops
+            .partition_point(|stop| stop.arrival <= seconds)
+            .checked_sub(1)?;
+
+        let stop = &self.stops[arrived];
+
+        // The trip is over once the vehicle has arrived at the last stop.
+        if arrived == self.stops.len() - 1 {
+            return match seconds == stop.arrival {
+                true => Some(Progress {
+                    stop: arrived,
+                    stopped: true,
+                    distance: stop.distance,
+                    speed: 0.0,
+                }),
+                false => None,
+            };
+        }
+
+        if seconds <= stop.departure {
+            return Some(Progress {
+                stop: arrived,
+                stopped: true,
+                distance: stop.distance,
+                speed: 0.0,
+            });
+        }
+
+        let next = &self.stops[arrived + 1];
+        let duration = (next.arrival - stop.departure).max(1) as f64;
+        let length = next.distance - stop.distance;
+        let fraction = (seconds - stop.departure) as f64 / duration;
+
+        Some(Progress {
+            stop: arrived + 1,
+            stopped: false,
+            distance: stop.distance + fraction.min(1.0) * length,
+            speed: length / duration,
+        })
+    }
+}
+
+/// Converts the stop times of a trip (in the order of their sequence) to stops on its shape.
+/// Stop times without times are skipped, since GTFS only requires times at some stops.
+fn scheduled_stops(
+    stop_times: &[StopTime],
+    shape: &(Vec<Point>, Vec<f64>),
+    stop_positions: &HashMap<&str, Point>,
+) -> Vec<ScheduledStop> {
+    let stop_times = stop_times
+        .iter()
+        .filter_map(|stop_time| {
+            let arrival = parse_gtfs_time(&stop_time.arrival_time)?;
+            let departure = parse_gtfs_time(&stop_time.departure_time).unwrap_or(arrival);
+
+            Some((stop_time, arrival, departure.max(arrival)))
+        })
+        .collect::<Vec<_>>();
+
+    let (points, distances) = shape;
+    let length = distances.last().copied().unwrap_or_default();
+
+    // The stops are placed on the shape where they are closest to it, in the same way as the
+    // stops that are sent to clients. If some stop is missing from the static data, the stops
+    // are spread out evenly along the shape instead.
+    let positions = stop_times
+        .iter()
+        .map(|(stop_time, _, _)| stop_positions.get(stop_time.stop_id.as_str()).copied())
+        .collect::<Option<Vec<Point>>>();
+
+    let stop_distances = match positions {
+        Some(positions) => geometry::distances_along(points, &positions),
+        None => {
+            let last = stop_times.len().saturating_sub(1).max(1) as f64;
+
+            (0..stop_times.len())
+                .map(|index| length * index as f64 / last)
+                .collect()
+        }
+    };
+
+    stop_times
+        .iter()
+        .zip(stop_distances)
+        .map(
+            |((stop_time, arrival, departure), distance)| ScheduledStop {
+                stop_id: stop_time.stop_id.clone(),
+                stop_sequence: stop_time.stop_sequence.max(0) as u32,
+                arrival: *arrival,
+                departure: *departure,
+                distance,
+            },
+        )
+        .collect()
+}
+
+/// The days that services run on, from calendar.txt and calendar_dates.txt.
+struct ServiceCalendar {
+    /// Services that run on some weekdays between two dates.
+    weekly: HashMap<String, WeeklyService>,
+
+    /// Days that services run on (true) or do not run on (false) regardless of the weekly
+    /// services.
+    exceptions: HashMap<(String, NaiveDate), bool>,
+}
+
+/// A service that runs on some weekdays between two dates.
+struct WeeklyService {
+    /// Whether the service runs on each weekday, starting with Monday.
+    weekdays: [bool; 7],
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+}
+
+impl ServiceCalendar {
+    /// Creates a calendar from the rows of calendar.txt and calendar_dates.txt. Rows with
+    /// dates that cannot be parsed are skipped.
+    fn new(calendar: &[Calendar], calendar_dates: &[CalendarDates]) -> Self {
+        let weekly = calendar
+            .iter()
+            .filter_map(|row| {
+                let service = WeeklyService {
+                    weekdays: [
+                        row.monday,
+                        row.tuesday,
+                        row.wednesday,
+                        row.thursday,
+                        row.friday,
+                        row.saturday,
+                        row.sunday,
+                    ]
+                    .map(|runs| runs == 1),
+                    start_date: parse_gtfs_date(&row.start_date)?,
+                    end_date: parse_gtfs_date(&row.end_date)?,
+                };
+
+                Some((row.service_id.clone(), service))
+            })
+            .collect();
+
+        // Exception type 1 means that the service has been added for the date, and 2 that it
+        // has been removed.
+        let exceptions = calendar_dates
+            .iter()
+            .filter_map(|row| {
+                let date = parse_gtfs_date(&row.date)?;
+
+                Some(((row.service_id.clone(), date), row.exception_type == 1))
+            })
+            .collect();
+
+        ServiceCalendar { weekly, exceptions }
+    }
+
+    /// Returns true if a service runs on a date. Every service runs every day if the feed has
+    /// no calendar at all.
+    fn runs_on(&self, service_id: &str, date: NaiveDate) -> bool {
+        if self.weekly.is_empty() && self.exceptions.is_empty() {
+            return true;
+        }
+
+        if let Some(runs) = self.exceptions.get(&(service_id.to_owned(), date)) {
+            return *runs;
+        }
+
+        match self.weekly.get(service_id) {
+            Some(service) => {
+                service.weekdays[date.weekday().num_days_from_monday() as usize]
+                    && service.start_date <= date
+                    && date <= service.end_date
+            }
+            None => false,
+        }
+    }
+}
+
+/// Builds a feed with a vehicle position and a trip update for every vehicle.
+fn feed(vehicles: &[SimulatedVehicle], timestamp: u64) -> RealtimeFeed {
+    let mut entity = Vec::with_capacity(vehicles.len() * 2);
+
+    for vehicle in vehicles {
+        let trip = vehicle.trip;
+        let stop = &trip.stops[vehicle.progress.stop];
+        let vehicle_id = format!("{}{}", VEHICLE_ID_PREFIX, trip.trip_id);
+
+        let trip_descriptor = TripDescriptor {
+            trip_id: Some(Cow::Borrowed(&trip.trip_id)),
+            route_id: Some(Cow::Borrowed(&trip.route_id)),
+            direction_id: trip.direction_id,
+            start_date: Some(Cow::Owned(
+                vehicle.service_date.format(GTFS_DATE_FORMAT).to_string(),
+            )),
+            ..TripDescriptor::default()
+        };
+
+        let vehicle_descriptor = VehicleDescriptor {
+            id: Some(Cow::Owned(vehicle_id.clone())),
+            ..VehicleDescriptor::default()
+        };
+
+        let current_status = match vehicle.progress.stopped {
+            true => VehicleStopStatus::STOPPED_AT,
+            false => VehicleStopStatus::IN_TRANSIT_TO,
+        };
+
+        entity.push(FeedEntity {
+            id: Cow::Owned(vehicle_id),
+            vehicle: Some(VehiclePosition {
+                trip: Some(trip_descriptor.clone()),
+                vehicle: Some(vehicle_descriptor.clone()),
+                position: Some(Position {
+                    latitude: vehicle.position.lat as f32,
+                    longitude: vehicle.position.lng as f32,
+                    bearing: Some(vehicle.bearing as f32),
+                    speed: Some(vehicle.progress.speed as f32),
+                    ..Position::default()
+                }),
+                current_stop_sequence: Some(stop.stop_sequence),
+                stop_id: Some(Cow::Borrowed(&stop.stop_id)),
+                current_status,
+                timestamp: Some(timestamp),
+                ..VehiclePosition::default()
+            }),
+            ..FeedEntity::default()
+        });
+
+        // The delay at the next stop applies to the rest of the trip as well.
+        let delay = vehicle.delay.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+
+        entity.push(FeedEntity {
+            id: Cow::Borrowed(&trip.trip_id),
+            trip_update: Some(TripUpdate {
+                trip: trip_descriptor,
+                vehicle: Some(vehicle_descriptor),
+                stop_time_update: vec![StopTimeUpdate {
+                    stop_sequence: Some(stop.stop_sequence),
+                    stop_id: Some(Cow::Borrowed(&stop.stop_id)),
+                    arrival: Some(StopTimeEvent {
+                        delay: Some(delay),
+                        ..StopTimeEvent::default()
+                    }),
+                    ..StopTimeUpdate::default()
+                }],
+                timestamp: Some(timestamp),
+                delay: Some(delay),
+            }),
+            ..FeedEntity::default()
+        });
+    }
+
+    RealtimeFeed::from_message(&FeedMessage {
+        header: republish::header(timestamp),
+        entity,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{gtfs_zip, line_5};
+
+    fn time(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn line_5_timetable() -> Timetable {
+        Timetable::new(StaticTables::from_zip(line_5()).unwrap())
+    }
+
+    /// Returns the trip ids and positions of the vehicles at a time.
+    fn vehicles_at(timetable: &Timetable, now: &str, delays: Delays) -> Vec<(String, Point)> {
+        timetable
+            .vehicles_at(time(now), delays)
+            .into_iter()
+            .map(|vehicle| (vehicle.trip.trip_id.clone(), vehicle.position))
+            .collect()
+    }
+
+    #[test]
+    fn test_progress() {
+        let timetable = line_5_timetable();
+        let trip = &timetable.trips[0];
+        let length = trip.stops[1].distance;
+
+        assert_eq!(trip.stops[0].distance, 0.0);
+        assert!((length - 111.2).abs() < 0.5, "{}", length);
+
+        // The trip departs from the first stop at 08:00 and arrives at the second at 08:02.
+        assert_eq!(trip.progress_at(8 * 3600 - 1), None);
+        assert_eq!(
+            trip.progress_at(8 * 3600),
+            Some(Progress {
+                stop: 0,
+                stopped: true,
+                distance: 0.0,
+                speed: 0.0
+            })
+        );
+
+        let halfway = trip.progress_at(8 * 3600 + 60).unwrap();
+        assert_eq!(halfway.stop, 1);
+        assert!(!halfway.stopped);
+        assert!((halfway.distance - length / 2.0).abs() < 1e-9);
+        assert!((halfway.speed - length / 120.0).abs() < 1e-9);
+
+        assert!(trip.progress_at(8 * 3600 + 120).unwrap().stopped);
+        assert_eq!(trip.progress_at(8 * 3600 + 121), None);
+    }
+
+    #[test]
+    fn test_vehicles_at() {
+        let timetable = line_5_timetable();
+        let trip_id = "141010000123456789".to_owned();
+
+        assert!(vehicles_at(&timetable, "2021-05-10 07:59:59", Delays::default()).is_empty());
+
+        let vehicles = vehicles_at(&timetable, "2021-05-10 08:01:00", Delays::default());
+        assert_eq!(vehicles.len(), 1);
+        assert_eq!(vehicles[0].0, trip_id);
+        assert!((vehicles[0].1.lat - 59.8585).abs() < 1e-9);
+
+        // A minute late, the vehicle has only just left.
+        let late = Delays {
+            mean: 60.0,
+            variation: 0.0,
+        };
+        let vehicles = vehicles_at(&timetable, "2021-05-10 08:01:00", late);
+        assert_eq!(vehicles[0].1.lat, 59.858);
+        assert_eq!(late.of(&trip_id), 60);
+
+        let vehicles = vehicles_at(&timetable, "2021-05-10 08:02:30", late);
+        assert!((vehicles[0].1.lat - 59.85875).abs() < 1e-9);
+
+        // Every trip keeps its delay, which is within the variation.
+        let varying = Delays {
+            mean: 60.0,
+            variation: 30.0,
+        };
+        assert_eq!(varying.of(&trip_id), varying.of(&trip_id));
+        assert!((30..=90).contains(&varying.of(&trip_id)));
+    }
+
+    #[test]
+    fn test_calendar_and_midnight() {
+        let tables = StaticTables::from_zip(gtfs_zip(&[
+            (
+                "routes.txt",
+                "route_id,agency_id,route_short_name,route_long_name,route_type,route_desc\n\
+                 1,1,1,,700,\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id,trip_headsign,direction_id,shape_id\n\
+                 1,weekdays,day,,0,1\n\
+                 1,weekdays,night,,1,1\n",
+            ),
+            (
+                "shapes.txt",
+                "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence,shape_dist_traveled\n\
+                 1,59.858,17.638,1,\n\
+                 1,59.859,17.638,2,\n",
+            ),
+            (
+                // There are no stops, so the stops are spread out along the shape.
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign,\
+                 pickup_type,drop_off_type,shape_dist_traveled,timepoint\n\
+                 day,12:00:00,12:00:00,A,1,,0,0,,1\n\
+                 day,,,B,2,,0,0,,0\n\
+                 day,12:10:00,12:10:00,C,3,,0,0,,1\n\
+                 night,24:50:00,24:50:00,A,1,,0,0,,1\n\
+                 night,25:10:00,25:10:00,C,2,,0,0,,1\n",
+            ),
+            (
+                "calendar.txt",
+                "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,\
+                 start_date,end_date\n\
+                 weekdays,1,1,1,1,1,0,0,20210101,20211231\n",
+            ),
+            (
+                "calendar_dates.txt",
+                "service_id,date,exception_type\n\
+                 weekdays,20210513,2\n",
+            ),
+        ]))
+        .unwrap();
+        let timetable = Timetable::new(tables);
+
+        // The stop without times is skipped.
+        assert_eq!(timetable.trips[0].stops.len(), 2);
+
+        // A Monday, a Saturday and a Thursday that is a holiday.
+        let trip_ids = |now: &str| {
+            vehicles_at(&timetable, now, Delays::default())
+                .into_iter()
+                .map(|(trip_id, _)| trip_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(trip_ids("2021-05-10 12:05:00"), vec!["day"]);
+        assert!(trip_ids("2021-05-15 12:05:00").is_empty());
+        assert!(trip_ids("2021-05-13 12:05:00").is_empty());
+
+        // The night trips of Monday and Friday run early on the next day, but there is no
+        // night trip early on Sunday since Saturday has no service.
+        assert_eq!(trip_ids("2021-05-11 01:00:00"), vec!["night"]);
+        assert_eq!(trip_ids("2021-05-15 01:00:00"), vec!["night"]);
+        assert!(trip_ids("2021-05-16 01:00:00").is_empty());
+    }
+
+    #[test]
+    fn test_feed() {
+        let timetable = line_5_timetable();
+        let vehicles = timetable.vehicles_at(time("2021-05-10 08:01:00"), Delays::default());
+        let feed = feed(&vehicles, 1_620_626_460);
+        let message = feed.message();
+
+        assert_eq!(message.header.timestamp, Some(1_620_626_460));
+        assert_eq!(message.entity.len(), 2);
+
+        let position = message.entity[0].vehicle.as_ref().unwrap();
+        assert_eq!(message.entity[0].id, "sim-141010000123456789");
+        assert_eq!(
+            position.trip.as_ref().unwrap().start_date.as_deref(),
+            Some("20210510")
+        );
+        assert_eq!(position.current_status, VehicleStopStatus::IN_TRANSIT_TO);
+        assert_eq!(position.stop_id.as_deref(), Some("9022003700021002"));
+        assert_eq!(position.position.as_ref().unwrap().bearing, Some(0.0));
+
+        let trip_update = message.entity[1].trip_update.as_ref().unwrap();
+        assert_eq!(trip_update.delay, Some(0));
+        assert_eq!(trip_update.stop_time_update[0].stop_sequence, Some(2));
+    }
+}
@@ -1,15 +1,21 @@
 //! Interface for receiving and storing data from Trafiklab's API:s.
 
+use std::fmt;
 use std::fs::File;
+use std::future::Future;
 use std::io::prelude::*;
 use std::str::from_utf8;
 
+use awc::error::SendRequestError;
+use awc::{Client, Connector};
 use curl::easy::Easy;
-use quick_protobuf::{BytesReader, MessageRead};
+use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
 use serde::{Deserialize, Serialize};
 use tempdir::TempDir;
 use zip::ZipArchive;
 
+use crate::config::Settings;
+use crate::gtfs::operator::Operator;
 use crate::gtfs::transit_realtime::FeedMessage;
 
 // The data the Trafiklab provides in their "GTFS Regional Realtime (Beta)" API is
@@ -23,13 +29,6 @@ use crate::gtfs::transit_realtime::FeedMessage;
 //
 // API Description URL: https://www.trafiklab.se/api/gtfs-regional-realtime-beta
 
-/// The URL for Trafiklab's Vehicle Positions API.
-const TRAFIKLAB_VEH_POS_API_URL: &str =
-    "https://opendata.samtrafiken.se/gtfs-rt/ul/VehiclePositions.pb?key=";
-
-/// The URL for Trafiklab's Static Data API.
-const TRAFIKLAB_STATIC_API_URL: &str = "https://opendata.samtrafiken.se/gtfs/ul/ul.zip?key=";
-
 /// Struct for representing JSON error data received from the Trafiklab API.
 /// Example JSON:
 ///
@@ -43,29 +42,53 @@ struct TrafiklabError {
     pub error_message: String,
 }
 
-/// Contains necessary data in order to communicate with and receive data from Trafiklab's API.
+/// Contains necessary data in order to communicate with and receive data from Trafiklab's API
+/// for a single operator.
 pub struct TrafiklabApi {
-    realtime_key: String,
-    static_key: String,
+    operator: Operator,
 
     // Handle to a directory that contains static files. None means that there are no fetched
     // static files.
     static_files: Option<TempDir>,
 
-    // Raw data received from the realtime API endpoint.
-    raw_data: Vec<u8>,
+    // Asynchronous HTTP client used for the realtime API.
+    client: Client,
+
+    // The maximum size (in bytes) of a response from the realtime API.
+    max_response_size: usize,
 }
 
 impl TrafiklabApi {
-    pub fn new(realtime_key: &str, static_key: &str) -> Self {
+    pub fn new(operator: Operator, settings: &Settings) -> Self {
         TrafiklabApi {
-            realtime_key: String::from(realtime_key),
-            static_key: String::from(static_key),
+            operator,
             static_files: None,
-            raw_data: Vec::new(),
+            client: TrafiklabApi::build_client(settings),
+            max_response_size: settings.limits.max_feed_size,
         }
     }
 
+    /// Applies new settings for the operator (such as API keys) and the API (such as timeouts).
+    /// Requests that already have been made are not affected.
+    pub fn reconfigure(&mut self, operator: Operator, settings: &Settings) {
+        self.operator = operator;
+        self.client = TrafiklabApi::build_client(settings);
+        self.max_response_size = settings.limits.max_feed_size;
+    }
+
+    fn build_client(settings: &Settings) -> Client {
+        let trafiklab = &settings.trafiklab_api;
+
+        Client::builder()
+            .connector(
+                Connector::new()
+                    .timeout(trafiklab.connect_timeout())
+                    .finish(),
+            )
+            .timeout(trafiklab.request_timeout())
+            .finish()
+    }
+
     /// Makes a request to Trafiklab's API for static data. The files that are received from the
     /// request is stored in the OS's temporary folder (%temp% on Windows).
     #[allow(dead_code)]
@@ -75,7 +98,10 @@ impl TrafiklabApi {
 
         let mut handle = Easy::new();
         handle
-            .url(&format!("{}{}", TRAFIKLAB_STATIC_API_URL, self.static_key))
+            .url(&format!(
+                "{}?key={}",
+                self.operator.static_url, self.operator.static_key
+            ))
             .unwrap();
 
         // We must use the "Accept-Encoding: gzip", since the protocol buffer data is compressed.
@@ -92,7 +118,7 @@ impl TrafiklabApi {
                 .unwrap();
 
             // Try to perform the request and if it fails, return.
-            if let Err(_) = transfer.perform() {
+            if transfer.perform().is_err() {
                 return Err(());
             }
         }
@@ -125,100 +151,233 @@ impl TrafiklabApi {
     /// Deletes all static data (if any are downloaded).
     #[allow(dead_code)]
     pub fn delete_static_data(&mut self) {
-        if self.static_files.is_some() {
-            // Drop the directory, essentially removing all the temporary directory
-            // and all files in it.
-            drop(self.static_files.as_ref());
+        // Dropping the directory handle removes the temporary directory and all files in it.
+        self.static_files = None;
+    }
 
-            self.static_files = None;
-        }
+    /// Makes an asynchronous request to Trafiklab's Vehicle Positions API.
+    ///
+    /// The request is bounded by the connect and request timeouts in the settings, so a slow or
+    /// unreachable API results in an `Err` instead of stalling the caller. On success the
+    /// received data is returned as a `RealtimeFeed` that is guaranteed to be decodable.
+    ///
+    /// The returned future does not borrow `self`, which means that it can be spawned
+    /// from within an actor.
+    pub fn fetch_vehicle_positions(
+        &self,
+    ) -> impl Future<Output = Result<RealtimeFeed, FetchError>> + 'static {
+        self.fetch_feed(&self.operator.realtime_url)
     }
 
-    /// Makes a request to the Trafiklab API endpoint and stores the received data.
-    /// To retrieve the data that was fetched, use `get_vehicle_positions()`.
-    /// If Err(reason) is returned, reason is the error reason sent back by the Trafiklab API.
-    pub fn fetch_vehicle_positions(&mut self) -> Result<(), String> {
-        // Clear any previous data stored in the local buffer.
-        self.raw_data.clear();
+    /// Makes an asynchronous request to Trafiklab's Trip Updates API, in the same way as
+    /// `fetch_vehicle_positions()`. Returns None if the operator has no TripUpdates feed.
+    pub fn fetch_trip_updates(
+        &self,
+    ) -> Option<impl Future<Output = Result<RealtimeFeed, FetchError>> + 'static> {
+        let url = self.operator.trip_updates_url.as_ref()?;
 
-        let mut handle = Easy::new();
-        handle
-            .url(&format!(
-                "{}{}",
-                TRAFIKLAB_VEH_POS_API_URL, self.realtime_key
-            ))
-            .unwrap();
+        Some(self.fetch_feed(url))
+    }
 
-        // We must use the "Accept-Encoding: gzip", since the protocol buffer data is compressed.
-        handle.accept_encoding("gzip").unwrap();
+    /// Makes an asynchronous request for the realtime feed at `url`.
+    fn fetch_feed(
+        &self,
+        url: &str,
+    ) -> impl Future<Output = Result<RealtimeFeed, FetchError>> + 'static {
+        // The "Accept-Encoding: gzip" header is set by the client automatically, and the
+        // response is decompressed before we read it.
+        let request = self
+            .client
+            .get(format!("{}?key={}", url, self.operator.realtime_key));
+        let max_response_size = self.max_response_size;
+
+        async move {
+            let mut response = request.send().await.map_err(|err| match err {
+                SendRequestError::Timeout => FetchError::Timeout,
+                other => FetchError::Network(other.to_string()),
+            })?;
+
+            let raw_data = response
+                .body()
+                .limit(max_response_size)
+                .await
+                .map_err(|err| FetchError::Network(err.to_string()))?
+                .to_vec();
+
+            // Check if the data received is parsable as a normal UTF-8 string.
+            // If the data is parseable, that means we have not received the Protocol Buffer
+            // data that was requested, but instead an error message in json.
+            if let Ok(err_str) = from_utf8(&raw_data) {
+                // Parse the str as a TrafiklabError so that we can return the reason for the error.
+                if let Ok(error_message) = serde_json::from_str::<TrafiklabError>(err_str) {
+                    return Err(FetchError::Api(error_message.error_message));
+                }
+            }
 
-        {
-            let mut transfer = handle.transfer();
-            transfer
-                .write_function(|data| {
-                    // Write the received binary data to self.raw_data.
-                    self.raw_data.extend_from_slice(data);
-                    Ok(data.len())
-                })
-                .unwrap();
-            transfer.perform().unwrap();
+            if !response.status().is_success() {
+                return Err(FetchError::Status(response.status().as_u16()));
+            }
+
+            RealtimeFeed::from_bytes(raw_data)
         }
+    }
+}
 
-        // Check if the data received is parsable as a normal UTF-8 string.
-        // If the data is parseable, that means we have not received the Protocol Buffer
-        // data that was requested, but instead an error message in json.
-        if let Ok(err_str) = from_utf8(&self.raw_data) {
-            // Parse the str as a TrafiklabError so that we can return the reason for the error.
-            if let Ok(error_message) = serde_json::from_str::<TrafiklabError>(err_str) {
-                // Remove all data that was received from the API since it's not considered raw data any more.
-                self.raw_data.clear();
-
-                return Err(error_message.error_message);
-            } else {
-                self.raw_data.clear();
-                return Err("Data not in Protocol Buffer format.".to_owned());
-            }
+/// Reasons for why a request to a realtime API failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchError {
+    /// The request did not complete within the configured timeout.
+    Timeout,
+
+    /// The connection could not be established or was broken during the transfer.
+    Network(String),
+
+    /// The API responded with an unexpected HTTP status code.
+    Status(u16),
+
+    /// The API responded with an error message, for example because of a bad API key.
+    Api(String),
+
+    /// The received data could not be decoded as a GTFS Realtime feed.
+    Decode(String),
+}
+
+impl FetchError {
+    /// Returns a short name for the kind of error, e.g. to be used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FetchError::Timeout => "timeout",
+            FetchError::Network(_) => "network",
+            FetchError::Status(_) => "status",
+            FetchError::Api(_) => "api",
+            FetchError::Decode(_) => "decode",
         }
+    }
+}
 
-        Ok(())
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Timeout => write!(f, "Request timed out."),
+            FetchError::Network(reason) => write!(f, "Network error: {}", reason),
+            FetchError::Status(status) => write!(f, "Unexpected HTTP status {}.", status),
+            FetchError::Api(reason) => write!(f, "API error: {}", reason),
+            FetchError::Decode(reason) => write!(f, "Could not decode feed: {}", reason),
+        }
     }
+}
+
+/// Protocol Buffer data received from a realtime API endpoint.
+///
+/// The raw data is owned so that it can be sent between actors, and it is only constructed
+/// from data that decodes successfully, which means that `message()` can be used freely
+/// without having to worry about malformed data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RealtimeFeed {
+    raw_data: Vec<u8>,
+}
 
-    /// Returns any data fetched from `fetch_vehicle_positions()`.
-    /// Make sure that you've called `fetch_vehicle_positions()` before you use this functions as the output is meaningless otherwise.
-    pub fn get_vehicle_positions(&self) -> Option<FeedMessage> {
-        // If the length of the stored raw data is 0 that means the user hasn't
-        // fetched any data yet or the fetch has previously failed.
-        if self.raw_data.len() == 0 {
-            return None;
+impl RealtimeFeed {
+    /// Validates that `raw_data` is a decodable `FeedMessage` and wraps it.
+    pub fn from_bytes(raw_data: Vec<u8>) -> Result<Self, FetchError> {
+        // An empty body is technically a valid (empty) protobuf message, but it is never
+        // something the API sends when it is working properly.
+        if raw_data.is_empty() {
+            return Err(FetchError::Decode("Received an empty response.".to_owned()));
         }
 
-        let mut reader = BytesReader::from_bytes(&self.raw_data.clone());
+        let feed = RealtimeFeed { raw_data };
+
+        if let Err(err) = feed.decode() {
+            return Err(FetchError::Decode(err.to_string()));
+        }
 
-        Some(FeedMessage::from_reader(&mut reader, &self.raw_data).unwrap())
+        Ok(feed)
+    }
+
+    /// Returns the decoded feed message.
+    pub fn message(&self) -> FeedMessage<'_> {
+        // The data is validated in `from_bytes()`, so decoding it again cannot fail.
+        self.decode()
+            .expect("RealtimeFeed contains data that has already been validated")
+    }
+
+    /// Encodes a feed message, e.g. one that is republished by the server.
+    pub fn from_message(message: &FeedMessage) -> Self {
+        let mut raw_data = Vec::new();
+
+        // Writing into a vector cannot fail.
+        message
+            .write_message(&mut Writer::new(&mut raw_data))
+            .expect("a feed message can be written to a vector");
+
+        RealtimeFeed { raw_data }
+    }
+
+    /// Returns the raw data, e.g. to send it to another instance.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw_data
+    }
+
+    fn decode(&self) -> quick_protobuf::Result<FeedMessage<'_>> {
+        let mut reader = BytesReader::from_bytes(&self.raw_data);
+
+        FeedMessage::from_reader(&mut reader, &self.raw_data)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
 
     #[test]
-    fn test_get_vehicle_positions() {
-        let handler = TrafiklabApi::new("this_doesnt_matter", "neither_does_this");
-        let get_result = handler.get_vehicle_positions();
-
-        // Since we haven't called "fetch_vehicle_positions()", we have not received
-        // any data and therefore we should always get None from get_vehicle_positions().
-        assert_eq!(get_result.is_none(), true);
+    fn test_empty_feed() {
+        // An empty response should never be accepted as a feed, even though it is
+        // technically a valid protobuf message.
+        assert!(RealtimeFeed::from_bytes(Vec::new()).is_err());
     }
 
     #[test]
-    fn test_bad_api_key() {
-        let mut handler = TrafiklabApi::new("this_is_not_a_valid_key", "neither_does_this");
-        let request_result = handler.fetch_vehicle_positions();
+    fn test_malformed_feed() {
+        // Data that is not a protobuf message should be rejected instead of panicking.
+        let result = RealtimeFeed::from_bytes(vec![0xff, 0xff, 0xff, 0xff]);
+
+        assert!(matches!(result, Err(FetchError::Decode(_))));
+    }
+
+    #[actix_rt::test]
+    async fn test_bad_api_key() {
+        // Stands in for Trafiklab's API, which answers every request with an error message
+        // when the API key is not valid.
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|| {
+                HttpResponse::Forbidden()
+                    .body(r#"{"errorMessage": "Key \"this_is_not_a_valid_key\" is invalid"}"#)
+            }))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+
+        let operator = Operator {
+            realtime_url: format!("http://{}/ul/VehiclePositions.pb", server.addrs()[0]),
+            ..Operator::new("ul", "this_is_not_a_valid_key", "neither_does_this")
+        };
+        let server = server.run();
+
+        let handler = TrafiklabApi::new(operator, &Settings::default());
+        let request_result = handler.fetch_vehicle_positions().await;
 
         // When making a request with a bad api_key an error should always be returned
         // since the API server do not accept a bad API key.
-        assert_eq!(request_result.is_err(), true);
+        assert_eq!(
+            request_result,
+            Err(FetchError::Api(
+                "Key \"this_is_not_a_valid_key\" is invalid".to_owned()
+            ))
+        );
+
+        server.stop(false).await;
     }
 }
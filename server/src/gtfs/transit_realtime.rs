@@ -364,6 +364,7 @@ pub struct VehiclePosition<'a> {
     pub timestamp: Option<u64>,
     pub congestion_level: Option<transit_realtime::mod_VehiclePosition::CongestionLevel>,
     pub occupancy_status: Option<transit_realtime::mod_VehiclePosition::OccupancyStatus>,
+    pub occupancy_percentage: Option<u32>,
 }
 
 impl<'a> MessageRead<'a> for VehiclePosition<'a> {
@@ -383,6 +384,7 @@ impl<'a> MessageRead<'a> for VehiclePosition<'a> {
                 Ok(40) => msg.timestamp = Some(r.read_uint64(bytes)?),
                 Ok(48) => msg.congestion_level = Some(r.read_enum(bytes)?),
                 Ok(72) => msg.occupancy_status = Some(r.read_enum(bytes)?),
+                Ok(96) => msg.occupancy_percentage = Some(r.read_uint32(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -403,6 +405,7 @@ impl<'a> MessageWrite for VehiclePosition<'a> {
         + self.timestamp.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
         + self.congestion_level.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
         + self.occupancy_status.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
+        + self.occupancy_percentage.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
@@ -415,6 +418,7 @@ impl<'a> MessageWrite for VehiclePosition<'a> {
         if let Some(ref s) = self.timestamp { w.write_with_tag(40, |w| w.write_uint64(*s))?; }
         if let Some(ref s) = self.congestion_level { w.write_with_tag(48, |w| w.write_enum(*s as i32))?; }
         if let Some(ref s) = self.occupancy_status { w.write_with_tag(72, |w| w.write_enum(*s as i32))?; }
+        if let Some(ref s) = self.occupancy_percentage { w.write_with_tag(96, |w| w.write_uint32(*s))?; }
         Ok(())
     }
 }
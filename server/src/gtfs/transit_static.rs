@@ -9,14 +9,14 @@
 //! use csv::Reader;
 //!
 //! {
-//!     let trips = File::open("./agency.txt").unwrap();
+//!     let stops = File::open("./stops.txt").unwrap();
 //!
-//!     let mut rdr = Reader::from_reader(trips);
+//!     let mut rdr = Reader::from_reader(stops);
 //!     let mut iter = rdr.deserialize();
 //!
-//!     // Iterates over every CSV record in "agency.txt"
+//!     // Iterates over every CSV record in "stops.txt"
 //!     while let Some(result) = iter.next() {
-//!         let record: Agency = result.unwrap();
+//!         let record: Stop = result.unwrap();
 //!
 //!         println!("{:?}", record);
 //!     }
@@ -25,29 +25,10 @@
 
 use serde::{Deserialize, Serialize};
 
-/// Represents an agency from Trafiklab's Static API.
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct Agency {
-    pub agency_id: String,
-    pub agency_name: String,
-    pub agency_url: String,
-    pub agency_timezone: String,
-    pub agency_lang: String,
-    pub agency_fare_url: Option<String>,
-}
-
-/// Represents an attribution (trip_id to organization) from Trafiklab's Static API.
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct Attributions {
-    pub trip_id: String,
-    pub organization_name: String,
-    pub is_operator: i32,
-}
-
 /// Represents a calendar from Trafiklab's Static API.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Calendar {
-    pub service_id: i32,
+    pub service_id: String,
     pub monday: i32,
     pub tuesday: i32,
     pub wednesday: i32,
@@ -67,16 +48,6 @@ pub struct CalendarDates {
     pub exception_type: i32,
 }
 
-/// Represents feed information from Trafiklab's Static API.
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct FeedInfo {
-    pub feed_id: String,
-    pub feed_publisher_name: String,
-    pub feed_publisher_url: String,
-    pub feed_lang: String,
-    pub feed_version: String,
-}
-
 /// Represents a route from Trafiklabs Static API.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Route {
@@ -114,10 +85,10 @@ pub struct StopTime {
     pub stop_id: String,
     pub stop_sequence: i32,
     pub stop_headsign: String,
-    pub pickup_type: i32,
-    pub drop_off_type: i32,
+    pub pickup_type: Option<i32>,
+    pub drop_off_type: Option<i32>,
     pub shape_dist_traveled: Option<f64>,
-    pub timepoint: i32,
+    pub timepoint: Option<i32>,
 }
 
 /// Represents a stop from Trafiklab's Static API.
@@ -127,22 +98,11 @@ pub struct Stop {
     pub stop_name: String,
     pub stop_lat: String,
     pub stop_lon: String,
-    pub location_type: i32,
+    pub location_type: Option<i32>,
     pub parent_station: Option<String>,
     pub platform_code: Option<String>,
 }
 
-/// Represents a transfer from Trafiklab's Static API.
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct Transfer {
-    pub from_stop_id: String,
-    pub to_stop_id: String,
-    pub transfer_type: i32,
-    pub min_transfer_time: Option<String>,
-    pub from_trip_id: String,
-    pub to_trip_id: String,
-}
-
 /// Represents a trip from Trafiklabs Static API.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Trip {
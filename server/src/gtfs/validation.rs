@@ -0,0 +1,342 @@
+//! Validation of entities in a GTFS Realtime feed.
+//!
+//! Feeds from external providers are not always well-formed, so every `FeedEntity` is
+//! classified before it is used. Invalid entities are skipped and counted instead of bringing
+//! down the server, and deleted entities and differential feeds are handled according to the
+//! GTFS Realtime specification.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::gtfs::transit_realtime::mod_FeedHeader::Incrementality;
+use crate::gtfs::transit_realtime::{FeedEntity, FeedMessage, Position};
+use crate::protocol::server_protocol::{EntityCounts, Vehicle};
+
+/// A valid entity from a feed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidEntity {
+    /// A vehicle position, converted to the representation that is sent to clients.
+    Vehicle { entity_id: String, vehicle: Vehicle },
+
+    /// A trip update. These are not used by the server yet, but they are still counted.
+    TripUpdate,
+
+    /// An alert. These are not used by the server yet, but they are still counted.
+    Alert,
+
+    /// An entity that should be removed. Only meaningful in differential feeds.
+    Deleted { entity_id: String },
+}
+
+/// Reasons for why an entity is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvalidEntity {
+    MissingEntityId,
+    NoPayload,
+    MissingVehicleDescriptor,
+    MissingVehicleId,
+    MissingPosition,
+    InvalidPosition,
+}
+
+impl InvalidEntity {
+    /// Returns a short name for the reason, e.g. to be used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InvalidEntity::MissingEntityId => "missing_entity_id",
+            InvalidEntity::NoPayload => "no_payload",
+            InvalidEntity::MissingVehicleDescriptor => "missing_vehicle_descriptor",
+            InvalidEntity::MissingVehicleId => "missing_vehicle_id",
+            InvalidEntity::MissingPosition => "missing_position",
+            InvalidEntity::InvalidPosition => "invalid_position",
+        }
+    }
+}
+
+impl fmt::Display for InvalidEntity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            InvalidEntity::MissingEntityId => "missing entity id",
+            InvalidEntity::NoPayload => "no vehicle, trip update or alert",
+            InvalidEntity::MissingVehicleDescriptor => "missing vehicle descriptor",
+            InvalidEntity::MissingVehicleId => "missing vehicle id",
+            InvalidEntity::MissingPosition => "missing position",
+            InvalidEntity::InvalidPosition => "invalid position",
+        };
+
+        write!(f, "{}", reason)
+    }
+}
+
+/// Returns true if the position is a plausible coordinate.
+fn is_valid_position(position: &Position) -> bool {
+    let (lat, lng) = (position.latitude, position.longitude);
+
+    // (0, 0) is technically a valid coordinate, but in practice it is what a vehicle reports
+    // when it has no GPS fix.
+    lat.is_finite()
+        && lng.is_finite()
+        && (-90.0..=90.0).contains(&lat)
+        && (-180.0..=180.0).contains(&lng)
+        && !(lat == 0.0 && lng == 0.0)
+}
+
+/// Classifies a single entity from a feed.
+pub fn classify_entity(entity: &FeedEntity) -> Result<ValidEntity, InvalidEntity> {
+    if entity.id.is_empty() {
+        return Err(InvalidEntity::MissingEntityId);
+    }
+
+    if entity.is_deleted {
+        return Ok(ValidEntity::Deleted {
+            entity_id: entity.id.to_string(),
+        });
+    }
+
+    if let Some(vehicle) = &entity.vehicle {
+        let descriptor = vehicle
+            .vehicle
+            .as_ref()
+            .ok_or(InvalidEntity::MissingVehicleDescriptor)?;
+
+        let descriptor_id = match descriptor.id.as_ref() {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => return Err(InvalidEntity::MissingVehicleId),
+        };
+
+        let position = vehicle
+            .position
+            .as_ref()
+            .ok_or(InvalidEntity::MissingPosition)?;
+
+        if !is_valid_position(position) {
+            return Err(InvalidEntity::InvalidPosition);
+        }
+
+        let trip_id = vehicle
+            .trip
+            .as_ref()
+            .and_then(|trip| trip.trip_id.as_ref())
+            .map(|id| id.to_string());
+
+        return Ok(ValidEntity::Vehicle {
+            entity_id: entity.id.to_string(),
+            vehicle: Vehicle {
+                descriptor_id,
+                trip_id,
+                line: None,
+                position: position.clone(),
+            },
+        });
+    }
+
+    if entity.trip_update.is_some() {
+        return Ok(ValidEntity::TripUpdate);
+    }
+
+    if entity.alert.is_some() {
+        return Ok(ValidEntity::Alert);
+    }
+
+    Err(InvalidEntity::NoPayload)
+}
+
+/// The result of validating every entity in a feed.
+#[derive(Debug, Clone)]
+pub struct ValidatedFeed {
+    pub incrementality: Incrementality,
+    pub timestamp: Option<u64>,
+
+    /// Valid vehicles mapped by their entity id.
+    pub vehicles: Vec<(String, Vehicle)>,
+
+    /// Entity ids of deleted entities.
+    pub deleted: Vec<String>,
+
+    /// The number of entities of each kind.
+    pub counts: EntityCounts,
+
+    /// The number of invalid entities for each reason.
+    pub invalid: HashMap<InvalidEntity, usize>,
+}
+
+impl ValidatedFeed {
+    /// Validates every entity in a feed.
+    pub fn from_message(message: &FeedMessage) -> Self {
+        let mut validated = ValidatedFeed {
+            incrementality: message.header.incrementality,
+            timestamp: message.header.timestamp,
+            vehicles: Vec::new(),
+            deleted: Vec::new(),
+            counts: EntityCounts::default(),
+            invalid: HashMap::new(),
+        };
+
+        for entity in &message.entity {
+            match classify_entity(entity) {
+                Ok(ValidEntity::Vehicle { entity_id, vehicle }) => {
+                    validated.counts.vehicles += 1;
+                    validated.vehicles.push((entity_id, vehicle));
+                }
+                Ok(ValidEntity::TripUpdate) => validated.counts.trip_updates += 1,
+                Ok(ValidEntity::Alert) => validated.counts.alerts += 1,
+                Ok(ValidEntity::Deleted { entity_id }) => {
+                    validated.counts.deleted += 1;
+                    validated.deleted.push(entity_id);
+                }
+                Err(reason) => {
+                    validated.counts.invalid += 1;
+                    *validated.invalid.entry(reason).or_insert(0) += 1;
+                }
+            }
+        }
+
+        validated
+    }
+
+    /// Applies the feed to the currently known vehicles, which are mapped by entity id.
+    ///
+    /// A full dataset replaces all known vehicles, while a differential feed only updates the
+    /// vehicles it contains and removes the ones that are marked as deleted.
+    pub fn apply(self, vehicles: &mut HashMap<String, Vehicle>) {
+        if self.incrementality == Incrementality::FULL_DATASET {
+            vehicles.clear();
+        }
+
+        for entity_id in self.deleted {
+            vehicles.remove(&entity_id);
+        }
+
+        vehicles.extend(self.vehicles);
+    }
+
+    /// Returns a human readable summary of the invalid entities, or None if there are none.
+    pub fn invalid_summary(&self) -> Option<String> {
+        if self.invalid.is_empty() {
+            return None;
+        }
+
+        let reasons = self
+            .invalid
+            .iter()
+            .map(|(reason, count)| format!("{} ({})", reason, count))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        Some(format!(
+            "Skipped {} invalid entities: {}",
+            self.counts.invalid, reasons
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::transit_realtime::{VehicleDescriptor, VehiclePosition};
+    use std::borrow::Cow;
+
+    fn vehicle_entity(
+        entity_id: &str,
+        vehicle_id: &str,
+        lat: f32,
+        lng: f32,
+    ) -> FeedEntity<'static> {
+        FeedEntity {
+            id: Cow::Owned(entity_id.to_owned()),
+            vehicle: Some(VehiclePosition {
+                vehicle: Some(VehicleDescriptor {
+                    id: Some(Cow::Owned(vehicle_id.to_owned())),
+                    ..VehicleDescriptor::default()
+                }),
+                position: Some(Position {
+                    latitude: lat,
+                    longitude: lng,
+                    ..Position::default()
+                }),
+                ..VehiclePosition::default()
+            }),
+            ..FeedEntity::default()
+        }
+    }
+
+    #[test]
+    fn test_classify_entity() {
+        let valid = vehicle_entity("1", "9031003", 59.85, 17.63);
+        assert!(matches!(
+            classify_entity(&valid),
+            Ok(ValidEntity::Vehicle { .. })
+        ));
+
+        let mut no_position = valid.clone();
+        no_position.vehicle.as_mut().unwrap().position = None;
+        assert_eq!(
+            classify_entity(&no_position),
+            Err(InvalidEntity::MissingPosition)
+        );
+
+        let no_descriptor_id = vehicle_entity("1", "", 59.85, 17.63);
+        assert_eq!(
+            classify_entity(&no_descriptor_id),
+            Err(InvalidEntity::MissingVehicleId)
+        );
+
+        let no_gps_fix = vehicle_entity("1", "9031003", 0.0, 0.0);
+        assert_eq!(
+            classify_entity(&no_gps_fix),
+            Err(InvalidEntity::InvalidPosition)
+        );
+
+        let empty = FeedEntity {
+            id: Cow::Borrowed("1"),
+            ..FeedEntity::default()
+        };
+        assert_eq!(classify_entity(&empty), Err(InvalidEntity::NoPayload));
+
+        // A deleted entity does not need a payload.
+        let deleted = FeedEntity {
+            id: Cow::Borrowed("1"),
+            is_deleted: true,
+            ..FeedEntity::default()
+        };
+        assert!(matches!(
+            classify_entity(&deleted),
+            Ok(ValidEntity::Deleted { .. })
+        ));
+    }
+
+    #[test]
+    fn test_apply_differential_feed() {
+        let mut vehicles = HashMap::new();
+
+        let mut full = FeedMessage::default();
+        full.entity.push(vehicle_entity("1", "a", 59.85, 17.63));
+        full.entity.push(vehicle_entity("2", "b", 59.86, 17.64));
+        full.entity.push(vehicle_entity("3", "c", 0.0, 0.0));
+
+        let validated = ValidatedFeed::from_message(&full);
+        assert_eq!(validated.counts.vehicles, 2);
+        assert_eq!(validated.counts.invalid, 1);
+
+        validated.apply(&mut vehicles);
+        assert_eq!(vehicles.len(), 2);
+
+        // A differential feed only touches the entities it contains.
+        let mut differential = FeedMessage::default();
+        differential.header.incrementality = Incrementality::DIFFERENTIAL;
+        differential.entity.push(FeedEntity {
+            id: Cow::Borrowed("1"),
+            is_deleted: true,
+            ..FeedEntity::default()
+        });
+        differential
+            .entity
+            .push(vehicle_entity("4", "d", 59.87, 17.65));
+
+        ValidatedFeed::from_message(&differential).apply(&mut vehicles);
+
+        let mut entity_ids = vehicles.keys().cloned().collect::<Vec<String>>();
+        entity_ids.sort();
+        assert_eq!(entity_ids, vec!["2", "4"]);
+    }
+}
@@ -0,0 +1,9 @@
+//! The protocol that clients use to talk to the server, for programs that are built alongside
+//! it, such as the load generator.
+
+pub mod protocol;
+
+/// The GTFS Realtime messages that the protocol refers to, e.g. the positions of vehicles.
+pub mod gtfs {
+    pub mod transit_realtime;
+}
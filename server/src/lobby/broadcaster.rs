@@ -0,0 +1,209 @@
+//! Sends messages to connected clients.
+//!
+//! The broadcaster is the only part of the lobby that holds the clients' addresses. It also
+//! knows where every client is on their map and which vehicle they last asked about, since
+//! that decides which messages they should get.
+//!
+//! Clients that cannot keep up with their messages for too long are disconnected, since
+//! they would only see outdated vehicles anyway.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix::prelude::{Actor, AsyncContext, Context, Handler};
+use tracing::{info, trace, warn};
+use uuid::Uuid;
+
+use crate::client::ClientData;
+use crate::config::LimitSettings;
+use crate::lobby::Lobby;
+use crate::messages::{
+    CloseConnection, PositionUpdate, ReloadSettings, SendToClient, SendToEveryone, SendToWatchers,
+    Subscribe, Unsubscribe, VehiclesChanged, WatchVehicle,
+};
+use crate::metrics::SLOW_CLIENT_DISCONNECTS;
+use crate::protocol::server_protocol::{ServerOutput, Vehicle, VehiclePositionsOutput};
+use crate::util::filter_vehicle_position;
+
+/// How often vehicle positions that are waiting to be sent are retried, and slow clients are
+/// looked for.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Keeps track of the connected clients and sends messages to them.
+pub struct Broadcaster {
+    /// Maps client IDs to client data.
+    clients: HashMap<Uuid, ClientData>,
+
+    /// How long a client can be too slow before it is disconnected.
+    slow_client_timeout: Duration,
+}
+
+impl Broadcaster {
+    pub fn new(limits: &LimitSettings) -> Self {
+        Broadcaster {
+            clients: HashMap::new(),
+            slow_client_timeout: limits.slow_client_timeout(),
+        }
+    }
+
+    /// Sends a message to a specific client.
+    fn send_message(&mut self, message: &str, id_to: &Uuid) {
+        if let Some(client) = self.clients.get_mut(id_to) {
+            client.send(message.to_owned());
+        } else {
+            warn!(client_id = %id_to, "Attempting to send message but couldn't find client id.");
+        }
+    }
+
+    /// Sends the vehicle positions that are waiting to be sent, and disconnects every client
+    /// that has been too slow for too long.
+    fn flush(&mut self) {
+        let timeout = self.slow_client_timeout;
+
+        for client in self.clients.values_mut() {
+            client.flush();
+        }
+
+        let slow_clients = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.is_slow(timeout))
+            .map(|(client_id, _)| *client_id)
+            .collect::<Vec<Uuid>>();
+
+        for client_id in slow_clients {
+            info!(%client_id, "Disconnecting client since it cannot keep up.");
+
+            SLOW_CLIENT_DISCONNECTS.inc();
+
+            // The client is forgotten right away, since it may take a while before its
+            // connection gets around to closing.
+            if let Some(client) = self.clients.remove(&client_id) {
+                let _ = client.close.do_send(CloseConnection(
+                    "The connection is too slow to keep up with updates".to_owned(),
+                ));
+            }
+        }
+    }
+}
+
+impl Actor for Broadcaster {
+    type Context = Context<Self>;
+
+    // This method is called when the broadcaster is started.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(FLUSH_INTERVAL, |act, _ctx| act.flush());
+    }
+}
+
+impl Handler<ReloadSettings> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReloadSettings, _: &mut Context<Self>) {
+        self.slow_client_timeout = msg.0.limits.slow_client_timeout();
+    }
+}
+
+impl Handler<Subscribe> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        self.clients
+            .insert(msg.client_id, ClientData::new(msg.addr, msg.close));
+    }
+}
+
+impl Handler<Unsubscribe> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Context<Self>) {
+        self.clients.remove(&msg.client_id);
+    }
+}
+
+impl Handler<PositionUpdate> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: PositionUpdate, _: &mut Context<Self>) {
+        if let Some(client_data) = self.clients.get_mut(&msg.self_id) {
+            trace!(client_id = %msg.self_id, "Updated position.");
+
+            // Update the client's position to the new position.
+            client_data.update_position(msg.position);
+        }
+    }
+}
+
+impl Handler<WatchVehicle> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: WatchVehicle, _: &mut Context<Self>) {
+        if let Some(client_data) = self.clients.get_mut(&msg.client_id) {
+            client_data.update_last_descriptor(msg.descriptor_id);
+        }
+    }
+}
+
+impl Handler<SendToClient> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendToClient, _: &mut Context<Self>) {
+        self.send_message(&msg.message, &msg.client_id);
+    }
+}
+
+impl Handler<SendToEveryone> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendToEveryone, _: &mut Context<Self>) {
+        self.clients
+            .values_mut()
+            .for_each(|client| client.send(msg.0.clone()));
+    }
+}
+
+impl Handler<SendToWatchers> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendToWatchers, _: &mut Context<Self>) {
+        self.clients.values_mut().for_each(|client| {
+            // If the clients last descriptor id is the same as the updated descriptor id
+            // we'll send the updated status to them.
+            if client.last_descriptor_request.as_deref() == Some(msg.descriptor_id.as_str()) {
+                client.send(msg.message.clone());
+            }
+        });
+    }
+}
+
+impl Handler<VehiclesChanged> for Broadcaster {
+    type Result = ();
+
+    // Sends the latest vehicles to every client that is close enough to them.
+    fn handle(&mut self, msg: VehiclesChanged, _: &mut Context<Self>) {
+        let timestamp = Lobby::get_current_timestamp();
+
+        self.clients.values_mut().for_each(|client| {
+            if let Some(client_pos) = &client.position {
+                let filtered_vhcs = msg
+                    .0
+                    .iter()
+                    .filter(|vhc| filter_vehicle_position(client_pos, vhc))
+                    .cloned()
+                    .collect::<Vec<Vehicle>>();
+
+                if !filtered_vhcs.is_empty() {
+                    client.send_vehicle_positions(
+                        serde_json::to_string(&ServerOutput::VehiclePositions(
+                            VehiclePositionsOutput {
+                                timestamp,
+                                vehicles: filtered_vhcs,
+                            },
+                        ))
+                        .unwrap(),
+                    );
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,380 @@
+//! Forecasts how crowded vehicles usually are at a stop, from the occupancy of the arrivals in
+//! the punctuality history (see `history`).
+//!
+//! A forecast is made for a stop, a kind of day (weekdays, Saturdays or Sundays) and a time of
+//! day. It is the median occupancy of the arrivals at the stop over the last few weeks that
+//! were scheduled at about the same time on the same kind of day, which is how crowded riders
+//! usually find the vehicle. Forecasts are sent with passenger information for the next stop
+//! of a bus, and with the stops of a single trip, so that riders can compare departures.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{Datelike, Local, NaiveDate, TimeDelta, TimeZone, Weekday};
+
+use crate::geometry::{self, Point};
+use crate::gtfs::operator::{split_namespaced_id, Operator};
+use crate::gtfs::transit_realtime::mod_VehiclePosition::OccupancyStatus;
+use crate::gtfs::transit_realtime::VehiclePosition;
+use crate::lobby::history::service_day_start;
+use crate::lobby::shapes::{ShapeCache, TripStop};
+use crate::protocol::server_protocol::{
+    CrowdingForecast, DayType, RouteInformationOutput, Vehicle,
+};
+use crate::store::{HistoryQuery, HistoryStore, ObservedArrival, StaticStore};
+use crate::util::parse_gtfs_time;
+
+/// How many days of arrivals forecasts are made from.
+const FORECAST_DAYS: i64 = 28;
+
+/// Arrivals that were scheduled within this many seconds of the time of a forecast count
+/// towards it.
+const FORECAST_WINDOW: i64 = 30 * 60;
+
+/// A forecast is only made from at least this many arrivals with an occupancy.
+const MIN_OBSERVATIONS: usize = 3;
+
+/// Returns the kind of day that a service date is.
+pub fn day_type(date: NaiveDate) -> DayType {
+    match date.weekday() {
+        Weekday::Sat => DayType::Saturday,
+        Weekday::Sun => DayType::Sunday,
+        _ => DayType::Weekday,
+    }
+}
+
+/// Returns how full (in percent) a vehicle in a realtime feed is, from its occupancy
+/// percentage or otherwise from its occupancy status. A vehicle that does not accept
+/// passengers says nothing about how crowded it is.
+pub fn feed_occupancy(vehicle: &VehiclePosition) -> Option<u32> {
+    if vehicle.occupancy_percentage.is_some() {
+        return vehicle.occupancy_percentage;
+    }
+
+    match vehicle.occupancy_status? {
+        OccupancyStatus::EMPTY => Some(0),
+        OccupancyStatus::MANY_SEATS_AVAILABLE => Some(25),
+        OccupancyStatus::FEW_SEATS_AVAILABLE => Some(60),
+        OccupancyStatus::STANDING_ROOM_ONLY => Some(85),
+        OccupancyStatus::CRUSHED_STANDING_ROOM_ONLY | OccupancyStatus::FULL => Some(100),
+        OccupancyStatus::NOT_ACCEPTING_PASSENGERS => None,
+    }
+}
+
+/// The arrivals that forecasts are made from, grouped by stop so that a forecast for a stop
+/// only looks at the arrivals at it.
+pub struct Observations {
+    /// The kind of day, the scheduled time (in seconds after the start of the service day)
+    /// and the occupancy of every arrival with an occupancy, by stop id (not namespaced).
+    by_stop: HashMap<String, Vec<(DayType, i64, u32)>>,
+}
+
+impl Observations {
+    /// Groups the arrivals that have an occupancy by stop. Times of day are in `tz`.
+    pub fn new<Tz: TimeZone>(arrivals: &[ObservedArrival], tz: &Tz) -> Self {
+        let mut day_starts = HashMap::new();
+        let mut by_stop: HashMap<String, Vec<_>> = HashMap::new();
+
+        for arrival in arrivals {
+            let occupancy = match arrival.occupancy {
+                Some(occupancy) => occupancy,
+                None => continue,
+            };
+
+            // Most arrivals share their service date with many others.
+            let start = *day_starts
+                .entry(arrival.service_date)
+                .or_insert_with(|| service_day_start(arrival.service_date, tz));
+
+            if let Some(start) = start {
+                by_stop.entry(arrival.stop_id.clone()).or_default().push((
+                    day_type(arrival.service_date),
+                    arrival.scheduled - start,
+                    occupancy,
+                ));
+            }
+        }
+
+        Observations { by_stop }
+    }
+}
+
+/// Forecasts how crowded vehicles of `operator` are at `stop_id` (not namespaced) at `time`
+/// (in seconds after the start of the service day) on days of `day_type`.
+///
+/// Returns `None` if too few of the arrivals have an occupancy.
+pub fn forecast(
+    operator: &Operator,
+    observations: &Observations,
+    stop_id: &str,
+    time: i64,
+    day: DayType,
+) -> Option<CrowdingForecast> {
+    let mut occupancies = observations
+        .by_stop
+        .get(stop_id)?
+        .iter()
+        .filter(|(day_type, scheduled, _)| {
+            *day_type == day && (scheduled - time).abs() <= FORECAST_WINDOW
+        })
+        .map(|(_, _, occupancy)| *occupancy)
+        .collect::<Vec<_>>();
+
+    if occupancies.len() < MIN_OBSERVATIONS {
+        return None;
+    }
+
+    occupancies.sort_unstable();
+    let middle = occupancies.len() / 2;
+
+    let median = match occupancies.len() % 2 {
+        0 => (occupancies[middle - 1] + occupancies[middle]).div_ceil(2),
+        _ => occupancies[middle],
+    };
+
+    Some(CrowdingForecast {
+        stop_id: operator.namespace_id(stop_id),
+        day_type: day,
+        time: format!("{:02}:{:02}", time / 3600, time % 3600 / 60),
+        occupancy: median,
+        observations: occupancies.len() as u32,
+    })
+}
+
+/// Returns the index of the first stop that a vehicle at `position` has not passed yet,
+/// measured along `shape`, or along the stops if the trip has no shape. A vehicle that is at
+/// a stop has not passed it, and neither has a vehicle that is past the end of the line.
+///
+/// Returns `None` if the trip has no stops.
+pub fn next_stop(shape: &[Point], stops: &[TripStop], position: Point) -> Option<usize> {
+    let points = stops.iter().map(|stop| stop.point).collect::<Vec<_>>();
+    let line = if shape.len() >= 2 { shape } else { &points };
+
+    let distances = geometry::distances_along(line, &points);
+    let distance = geometry::distances_along(line, &[position])[0];
+
+    distances
+        .iter()
+        .position(|stop_distance| *stop_distance >= distance)
+}
+
+/// Looks up the arrivals on a line of `operator` that forecasts for today are made from.
+async fn recent_arrivals(
+    operator: &Operator,
+    history: &dyn HistoryStore,
+    line: &str,
+    stop_id: Option<&str>,
+) -> Option<Vec<ObservedArrival>> {
+    let today = Local::now().date_naive();
+
+    let query = HistoryQuery {
+        operator: operator.name.clone(),
+        line: Some(line.to_owned()),
+        stop_id: stop_id.map(str::to_owned),
+        from: today - TimeDelta::days(FORECAST_DAYS),
+        to: today - TimeDelta::days(1),
+    };
+
+    history.arrivals(query).await
+}
+
+/// Forecasts how crowded a vehicle of `operator` will be at its next stop today.
+pub async fn vehicle_forecast(
+    operator: &Operator,
+    store: Arc<dyn StaticStore>,
+    shapes: &ShapeCache,
+    history: &dyn HistoryStore,
+    vehicle: &Vehicle,
+) -> Option<CrowdingForecast> {
+    let trip_id = vehicle.plain_trip_id()?;
+    let line = vehicle.line.as_deref()?;
+
+    let trip = store.trip(trip_id).await?;
+    let shape = shapes
+        .shape(&operator.name, &*store, &trip.shape_id, None)
+        .await
+        .ok()?;
+    let stops = shapes
+        .trip_stops(&operator.name, &*store, trip_id)
+        .await
+        .ok()?;
+
+    let position = Point {
+        lat: vehicle.position.latitude as f64,
+        lng: vehicle.position.longitude as f64,
+    };
+
+    let stop = &stops[next_stop(&shape, &stops, position)?];
+    let time = stop_time_of_day(&stop.arrival_time, &stop.departure_time)?;
+
+    let arrivals = recent_arrivals(operator, history, line, Some(&stop.stop_id)).await?;
+    let observations = Observations::new(&arrivals, &Local);
+    let day = day_type(Local::now().date_naive());
+
+    forecast(operator, &observations, &stop.stop_id, time, day)
+}
+
+/// Adds forecasts for today to the stops of a single trip, which have scheduled times.
+pub async fn add_trip_forecasts(
+    operator: &Operator,
+    store: Arc<dyn StaticStore>,
+    history: &dyn HistoryStore,
+    trip_id: &str,
+    output: &mut RouteInformationOutput,
+) {
+    let line = match store.trip(trip_id).await {
+        Some(trip) => store.route(&trip.route_id).await,
+        None => None,
+    };
+
+    let arrivals = match line {
+        Some(route) => recent_arrivals(operator, history, &route.route_short_name, None).await,
+        None => None,
+    };
+
+    let observations = match arrivals {
+        Some(arrivals) => Observations::new(&arrivals, &Local),
+        None => return,
+    };
+
+    let day = day_type(Local::now().date_naive());
+
+    let stops = output
+        .directions
+        .iter_mut()
+        .flat_map(|direction| direction.variants.iter_mut())
+        .flat_map(|variant| variant.stops.iter_mut());
+
+    for stop in stops {
+        let time = stop_time_of_day(
+            stop.arrival_time.as_deref().unwrap_or_default(),
+            stop.departure_time.as_deref().unwrap_or_default(),
+        );
+
+        let (_, stop_id) = split_namespaced_id(&stop.stop_id, &[&operator.name]);
+
+        stop.forecast = time.and_then(|time| forecast(operator, &observations, stop_id, time, day));
+    }
+}
+
+/// Returns when a trip is scheduled at a stop, in seconds after the start of the service day.
+/// Riders board when the trip departs, so the departure time is used if there is one.
+fn stop_time_of_day(arrival_time: &str, departure_time: &str) -> Option<i64> {
+    parse_gtfs_time(departure_time).or_else(|| parse_gtfs_time(arrival_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::store::arrival;
+
+    /// Arrivals of a trip that is scheduled at 08:02 UTC on Monday 2021-05-10, on `days` days
+    /// after that, with the occupancy of each.
+    fn arrivals(days: &[(i64, Option<u32>)]) -> Vec<ObservedArrival> {
+        days.iter()
+            .map(|(day, occupancy)| {
+                let mut arrival = arrival(2, 1_620_633_720 + day * 86_400);
+                arrival.service_date += TimeDelta::days(*day);
+                arrival.scheduled += day * 86_400;
+                arrival.occupancy = *occupancy;
+                arrival
+            })
+            .collect()
+    }
+
+    fn stop(lat: f64) -> TripStop {
+        TripStop {
+            stop_id: lat.to_string(),
+            name: String::new(),
+            point: Point { lat, lng: 17.6 },
+            platform: None,
+            arrival_time: String::new(),
+            departure_time: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_day_type() {
+        let day = |day| day_type(NaiveDate::from_ymd_opt(2021, 5, day).unwrap());
+
+        assert_eq!(day(10), DayType::Weekday);
+        assert_eq!(day(14), DayType::Weekday);
+        assert_eq!(day(15), DayType::Saturday);
+        assert_eq!(day(16), DayType::Sunday);
+    }
+
+    #[test]
+    fn test_feed_occupancy() {
+        let mut vehicle = VehiclePosition {
+            occupancy_status: Some(OccupancyStatus::FEW_SEATS_AVAILABLE),
+            ..VehiclePosition::default()
+        };
+        assert_eq!(feed_occupancy(&vehicle), Some(60));
+
+        vehicle.occupancy_percentage = Some(72);
+        assert_eq!(feed_occupancy(&vehicle), Some(72));
+
+        vehicle.occupancy_percentage = None;
+        vehicle.occupancy_status = Some(OccupancyStatus::NOT_ACCEPTING_PASSENGERS);
+        assert_eq!(feed_occupancy(&vehicle), None);
+    }
+
+    #[test]
+    fn test_forecast() {
+        let operator = Operator::new("ul", "realtime", "static");
+        let time = 8 * 3600 + 2 * 60;
+
+        // Four weekdays with an occupancy, one without and a Saturday.
+        let arrivals = arrivals(&[
+            (0, Some(80)),
+            (1, Some(70)),
+            (2, None),
+            (3, Some(90)),
+            (4, Some(40)),
+            (5, Some(10)),
+        ]);
+
+        let observations = Observations::new(&arrivals, &Utc);
+
+        let forecast = forecast(
+            &operator,
+            &observations,
+            "9022003700021002",
+            time,
+            DayType::Weekday,
+        )
+        .unwrap();
+        assert_eq!(forecast.stop_id, "ul:9022003700021002");
+        assert_eq!(forecast.time, "08:02");
+        assert_eq!(forecast.occupancy, 75);
+        assert_eq!(forecast.observations, 4);
+
+        // Other times of day, other stops and days with too few arrivals have no forecast.
+        let forecast =
+            |stop_id, time, day| super::forecast(&operator, &observations, stop_id, time, day);
+
+        assert!(forecast("9022003700021002", time + 1800, DayType::Weekday).is_some());
+        assert_eq!(
+            forecast("9022003700021002", time + 1801, DayType::Weekday),
+            None
+        );
+        assert_eq!(forecast("9022003700021001", time, DayType::Weekday), None);
+        assert_eq!(forecast("9022003700021002", time, DayType::Saturday), None);
+    }
+
+    #[test]
+    fn test_next_stop() {
+        let stops = [stop(59.80), stop(59.81), stop(59.82)];
+        let at = |lat| Point { lat, lng: 17.6 };
+
+        assert_eq!(next_stop(&[], &stops, at(59.79)), Some(0));
+        assert_eq!(next_stop(&[], &stops, at(59.81)), Some(1));
+        assert_eq!(next_stop(&[], &stops, at(59.815)), Some(2));
+
+        // Positions past the end of the line are at its last stop.
+        assert_eq!(next_stop(&[], &stops, at(59.83)), Some(2));
+        assert_eq!(next_stop(&[], &[], at(59.83)), None);
+    }
+}
@@ -0,0 +1,324 @@
+//! Measures how evenly the vehicles on a line are spread out.
+//!
+//! Every vehicle is placed on the timetable of its trip: how long the trip is scheduled to
+//! take from its first stop to where the vehicle is now. Two consecutive vehicles in the same
+//! direction are then as far apart (in time) as the difference between their places, which
+//! is compared to how far apart their trips are scheduled to depart.
+//!
+//! Measuring headways in scheduled time rather than in metres means that a vehicle that is
+//! stuck in a slow part of the route is not mistaken for being close to the one behind it.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::geometry::{self, Point};
+use crate::lobby::shapes::{ShapeCache, TripStop};
+use crate::protocol::server_protocol::{
+    DirectionHealth, ErrorOutput, Headway, HeadwayStatus, Vehicle,
+};
+use crate::store::StaticStore;
+use crate::util::parse_gtfs_time;
+
+/// A headway that is shorter than this share of the scheduled headway is bunched.
+const BUNCHING_RATIO: f64 = 0.5;
+
+/// A headway that is longer than this multiple of the scheduled headway is a gap.
+const GAP_RATIO: f64 = 1.5;
+
+/// A vehicle that has been placed on the timetable of its trip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlacedVehicle {
+    pub descriptor_id: String,
+    pub direction_id: Option<u8>,
+    pub headsign: Option<String>,
+
+    /// When the trip is scheduled to depart from its first stop, in seconds after midnight.
+    pub start: i64,
+
+    /// How long (in seconds) the trip is scheduled to take to where the vehicle is now.
+    pub elapsed: i64,
+}
+
+/// Places every vehicle of `operator` on the timetable of its trip. Returns the vehicles that
+/// could be placed and the number of vehicles that could not.
+pub async fn place_vehicles(
+    operator: &str,
+    store: Arc<dyn StaticStore>,
+    shapes: &ShapeCache,
+    vehicles: &[Vehicle],
+) -> Result<(Vec<PlacedVehicle>, u32), ErrorOutput> {
+    let mut placed = Vec::new();
+    let mut untracked = 0;
+
+    for vehicle in vehicles {
+        let trip_id = match vehicle.plain_trip_id() {
+            Some(trip_id) => trip_id,
+            None => {
+                untracked += 1;
+                continue;
+            }
+        };
+
+        let trip = match store.trip(trip_id).await {
+            Some(trip) => trip,
+            None => {
+                untracked += 1;
+                continue;
+            }
+        };
+
+        let shape = shapes
+            .shape(operator, &*store, &trip.shape_id, None)
+            .await?;
+        let stops = shapes.trip_stops(operator, &*store, trip_id).await?;
+
+        let position = Point {
+            lat: vehicle.position.latitude as f64,
+            lng: vehicle.position.longitude as f64,
+        };
+
+        match scheduled_progress(&shape, &stops, position) {
+            Some((start, elapsed)) => placed.push(PlacedVehicle {
+                descriptor_id: vehicle.descriptor_id.clone(),
+                direction_id: trip.direction_id.parse().ok(),
+                headsign: trip.trip_headsign,
+                start,
+                elapsed,
+            }),
+            None => untracked += 1,
+        }
+    }
+
+    Ok((placed, untracked))
+}
+
+/// Returns when a trip is scheduled to depart from its first stop and how long it is
+/// scheduled to take to `position`, both in seconds. The times between stops are
+/// interpolated by the distance along `shape`, or along the stops if the trip has no shape.
+///
+/// Returns `None` if fewer than two of the stops have scheduled times.
+pub fn scheduled_progress(
+    shape: &[Point],
+    stops: &[TripStop],
+    position: Point,
+) -> Option<(i64, i64)> {
+    // GTFS only requires times at some stops, so the others are left out.
+    let timed = stops
+        .iter()
+        .filter_map(|stop| {
+            let arrival = parse_gtfs_time(&stop.arrival_time)?;
+            let departure = parse_gtfs_time(&stop.departure_time).unwrap_or(arrival);
+
+            Some((stop.point, arrival, departure.max(arrival)))
+        })
+        .collect::<Vec<_>>();
+
+    if timed.len() < 2 {
+        return None;
+    }
+
+    let points = timed.iter().map(|(point, _, _)| *point).collect::<Vec<_>>();
+    let line = if shape.len() >= 2 { shape } else { &points };
+
+    let distances = geometry::distances_along(line, &points);
+    let distance = geometry::distances_along(line, &[position])[0];
+
+    let start = timed[0].2;
+
+    // The vehicle is between the last stop it has passed and the next one.
+    let next = distances
+        .iter()
+        .position(|stop_distance| *stop_distance > distance);
+
+    let time = match next {
+        None => timed[timed.len() - 1].1,
+        Some(0) => start,
+        Some(next) => {
+            let (_, _, departure) = timed[next - 1];
+            let (_, arrival, _) = timed[next];
+            let length = distances[next] - distances[next - 1];
+            let fraction = (distance - distances[next - 1]) / length;
+
+            departure + ((arrival - departure) as f64 * fraction).round() as i64
+        }
+    };
+
+    Some((start, (time - start).max(0)))
+}
+
+/// Groups the vehicles by direction and measures the headway between every vehicle and the
+/// one behind it.
+pub fn direction_health(vehicles: Vec<PlacedVehicle>) -> Vec<DirectionHealth> {
+    let mut directions = BTreeMap::<_, Vec<PlacedVehicle>>::new();
+
+    for vehicle in vehicles {
+        directions
+            .entry(vehicle.direction_id)
+            .or_default()
+            .push(vehicle);
+    }
+
+    directions
+        .into_iter()
+        .map(|(direction_id, mut vehicles)| {
+            // Sorting by descriptor id as well keeps the order stable between requests.
+            vehicles.sort_by(|a, b| {
+                let key = |vehicle: &PlacedVehicle| {
+                    (Reverse(vehicle.elapsed), vehicle.descriptor_id.clone())
+                };
+                key(a).cmp(&key(b))
+            });
+
+            let headways = vehicles
+                .windows(2)
+                .map(|pair| {
+                    let (leading, following) = (&pair[0], &pair[1]);
+                    let actual = leading.elapsed - following.elapsed;
+                    let scheduled = (following.start - leading.start).abs();
+
+                    Headway {
+                        leading: leading.descriptor_id.clone(),
+                        following: following.descriptor_id.clone(),
+                        actual,
+                        scheduled,
+                        status: classify(actual, scheduled),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let count = |status| headways.iter().filter(|h| h.status == status).count() as u32;
+
+            DirectionHealth {
+                direction_id,
+                headsign: vehicles
+                    .first()
+                    .and_then(|vehicle| vehicle.headsign.clone()),
+                vehicles: vehicles
+                    .iter()
+                    .map(|vehicle| vehicle.descriptor_id.clone())
+                    .collect(),
+                bunched: count(HeadwayStatus::Bunched),
+                gaps: count(HeadwayStatus::Gap),
+                headways,
+            }
+        })
+        .collect()
+}
+
+/// Compares a headway to the scheduled one. Trips that are scheduled to depart at the same
+/// time (like on different branches of a line) can not be compared, so they are regular.
+pub fn classify(actual: i64, scheduled: i64) -> HeadwayStatus {
+    if scheduled <= 0 {
+        return HeadwayStatus::Regular;
+    }
+
+    let ratio = actual as f64 / scheduled as f64;
+
+    if ratio < BUNCHING_RATIO {
+        HeadwayStatus::Bunched
+    } else if ratio > GAP_RATIO {
+        HeadwayStatus::Gap
+    } else {
+        HeadwayStatus::Regular
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stop on a line that goes straight north along a meridian.
+    fn stop(lat: f64, arrival_time: &str, departure_time: &str) -> TripStop {
+        TripStop {
+            stop_id: lat.to_string(),
+            name: String::new(),
+            point: Point { lat, lng: 17.6 },
+            platform: None,
+            arrival_time: arrival_time.to_owned(),
+            departure_time: departure_time.to_owned(),
+        }
+    }
+
+    fn vehicle(descriptor_id: &str, direction_id: u8, start: i64, elapsed: i64) -> PlacedVehicle {
+        PlacedVehicle {
+            descriptor_id: descriptor_id.to_owned(),
+            direction_id: Some(direction_id),
+            headsign: Some(format!("Headsign {}", direction_id)),
+            start,
+            elapsed,
+        }
+    }
+
+    #[test]
+    fn test_scheduled_progress() {
+        let stops = [
+            stop(59.80, "08:00:00", "08:00:00"),
+            stop(59.81, "08:09:00", "08:10:00"),
+            stop(59.82, "", ""),
+            stop(59.83, "08:30:00", "08:30:00"),
+        ];
+        let at = |lat| Point { lat, lng: 17.6 };
+        let start = 8 * 3600;
+
+        // Without a shape the stops are used as the line.
+        assert_eq!(scheduled_progress(&[], &stops, at(59.79)), Some((start, 0)));
+        assert_eq!(
+            scheduled_progress(&[], &stops, at(59.805)),
+            Some((start, 270))
+        );
+
+        // Between the second stop and the last one, where the stop without times is skipped.
+        assert_eq!(
+            scheduled_progress(&[], &stops, at(59.82)),
+            Some((start, 600 + 600))
+        );
+        assert_eq!(
+            scheduled_progress(&[], &stops, at(59.84)),
+            Some((start, 1800))
+        );
+
+        assert_eq!(scheduled_progress(&[], &stops[..1], at(59.8)), None);
+    }
+
+    #[test]
+    fn test_direction_health() {
+        let vehicles = vec![
+            // Every ten minutes in direction 0, where the last two are bunched.
+            vehicle("a", 0, 8 * 3600, 1500),
+            vehicle("c", 0, 8 * 3600 + 1200, 260),
+            vehicle("b", 0, 8 * 3600 + 600, 400),
+            // Every five minutes in direction 1, with a gap.
+            vehicle("d", 1, 8 * 3600, 1000),
+            vehicle("e", 1, 8 * 3600 + 300, 100),
+        ];
+
+        let directions = direction_health(vehicles);
+        assert_eq!(directions.len(), 2);
+
+        let forward = &directions[0];
+        assert_eq!(forward.direction_id, Some(0));
+        assert_eq!(forward.headsign.as_deref(), Some("Headsign 0"));
+        assert_eq!(forward.vehicles, ["a", "b", "c"]);
+        assert_eq!(forward.headways[0].actual, 1100);
+        assert_eq!(forward.headways[0].scheduled, 600);
+        assert_eq!(forward.headways[0].status, HeadwayStatus::Gap);
+        assert_eq!(forward.headways[1].actual, 140);
+        assert_eq!(forward.headways[1].status, HeadwayStatus::Bunched);
+        assert_eq!((forward.bunched, forward.gaps), (1, 1));
+
+        let backward = &directions[1];
+        assert_eq!(backward.vehicles, ["d", "e"]);
+        assert_eq!(backward.headways[0].status, HeadwayStatus::Gap);
+        assert_eq!((backward.bunched, backward.gaps), (0, 1));
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(600, 600), HeadwayStatus::Regular);
+        assert_eq!(classify(299, 600), HeadwayStatus::Bunched);
+        assert_eq!(classify(300, 600), HeadwayStatus::Regular);
+        assert_eq!(classify(901, 600), HeadwayStatus::Gap);
+        assert_eq!(classify(100, 0), HeadwayStatus::Regular);
+    }
+}
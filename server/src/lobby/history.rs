@@ -0,0 +1,683 @@
+//! Records when trips actually arrive at their stops and measures how punctual they are.
+//!
+//! Arrivals are observed in the realtime feeds: a vehicle position that says that a vehicle
+//! is stopped at a stop, or a trip update with the time that a trip arrived at a stop that it
+//! has already passed. Every arrival is matched to the stop time of its trip to find when it
+//! was scheduled, and is kept in the history store.
+//!
+//! A vehicle is usually seen stopped at the same stop in several feeds, and the first of them
+//! is recorded. A vehicle that stops for a shorter time than between two feeds is not seen at
+//! all, so feeds with trip updates give more complete histories.
+//!
+//! Vehicles that are stopped at a stop can also tell how full they are in the feed, which
+//! crowding forecasts are made from (see `crowding`). The passenger counts that riders see
+//! start out made up, so they are never recorded.
+//!
+//! Times of day are in the server's local timezone, which is assumed to be the timezone of
+//! the timetables (like in the simulator).
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::{Actor, AsyncContext, Context, Handler, ResponseActFuture, WrapFuture};
+use chrono::{Datelike, Local, NaiveDate, TimeDelta, TimeZone, Timelike};
+use tracing::warn;
+
+use crate::config::HistorySettings;
+use crate::gtfs::operator::Operator;
+use crate::gtfs::transit_realtime::mod_TripUpdate::mod_StopTimeUpdate::ScheduleRelationship;
+use crate::gtfs::transit_realtime::mod_VehiclePosition::VehicleStopStatus;
+use crate::gtfs::transit_realtime::FeedMessage;
+use crate::gtfs::transit_static::StopTime;
+use crate::lobby::crowding::feed_occupancy;
+use crate::lobby::Lobby;
+use crate::messages::RecordArrivals;
+use crate::protocol::server_protocol::{Punctuality, PunctualityGroup, PunctualityGrouping};
+use crate::store::{HistoryStore, ObservedArrival, StaticStore};
+use crate::util::{parse_gtfs_date, parse_gtfs_time};
+
+/// How often arrivals that are older than the retention are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long (in seconds) a sighting is remembered, so that a vehicle that stands at a stop
+/// for several feeds is only looked up once.
+const SIGHTING_MEMORY: i64 = 30 * 60;
+
+/// Arrivals that are further than this (in seconds) from the timetable have most likely been
+/// matched to the wrong visit or service date, and are not recorded.
+const MAX_DELAY: i64 = 3 * 60 * 60;
+
+/// The days of the week as they are sent to clients, starting with Monday.
+const WEEKDAYS: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// A trip that was seen arriving at a stop in a realtime feed. Ids are not namespaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sighting {
+    pub trip_id: String,
+
+    /// The service date of the trip, if the feed has one.
+    pub start_date: Option<NaiveDate>,
+
+    /// The stop, which the feed gives by id, by stop sequence or both.
+    pub stop_id: Option<String>,
+    pub stop_sequence: Option<u32>,
+
+    /// When the trip arrived, in seconds since 1970-01-01 00:00:00 UTC.
+    pub observed: i64,
+
+    /// The vehicle that drives the trip and how full it is (in percent), if the feed has them.
+    pub vehicle_id: Option<String>,
+    pub occupancy: Option<u32>,
+}
+
+/// Identifies a sighting of a trip at a stop of an operator.
+type SightingKey = (String, String, Option<String>, Option<u32>);
+
+/// Records the arrivals in the realtime feeds of every operator and removes the ones that
+/// are older than the retention.
+pub struct HistoryRecorder {
+    /// Every operator and its static data, by name.
+    operators: HashMap<String, (Operator, Arc<dyn StaticStore>)>,
+
+    history: Arc<dyn HistoryStore>,
+
+    /// How many days of arrivals are kept.
+    retention_days: u32,
+
+    /// Maps recent sightings to when they were observed.
+    recent: HashMap<SightingKey, i64>,
+}
+
+impl HistoryRecorder {
+    pub fn new(
+        operators: &[Operator],
+        static_stores: &HashMap<String, Arc<dyn StaticStore>>,
+        history: Arc<dyn HistoryStore>,
+        retention_days: u32,
+    ) -> Self {
+        let operators = operators
+            .iter()
+            .map(|operator| {
+                let store = static_stores[&operator.name].clone();
+                (operator.name.clone(), (operator.clone(), store))
+            })
+            .collect();
+
+        HistoryRecorder {
+            operators,
+            history,
+            retention_days,
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Removes the arrivals with a service date that is older than the retention.
+    fn prune(&self, ctx: &mut Context<Self>) {
+        let today = Local::now().date_naive();
+        let oldest = today - TimeDelta::days(self.retention_days as i64 - 1);
+        let pruned = self.history.prune(oldest);
+
+        ctx.spawn(
+            async move {
+                if !pruned.await {
+                    warn!(%oldest, "Could not remove old arrivals.");
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+impl Actor for HistoryRecorder {
+    type Context = Context<Self>;
+
+    // This method is called when the recorder is started.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.prune(ctx);
+
+        ctx.run_interval(PRUNE_INTERVAL, |act, ctx| act.prune(ctx));
+    }
+}
+
+impl Handler<RecordArrivals> for HistoryRecorder {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: RecordArrivals, _: &mut Context<Self>) -> Self::Result {
+        let (operator, store) = match self.operators.get(&msg.operator) {
+            Some((operator, store)) => (operator.clone(), store.clone()),
+            None => {
+                warn!(operator = %msg.operator, "Received arrivals for unknown operator.");
+                return Box::pin(async {}.into_actor(self));
+            }
+        };
+
+        let now = Lobby::get_current_timestamp() as i64;
+        self.recent
+            .retain(|_, observed| now - *observed < SIGHTING_MEMORY);
+
+        let mut new_sightings = Vec::new();
+
+        for sighting in sightings(&msg.feed.message()) {
+            let key = (
+                operator.name.clone(),
+                sighting.trip_id.clone(),
+                sighting.stop_id.clone(),
+                sighting.stop_sequence,
+            );
+
+            if self.recent.insert(key, sighting.observed).is_none() {
+                new_sightings.push(sighting);
+            }
+        }
+
+        let history = self.history.clone();
+
+        Box::pin(
+            async move {
+                // A trip update has a sighting for every stop that the trip has passed, so
+                // every trip is only looked up once.
+                let mut trips = HashMap::new();
+                let mut arrivals = Vec::new();
+
+                for sighting in &new_sightings {
+                    if !trips.contains_key(&sighting.trip_id) {
+                        let trip = scheduled_trip(&*store, &sighting.trip_id).await;
+                        trips.insert(sighting.trip_id.clone(), trip);
+                    }
+
+                    let (line, stop_times) = match &trips[&sighting.trip_id] {
+                        Some(trip) => trip,
+                        None => continue,
+                    };
+
+                    if let Some(arrival) =
+                        resolve(&operator.name, line, sighting, stop_times, &Local)
+                    {
+                        arrivals.push(arrival);
+                    }
+                }
+
+                if !arrivals.is_empty() && !history.record(arrivals).await {
+                    warn!(operator = %operator.name, "Could not record arrivals.");
+                }
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+/// Looks up the line number and the stop times of a trip.
+async fn scheduled_trip(store: &dyn StaticStore, trip_id: &str) -> Option<(String, Vec<StopTime>)> {
+    let trip = store.trip(trip_id).await?;
+    let route = store.route(&trip.route_id).await?;
+    let stop_times = store.stop_times(trip_id).await?;
+
+    Some((route.route_short_name, stop_times))
+}
+
+/// Finds every trip in a feed that has arrived at a stop: vehicles that are stopped at a stop
+/// and stops in trip updates that have an arrival time that has passed.
+pub fn sightings(message: &FeedMessage) -> Vec<Sighting> {
+    let feed_timestamp = message.header.timestamp.map(|timestamp| timestamp as i64);
+    let mut sightings = Vec::new();
+
+    for entity in &message.entity {
+        if let Some(vehicle) = &entity.vehicle {
+            let trip = vehicle.trip.as_ref();
+            let trip_id = trip.and_then(|trip| trip.trip_id.as_deref());
+            let observed = vehicle.timestamp.map(|timestamp| timestamp as i64);
+
+            if let (Some(trip_id), Some(observed), VehicleStopStatus::STOPPED_AT) =
+                (trip_id, observed.or(feed_timestamp), vehicle.current_status)
+            {
+                if vehicle.stop_id.is_some() || vehicle.current_stop_sequence.is_some() {
+                    sightings.push(Sighting {
+                        trip_id: trip_id.to_owned(),
+                        start_date: trip
+                            .and_then(|trip| trip.start_date.as_deref())
+                            .and_then(parse_gtfs_date),
+                        stop_id: vehicle.stop_id.as_deref().map(str::to_owned),
+                        stop_sequence: vehicle.current_stop_sequence,
+                        observed,
+                        vehicle_id: vehicle
+                            .vehicle
+                            .as_ref()
+                            .and_then(|descriptor| descriptor.id.as_deref())
+                            .map(str::to_owned),
+                        occupancy: feed_occupancy(vehicle),
+                    });
+                }
+            }
+        }
+
+        if let (Some(trip_update), Some(feed_timestamp)) = (&entity.trip_update, feed_timestamp) {
+            let trip_id = match trip_update.trip.trip_id.as_deref() {
+                Some(trip_id) => trip_id,
+                None => continue,
+            };
+
+            let start_date = trip_update
+                .trip
+                .start_date
+                .as_deref()
+                .and_then(parse_gtfs_date);
+
+            for update in &trip_update.stop_time_update {
+                if update.schedule_relationship != ScheduleRelationship::SCHEDULED
+                    || (update.stop_id.is_none() && update.stop_sequence.is_none())
+                {
+                    continue;
+                }
+
+                // Times that have not passed yet are predictions.
+                let arrived = update.arrival.as_ref().and_then(|arrival| arrival.time);
+
+                if let Some(observed) = arrived.filter(|time| *time <= feed_timestamp) {
+                    sightings.push(Sighting {
+                        trip_id: trip_id.to_owned(),
+                        start_date,
+                        stop_id: update.stop_id.as_deref().map(str::to_owned),
+                        stop_sequence: update.stop_sequence,
+                        observed,
+                        vehicle_id: None,
+                        occupancy: None,
+                    });
+                }
+            }
+        }
+    }
+
+    sightings
+}
+
+/// Matches a sighting to the stop time of its trip, where times of day are in `tz`.
+///
+/// Stop times are matched by stop sequence if the feed has one and by stop id otherwise. A
+/// trip that passes the stop more than once is matched to the visit that was scheduled closest
+/// to the sighting. Without a start date in the feed, the trip may have started on the day
+/// before the sighting as well, if it runs past midnight.
+pub fn resolve<Tz: TimeZone>(
+    operator: &str,
+    line: &str,
+    sighting: &Sighting,
+    stop_times: &[StopTime],
+    tz: &Tz,
+) -> Option<ObservedArrival> {
+    let observed_date = tz
+        .timestamp_opt(sighting.observed, 0)
+        .single()?
+        .date_naive();
+
+    let dates = match sighting.start_date {
+        Some(date) => vec![date],
+        None => vec![observed_date, observed_date.pred_opt()?],
+    };
+
+    let matches = |stop_time: &&StopTime| match (sighting.stop_sequence, &sighting.stop_id) {
+        (Some(stop_sequence), _) => stop_time.stop_sequence == stop_sequence as i32,
+        (None, Some(stop_id)) => &stop_time.stop_id == stop_id,
+        (None, None) => false,
+    };
+
+    let (stop_time, service_date, scheduled) = stop_times
+        .iter()
+        .filter(matches)
+        .filter_map(|stop_time| {
+            // The first stop of a trip often only has a departure time.
+            let time = parse_gtfs_time(&stop_time.arrival_time)
+                .or_else(|| parse_gtfs_time(&stop_time.departure_time))?;
+
+            Some((stop_time, time))
+        })
+        .flat_map(|(stop_time, time)| {
+            dates.iter().filter_map(move |date| {
+                Some((stop_time, *date, service_day_start(*date, tz)? + time))
+            })
+        })
+        .min_by_key(|(_, _, scheduled)| (sighting.observed - scheduled).abs())?;
+
+    if (sighting.observed - scheduled).abs() > MAX_DELAY {
+        return None;
+    }
+
+    Some(ObservedArrival {
+        operator: operator.to_owned(),
+        line: line.to_owned(),
+        trip_id: sighting.trip_id.clone(),
+        stop_id: stop_time.stop_id.clone(),
+        stop_sequence: stop_time.stop_sequence.max(0) as u32,
+        service_date,
+        scheduled,
+        observed: sighting.observed,
+        occupancy: sighting.occupancy,
+    })
+}
+
+/// Returns when the service day `date` starts in `tz`, in seconds since 1970-01-01 00:00:00
+/// UTC. GTFS measures times from noon minus twelve hours, which is midnight except on the days
+/// when daylight saving time starts or ends.
+pub fn service_day_start<Tz: TimeZone>(date: NaiveDate, tz: &Tz) -> Option<i64> {
+    let noon = tz
+        .from_local_datetime(&date.and_hms_opt(12, 0, 0)?)
+        .single()?;
+
+    Some(noon.timestamp() - 12 * 60 * 60)
+}
+
+/// Counts arrivals that were early, on time and late.
+#[derive(Debug, Clone, Copy, Default)]
+struct Tally {
+    arrivals: u32,
+    early: u32,
+    on_time: u32,
+    late: u32,
+
+    /// The sum of the delays in seconds.
+    delay: i64,
+}
+
+impl Tally {
+    fn add(&mut self, delay: i64, settings: &HistorySettings) {
+        self.arrivals += 1;
+        self.delay += delay;
+
+        if delay < -(settings.early_tolerance as i64) {
+            self.early += 1;
+        } else if delay > settings.late_tolerance as i64 {
+            self.late += 1;
+        } else {
+            self.on_time += 1;
+        }
+    }
+
+    fn to_output(self) -> Punctuality {
+        let arrivals = Some(self.arrivals as f64).filter(|arrivals| *arrivals > 0.0);
+
+        Punctuality {
+            arrivals: self.arrivals,
+            early: self.early,
+            on_time: self.on_time,
+            late: self.late,
+            on_time_share: arrivals.map(|arrivals| self.on_time as f64 / arrivals),
+            average_delay: arrivals.map(|arrivals| self.delay as f64 / arrivals),
+        }
+    }
+}
+
+/// Measures the on-time performance of arrivals of `operator`, in total and grouped by
+/// `group_by`. Hours and weekdays are those of the scheduled times in `tz`.
+///
+/// Groups are sorted by hour or weekday (starting with Monday), and lines and stops by their
+/// ids.
+pub fn punctuality<Tz: TimeZone>(
+    operator: &Operator,
+    arrivals: &[ObservedArrival],
+    group_by: Option<PunctualityGrouping>,
+    settings: &HistorySettings,
+    tz: &Tz,
+) -> (Punctuality, Vec<PunctualityGroup>) {
+    let mut total = Tally::default();
+    let mut groups = BTreeMap::<(u32, String), Tally>::new();
+
+    for arrival in arrivals {
+        total.add(arrival.delay(), settings);
+
+        let scheduled = tz.timestamp_opt(arrival.scheduled, 0).single();
+
+        let key = match (group_by, scheduled) {
+            (Some(PunctualityGrouping::Line), _) => (0, operator.namespace_id(&arrival.line)),
+            (Some(PunctualityGrouping::Stop), _) => (0, operator.namespace_id(&arrival.stop_id)),
+            (Some(PunctualityGrouping::Hour), Some(scheduled)) => {
+                (scheduled.hour(), scheduled.hour().to_string())
+            }
+            (Some(PunctualityGrouping::Weekday), Some(scheduled)) => {
+                let day = scheduled.weekday().num_days_from_monday();
+                (day, WEEKDAYS[day as usize].to_owned())
+            }
+            _ => continue,
+        };
+
+        groups
+            .entry(key)
+            .or_default()
+            .add(arrival.delay(), settings);
+    }
+
+    let groups = groups
+        .into_iter()
+        .map(|((_, key), tally)| PunctualityGroup {
+            key,
+            punctuality: tally.to_output(),
+        })
+        .collect();
+
+    (total.to_output(), groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::gtfs::transit_realtime::mod_TripUpdate::{StopTimeEvent, StopTimeUpdate};
+    use crate::gtfs::transit_realtime::{
+        FeedEntity, FeedHeader, TripDescriptor, TripUpdate, VehicleDescriptor, VehiclePosition,
+    };
+    use crate::store::arrival;
+
+    /// 2021-05-10 08:00:00 UTC, which was a Monday.
+    const MONDAY_8: i64 = 1_620_633_600;
+
+    fn stop_time(stop_sequence: i32, stop_id: &str, time: &str) -> StopTime {
+        StopTime {
+            trip_id: "1".to_owned(),
+            arrival_time: time.to_owned(),
+            departure_time: time.to_owned(),
+            stop_id: stop_id.to_owned(),
+            stop_sequence,
+            stop_headsign: String::new(),
+            pickup_type: Some(0),
+            drop_off_type: Some(0),
+            shape_dist_traveled: None,
+            timepoint: Some(1),
+        }
+    }
+
+    fn sighting(stop_id: Option<&str>, stop_sequence: Option<u32>, observed: i64) -> Sighting {
+        Sighting {
+            trip_id: "1".to_owned(),
+            start_date: None,
+            stop_id: stop_id.map(str::to_owned),
+            stop_sequence,
+            observed,
+            vehicle_id: None,
+            occupancy: None,
+        }
+    }
+
+    #[test]
+    fn test_sightings() {
+        let trip = TripDescriptor {
+            trip_id: Some(Cow::Borrowed("1")),
+            start_date: Some(Cow::Borrowed("20210510")),
+            ..TripDescriptor::default()
+        };
+
+        let vehicle = |status, timestamp| FeedEntity {
+            vehicle: Some(VehiclePosition {
+                trip: Some(trip.clone()),
+                stop_id: Some(Cow::Borrowed("A")),
+                current_status: status,
+                timestamp,
+                vehicle: Some(VehicleDescriptor {
+                    id: Some(Cow::Borrowed("9031003")),
+                    ..VehicleDescriptor::default()
+                }),
+                occupancy_percentage: Some(80),
+                ..VehiclePosition::default()
+            }),
+            ..FeedEntity::default()
+        };
+
+        let update = |stop_sequence, time| StopTimeUpdate {
+            stop_sequence: Some(stop_sequence),
+            arrival: Some(StopTimeEvent {
+                time: Some(time),
+                ..StopTimeEvent::default()
+            }),
+            ..StopTimeUpdate::default()
+        };
+
+        let message = FeedMessage {
+            header: FeedHeader {
+                timestamp: Some(MONDAY_8 as u64),
+                ..FeedHeader::default()
+            },
+            entity: vec![
+                vehicle(VehicleStopStatus::STOPPED_AT, Some(MONDAY_8 as u64 - 10)),
+                vehicle(VehicleStopStatus::IN_TRANSIT_TO, None),
+                FeedEntity {
+                    trip_update: Some(TripUpdate {
+                        trip: trip.clone(),
+                        // The second stop has not been reached yet.
+                        stop_time_update: vec![update(1, MONDAY_8 - 60), update(2, MONDAY_8 + 60)],
+                        ..TripUpdate::default()
+                    }),
+                    ..FeedEntity::default()
+                },
+            ],
+        };
+
+        let date = NaiveDate::from_ymd_opt(2021, 5, 10);
+
+        assert_eq!(
+            sightings(&message),
+            vec![
+                Sighting {
+                    start_date: date,
+                    vehicle_id: Some("9031003".to_owned()),
+                    occupancy: Some(80),
+                    ..sighting(Some("A"), None, MONDAY_8 - 10)
+                },
+                Sighting {
+                    start_date: date,
+                    ..sighting(None, Some(1), MONDAY_8 - 60)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        // A trip that loops back to where it started, and runs past midnight.
+        let stop_times = [
+            stop_time(1, "A", "23:50:00"),
+            stop_time(2, "B", "24:00:00"),
+            stop_time(3, "A", "24:10:00"),
+        ];
+        let midnight = MONDAY_8 + 16 * 60 * 60;
+
+        let resolved = |sighting: Sighting| resolve("ul", "5", &sighting, &stop_times, &Utc);
+
+        // The loop is matched to the visit that was scheduled closest to the sighting, on the
+        // service day before.
+        let arrival = resolved(sighting(Some("A"), None, midnight + 11 * 60)).unwrap();
+        assert_eq!(arrival.stop_sequence, 3);
+        assert_eq!(
+            arrival.service_date,
+            NaiveDate::from_ymd_opt(2021, 5, 10).unwrap()
+        );
+        assert_eq!(arrival.delay(), 60);
+
+        let arrival = resolved(sighting(Some("A"), Some(1), midnight + 11 * 60)).unwrap();
+        assert_eq!(arrival.stop_sequence, 1);
+        assert_eq!(arrival.delay(), 21 * 60);
+
+        // The start date in the feed decides the service day.
+        let next_day = Sighting {
+            start_date: NaiveDate::from_ymd_opt(2021, 5, 11),
+            ..sighting(Some("B"), None, midnight)
+        };
+        assert_eq!(resolved(next_day), None);
+
+        assert_eq!(resolved(sighting(Some("C"), None, midnight)), None);
+        assert_eq!(resolved(sighting(None, None, midnight)), None);
+    }
+
+    #[test]
+    fn test_punctuality() {
+        let operator = Operator::new("ul", "realtime", "static");
+        let settings = HistorySettings::default();
+
+        let mut arrivals = vec![
+            // Scheduled at 08:02 on Monday.
+            arrival(1, 1_620_633_720 - 120),
+            arrival(2, 1_620_633_720),
+            arrival(3, 1_620_633_720 + 301),
+        ];
+
+        let mut tuesday = arrival(4, 1_620_633_720 + 86_400);
+        tuesday.scheduled += 86_400 - 3600;
+        arrivals.push(tuesday);
+
+        let (total, groups) = punctuality(
+            &operator,
+            &arrivals,
+            Some(PunctualityGrouping::Hour),
+            &settings,
+            &Utc,
+        );
+
+        assert_eq!(total.arrivals, 4);
+        assert_eq!((total.early, total.on_time, total.late), (1, 1, 2));
+        assert_eq!(total.on_time_share, Some(0.25));
+        assert_eq!(
+            total.average_delay,
+            Some((-120.0 + 0.0 + 301.0 + 3600.0) / 4.0)
+        );
+
+        let keys = groups
+            .iter()
+            .map(|group| group.key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["7", "8"]);
+        assert_eq!(groups[1].punctuality.arrivals, 3);
+
+        let (_, groups) = punctuality(
+            &operator,
+            &arrivals,
+            Some(PunctualityGrouping::Weekday),
+            &settings,
+            &Utc,
+        );
+        let keys = groups
+            .iter()
+            .map(|group| group.key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["monday", "tuesday"]);
+
+        let (_, groups) = punctuality(
+            &operator,
+            &arrivals,
+            Some(PunctualityGrouping::Stop),
+            &settings,
+            &Utc,
+        );
+        assert_eq!(groups[0].key, "ul:9022003700021002");
+
+        let (total, groups) = punctuality(&operator, &[], None, &settings, &Utc);
+        assert_eq!(total.on_time_share, None);
+        assert!(groups.is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+//! Receives the realtime feeds from the event bus and keeps track of how healthy they are.
+//!
+//! The health of an operator only depends on its VehiclePositions feed. Its TripUpdates feed
+//! is passed on to the vehicle store and the history recorder whenever it has been fetched.
+//!
+//! Feeds are only fetched by one instance, but every instance receives them, so every
+//! instance tells its clients about the same changes in feed status.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix::prelude::{Actor, Addr, AsyncContext, Context, Handler, MessageResult, StreamHandler};
+use tracing::{info, warn};
+
+use crate::cluster::{ClusterEvent, EventStream};
+use crate::gtfs::health::FeedHealth;
+use crate::gtfs::trafiklab::RealtimeFeed;
+use crate::gtfs::validation::ValidatedFeed;
+use crate::lobby::broadcaster::Broadcaster;
+use crate::lobby::history::HistoryRecorder;
+use crate::lobby::vehicles::VehicleStore;
+use crate::lobby::Lobby;
+use crate::messages::{
+    ApplyFeed, ApplyTripUpdates, FeedStatusesRequest, RecordArrivals, SendFeedStatuses,
+    SendToClient, SendToEveryone,
+};
+use crate::metrics::INVALID_ENTITIES;
+use crate::protocol::server_protocol::{FeedStatus, ServerOutput};
+
+/// How often the health of the realtime feed is checked.
+const FEED_STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The health of a single operator's feed.
+struct OperatorFeed {
+    /// Keeps track of how healthy the operator's realtime feed is.
+    feed_health: FeedHealth,
+
+    /// The feed status that was last sent to the clients.
+    feed_status: FeedStatus,
+}
+
+/// Validates realtime feeds, passes the vehicles on to the vehicle store and tells clients
+/// when the status of a feed changes.
+pub struct FeedIngester {
+    /// Maps operator names to the health of their feeds.
+    operators: HashMap<String, OperatorFeed>,
+
+    /// The events from the event bus, until the ingester has started reading them.
+    events: Option<EventStream>,
+
+    vehicles: Addr<VehicleStore>,
+    broadcaster: Addr<Broadcaster>,
+
+    /// Records arrivals in both kinds of feeds, if the punctuality history is enabled.
+    history: Option<Addr<HistoryRecorder>>,
+}
+
+impl FeedIngester {
+    pub fn new(
+        operators: &[String],
+        events: EventStream,
+        vehicles: Addr<VehicleStore>,
+        broadcaster: Addr<Broadcaster>,
+        history: Option<Addr<HistoryRecorder>>,
+    ) -> Self {
+        let operators = operators
+            .iter()
+            .map(|operator| {
+                let feed = OperatorFeed {
+                    feed_health: FeedHealth::new(),
+                    feed_status: FeedStatus::Stale,
+                };
+
+                (operator.clone(), feed)
+            })
+            .collect();
+
+        FeedIngester {
+            operators,
+            events: Some(events),
+            vehicles,
+            broadcaster,
+            history,
+        }
+    }
+
+    /// Sends the feed status of an operator to all connected clients if it has changed since
+    /// it was last sent.
+    fn broadcast_feed_status_if_changed(&mut self, operator: &str) {
+        let now = Lobby::get_current_timestamp();
+
+        let feed = match self.operators.get_mut(operator) {
+            Some(feed) => feed,
+            None => return,
+        };
+
+        let status = feed.feed_health.status(now);
+
+        if status == feed.feed_status {
+            return;
+        }
+
+        info!(operator, ?status, "Realtime feed status changed.");
+
+        feed.feed_status = status;
+
+        let message = FeedIngester::feed_status_message(operator, feed, now);
+        self.broadcaster.do_send(SendToEveryone(message));
+    }
+
+    /// Creates a serialized feed status message for an operator.
+    fn feed_status_message(operator: &str, feed: &OperatorFeed, now: u64) -> String {
+        serde_json::to_string(&ServerOutput::FeedStatus(
+            feed.feed_health.to_output(operator, now),
+        ))
+        .unwrap()
+    }
+}
+
+impl Actor for FeedIngester {
+    type Context = Context<Self>;
+
+    // This method is called when the ingester is started.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(events) = self.events.take() {
+            ctx.add_stream(events);
+        }
+
+        // The health is checked regularly since a feed can become stale without anything
+        // happening, e.g. when the fetcher is waiting for the circuit breaker to close.
+        ctx.run_interval(FEED_STATUS_CHECK_INTERVAL, |act, _ctx| {
+            let operators = act.operators.keys().cloned().collect::<Vec<String>>();
+
+            for operator in operators {
+                act.broadcast_feed_status_if_changed(&operator);
+            }
+        });
+    }
+}
+
+impl FeedIngester {
+    /// Handles the outcome of an attempt to fetch an operator's realtime feed.
+    fn ingest(&mut self, operator: String, result: Result<RealtimeFeed, String>) {
+        let now = Lobby::get_current_timestamp();
+
+        let feed = match self.operators.get_mut(&operator) {
+            Some(feed) => feed,
+            None => {
+                warn!(%operator, "Received realtime data for unknown operator.");
+                return;
+            }
+        };
+
+        match result {
+            Ok(realtime_feed) => {
+                let validated = ValidatedFeed::from_message(&realtime_feed.message());
+
+                if let Some(summary) = validated.invalid_summary() {
+                    warn!(%operator, "{}", summary);
+                }
+
+                for (reason, count) in &validated.invalid {
+                    INVALID_ENTITIES
+                        .with_label_values(&[&operator, reason.kind()])
+                        .inc_by(*count as u64);
+                }
+
+                feed.feed_health
+                    .record_success(now, validated.timestamp, validated.counts.clone());
+
+                if let Some(history) = &self.history {
+                    history.do_send(RecordArrivals {
+                        operator: operator.clone(),
+                        feed: realtime_feed,
+                    });
+                }
+
+                // The data is processed even if no clients are connected, since it is also
+                // served by the REST API.
+                self.vehicles.do_send(ApplyFeed {
+                    operator: operator.clone(),
+                    feed: validated,
+                });
+            }
+            Err(reason) => feed.feed_health.record_failure(reason),
+        }
+
+        self.broadcast_feed_status_if_changed(&operator);
+    }
+}
+
+impl StreamHandler<ClusterEvent> for FeedIngester {
+    fn handle(&mut self, event: ClusterEvent, _: &mut Context<Self>) {
+        match event {
+            ClusterEvent::RealtimeFeed { operator, result } => self.ingest(operator, result),
+            ClusterEvent::TripUpdates { operator, feed } => {
+                if let Some(history) = &self.history {
+                    history.do_send(RecordArrivals {
+                        operator: operator.clone(),
+                        feed: feed.clone(),
+                    });
+                }
+
+                self.vehicles.do_send(ApplyTripUpdates { operator, feed });
+            }
+            ClusterEvent::PassengerInfoChanged { .. } => {}
+        }
+    }
+
+    // The event bus never ends on its own, and the feed status should keep being checked
+    // even if it does.
+    fn finished(&mut self, _: &mut Context<Self>) {}
+}
+
+impl Handler<SendFeedStatuses> for FeedIngester {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendFeedStatuses, _: &mut Context<Self>) {
+        let now = Lobby::get_current_timestamp();
+
+        for (operator, feed) in &self.operators {
+            self.broadcaster.do_send(SendToClient {
+                client_id: msg.client_id,
+                message: FeedIngester::feed_status_message(operator, feed, now),
+            });
+        }
+    }
+}
+
+impl Handler<FeedStatusesRequest> for FeedIngester {
+    type Result = MessageResult<FeedStatusesRequest>;
+
+    fn handle(&mut self, _: FeedStatusesRequest, _: &mut Context<Self>) -> Self::Result {
+        let now = Lobby::get_current_timestamp();
+
+        MessageResult(
+            self.operators
+                .iter()
+                .map(|(operator, feed)| (operator.clone(), feed.feed_health.status(now)))
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,1329 @@
+//! Keeps track of all connected clients and a shared state.
+//!
+//! The lobby is made up of several actors so that a slow database query or a large feed
+//! does not hold up everything else:
+//!
+//! - `SessionRegistry` keeps track of which clients are connected.
+//! - `FeedIngester` receives the realtime feeds and keeps track of their health.
+//! - `VehicleStore` keeps the latest vehicles and enriches them with static data.
+//! - `ReservationManager` owns passenger information and seat reservations.
+//! - `Broadcaster` sends messages to the clients.
+//!
+//! `Lobby` itself is the entry point for the rest of the server. It forwards messages to the
+//! actor that handles them and answers the requests that only need the static data.
+//!
+//! Realtime feeds and reservations arrive through the cluster's event bus (see `cluster`),
+//! so that every instance of the server shows the same vehicles and seat counts.
+
+mod broadcaster;
+mod crowding;
+mod headways;
+mod history;
+mod ingester;
+mod reservations;
+mod sessions;
+mod shapes;
+mod vehicles;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::{
+    Actor, ActorFuture, Addr, AsyncContext, Context, Handler, Recipient, ResponseActFuture,
+    ResponseFuture, WrapFuture,
+};
+use chrono::Local;
+use futures::future::join;
+use tokio::time::timeout;
+use tracing::debug;
+
+use crate::cluster::Cluster;
+use crate::config::{HistorySettings, LimitSettings};
+use crate::gtfs::operator::{split_namespaced_id, Operator};
+use crate::gtfs::trafiklab::RealtimeFeed;
+use crate::messages::{
+    AllPassengerInfoRequest, BuildVehiclePositionsFeed, ClientCountRequest, Connect, Disconnect,
+    FeedStatusesRequest, LineHealth, LineHealthRequest, LineRequest, LineVehiclesRequest,
+    PassengerInfo, PassengerInfoForecast, PassengerInfoRequest, PositionUpdate, PunctualityRequest,
+    ReadinessRequest, ReloadSettings, ReserveSeat, RouteRequest, RouteShapeRequest, SendToClient,
+    TripUpdatesFeedRequest, UnreserveSeat, VehiclePassengerInfoRequest,
+    VehiclePositionsFeedRequest, VehicleRequest, VehiclesRequest, WsMessage,
+};
+use crate::protocol::client_protocol::{GeometryFormat, IdentifierKind};
+use crate::protocol::server_protocol::{
+    CrowdingForecast, ErrorOutput, ErrorType, FeedStatus, LineHealthOutput, LineOutput,
+    OperatorReadiness, PassengerInformationOutput, PunctualityOutput, ReadinessOutput,
+    RouteInformationOutput, ServerOutput, Vehicle,
+};
+use crate::store::{HistoryQuery, HistoryStore, StaticStore, Storage};
+
+use broadcaster::Broadcaster;
+use history::HistoryRecorder;
+use ingester::FeedIngester;
+use reservations::ReservationManager;
+use sessions::SessionRegistry;
+use shapes::{infer_kind, route_info_error, ShapeCache};
+use vehicles::VehicleStore;
+
+/// How long the readiness check waits for the static store before it is considered to be
+/// down.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The actors behind the lobby are started together with it and are never stopped, so
+/// sending to them can only fail if the whole system is shutting down.
+const ACTOR_RUNNING: &str = "The actors behind the lobby run as long as the lobby does";
+
+/// The format of service dates in punctuality output.
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Type alias, which is essentially an address to an actor which you can
+/// send messages to.
+pub type Socket = Recipient<WsMessage>;
+
+/// Static data for a single operator.
+struct OperatorState {
+    /// Settings for the operator.
+    operator: Operator,
+
+    /// The operator's static data.
+    store: Arc<dyn StaticStore>,
+}
+
+/// The lobby keeps track of a common/shared state between all clients.
+pub struct Lobby {
+    /// The operators in the order they were configured, mapped by name.
+    operators: Vec<(String, OperatorState)>,
+
+    /// The operator that is used for ids that are not namespaced.
+    default_operator: String,
+
+    /// Limits for what clients can request.
+    limits: LimitSettings,
+
+    /// Route shapes that have already been looked up.
+    shapes: ShapeCache,
+
+    /// Observed arrivals, if the punctuality history is enabled.
+    history: Option<Arc<dyn HistoryStore>>,
+
+    /// Settings for the punctuality history, of which the tolerances can be reloaded.
+    history_settings: HistorySettings,
+
+    sessions: Addr<SessionRegistry>,
+    ingester: Addr<FeedIngester>,
+    vehicles: Addr<VehicleStore>,
+    reservations: Addr<ReservationManager>,
+    broadcaster: Addr<Broadcaster>,
+}
+
+impl Lobby {
+    /// Creates a lobby that serves data for `operators`. The first operator is used for ids
+    /// that are not namespaced. Arrivals are recorded if `storage` has a history store.
+    ///
+    /// The actors behind the lobby are started right away, so this has to be called from
+    /// within a running actix system.
+    pub fn new(
+        storage: Storage,
+        operators: Vec<Operator>,
+        limits: LimitSettings,
+        history_settings: HistorySettings,
+        cluster: Cluster,
+    ) -> Self {
+        let default_operator = operators
+            .first()
+            .expect("The lobby needs at least one operator")
+            .name
+            .clone();
+
+        let names = operators
+            .iter()
+            .map(|operator| operator.name.clone())
+            .collect::<Vec<String>>();
+
+        let Storage {
+            static_stores,
+            reservations,
+            history,
+        } = storage;
+
+        let broadcaster = Broadcaster::new(&limits).start();
+        let reservations =
+            ReservationManager::new(reservations, cluster.clone(), broadcaster.clone()).start();
+
+        let recorder = history.as_ref().map(|history| {
+            HistoryRecorder::new(
+                &operators,
+                &static_stores,
+                history.clone(),
+                history_settings.retention_days,
+            )
+            .start()
+        });
+
+        let vehicles = VehicleStore::new(&static_stores, &operators, broadcaster.clone()).start();
+        let ingester = FeedIngester::new(
+            &names,
+            cluster.bus.subscribe(),
+            vehicles.clone(),
+            broadcaster.clone(),
+            recorder,
+        )
+        .start();
+        let sessions =
+            SessionRegistry::new(broadcaster.clone(), ingester.clone(), reservations.clone())
+                .start();
+
+        let operators = operators
+            .into_iter()
+            .map(|operator| {
+                let state = OperatorState {
+                    store: static_stores[&operator.name].clone(),
+                    operator,
+                };
+
+                (state.operator.name.clone(), state)
+            })
+            .collect();
+
+        Lobby {
+            operators,
+            default_operator,
+            limits,
+            shapes: ShapeCache::new(),
+            history,
+            history_settings,
+            sessions,
+            ingester,
+            vehicles,
+            reservations,
+            broadcaster,
+        }
+    }
+
+    /// Returns POSIX timestamp in seconds since 1970-01-01 00:00:00.
+    pub fn get_current_timestamp() -> u64 {
+        let start = std::time::SystemTime::now();
+        let since_epoch_start = start.duration_since(std::time::UNIX_EPOCH).unwrap();
+
+        since_epoch_start.as_secs()
+    }
+
+    /// Returns the names of every operator.
+    fn operator_names(&self) -> impl Iterator<Item = &String> {
+        self.operators.iter().map(|(name, _)| name)
+    }
+
+    /// Splits a (possibly) namespaced id into the state of the operator it belongs to and
+    /// the id without namespace. Ids without namespace belong to the default operator.
+    fn resolve_id<'a>(&self, id: &'a str) -> Option<(&OperatorState, &'a str)> {
+        let (operator, id) = split_namespaced_id(id, self.operator_names());
+        let operator = operator.unwrap_or(&self.default_operator);
+
+        self.operators
+            .iter()
+            .find(|(name, _)| name == operator)
+            .map(|(_, state)| (state, id))
+    }
+
+    /// Namespaces a descriptor id, so that every form of a vehicle's id shares the same
+    /// passenger information and reservations. Ids of unknown operators are kept as they are.
+    fn namespace_descriptor_id(&self, raw_descriptor_id: &str) -> String {
+        self.resolve_id(raw_descriptor_id)
+            .map(|(state, descriptor_id)| state.operator.namespace_id(descriptor_id))
+            .unwrap_or_else(|| raw_descriptor_id.to_owned())
+    }
+}
+
+impl Actor for Lobby {
+    type Context = Context<Self>;
+}
+
+impl Handler<Connect> for Lobby {
+    type Result = ();
+
+    // This method is called whenever the Lobby receives a "Connect" message.
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) {
+        self.sessions.do_send(msg);
+    }
+}
+
+impl Handler<Disconnect> for Lobby {
+    type Result = ();
+
+    // This method is called whenever the Lobby receives a "Disconnect" message.
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        self.sessions.do_send(msg);
+    }
+}
+
+impl Handler<ClientCountRequest> for Lobby {
+    type Result = ResponseFuture<usize>;
+
+    // This method is called whenever a RealtimeFetcher is about to fetch data.
+    fn handle(&mut self, msg: ClientCountRequest, _: &mut Context<Self>) -> Self::Result {
+        let request = self.sessions.send(msg);
+
+        Box::pin(async move { request.await.expect(ACTOR_RUNNING) })
+    }
+}
+
+impl Handler<ReloadSettings> for Lobby {
+    type Result = ();
+
+    // This method is called when the settings have been reloaded.
+    fn handle(&mut self, msg: ReloadSettings, _: &mut Context<Self>) {
+        self.broadcaster.do_send(msg.clone());
+
+        // Positions that clients already have sent are clamped when they send a new one.
+        self.limits = msg.0.limits;
+
+        // Arrivals are recorded the same way whatever the tolerances are, so they apply to
+        // every arrival in the history from now on. Forecasts are made for every request.
+        self.history_settings.early_tolerance = msg.0.history.early_tolerance;
+        self.history_settings.late_tolerance = msg.0.history.late_tolerance;
+        self.history_settings.forecasts = msg.0.history.forecasts;
+    }
+}
+
+impl Handler<PositionUpdate> for Lobby {
+    type Result = ();
+
+    // This method is called whenever the Lobby receives a "PositionUpdate" message.
+    fn handle(&mut self, mut msg: PositionUpdate, _: &mut Context<Self>) {
+        // Clients are not allowed to request vehicles from an arbitrarily large area.
+        msg.position.max_distance = msg.position.max_distance.min(self.limits.max_view_distance);
+
+        self.broadcaster.do_send(msg);
+    }
+}
+
+impl Lobby {
+    /// Figures out which operator an identifier belongs to and clones its name and its static
+    /// store, since "self" cannot be accessed inside async blocks.
+    fn resolve_store(
+        &self,
+        identifier: &str,
+    ) -> Result<(Operator, Arc<dyn StaticStore>, String), ErrorOutput> {
+        self.resolve_id(identifier)
+            .map(|(state, id)| (state.operator.clone(), state.store.clone(), id.to_owned()))
+            .ok_or_else(|| {
+                route_info_error(format!("'{}' belongs to an unknown operator", identifier))
+            })
+    }
+
+    /// Looks up every shape of a route for the WebSocket and REST APIs. The returned future
+    /// does not borrow the lobby, so it can be awaited inside async blocks.
+    fn route_information(
+        &self,
+        raw_identifier: String,
+        kind: Option<IdentifierKind>,
+        format: GeometryFormat,
+        zoom: Option<u8>,
+    ) -> impl Future<Output = Result<RouteInformationOutput, ErrorOutput>> {
+        let resolved = self.resolve_store(&raw_identifier);
+        let shapes = self.shapes.clone();
+        let vehicles = self.vehicles.clone();
+        let history = self.forecast_history();
+
+        async move {
+            let (operator, store, identifier) = resolved?;
+            let (kind, identifier) =
+                resolve_route_identifier(&vehicles, &operator, kind, identifier, &raw_identifier)
+                    .await?;
+
+            let route_shapes = shapes
+                .route_shapes(
+                    &operator.name,
+                    store.clone(),
+                    kind,
+                    identifier.clone(),
+                    &raw_identifier,
+                    zoom,
+                )
+                .await?;
+
+            let mut output = route_shapes.to_output(&operator, format);
+
+            // Only the stops of a single trip have times that crowding can be forecast for.
+            if let (IdentifierKind::TripId, Some(history)) = (kind, history) {
+                crowding::add_trip_forecasts(&operator, store, &*history, &identifier, &mut output)
+                    .await;
+            }
+
+            Ok(output)
+        }
+    }
+
+    /// The punctuality history, if crowding forecasts are made from it.
+    fn forecast_history(&self) -> Option<Arc<dyn HistoryStore>> {
+        self.history
+            .clone()
+            .filter(|_| self.history_settings.forecasts)
+    }
+
+    /// Forecasts how crowded a vehicle will be at its next stop, if crowding forecasts are
+    /// enabled. The returned future does not borrow the lobby, so it can be awaited inside
+    /// async blocks.
+    fn vehicle_forecast(
+        &self,
+        raw_descriptor_id: &str,
+    ) -> impl Future<Output = Option<CrowdingForecast>> {
+        let resolved = self
+            .resolve_id(raw_descriptor_id)
+            .map(|(state, descriptor_id)| {
+                (
+                    state.operator.clone(),
+                    state.store.clone(),
+                    descriptor_id.to_owned(),
+                )
+            });
+        let history = self.forecast_history();
+        let shapes = self.shapes.clone();
+        let vehicles = self.vehicles.clone();
+
+        async move {
+            let history = history?;
+            let (operator, store, descriptor_id) = resolved?;
+
+            let vehicle = vehicles
+                .send(VehicleRequest {
+                    operator: operator.name.clone(),
+                    descriptor_id,
+                })
+                .await
+                .expect(ACTOR_RUNNING)?;
+
+            crowding::vehicle_forecast(&operator, store, &shapes, &*history, &vehicle).await
+        }
+    }
+
+    /// Measures the headways between the vehicles on a line for the WebSocket and REST APIs.
+    /// The returned future does not borrow the lobby, so it can be awaited inside async blocks.
+    fn line_health(
+        &self,
+        raw_line: String,
+    ) -> impl Future<Output = Result<LineHealthOutput, ErrorOutput>> {
+        let resolved = self
+            .resolve_id(&raw_line)
+            .map(|(state, line)| (state.operator.clone(), state.store.clone(), line.to_owned()));
+        let shapes = self.shapes.clone();
+        let vehicles = self.vehicles.clone();
+
+        async move {
+            let (operator, store, line) = resolved.ok_or_else(|| {
+                line_info_error(format!("'{}' belongs to an unknown operator", raw_line))
+            })?;
+
+            let route = store.route_by_short_name(&line).await.ok_or_else(|| {
+                line_info_error(format!("'{}' is not a valid line number", raw_line))
+            })?;
+
+            let vehicles = vehicles
+                .send(LineVehiclesRequest {
+                    operator: operator.name.clone(),
+                    line: line.clone(),
+                })
+                .await
+                .expect(ACTOR_RUNNING);
+
+            let (placed, untracked) =
+                headways::place_vehicles(&operator.name, store, &shapes, &vehicles).await?;
+
+            Ok(LineHealthOutput {
+                timestamp: Lobby::get_current_timestamp(),
+                line: operator.namespace_id(&line),
+                route_id: operator.namespace_id(&route.route_id),
+                directions: headways::direction_health(placed),
+                untracked,
+            })
+        }
+    }
+}
+
+impl Lobby {
+    /// Figures out which operator a punctuality request is for and what to look up in the
+    /// history. The line and the stop decide the operator if they are namespaced, and every
+    /// operator that is given must be the same one.
+    fn history_query(
+        &self,
+        msg: &PunctualityRequest,
+    ) -> Result<(Operator, HistoryQuery), ErrorOutput> {
+        let split = |id| split_namespaced_id(id, self.operator_names());
+        let line = msg.line.as_deref().map(split);
+        let stop = msg.stop_id.as_deref().map(split);
+
+        let mut names = [
+            msg.operator.as_deref(),
+            line.and_then(|(operator, _)| operator),
+            stop.and_then(|(operator, _)| operator),
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<Vec<_>>();
+        names.dedup();
+
+        let name = match names.as_slice() {
+            [] => self.default_operator.as_str(),
+            [name] => name,
+            [first, second, ..] => {
+                return Err(bad_data(format!(
+                    "'{}' and '{}' are different operators",
+                    first, second
+                )))
+            }
+        };
+
+        let (_, state) = self
+            .operators
+            .iter()
+            .find(|(operator, _)| operator == name)
+            .ok_or_else(|| bad_data(format!("'{}' is not a known operator", name)))?;
+
+        let query = HistoryQuery {
+            operator: name.to_owned(),
+            line: line.map(|(_, line)| line.to_owned()),
+            stop_id: stop.map(|(_, stop_id)| stop_id.to_owned()),
+            from: msg.from,
+            to: msg.to,
+        };
+
+        Ok((state.operator.clone(), query))
+    }
+}
+
+/// Creates an error about a request that does not make sense.
+fn bad_data(error_message: String) -> ErrorOutput {
+    ErrorOutput {
+        error_type: ErrorType::BadData,
+        error_message,
+    }
+}
+
+/// Creates an error about a line that could not be looked up.
+fn line_info_error(error_message: String) -> ErrorOutput {
+    ErrorOutput {
+        error_type: ErrorType::LineInfo,
+        error_message,
+    }
+}
+
+/// Figures out what kind of identifier a route is looked up by, where `identifier` is not
+/// namespaced. Descriptor ids are replaced by the trip that the vehicle is currently on.
+async fn resolve_route_identifier(
+    vehicles: &Addr<VehicleStore>,
+    operator: &Operator,
+    kind: Option<IdentifierKind>,
+    identifier: String,
+    raw_identifier: &str,
+) -> Result<(IdentifierKind, String), ErrorOutput> {
+    if identifier.is_empty() {
+        return Err(route_info_error(format!(
+            "'{}' is not a valid identifier",
+            raw_identifier
+        )));
+    }
+
+    let kind = kind.or_else(|| infer_kind(&identifier)).ok_or_else(|| {
+        route_info_error(format!(
+            "'{}' could be any kind of identifier, set \"kind\" to tell which",
+            raw_identifier
+        ))
+    })?;
+
+    if kind != IdentifierKind::DescriptorId {
+        return Ok((kind, identifier));
+    }
+
+    let vehicle = vehicles
+        .send(VehicleRequest {
+            operator: operator.name.clone(),
+            descriptor_id: identifier,
+        })
+        .await
+        .expect(ACTOR_RUNNING)
+        .ok_or_else(|| {
+            route_info_error(format!("'{}' is not a vehicle in traffic", raw_identifier))
+        })?;
+
+    let trip_id = vehicle.plain_trip_id().ok_or_else(|| {
+        route_info_error(format!("The vehicle '{}' is not on a trip", raw_identifier))
+    })?;
+
+    Ok((IdentifierKind::TripId, trip_id.to_owned()))
+}
+
+impl Handler<RouteRequest> for Lobby {
+    type Result = ResponseActFuture<Self, ()>;
+
+    // This method is called whenever the Lobby receives a "RouteRequest" message.
+    fn handle(&mut self, msg: RouteRequest, _: &mut Context<Self>) -> Self::Result {
+        debug!(
+            client_id = %msg.self_id,
+            identifier = %msg.identifier,
+            "Client requested route information."
+        );
+
+        // Important to clone this value so it will be accessible inside the async block in the pinned box.
+        let client_id = msg.self_id;
+
+        let route_information =
+            self.route_information(msg.identifier, msg.kind, msg.format, msg.zoom);
+
+        Box::pin(
+            async move {
+                // Create the serialized json message that will be sent back to the client.
+                match route_information.await {
+                    Ok(output) => {
+                        serde_json::to_string(&ServerOutput::RouteInformation(output)).unwrap()
+                    }
+                    Err(err) => ServerOutput::error_message(err.error_type, err.error_message),
+                }
+            }
+            // Converts future to ActorFuture
+            .into_actor(self)
+            // message is the value that is returned from the async block above, act is a mutable reference to "self" (the lobby)
+            // and ctx is a mutable referenced context with an actor handle to the lobby.
+            .map(move |message, act, _ctx| {
+                // Send the data back to the client.
+                // We don't need to check if the client is still connected here since the broadcaster checks this.
+                act.broadcaster.do_send(SendToClient { client_id, message });
+            }),
+        )
+    }
+}
+
+impl Handler<LineHealth> for Lobby {
+    type Result = ResponseActFuture<Self, ()>;
+
+    // This method is called whenever the Lobby receives a "LineHealth" message.
+    fn handle(&mut self, msg: LineHealth, _: &mut Context<Self>) -> Self::Result {
+        debug!(client_id = %msg.self_id, line = %msg.line, "Client requested line health.");
+
+        let client_id = msg.self_id;
+        let line_health = self.line_health(msg.line);
+
+        Box::pin(
+            async move {
+                match line_health.await {
+                    Ok(output) => serde_json::to_string(&ServerOutput::LineHealth(output)).unwrap(),
+                    Err(err) => ServerOutput::error_message(err.error_type, err.error_message),
+                }
+            }
+            .into_actor(self)
+            .map(move |message, act, _ctx| {
+                act.broadcaster.do_send(SendToClient { client_id, message });
+            }),
+        )
+    }
+}
+
+impl Handler<PassengerInfo> for Lobby {
+    type Result = ();
+
+    // This method is called whenever the Lobby receives a "PassengerInfo" message.
+    fn handle(&mut self, msg: PassengerInfo, ctx: &mut Context<Self>) -> Self::Result {
+        let self_id = msg.self_id;
+        let descriptor_id = self.namespace_descriptor_id(&msg.descriptor_id);
+        let forecast = self.vehicle_forecast(&descriptor_id);
+
+        // The request is passed on right away, so that it is answered before any seat that the
+        // client reserves afterwards. The forecast follows in an answer of its own.
+        self.reservations.do_send(PassengerInfo {
+            self_id,
+            descriptor_id: descriptor_id.clone(),
+        });
+
+        ctx.spawn(forecast.into_actor(self).map(move |forecast, act, _| {
+            if let Some(forecast) = forecast {
+                act.reservations.do_send(PassengerInfoForecast {
+                    self_id,
+                    descriptor_id,
+                    forecast,
+                });
+            }
+        }));
+    }
+}
+
+impl Handler<ReserveSeat> for Lobby {
+    type Result = ();
+
+    // This method is called whenever the Lobby receives a "ReserveSeat" message.
+    fn handle(&mut self, msg: ReserveSeat, _: &mut Context<Self>) {
+        self.reservations.do_send(ReserveSeat {
+            descriptor_id: self.namespace_descriptor_id(&msg.descriptor_id),
+            ..msg
+        });
+    }
+}
+
+impl Handler<UnreserveSeat> for Lobby {
+    type Result = ();
+
+    // This method is called whenever the Lobby receives a "UnreserveSeat" message.
+    fn handle(&mut self, msg: UnreserveSeat, _: &mut Context<Self>) {
+        self.reservations.do_send(msg);
+    }
+}
+
+impl Handler<VehiclesRequest> for Lobby {
+    type Result = ResponseFuture<Vec<Vehicle>>;
+
+    // This method is called whenever the REST API requests vehicles.
+    fn handle(&mut self, msg: VehiclesRequest, _: &mut Context<Self>) -> Self::Result {
+        let request = self.vehicles.send(msg);
+
+        Box::pin(async move { request.await.expect(ACTOR_RUNNING) })
+    }
+}
+
+impl Handler<VehiclePositionsFeedRequest> for Lobby {
+    type Result = ResponseFuture<RealtimeFeed>;
+
+    // This method is called whenever the VehiclePositions feed is requested.
+    fn handle(&mut self, _: VehiclePositionsFeedRequest, _: &mut Context<Self>) -> Self::Result {
+        let passenger_info = self.reservations.send(AllPassengerInfoRequest);
+        let vehicles = self.vehicles.clone();
+
+        Box::pin(async move {
+            let passenger_info = passenger_info.await.expect(ACTOR_RUNNING);
+
+            vehicles
+                .send(BuildVehiclePositionsFeed { passenger_info })
+                .await
+                .expect(ACTOR_RUNNING)
+        })
+    }
+}
+
+impl Handler<TripUpdatesFeedRequest> for Lobby {
+    type Result = ResponseFuture<RealtimeFeed>;
+
+    // This method is called whenever the TripUpdates feed is requested.
+    fn handle(&mut self, msg: TripUpdatesFeedRequest, _: &mut Context<Self>) -> Self::Result {
+        let request = self.vehicles.send(msg);
+
+        Box::pin(async move { request.await.expect(ACTOR_RUNNING) })
+    }
+}
+
+impl Handler<LineRequest> for Lobby {
+    type Result = ResponseActFuture<Self, Result<LineOutput, ErrorOutput>>;
+
+    // This method is called whenever the REST API requests information about a line.
+    fn handle(&mut self, msg: LineRequest, _: &mut Context<Self>) -> Self::Result {
+        let resolved = self
+            .resolve_id(&msg.line)
+            .map(|(state, line)| (state.operator.clone(), state.store.clone(), line.to_owned()));
+
+        let vehicles = self.vehicles.clone();
+
+        Box::pin(
+            async move {
+                let (operator, store, line) = resolved.ok_or_else(|| {
+                    line_info_error(format!("'{}' belongs to an unknown operator", &msg.line))
+                })?;
+
+                let route = store.route_by_short_name(&line).await.ok_or_else(|| {
+                    line_info_error(format!("'{}' is not a valid line number", &msg.line))
+                })?;
+
+                let vehicles = vehicles
+                    .send(LineVehiclesRequest {
+                        operator: operator.name.clone(),
+                        line: line.clone(),
+                    })
+                    .await
+                    .expect(ACTOR_RUNNING);
+
+                Ok(LineOutput {
+                    timestamp: Lobby::get_current_timestamp(),
+                    line: operator.namespace_id(&line),
+                    route_id: operator.namespace_id(&route.route_id),
+                    name: route.route_long_name,
+                    description: route.route_desc,
+                    vehicles,
+                })
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+impl Handler<LineHealthRequest> for Lobby {
+    type Result = ResponseActFuture<Self, Result<LineHealthOutput, ErrorOutput>>;
+
+    // This method is called whenever the REST API requests the headways on a line.
+    fn handle(&mut self, msg: LineHealthRequest, _: &mut Context<Self>) -> Self::Result {
+        Box::pin(self.line_health(msg.line).into_actor(self))
+    }
+}
+
+impl Handler<PunctualityRequest> for Lobby {
+    type Result = ResponseActFuture<Self, Result<PunctualityOutput, ErrorOutput>>;
+
+    // This method is called whenever the REST API requests the on-time performance of arrivals.
+    fn handle(&mut self, msg: PunctualityRequest, _: &mut Context<Self>) -> Self::Result {
+        let resolved = self.history_query(&msg);
+        let history = self.history.clone();
+        let settings = self.history_settings.clone();
+
+        Box::pin(
+            async move {
+                let (operator, query) = resolved?;
+
+                let history = history.ok_or_else(|| ErrorOutput {
+                    error_type: ErrorType::ServerError,
+                    error_message: "The punctuality history is not enabled".to_owned(),
+                })?;
+
+                let arrivals =
+                    history
+                        .arrivals(query.clone())
+                        .await
+                        .ok_or_else(|| ErrorOutput {
+                            error_type: ErrorType::ServerError,
+                            error_message: "Unable to look up arrivals".to_owned(),
+                        })?;
+
+                let (total, groups) =
+                    history::punctuality(&operator, &arrivals, msg.group_by, &settings, &Local);
+
+                Ok(PunctualityOutput {
+                    timestamp: Lobby::get_current_timestamp(),
+                    line: query.line.map(|line| operator.namespace_id(&line)),
+                    stop_id: query.stop_id.map(|stop_id| operator.namespace_id(&stop_id)),
+                    operator: operator.name,
+                    from: query.from.format(DATE_FORMAT).to_string(),
+                    to: query.to.format(DATE_FORMAT).to_string(),
+                    early_tolerance: settings.early_tolerance,
+                    late_tolerance: settings.late_tolerance,
+                    total,
+                    group_by: msg.group_by,
+                    groups,
+                })
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+impl Handler<RouteShapeRequest> for Lobby {
+    type Result = ResponseActFuture<Self, Result<RouteInformationOutput, ErrorOutput>>;
+
+    // This method is called whenever the REST API requests the shape of a route.
+    fn handle(&mut self, msg: RouteShapeRequest, _: &mut Context<Self>) -> Self::Result {
+        Box::pin(
+            self.route_information(msg.identifier, msg.kind, msg.format, msg.zoom)
+                .into_actor(self),
+        )
+    }
+}
+
+impl Handler<PassengerInfoRequest> for Lobby {
+    type Result = ResponseFuture<Result<PassengerInformationOutput, ErrorOutput>>;
+
+    // This method is called whenever the REST API requests passenger information. Only
+    // vehicles that are in traffic have passenger information.
+    fn handle(&mut self, msg: PassengerInfoRequest, _: &mut Context<Self>) -> Self::Result {
+        let resolved = self
+            .resolve_id(&msg.descriptor_id)
+            .map(|(state, descriptor_id)| (state.operator.name.clone(), descriptor_id.to_owned()));
+        let namespaced_id = self.namespace_descriptor_id(&msg.descriptor_id);
+        let forecast = self.vehicle_forecast(&msg.descriptor_id);
+        let vehicles = self.vehicles.clone();
+        let reservations = self.reservations.clone();
+
+        Box::pin(async move {
+            let not_found = || ErrorOutput {
+                error_type: ErrorType::PassengerInfo,
+                error_message: format!("'{}' is not a vehicle in traffic", msg.descriptor_id),
+            };
+
+            let (operator, descriptor_id) = resolved.ok_or_else(not_found)?;
+
+            vehicles
+                .send(VehicleRequest {
+                    operator,
+                    descriptor_id,
+                })
+                .await
+                .expect(ACTOR_RUNNING)
+                .ok_or_else(not_found)?;
+
+            let request = reservations.send(VehiclePassengerInfoRequest {
+                descriptor_id: namespaced_id,
+            });
+            let (passenger_info, forecast) = join(request, forecast).await;
+
+            Ok(PassengerInformationOutput {
+                forecast,
+                ..passenger_info.expect(ACTOR_RUNNING)
+            })
+        })
+    }
+}
+
+impl Handler<ReadinessRequest> for Lobby {
+    type Result = ResponseActFuture<Self, ReadinessOutput>;
+
+    // This method is called whenever the readiness endpoint is requested.
+    fn handle(&mut self, _: ReadinessRequest, _: &mut Context<Self>) -> Self::Result {
+        let operators = self
+            .operators
+            .iter()
+            .map(|(name, state)| (name.clone(), state.store.clone()))
+            .collect::<Vec<_>>();
+
+        let feed_statuses = self.ingester.send(FeedStatusesRequest);
+        let clients = self.sessions.send(ClientCountRequest);
+
+        Box::pin(
+            async move {
+                let feed_statuses = feed_statuses
+                    .await
+                    .expect(ACTOR_RUNNING)
+                    .into_iter()
+                    .collect::<HashMap<String, FeedStatus>>();
+
+                // Feeds are not fetched while no clients are connected, so they are only
+                // expected to be fresh once someone is.
+                let idle = clients.await.expect(ACTOR_RUNNING) == 0;
+
+                // Every operator's store uses the same backend, so any of them can be pinged.
+                let database = match operators.first() {
+                    Some((_, store)) => timeout(READINESS_TIMEOUT, store.ping())
+                        .await
+                        .unwrap_or(false),
+                    None => false,
+                };
+
+                let mut readiness = Vec::new();
+
+                for (operator, store) in operators {
+                    let static_data = database
+                        && timeout(READINESS_TIMEOUT, store.has_static_data())
+                            .await
+                            .unwrap_or(false);
+
+                    let feed_status = feed_statuses
+                        .get(&operator)
+                        .copied()
+                        .unwrap_or(FeedStatus::Stale);
+
+                    readiness.push(OperatorReadiness {
+                        operator,
+                        feed_status,
+                        static_data,
+                    });
+                }
+
+                ReadinessOutput {
+                    ready: database
+                        && readiness.iter().all(|operator| {
+                            operator.static_data
+                                && (idle || operator.feed_status != FeedStatus::Stale)
+                        }),
+                    database,
+                    operators: readiness,
+                }
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use chrono::TimeDelta;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::client::tests::TestClient;
+    use crate::cluster::ClusterEvent;
+    use crate::config::StorageSettings;
+    use crate::database::init_db_connection;
+    use crate::gtfs::trafiklab::RealtimeFeed;
+    use crate::gtfs::transit_realtime::{
+        FeedEntity, FeedMessage, Position, TripDescriptor, VehicleDescriptor, VehiclePosition,
+    };
+    use crate::gtfs::transit_static::{Route, Shape, Trip};
+    use crate::protocol::server_protocol::PunctualityGrouping;
+    use crate::store::{
+        arrival, MemoryHistory, MemoryReservations, MemoryStaticStore, ObservedArrival,
+        StaticTables,
+    };
+
+    /// Waits for a message of type `message_type`, skipping any other messages.
+    async fn wait_for(messages: &mut UnboundedReceiver<String>, message_type: &str) -> String {
+        let tag = format!("\"type\":\"{}\"", message_type);
+
+        timeout(Duration::from_secs(1), async {
+            loop {
+                let message = messages.recv().await.expect("the client is still running");
+
+                if message.contains(&tag) {
+                    return message;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Timed out waiting for a {} message", message_type))
+    }
+
+    /// A feed with a single vehicle on a trip, which has to be looked up in the database.
+    fn feed_with_vehicle_on_trip() -> RealtimeFeed {
+        let mut message = FeedMessage::default();
+        message.entity.push(FeedEntity {
+            id: Cow::Borrowed("1"),
+            vehicle: Some(VehiclePosition {
+                trip: Some(TripDescriptor {
+                    trip_id: Some(Cow::Borrowed("141010000123456789")),
+                    ..TripDescriptor::default()
+                }),
+                vehicle: Some(VehicleDescriptor {
+                    id: Some(Cow::Borrowed("9031003")),
+                    ..VehicleDescriptor::default()
+                }),
+                position: Some(Position {
+                    latitude: 59.85,
+                    longitude: 17.63,
+                    ..Position::default()
+                }),
+                ..VehiclePosition::default()
+            }),
+            ..FeedEntity::default()
+        });
+
+        RealtimeFeed::from_message(&message)
+    }
+
+    /// Storage for UL in a MongoDB database that never answers.
+    async fn stuck_storage(operators: &[Operator]) -> Storage {
+        // Nothing listens on this port, so every query waits until server selection times
+        // out, long after the tests have finished.
+        let db_connection =
+            init_db_connection("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=60000")
+                .await
+                .unwrap();
+
+        Storage::from_settings(
+            &StorageSettings::default(),
+            &HistorySettings::default(),
+            operators,
+            Some(&db_connection),
+        )
+        .unwrap()
+    }
+
+    /// Starts a lobby for UL that shares state with other lobbies through `cluster`. Its
+    /// static data is stuck unless `storage` is given.
+    async fn start_lobby(cluster: Cluster, storage: Option<Storage>) -> Addr<Lobby> {
+        let operators = vec![Operator::new("ul", "realtime", "static")];
+
+        let storage = match storage {
+            Some(storage) => storage,
+            None => stuck_storage(&operators).await,
+        };
+
+        Lobby::new(
+            storage,
+            operators,
+            LimitSettings::default(),
+            HistorySettings::default(),
+            cluster,
+        )
+        .start()
+    }
+
+    /// Connects a client to the lobby and returns its id and the messages it receives.
+    async fn connect(lobby: &Addr<Lobby>) -> (Uuid, UnboundedReceiver<String>) {
+        let (sender, mut messages) = unbounded_channel();
+        let client_id = Uuid::new_v4();
+
+        let client = TestClient(sender).start();
+
+        lobby.do_send(Connect {
+            addr: client.clone().recipient(),
+            close: client.recipient(),
+            self_id: client_id,
+        });
+
+        // The client is told about the feed status as soon as it connects.
+        wait_for(&mut messages, "feed-status").await;
+
+        (client_id, messages)
+    }
+
+    #[actix_rt::test]
+    async fn test_independent_progress() {
+        let cluster = Cluster::local("test");
+        let lobby = start_lobby(cluster.clone(), None).await;
+        let (client_id, mut messages) = connect(&lobby).await;
+
+        // Both of these are stuck waiting for the database...
+        cluster.bus.publish(ClusterEvent::RealtimeFeed {
+            operator: "ul".to_owned(),
+            result: Ok(feed_with_vehicle_on_trip()),
+        });
+        lobby.do_send(RouteRequest {
+            self_id: client_id,
+            identifier: "5".to_owned(),
+            kind: None,
+            format: GeometryFormat::Geojson,
+            zoom: None,
+        });
+
+        // ...but the feed is still ingested, since its status changes from stale to ok...
+        let status = wait_for(&mut messages, "feed-status").await;
+        assert!(status.contains("\"status\":\"ok\""));
+
+        // ...and reservations are handled as usual.
+        lobby.do_send(PassengerInfo {
+            self_id: client_id,
+            descriptor_id: "ul:9031003".to_owned(),
+        });
+        wait_for(&mut messages, "passenger-info").await;
+
+        lobby.do_send(UnreserveSeat { self_id: client_id });
+        let error = wait_for(&mut messages, "error").await;
+        assert!(error.contains("no active reservation"));
+
+        // The REST API is answered with the vehicles from before the enrichment started.
+        let vehicles = timeout(
+            Duration::from_secs(1),
+            lobby.send(VehiclesRequest { bbox: None }),
+        )
+        .await
+        .expect("vehicles are served while the enrichment is running")
+        .unwrap();
+        assert!(vehicles.is_empty());
+
+        // The vehicle is not in traffic until its enrichment has finished.
+        let passenger_info = timeout(
+            Duration::from_secs(1),
+            lobby.send(PassengerInfoRequest {
+                descriptor_id: "ul:9031003".to_owned(),
+            }),
+        )
+        .await
+        .expect("passenger information is served while the enrichment is running")
+        .unwrap();
+        assert_eq!(
+            passenger_info.unwrap_err().error_type,
+            ErrorType::PassengerInfo
+        );
+
+        lobby.do_send(Disconnect { self_id: client_id });
+    }
+
+    #[actix_rt::test]
+    async fn test_reservations_are_shared_between_instances() {
+        // Two instances that share the same event bus and the same reservation store, like
+        // instances that use the same MongoDB database.
+        let first_cluster = Cluster::local("first");
+        let second_cluster = Cluster {
+            instance_id: "second".to_owned(),
+            ..first_cluster.clone()
+        };
+
+        let operators = vec![Operator::new("ul", "realtime", "static")];
+        let reservations = MemoryReservations::default();
+
+        let mut first_storage = stuck_storage(&operators).await;
+        first_storage.reservations = Box::new(reservations.clone());
+        let mut second_storage = stuck_storage(&operators).await;
+        second_storage.reservations = Box::new(reservations);
+
+        let first = start_lobby(first_cluster, Some(first_storage)).await;
+        let second = start_lobby(second_cluster, Some(second_storage)).await;
+
+        let (first_client, mut first_messages) = connect(&first).await;
+        let (second_client, mut second_messages) = connect(&second).await;
+
+        let descriptor_id = "ul:9031003".to_owned();
+        let passenger_count = |message: &str| {
+            let output: serde_json::Value = serde_json::from_str(message).unwrap();
+            output["payload"]["passengers"].as_i64().unwrap()
+        };
+
+        // Both clients see the same passenger information, even though the second one leaves
+        // out the namespace of the default operator...
+        first.do_send(PassengerInfo {
+            self_id: first_client,
+            descriptor_id: descriptor_id.clone(),
+        });
+        let passengers = passenger_count(&wait_for(&mut first_messages, "passenger-info").await);
+
+        second.do_send(PassengerInfo {
+            self_id: second_client,
+            descriptor_id: "9031003".to_owned(),
+        });
+        let message = wait_for(&mut second_messages, "passenger-info").await;
+        assert_eq!(passenger_count(&message), passengers);
+
+        // ...and a reservation on one instance reaches the clients on the other one.
+        second.do_send(ReserveSeat {
+            self_id: second_client,
+            descriptor_id: "9031003".to_owned(),
+        });
+
+        let message = wait_for(&mut first_messages, "passenger-info").await;
+        assert_eq!(passenger_count(&message), passengers + 1);
+        let message = wait_for(&mut second_messages, "passenger-info").await;
+        assert_eq!(passenger_count(&message), passengers + 1);
+
+        // Only the client that made a request is told about errors.
+        first.do_send(UnreserveSeat {
+            self_id: first_client,
+        });
+        wait_for(&mut first_messages, "error").await;
+
+        second.do_send(UnreserveSeat {
+            self_id: second_client,
+        });
+        let message = wait_for(&mut second_messages, "passenger-info").await;
+        assert_eq!(passenger_count(&message), passengers);
+        assert!(second_messages.try_recv().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_static_data_in_memory() {
+        let route = Route {
+            route_id: "9011003000500000".to_owned(),
+            agency_id: "1".to_owned(),
+            route_short_name: "5".to_owned(),
+            route_long_name: Some("Stenhagen - Gottsunda".to_owned()),
+            route_type: "700".to_owned(),
+            route_desc: None,
+        };
+        let trip = Trip {
+            route_id: route.route_id.clone(),
+            service_id: "1".to_owned(),
+            trip_id: "141010000123456789".to_owned(),
+            trip_headsign: Some("Gottsunda".to_owned()),
+            direction_id: "0".to_owned(),
+            shape_id: "1".to_owned(),
+        };
+        let shapes = [("1", "59.858"), ("2", "59.859")]
+            .iter()
+            .map(|(sequence, lat)| Shape {
+                shape_id: "1".to_owned(),
+                shape_pt_lat: lat.to_string(),
+                shape_pt_lon: "17.638".to_owned(),
+                shape_pt_sequence: sequence.to_string(),
+                shape_dist_traveled: None,
+            })
+            .collect();
+
+        let store = MemoryStaticStore::new(StaticTables {
+            routes: vec![route],
+            trips: vec![trip],
+            shapes,
+            ..StaticTables::default()
+        });
+
+        let mut static_stores: HashMap<String, Arc<dyn StaticStore>> = HashMap::new();
+        static_stores.insert("ul".to_owned(), Arc::new(store));
+
+        let storage = Storage {
+            static_stores,
+            reservations: Box::new(MemoryReservations::default()),
+            history: None,
+        };
+
+        let lobby = start_lobby(Cluster::local("test"), Some(storage)).await;
+        let (client_id, mut messages) = connect(&lobby).await;
+
+        // The whole server works without a database.
+        lobby.do_send(RouteRequest {
+            self_id: client_id,
+            identifier: "5".to_owned(),
+            kind: None,
+            format: GeometryFormat::Polyline,
+            zoom: None,
+        });
+
+        let message = wait_for(&mut messages, "route-info").await;
+        let output: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(output["payload"]["directions"][0]["headsign"], "Gottsunda");
+
+        let line = lobby
+            .send(LineRequest {
+                line: "5".to_owned(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.route_id, "ul:9011003000500000");
+
+        let readiness = lobby.send(ReadinessRequest).await.unwrap();
+        assert!(readiness.database);
+        assert!(readiness.operators[0].static_data);
+    }
+
+    #[actix_rt::test]
+    async fn test_punctuality() {
+        let today = Local::now().date_naive();
+
+        // Recorded arrivals are pruned when the lobby starts, unless they are recent.
+        let history = MemoryHistory::default();
+        let arrivals = [
+            arrival(1, 1_620_633_720 - 30),
+            arrival(2, 1_620_633_720 + 600),
+        ]
+        .iter()
+        .cloned()
+        .map(|arrival| ObservedArrival {
+            service_date: today,
+            ..arrival
+        })
+        .collect();
+        assert!(history.record(arrivals).await);
+
+        let mut static_stores: HashMap<String, Arc<dyn StaticStore>> = HashMap::new();
+        static_stores.insert(
+            "ul".to_owned(),
+            Arc::new(MemoryStaticStore::new(StaticTables::default())),
+        );
+
+        let storage = Storage {
+            static_stores,
+            reservations: Box::new(MemoryReservations::default()),
+            history: Some(Arc::new(history)),
+        };
+
+        let lobby = start_lobby(Cluster::local("test"), Some(storage)).await;
+
+        let request = |operator: Option<&str>, line: &str| PunctualityRequest {
+            operator: operator.map(str::to_owned),
+            line: Some(line.to_owned()),
+            stop_id: None,
+            from: today - TimeDelta::days(6),
+            to: today,
+            group_by: Some(PunctualityGrouping::Line),
+        };
+
+        let output = lobby.send(request(None, "ul:5")).await.unwrap().unwrap();
+        assert_eq!(output.operator, "ul");
+        assert_eq!(output.line.as_deref(), Some("ul:5"));
+        assert_eq!((output.total.on_time, output.total.late), (1, 1));
+        assert_eq!(output.groups[0].key, "ul:5");
+
+        let output = lobby.send(request(Some("ul"), "7")).await.unwrap().unwrap();
+        assert_eq!(output.total.arrivals, 0);
+
+        let err = lobby
+            .send(request(Some("sl"), "ul:5"))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.error_type, ErrorType::BadData);
+    }
+}
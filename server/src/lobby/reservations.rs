@@ -0,0 +1,399 @@
+//! Passenger information and seat reservations.
+//!
+//! Reservations are kept in a reservation store, which every instance shares when several of
+//! them are running (see `cluster`). Seats are taken and given up with atomic updates in the
+//! store, so a seat can never be reserved twice because of a race between two instances.
+//! Every change is then published on the event bus, so that every instance sends the new
+//! passenger information to its clients.
+//!
+//! Requests are handled one at a time, in the order they arrive, so that a client that asks
+//! about a vehicle and then reserves a seat on it always has its requests applied in that
+//! order.
+
+use std::collections::HashMap;
+
+use actix::prelude::{
+    Actor, ActorFuture, Addr, AsyncContext, Context, Handler, ResponseFuture, StreamHandler,
+    WrapFuture,
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::cluster::{Cluster, ClusterEvent, EventStream};
+use crate::lobby::broadcaster::Broadcaster;
+use crate::messages::{
+    AllPassengerInfoRequest, ClientDisconnected, PassengerInfo, PassengerInfoForecast, ReserveSeat,
+    SendToClient, SendToWatchers, UnreserveSeat, VehiclePassengerInfoRequest, WatchVehicle,
+};
+use crate::metrics::{ACTIVE_RESERVATIONS, RESERVATIONS};
+use crate::protocol::server_protocol::{
+    ErrorType, PassengerInformationOutput, ServerOutput, VehiclePassengerInformationOutput,
+};
+use crate::store::{ReservationStore, ReserveError};
+
+/// Keeps track of how many passengers there are on every vehicle and which clients have
+/// reserved a seat.
+pub struct ReservationManager {
+    /// Passenger information for every vehicle and the seats that clients have reserved.
+    store: Box<dyn ReservationStore>,
+
+    cluster: Cluster,
+
+    /// The events from the event bus, until the manager has started reading them.
+    events: Option<EventStream>,
+
+    broadcaster: Addr<Broadcaster>,
+}
+
+impl ReservationManager {
+    pub fn new(
+        store: Box<dyn ReservationStore>,
+        cluster: Cluster,
+        broadcaster: Addr<Broadcaster>,
+    ) -> Self {
+        ReservationManager {
+            store,
+            events: Some(cluster.bus.subscribe()),
+            cluster,
+            broadcaster,
+        }
+    }
+
+    /// Sends a message to a specific client.
+    fn send_message(&self, message: String, client_id: Uuid) {
+        self.broadcaster
+            .do_send(SendToClient { client_id, message });
+    }
+
+    /// Sends an error message to a client.
+    fn send_error(&self, client_id: Uuid, error_type: ErrorType, error_message: String) {
+        self.send_message(
+            ServerOutput::error_message(error_type, error_message),
+            client_id,
+        );
+    }
+
+    /// Tells every instance that the passenger information of a vehicle has changed.
+    fn publish_change(&self, descriptor_id: String) {
+        self.cluster
+            .bus
+            .publish(ClusterEvent::PassengerInfoChanged { descriptor_id });
+    }
+
+    /// Counts the seats that are reserved on every instance.
+    fn update_active_reservations(&self, ctx: &mut Context<Self>) {
+        ctx.spawn(
+            self.store
+                .reservation_count()
+                .into_actor(self)
+                .map(|count, _, _| {
+                    ACTIVE_RESERVATIONS.set(count as i64);
+                }),
+        );
+    }
+}
+
+impl Actor for ReservationManager {
+    type Context = Context<Self>;
+
+    // This method is called when the manager is started.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(events) = self.events.take() {
+            ctx.add_stream(events);
+        }
+
+        // Other instances may have reserved seats before this one started.
+        self.update_active_reservations(ctx);
+    }
+}
+
+impl Handler<PassengerInfo> for ReservationManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: PassengerInfo, ctx: &mut Context<Self>) {
+        debug!(
+            client_id = %msg.self_id,
+            descriptor_id = %msg.descriptor_id,
+            "Client requested passenger information."
+        );
+
+        // Update the clients last descriptor, so that they get updates about the bus.
+        self.broadcaster.do_send(WatchVehicle {
+            client_id: msg.self_id,
+            descriptor_id: msg.descriptor_id.clone(),
+        });
+
+        // Passenger information is made up the first time a vehicle is asked about.
+        let passenger_info = self.store.create_passenger_info(
+            &msg.descriptor_id,
+            made_up_passenger_info(&msg.descriptor_id),
+        );
+
+        ctx.wait(
+            passenger_info.into_actor(self).map(
+                move |passenger_info, act, _| match passenger_info {
+                    Some(passenger_info) => act.send_message(
+                        serde_json::to_string(&ServerOutput::PassengerInformation(
+                            VehiclePassengerInformationOutput {
+                                descriptor_id: msg.descriptor_id,
+                                passenger_info,
+                            },
+                        ))
+                        .unwrap(),
+                        msg.self_id,
+                    ),
+                    None => act.send_error(
+                        msg.self_id,
+                        ErrorType::ServerError,
+                        format!(
+                            "Could not look up passenger information for '{}'",
+                            msg.descriptor_id
+                        ),
+                    ),
+                },
+            ),
+        );
+    }
+}
+
+impl Handler<PassengerInfoForecast> for ReservationManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: PassengerInfoForecast, ctx: &mut Context<Self>) {
+        let passenger_info = self.store.passenger_info(&msg.descriptor_id);
+
+        // The client has already been sent the passenger information, so there is nothing to
+        // say if it cannot be looked up again.
+        ctx.wait(
+            passenger_info
+                .into_actor(self)
+                .map(move |passenger_info, act, _| {
+                    if let Some(passenger_info) = passenger_info {
+                        let passenger_info = PassengerInformationOutput {
+                            forecast: Some(msg.forecast),
+                            ..passenger_info
+                        };
+
+                        act.send_message(
+                            serde_json::to_string(&ServerOutput::PassengerInformation(
+                                VehiclePassengerInformationOutput {
+                                    descriptor_id: msg.descriptor_id,
+                                    passenger_info,
+                                },
+                            ))
+                            .unwrap(),
+                            msg.self_id,
+                        );
+                    }
+                }),
+        );
+    }
+}
+
+impl Handler<ReserveSeat> for ReservationManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReserveSeat, ctx: &mut Context<Self>) {
+        info!(
+            client_id = %msg.self_id,
+            descriptor_id = %msg.descriptor_id,
+            "Client requested to reserve a seat."
+        );
+
+        let client_id = msg.self_id;
+        let descriptor_id = msg.descriptor_id;
+        let reserved = self.store.reserve_seat(client_id, &descriptor_id);
+
+        ctx.wait(reserved.into_actor(self).map(move |reserved, act, _| {
+            let outcome = match reserved {
+                Ok(()) => {
+                    // Send updates to all concerned clients.
+                    act.publish_change(descriptor_id);
+
+                    Ok("success")
+                }
+                Err(ReserveError::Full) => Err((
+                    "full",
+                    format!("The bus with descriptor id '{}' is full", &descriptor_id),
+                )),
+                // If a bus with the descriptor id does not have passenger information the
+                // client gets an error message.
+                Err(ReserveError::UnknownVehicle) => Err((
+                    "unknown_vehicle",
+                    format!(
+                        "A bus with descriptor id '{}' does not exist.",
+                        &descriptor_id
+                    ),
+                )),
+            };
+
+            act.report_outcome("reserve", client_id, ErrorType::Reserve, outcome);
+        }));
+    }
+}
+
+impl Handler<UnreserveSeat> for ReservationManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnreserveSeat, ctx: &mut Context<Self>) {
+        info!(client_id = %msg.self_id, "Client requested to unreserve their seat.");
+
+        let client_id = msg.self_id;
+        let unreserved = self.store.unreserve_seat(client_id);
+
+        ctx.wait(
+            unreserved
+                .into_actor(self)
+                .map(move |descriptor_id, act, _| {
+                    let outcome = match descriptor_id {
+                        Some(descriptor_id) => {
+                            // Send updates to all concerned clients.
+                            act.publish_change(descriptor_id);
+
+                            Ok("success")
+                        }
+                        None => Err((
+                            "no_reservation",
+                            "Cannot unreserve since there is no active reservation.".to_owned(),
+                        )),
+                    };
+
+                    act.report_outcome("unreserve", client_id, ErrorType::Unreserve, outcome);
+                }),
+        );
+    }
+}
+
+impl Handler<ClientDisconnected> for ReservationManager {
+    type Result = ();
+
+    // The seat stays taken, but the client can no longer unreserve it.
+    fn handle(&mut self, msg: ClientDisconnected, ctx: &mut Context<Self>) {
+        let forgotten = self.store.forget_reservation(msg.client_id);
+
+        ctx.wait(forgotten.into_actor(self).map(|_, act, ctx| {
+            act.update_active_reservations(ctx);
+        }));
+    }
+}
+
+impl Handler<VehiclePassengerInfoRequest> for ReservationManager {
+    type Result = ResponseFuture<PassengerInformationOutput>;
+
+    // Nothing is stored here, since it is only read. The information that would be made up is
+    // the same as the information that is created when a client asks about the vehicle.
+    fn handle(&mut self, msg: VehiclePassengerInfoRequest, _: &mut Context<Self>) -> Self::Result {
+        let passenger_info = self.store.passenger_info(&msg.descriptor_id);
+
+        Box::pin(async move {
+            passenger_info
+                .await
+                .unwrap_or_else(|| made_up_passenger_info(&msg.descriptor_id))
+        })
+    }
+}
+
+impl Handler<AllPassengerInfoRequest> for ReservationManager {
+    type Result = ResponseFuture<HashMap<String, PassengerInformationOutput>>;
+
+    fn handle(&mut self, _: AllPassengerInfoRequest, _: &mut Context<Self>) -> Self::Result {
+        self.store.all_passenger_info()
+    }
+}
+
+impl ReservationManager {
+    /// Counts the outcome of a reservation request and sends an error to the client if it
+    /// failed.
+    fn report_outcome(
+        &self,
+        action: &str,
+        client_id: Uuid,
+        error_type: ErrorType,
+        outcome: Result<&str, (&str, String)>,
+    ) {
+        match outcome {
+            Ok(outcome) => RESERVATIONS.with_label_values(&[action, outcome]).inc(),
+            Err((outcome, error_message)) => {
+                RESERVATIONS.with_label_values(&[action, outcome]).inc();
+
+                self.send_error(client_id, error_type, error_message);
+            }
+        }
+    }
+}
+
+/// Makes up passenger information for a vehicle, since there are no real passenger counts.
+/// The numbers are derived from the descriptor id, so that every instance makes up the same
+/// numbers for a vehicle.
+fn made_up_passenger_info(descriptor_id: &str) -> PassengerInformationOutput {
+    let hash = fnv1a(descriptor_id.as_bytes());
+
+    PassengerInformationOutput {
+        passengers: (hash % 15) as i32,
+        capacity: 20 + (hash / 15 % 15) as i32,
+        forecast: None,
+    }
+}
+
+/// Hashes bytes with 64-bit FNV-1a. Unlike the hashers in the standard library, its output
+/// never changes, so instances that run different builds still agree on it.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+impl StreamHandler<ClusterEvent> for ReservationManager {
+    fn handle(&mut self, event: ClusterEvent, ctx: &mut Context<Self>) {
+        if let ClusterEvent::PassengerInfoChanged { descriptor_id } = event {
+            let passenger_info = self.store.passenger_info(&descriptor_id);
+
+            // Sends the passenger information for the bus to every client that last asked
+            // about it.
+            ctx.wait(
+                passenger_info
+                    .into_actor(self)
+                    .map(move |passenger_info, act, ctx| {
+                        if let Some(passenger_info) = passenger_info {
+                            let message =
+                                serde_json::to_string(&ServerOutput::PassengerInformation(
+                                    VehiclePassengerInformationOutput {
+                                        descriptor_id: descriptor_id.clone(),
+                                        passenger_info,
+                                    },
+                                ))
+                                .unwrap();
+
+                            act.broadcaster.do_send(SendToWatchers {
+                                descriptor_id,
+                                message,
+                            });
+                        }
+
+                        act.update_active_reservations(ctx);
+                    }),
+            );
+        }
+    }
+
+    // Clients are not told about changes without the event bus, but the manager keeps
+    // handling their requests.
+    fn finished(&mut self, _: &mut Context<Self>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_made_up_passenger_info() {
+        // The published test vectors of FNV-1a.
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        // Every build makes up the same numbers.
+        let passenger_info = made_up_passenger_info("ul:9031003");
+        assert_eq!(passenger_info.passengers, 2);
+        assert_eq!(passenger_info.capacity, 26);
+    }
+}
@@ -0,0 +1,103 @@
+//! Keeps track of which clients are connected.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use actix::prelude::{Actor, Addr, Context, Handler, MessageResult};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::lobby::broadcaster::Broadcaster;
+use crate::lobby::ingester::FeedIngester;
+use crate::lobby::reservations::ReservationManager;
+use crate::messages::{
+    ClientCountRequest, ClientDisconnected, Connect, Disconnect, SendFeedStatuses, Subscribe,
+    Unsubscribe,
+};
+use crate::metrics::CONNECTED_CLIENTS;
+
+/// Registers clients when they connect and tells the rest of the lobby about them.
+pub struct SessionRegistry {
+    /// Maps client IDs to when they connected.
+    sessions: HashMap<Uuid, Instant>,
+
+    broadcaster: Addr<Broadcaster>,
+    ingester: Addr<FeedIngester>,
+    reservations: Addr<ReservationManager>,
+}
+
+impl SessionRegistry {
+    pub fn new(
+        broadcaster: Addr<Broadcaster>,
+        ingester: Addr<FeedIngester>,
+        reservations: Addr<ReservationManager>,
+    ) -> Self {
+        SessionRegistry {
+            sessions: HashMap::new(),
+            broadcaster,
+            ingester,
+            reservations,
+        }
+    }
+}
+
+impl Actor for SessionRegistry {
+    type Context = Context<Self>;
+}
+
+impl Handler<Connect> for SessionRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) {
+        self.sessions.insert(msg.self_id, Instant::now());
+
+        info!(client_id = %msg.self_id, "Client connected.");
+
+        CONNECTED_CLIENTS.set(self.sessions.len() as i64);
+
+        // The client is subscribed before anything is sent to it, which is why the feed
+        // statuses are requested from here instead of by the lobby.
+        self.broadcaster.do_send(Subscribe {
+            addr: msg.addr,
+            close: msg.close,
+            client_id: msg.self_id,
+        });
+
+        // Let the client know right away whether live data is available.
+        self.ingester.do_send(SendFeedStatuses {
+            client_id: msg.self_id,
+        });
+    }
+}
+
+impl Handler<Disconnect> for SessionRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        // Try and remove the client from the sessions hashmap.
+        if let Some(connected_at) = self.sessions.remove(&msg.self_id) {
+            info!(
+                client_id = %msg.self_id,
+                duration_secs = connected_at.elapsed().as_secs(),
+                "Client disconnected."
+            );
+
+            CONNECTED_CLIENTS.set(self.sessions.len() as i64);
+
+            self.broadcaster.do_send(Unsubscribe {
+                client_id: msg.self_id,
+            });
+            self.reservations.do_send(ClientDisconnected {
+                client_id: msg.self_id,
+            });
+        }
+    }
+}
+
+impl Handler<ClientCountRequest> for SessionRegistry {
+    type Result = MessageResult<ClientCountRequest>;
+
+    fn handle(&mut self, _: ClientCountRequest, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.sessions.len())
+    }
+}
@@ -0,0 +1,746 @@
+//! Looks up the shapes of routes and keeps the processed shapes in memory.
+//!
+//! A line usually has several shapes: one for each direction, and often a few more for trips
+//! that take a detour or only drive part of the route. Every shape is sent together with the
+//! share of the line's trips that use it, so that clients can tell the main route from rare
+//! branches.
+//!
+//! Shapes are static data, so once a shape has been fetched, sorted and simplified for a
+//! zoom level it is kept, and the next client that looks at the same route gets it without
+//! any queries for static data. The cache holds a limited number of shapes, and the ones that
+//! were added first are removed when it is full.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use crate::geometry::{self, Point};
+use crate::gtfs::operator::Operator;
+use crate::gtfs::transit_static::{Stop, StopTime, Trip};
+use crate::lobby::Lobby;
+use crate::protocol::client_protocol::{GeometryFormat, IdentifierKind};
+use crate::protocol::server_protocol::{
+    ErrorOutput, ErrorType, RouteDirection, RouteInformationOutput, RouteStop, RouteVariant,
+};
+use crate::store::{StaticStore, TripQuery};
+use crate::util::only_numbers;
+
+/// The number of shapes (and of identifiers) that are kept.
+const CACHE_CAPACITY: usize = 1024;
+
+/// The most detailed zoom level of web maps. Shapes are not simplified at all at this level.
+const MAX_ZOOM: u8 = 22;
+
+/// A map that forgets the oldest entries when it is full.
+struct BoundedCache<K, V> {
+    entries: HashMap<K, V>,
+
+    /// The keys in the order they were inserted.
+    order: VecDeque<K>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> BoundedCache<K, V> {
+    fn new() -> Self {
+        BoundedCache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.entries.insert(key.clone(), value).is_some() {
+            return;
+        }
+
+        self.order.push_back(key);
+
+        if self.order.len() > CACHE_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Identifies a shape of an operator, simplified for a zoom level (or not at all).
+type ShapeKey = (String, String, Option<u8>);
+
+/// The trips of a route that go in the same direction with the same headsign, and the shapes
+/// they use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Direction {
+    pub direction_id: Option<u8>,
+    pub headsign: Option<String>,
+
+    /// The shapes that the trips use, the most used first.
+    pub shapes: Vec<ShapeUsage>,
+}
+
+impl Direction {
+    /// Returns the number of trips in the direction.
+    pub fn trips(&self) -> u32 {
+        self.shapes.iter().map(|usage| usage.trips).sum()
+    }
+}
+
+/// A shape that some of the trips of a route use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeUsage {
+    pub shape_id: String,
+
+    /// The number of trips that use the shape.
+    pub trips: u32,
+
+    /// One of the trips that use the shape, whose stops are shown along it.
+    pub trip_id: String,
+}
+
+/// A stop that a trip makes, joined from its stop time and the stop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TripStop {
+    pub stop_id: String,
+    pub name: String,
+    pub point: Point,
+    pub platform: Option<String>,
+    pub arrival_time: String,
+    pub departure_time: String,
+}
+
+/// The directions of a route together with their shapes, which are mapped by shape id, and
+/// their stops, which are mapped by the trip id of the shape usages.
+pub struct RouteShapes {
+    pub directions: Arc<Vec<Direction>>,
+    pub shapes: HashMap<String, Arc<Vec<Point>>>,
+    pub stops: HashMap<String, Arc<Vec<TripStop>>>,
+
+    /// Whether the route is a single trip, in which case the stops have scheduled times.
+    pub scheduled: bool,
+}
+
+impl RouteShapes {
+    /// Creates the output that is sent to clients, where shape and stop ids are namespaced by
+    /// `operator`.
+    pub fn to_output(&self, operator: &Operator, format: GeometryFormat) -> RouteInformationOutput {
+        let total = self
+            .directions
+            .iter()
+            .map(Direction::trips)
+            .sum::<u32>()
+            .max(1) as f64;
+
+        let points = |shape_id: &str| {
+            let points = self.shapes.get(shape_id).map(|points| points.as_slice());
+            points.unwrap_or_default()
+        };
+
+        // The directions are sorted within, but the most used shape can be in any of them.
+        let main_shape = self
+            .directions
+            .iter()
+            .filter_map(|direction| direction.shapes.first())
+            .fold(None, |main: Option<&ShapeUsage>, usage| match main {
+                Some(main) if main.trips >= usage.trips => Some(main),
+                _ => Some(usage),
+            })
+            .map(|usage| usage.shape_id.as_str())
+            .unwrap_or_default();
+
+        let directions = self
+            .directions
+            .iter()
+            .map(|direction| RouteDirection {
+                direction_id: direction.direction_id,
+                headsign: direction.headsign.clone(),
+                share: direction.trips() as f64 / total,
+                variants: direction
+                    .shapes
+                    .iter()
+                    .map(|usage| RouteVariant {
+                        shape_id: operator.namespace_id(&usage.shape_id),
+                        trips: usage.trips,
+                        share: usage.trips as f64 / total,
+                        route: geometry::to_geometry(points(&usage.shape_id), format),
+                        stops: self.route_stops(operator, usage, points(&usage.shape_id)),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        RouteInformationOutput {
+            timestamp: Lobby::get_current_timestamp(),
+            route: geometry::to_geometry(points(main_shape), format),
+            directions,
+        }
+    }
+
+    /// Creates the output for the stops of a shape usage, placed along the shape as it is sent.
+    fn route_stops(
+        &self,
+        operator: &Operator,
+        usage: &ShapeUsage,
+        shape: &[Point],
+    ) -> Vec<RouteStop> {
+        let stops = match self.stops.get(&usage.trip_id) {
+            Some(stops) => stops,
+            None => return Vec::new(),
+        };
+
+        let points = stops.iter().map(|stop| stop.point).collect::<Vec<_>>();
+        let distances = geometry::distances_along(shape, &points);
+
+        stops
+            .iter()
+            .zip(distances)
+            .map(|(stop, distance)| RouteStop {
+                stop_id: operator.namespace_id(&stop.stop_id),
+                name: stop.name.clone(),
+                lat: stop.point.lat,
+                lng: stop.point.lng,
+                platform: stop.platform.clone(),
+                distance,
+                arrival_time: Some(stop.arrival_time.clone()).filter(|_| self.scheduled),
+                departure_time: Some(stop.departure_time.clone()).filter(|_| self.scheduled),
+                forecast: None,
+            })
+            .collect()
+    }
+}
+
+/// Groups trips by direction and headsign, and counts how many of them use each shape. The
+/// directions with the most trips come first.
+pub fn group_by_direction(trips: &[Trip]) -> Vec<Direction> {
+    // Maps the shapes of every direction to the number of trips and the first trip id.
+    let mut groups = BTreeMap::<_, BTreeMap<&str, (u32, &str)>>::new();
+
+    for trip in trips {
+        let key = (
+            trip.direction_id.parse::<u8>().ok(),
+            trip.trip_headsign.as_deref(),
+        );
+
+        let (count, trip_id) = groups
+            .entry(key)
+            .or_default()
+            .entry(&trip.shape_id)
+            .or_insert((0, &trip.trip_id));
+
+        *count += 1;
+        *trip_id = (*trip_id).min(&trip.trip_id);
+    }
+
+    let mut directions = groups
+        .into_iter()
+        .map(|((direction_id, headsign), shapes)| {
+            let mut shapes = shapes
+                .into_iter()
+                .map(|(shape_id, (trips, trip_id))| ShapeUsage {
+                    shape_id: shape_id.to_owned(),
+                    trips,
+                    trip_id: trip_id.to_owned(),
+                })
+                .collect::<Vec<_>>();
+
+            // Sorting is stable, so shapes that are used equally often stay sorted by id.
+            shapes.sort_by_key(|usage| Reverse(usage.trips));
+
+            Direction {
+                direction_id,
+                headsign: headsign.map(str::to_owned),
+                shapes,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    directions.sort_by_key(|direction| Reverse(direction.trips()));
+
+    directions
+}
+
+/// Joins the stop times of a trip with their stops. Stop times of unknown stops, or of stops
+/// without a valid position, are skipped.
+pub fn join_stops(stop_times: &[StopTime], stops: &[Stop]) -> Vec<TripStop> {
+    let stops = stops
+        .iter()
+        .map(|stop| (stop.stop_id.as_str(), stop))
+        .collect::<HashMap<_, _>>();
+
+    stop_times
+        .iter()
+        .filter_map(|stop_time| {
+            let stop = stops.get(stop_time.stop_id.as_str())?;
+            let point = Point {
+                lat: stop.stop_lat.parse().ok()?,
+                lng: stop.stop_lon.parse().ok()?,
+            };
+
+            Some(TripStop {
+                stop_id: stop.stop_id.clone(),
+                name: stop.stop_name.clone(),
+                point,
+                platform: stop.platform_code.clone(),
+                arrival_time: stop_time.arrival_time.clone(),
+                departure_time: stop_time.departure_time.clone(),
+            })
+        })
+        .collect()
+}
+
+struct Caches {
+    /// Maps an operator and an identifier of a route to the directions of the route.
+    directions: BoundedCache<(String, IdentifierKind, String), Arc<Vec<Direction>>>,
+
+    shapes: BoundedCache<ShapeKey, Arc<Vec<Point>>>,
+
+    /// Maps an operator and a trip id to the stops of the trip.
+    stops: BoundedCache<(String, String), Arc<Vec<TripStop>>>,
+}
+
+/// Processed shapes, shared by every request. Clones share the same cache.
+#[derive(Clone)]
+pub struct ShapeCache {
+    caches: Arc<Mutex<Caches>>,
+}
+
+impl ShapeCache {
+    pub fn new() -> Self {
+        let caches = Caches {
+            directions: BoundedCache::new(),
+            shapes: BoundedCache::new(),
+            stops: BoundedCache::new(),
+        };
+
+        ShapeCache {
+            caches: Arc::new(Mutex::new(caches)),
+        }
+    }
+
+    /// Looks up every shape of a route of `operator`, where `identifier` is of the kind `kind`
+    /// and not namespaced, and simplifies them for `zoom`.
+    /// `raw_identifier` is what the client sent and is used in error messages.
+    ///
+    /// A line or route has every shape that its trips use, while a trip only has its own. The
+    /// stops of a shape are those of one of the trips that use it.
+    pub async fn route_shapes(
+        &self,
+        operator: &str,
+        store: Arc<dyn StaticStore>,
+        kind: IdentifierKind,
+        identifier: String,
+        raw_identifier: &str,
+        zoom: Option<u8>,
+    ) -> Result<RouteShapes, ErrorOutput> {
+        let id_key = (operator.to_owned(), kind, identifier.clone());
+
+        let directions = match self.with(|caches| caches.directions.get(&id_key)) {
+            Some(directions) => directions,
+            None => {
+                let trips = fetch_trips(&*store, kind, &identifier, raw_identifier).await?;
+                let directions = Arc::new(group_by_direction(&trips));
+
+                self.with(|caches| caches.directions.insert(id_key, directions.clone()));
+                directions
+            }
+        };
+
+        let mut shapes = HashMap::new();
+        let mut stops = HashMap::new();
+
+        for direction in directions.iter() {
+            for usage in &direction.shapes {
+                if !shapes.contains_key(&usage.shape_id) {
+                    let points = self.shape(operator, &*store, &usage.shape_id, zoom).await?;
+                    shapes.insert(usage.shape_id.clone(), points);
+                }
+
+                let trip_stops = self.trip_stops(operator, &*store, &usage.trip_id).await?;
+                stops.insert(usage.trip_id.clone(), trip_stops);
+            }
+        }
+
+        Ok(RouteShapes {
+            directions,
+            shapes,
+            stops,
+            scheduled: kind == IdentifierKind::TripId,
+        })
+    }
+
+    /// Looks up the stops of a trip of `operator`.
+    pub async fn trip_stops(
+        &self,
+        operator: &str,
+        store: &dyn StaticStore,
+        trip_id: &str,
+    ) -> Result<Arc<Vec<TripStop>>, ErrorOutput> {
+        let key = (operator.to_owned(), trip_id.to_owned());
+
+        if let Some(stops) = self.with(|caches| caches.stops.get(&key)) {
+            return Ok(stops);
+        }
+
+        let stop_times = store
+            .stop_times(trip_id)
+            .await
+            .ok_or_else(unable_to_retrieve)?;
+
+        let stop_ids = stop_times
+            .iter()
+            .map(|stop_time| stop_time.stop_id.clone())
+            .collect::<Vec<_>>();
+
+        let stops = match stop_ids.is_empty() {
+            true => Vec::new(),
+            false => store
+                .stops(&stop_ids)
+                .await
+                .ok_or_else(unable_to_retrieve)?,
+        };
+
+        let stops = Arc::new(join_stops(&stop_times, &stops));
+        self.with(|caches| caches.stops.insert(key, stops.clone()));
+
+        Ok(stops)
+    }
+
+    /// Looks up a shape of `operator` and simplifies it for `zoom`.
+    pub async fn shape(
+        &self,
+        operator: &str,
+        store: &dyn StaticStore,
+        shape_id: &str,
+        zoom: Option<u8>,
+    ) -> Result<Arc<Vec<Point>>, ErrorOutput> {
+        let zoom = zoom.filter(|zoom| *zoom < MAX_ZOOM);
+        let key = (operator.to_owned(), shape_id.to_owned(), zoom);
+
+        if let Some(points) = self.cached_shape(&key) {
+            return Ok(points);
+        }
+
+        // The full shape is kept as well, so that it only has to be fetched once for every
+        // zoom level.
+        let full_key = (operator.to_owned(), shape_id.to_owned(), None);
+
+        let full = match self.cached_shape(&full_key) {
+            Some(points) => points,
+            None => {
+                let points = store.shape(shape_id).await.ok_or_else(unable_to_retrieve)?;
+
+                let points = Arc::new(points);
+                self.insert_shape(full_key, points.clone());
+                points
+            }
+        };
+
+        let points = match zoom {
+            Some(zoom) => {
+                let simplified = Arc::new(geometry::simplify(
+                    &full,
+                    geometry::tolerance_for_zoom(zoom),
+                ));
+                self.insert_shape(key, simplified.clone());
+                simplified
+            }
+            None => full,
+        };
+
+        Ok(points)
+    }
+
+    fn cached_shape(&self, key: &ShapeKey) -> Option<Arc<Vec<Point>>> {
+        self.with(|caches| caches.shapes.get(key))
+    }
+
+    fn insert_shape(&self, key: ShapeKey, points: Arc<Vec<Point>>) {
+        self.with(|caches| caches.shapes.insert(key, points));
+    }
+
+    /// Runs `f` with the caches locked. The lock is never held across an await.
+    fn with<T>(&self, f: impl FnOnce(&mut Caches) -> T) -> T {
+        let mut caches = self.caches.lock().unwrap();
+
+        f(&mut caches)
+    }
+}
+
+fn unable_to_retrieve() -> ErrorOutput {
+    ErrorOutput {
+        error_type: ErrorType::ServerError,
+        error_message: "Unable to retrieve data".to_owned(),
+    }
+}
+
+/// Guesses what kind of identifier a route is looked up by when the client has not said, in
+/// the same way as before there were kinds: ids of up to three characters are line numbers and
+/// longer numeric ids are trip ids. Other ids are ambiguous.
+pub fn infer_kind(identifier: &str) -> Option<IdentifierKind> {
+    if identifier.chars().count() <= 3 {
+        Some(IdentifierKind::Line)
+    } else if only_numbers(identifier) {
+        Some(IdentifierKind::TripId)
+    } else {
+        None
+    }
+}
+
+/// Creates an error about a route that cannot be looked up.
+pub fn route_info_error(error_message: String) -> ErrorOutput {
+    ErrorOutput {
+        error_type: ErrorType::RouteInfo,
+        error_message,
+    }
+}
+
+/// Looks up the trips of a route, where `identifier` is of the kind `kind` and not namespaced.
+/// A trip id only gives that trip and a shape id gives the trips that use the shape.
+async fn fetch_trips(
+    store: &dyn StaticStore,
+    kind: IdentifierKind,
+    identifier: &str,
+    raw_identifier: &str,
+) -> Result<Vec<Trip>, ErrorOutput> {
+    let (query, unknown) = match kind {
+        IdentifierKind::Line => {
+            let route = store.route_by_short_name(identifier).await.ok_or_else(|| {
+                route_info_error(format!("'{}' is not a valid line number", raw_identifier))
+            })?;
+
+            (TripQuery::Route(route.route_id), "has no trips")
+        }
+        IdentifierKind::RouteId => (
+            TripQuery::Route(identifier.to_owned()),
+            "is not a known route id",
+        ),
+        // Descriptor ids have already been replaced by the trip the vehicle is on.
+        IdentifierKind::TripId | IdentifierKind::DescriptorId => (
+            TripQuery::Trip(identifier.to_owned()),
+            "is not a known trip id",
+        ),
+        IdentifierKind::ShapeId => (
+            TripQuery::Shape(identifier.to_owned()),
+            "is not a shape that any trip uses",
+        ),
+    };
+
+    match store.trips(query).await {
+        Some(trips) if trips.is_empty() => Err(route_info_error(format!(
+            "'{}' {}",
+            raw_identifier, unknown
+        ))),
+        Some(trips) => Ok(trips),
+        None => Err(unable_to_retrieve()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded_cache() {
+        let mut cache = BoundedCache::new();
+
+        for i in 0..CACHE_CAPACITY + 1 {
+            cache.insert(i, i * 2);
+        }
+
+        // The first entry was removed to make room for the last one.
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get(&1), Some(2));
+        assert_eq!(cache.get(&CACHE_CAPACITY), Some(CACHE_CAPACITY * 2));
+        assert_eq!(cache.entries.len(), CACHE_CAPACITY);
+
+        // Replacing an entry does not count as another entry.
+        cache.insert(1, 3);
+        assert_eq!(cache.get(&1), Some(3));
+        assert_eq!(cache.order.len(), CACHE_CAPACITY);
+    }
+
+    fn trip(direction_id: &str, headsign: &str, shape_id: &str) -> Trip {
+        Trip {
+            route_id: "9011003000500000".to_owned(),
+            service_id: "1".to_owned(),
+            trip_id: "141010000123456789".to_owned(),
+            trip_headsign: Some(headsign.to_owned()),
+            direction_id: direction_id.to_owned(),
+            shape_id: shape_id.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_infer_kind() {
+        assert_eq!(infer_kind("5"), Some(IdentifierKind::Line));
+        assert_eq!(infer_kind("5X"), Some(IdentifierKind::Line));
+        assert_eq!(infer_kind("801"), Some(IdentifierKind::Line));
+        assert_eq!(
+            infer_kind("141010000123456789"),
+            Some(IdentifierKind::TripId)
+        );
+        assert_eq!(infer_kind("9011003000500000_shape"), None);
+    }
+
+    /// Gives the trips ids "0", "1" and so on.
+    fn number_trips(trips: Vec<Trip>) -> Vec<Trip> {
+        trips
+            .into_iter()
+            .enumerate()
+            .map(|(i, trip)| Trip {
+                trip_id: i.to_string(),
+                ..trip
+            })
+            .collect()
+    }
+
+    fn usage(shape_id: &str, trips: u32, trip_id: &str) -> ShapeUsage {
+        ShapeUsage {
+            shape_id: shape_id.to_owned(),
+            trips,
+            trip_id: trip_id.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_group_by_direction() {
+        let mut trips = vec![trip("1", "Stenhagen", "2"); 4];
+        trips.push(trip("1", "Stenhagen", "3"));
+        trips.extend(vec![trip("0", "Gottsunda", "1"); 3]);
+        trips.push(trip("0", "Centralstationen", "4"));
+
+        let directions = group_by_direction(&number_trips(trips));
+
+        assert_eq!(
+            directions,
+            vec![
+                Direction {
+                    direction_id: Some(1),
+                    headsign: Some("Stenhagen".to_owned()),
+                    shapes: vec![usage("2", 4, "0"), usage("3", 1, "4")],
+                },
+                Direction {
+                    direction_id: Some(0),
+                    headsign: Some("Gottsunda".to_owned()),
+                    shapes: vec![usage("1", 3, "5")],
+                },
+                // Trips that end early have a headsign of their own.
+                Direction {
+                    direction_id: Some(0),
+                    headsign: Some("Centralstationen".to_owned()),
+                    shapes: vec![usage("4", 1, "8")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_stops() {
+        let stop_time = |stop_id: &str, time: &str| StopTime {
+            trip_id: "0".to_owned(),
+            arrival_time: time.to_owned(),
+            departure_time: time.to_owned(),
+            stop_id: stop_id.to_owned(),
+            stop_sequence: 1,
+            stop_headsign: String::new(),
+            pickup_type: Some(0),
+            drop_off_type: Some(0),
+            shape_dist_traveled: None,
+            timepoint: Some(1),
+        };
+        let stop = |stop_id: &str, lat: &str| Stop {
+            stop_id: stop_id.to_owned(),
+            stop_name: "Centralstationen".to_owned(),
+            stop_lat: lat.to_owned(),
+            stop_lon: "17.638".to_owned(),
+            location_type: Some(0),
+            parent_station: None,
+            platform_code: Some("A1".to_owned()),
+        };
+
+        let stop_times = [
+            stop_time("1", "08:00:00"),
+            stop_time("2", "08:02:00"),
+            stop_time("3", "08:04:00"),
+            stop_time("1", "25:10:00"),
+        ];
+        // Stop "2" has no position and stop "3" is unknown.
+        let stops = [stop("1", "59.858"), stop("2", "")];
+
+        let joined = join_stops(&stop_times, &stops);
+
+        assert_eq!(joined.len(), 2);
+        assert_eq!(joined[0].point.lat, 59.858);
+        assert_eq!(joined[0].platform.as_deref(), Some("A1"));
+        assert_eq!(joined[1].arrival_time, "25:10:00");
+    }
+
+    #[test]
+    fn test_to_output() {
+        let mut trips = vec![trip("0", "Gottsunda", "1"); 3];
+        trips.push(trip("1", "Stenhagen", "2"));
+
+        let start = Point {
+            lat: 59.858,
+            lng: 17.638,
+        };
+        let end = Point {
+            lat: 59.859,
+            lng: 17.638,
+        };
+
+        let mut shapes = HashMap::new();
+        shapes.insert("1".to_owned(), Arc::new(vec![start, end]));
+        shapes.insert("2".to_owned(), Arc::new(vec![end]));
+
+        let trip_stop = |stop_id: &str, point: Point| TripStop {
+            stop_id: stop_id.to_owned(),
+            name: "Centralstationen".to_owned(),
+            point,
+            platform: None,
+            arrival_time: "08:00:00".to_owned(),
+            departure_time: "08:00:00".to_owned(),
+        };
+
+        let mut stops = HashMap::new();
+        stops.insert(
+            "0".to_owned(),
+            Arc::new(vec![trip_stop("1", start), trip_stop("2", end)]),
+        );
+
+        let route_shapes = RouteShapes {
+            directions: Arc::new(group_by_direction(&number_trips(trips))),
+            shapes,
+            stops,
+            scheduled: false,
+        };
+        let operator = Operator::new("ul", "realtime", "static");
+        let output = route_shapes.to_output(&operator, GeometryFormat::Geojson);
+
+        // The main route is the shape that most trips use.
+        assert_eq!(
+            output.route,
+            geometry::to_geometry(&[start, end], GeometryFormat::Geojson)
+        );
+
+        assert_eq!(output.directions.len(), 2);
+        assert_eq!(output.directions[0].share, 0.75);
+        assert_eq!(output.directions[1].direction_id, Some(1));
+        assert_eq!(output.directions[1].variants[0].shape_id, "ul:2");
+        assert_eq!(output.directions[1].variants[0].trips, 1);
+        assert_eq!(output.directions[1].variants[0].share, 0.25);
+
+        // The stops are placed along the shape, without times since the route is a line.
+        let stops = &output.directions[0].variants[0].stops;
+        assert_eq!(stops.len(), 2);
+        assert_eq!(stops[0].stop_id, "ul:1");
+        assert_eq!(stops[0].distance, 0.0);
+        assert!((stops[1].distance - 111.2).abs() < 0.1);
+        assert_eq!(stops[1].arrival_time, None);
+
+        // Nothing is known about the stops of the other direction.
+        assert!(output.directions[1].variants[0].stops.is_empty());
+    }
+}
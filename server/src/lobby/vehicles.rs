@@ -0,0 +1,407 @@
+//! The latest known vehicles of every operator.
+//!
+//! Vehicles are enriched with static data (e.g. their line number) from the static store
+//! before they are served. Enrichment runs in the background, so queries keep being answered
+//! with the previous vehicles while a slow database is being waited on. Only the enrichment of
+//! the newest feed is kept, whatever order the enrichments finish in.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix::prelude::{
+    Actor, ActorFuture, Addr, Context, Handler, MessageResult, ResponseActFuture, WrapFuture,
+};
+use tracing::warn;
+
+use crate::gtfs::operator::Operator;
+use crate::gtfs::republish::{self, RepublishedVehicle};
+use crate::gtfs::trafiklab::RealtimeFeed;
+use crate::lobby::broadcaster::Broadcaster;
+use crate::lobby::Lobby;
+use crate::messages::{
+    ApplyFeed, ApplyTripUpdates, BuildVehiclePositionsFeed, LineVehiclesRequest,
+    TripUpdatesFeedRequest, VehicleRequest, VehiclesChanged, VehiclesRequest,
+};
+use crate::protocol::server_protocol::Vehicle;
+use crate::store::StaticStore;
+
+/// The vehicles of a single operator.
+struct OperatorVehicles {
+    /// Settings for the operator.
+    operator: Operator,
+
+    /// The operator's static data.
+    store: Arc<dyn StaticStore>,
+
+    /// The latest known vehicles, mapped by the entity id in the realtime feed. The ids in
+    /// these vehicles are not namespaced.
+    vehicles: HashMap<String, Vehicle>,
+
+    /// The latest vehicles that have been enriched with static data and namespaced, ready to
+    /// be sent to clients.
+    enriched_vehicles: Vec<Vehicle>,
+
+    /// Maps the namespaced trip ids of the enriched vehicles to namespaced route ids.
+    route_ids: HashMap<String, String>,
+
+    /// How many feeds have been received, and which of them the enriched vehicles are from.
+    /// Feeds are enriched concurrently, so the enrichment of an older feed can finish after
+    /// that of a newer one, and is then dropped.
+    feeds_received: u64,
+    feed_enriched: u64,
+
+    /// The latest TripUpdates feed, as it was received.
+    trip_updates: Option<RealtimeFeed>,
+}
+
+/// Keeps the vehicles of every operator and tells the broadcaster when they change.
+pub struct VehicleStore {
+    /// Maps operator names to their vehicles.
+    operators: HashMap<String, OperatorVehicles>,
+
+    broadcaster: Addr<Broadcaster>,
+}
+
+impl VehicleStore {
+    pub fn new(
+        static_stores: &HashMap<String, Arc<dyn StaticStore>>,
+        operators: &[Operator],
+        broadcaster: Addr<Broadcaster>,
+    ) -> Self {
+        let operators = operators
+            .iter()
+            .map(|operator| {
+                let state = OperatorVehicles {
+                    store: static_stores[&operator.name].clone(),
+                    operator: operator.clone(),
+                    vehicles: HashMap::new(),
+                    enriched_vehicles: Vec::new(),
+                    route_ids: HashMap::new(),
+                    feeds_received: 0,
+                    feed_enriched: 0,
+                    trip_updates: None,
+                };
+
+                (operator.name.clone(), state)
+            })
+            .collect();
+
+        VehicleStore {
+            operators,
+            broadcaster,
+        }
+    }
+
+    /// Returns the latest vehicles of every operator.
+    fn all_vehicles(&self) -> impl Iterator<Item = &Vehicle> {
+        self.operators
+            .values()
+            .flat_map(|state| state.enriched_vehicles.iter())
+    }
+}
+
+impl Actor for VehicleStore {
+    type Context = Context<Self>;
+}
+
+impl Handler<ApplyFeed> for VehicleStore {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: ApplyFeed, _: &mut Context<Self>) -> Self::Result {
+        let operator = msg.operator;
+
+        let state = match self.operators.get_mut(&operator) {
+            Some(state) => state,
+            None => {
+                warn!(%operator, "Received vehicles for unknown operator.");
+                return Box::pin(async {}.into_actor(self));
+            }
+        };
+
+        // Update the known vehicles with the new data.
+        msg.feed.apply(&mut state.vehicles);
+        state.feeds_received += 1;
+
+        let sequence = state.feeds_received;
+
+        let mut vehicle_positions: Vec<Vehicle> = state.vehicles.values().cloned().collect();
+        let namespace = state.operator.clone();
+        let store = state.store.clone();
+
+        Box::pin(
+            async move {
+                // Remove all vehicles that are not mapped to a trip_id since they are most likely not in trafic
+                vehicle_positions.retain(|vehicle| vehicle.trip_id.is_some());
+
+                let mut route_ids = HashMap::new();
+
+                for v in vehicle_positions.iter_mut() {
+                    // Ids are only unique within an operator, so they are namespaced before
+                    // they are sent to clients.
+                    v.descriptor_id = namespace.namespace_id(&v.descriptor_id);
+
+                    if let Some(trip_id) = &v.trip_id {
+                        let namespaced_trip_id = namespace.namespace_id(trip_id);
+
+                        if let Some(trip) = store.trip(trip_id).await {
+                            route_ids.insert(
+                                namespaced_trip_id.clone(),
+                                namespace.namespace_id(&trip.route_id),
+                            );
+
+                            if let Some(route) = store.route(&trip.route_id).await {
+                                v.line = Some(route.route_short_name);
+                            }
+                        }
+
+                        v.trip_id = Some(namespaced_trip_id);
+                    }
+                }
+
+                (vehicle_positions, route_ids)
+            }
+            .into_actor(self)
+            .map(move |(positions, route_ids), act, _ctx| {
+                let state = match act.operators.get_mut(&operator) {
+                    Some(state) if state.feed_enriched < sequence => state,
+                    _ => return,
+                };
+
+                state.enriched_vehicles = positions;
+                state.route_ids = route_ids;
+                state.feed_enriched = sequence;
+
+                act.broadcaster
+                    .do_send(VehiclesChanged(act.all_vehicles().cloned().collect()));
+            }),
+        )
+    }
+}
+
+impl Handler<VehiclesRequest> for VehicleStore {
+    type Result = MessageResult<VehiclesRequest>;
+
+    fn handle(&mut self, msg: VehiclesRequest, _: &mut Context<Self>) -> Self::Result {
+        let vehicles = self
+            .all_vehicles()
+            .filter(|vehicle| match &msg.bbox {
+                Some(bbox) => bbox.contains(&vehicle.position),
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        MessageResult(vehicles)
+    }
+}
+
+impl Handler<LineVehiclesRequest> for VehicleStore {
+    type Result = MessageResult<LineVehiclesRequest>;
+
+    fn handle(&mut self, msg: LineVehiclesRequest, _: &mut Context<Self>) -> Self::Result {
+        let vehicles = match self.operators.get(&msg.operator) {
+            Some(state) => state
+                .enriched_vehicles
+                .iter()
+                .filter(|vehicle| vehicle.line.as_deref() == Some(msg.line.as_str()))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        MessageResult(vehicles)
+    }
+}
+
+impl Handler<VehicleRequest> for VehicleStore {
+    type Result = MessageResult<VehicleRequest>;
+
+    fn handle(&mut self, msg: VehicleRequest, _: &mut Context<Self>) -> Self::Result {
+        let vehicle = self.operators.get(&msg.operator).and_then(|state| {
+            let descriptor_id = state.operator.namespace_id(&msg.descriptor_id);
+
+            state
+                .enriched_vehicles
+                .iter()
+                .find(|vehicle| vehicle.descriptor_id == descriptor_id)
+                .cloned()
+        });
+
+        MessageResult(vehicle)
+    }
+}
+
+impl Handler<BuildVehiclePositionsFeed> for VehicleStore {
+    type Result = MessageResult<BuildVehiclePositionsFeed>;
+
+    fn handle(&mut self, msg: BuildVehiclePositionsFeed, _: &mut Context<Self>) -> Self::Result {
+        let vehicles = self
+            .operators
+            .values()
+            .flat_map(|state| {
+                state.enriched_vehicles.iter().map(move |vehicle| {
+                    let route_id = vehicle
+                        .trip_id
+                        .as_ref()
+                        .and_then(|trip_id| state.route_ids.get(trip_id))
+                        .map(String::as_str);
+
+                    RepublishedVehicle { vehicle, route_id }
+                })
+            })
+            .collect::<Vec<RepublishedVehicle>>();
+
+        MessageResult(republish::vehicle_positions(
+            &vehicles,
+            &msg.passenger_info,
+            Lobby::get_current_timestamp(),
+        ))
+    }
+}
+
+impl Handler<ApplyTripUpdates> for VehicleStore {
+    type Result = ();
+
+    fn handle(&mut self, msg: ApplyTripUpdates, _: &mut Context<Self>) {
+        match self.operators.get_mut(&msg.operator) {
+            Some(state) => state.trip_updates = Some(msg.feed),
+            None => warn!(operator = %msg.operator, "Received trip updates for unknown operator."),
+        }
+    }
+}
+
+impl Handler<TripUpdatesFeedRequest> for VehicleStore {
+    type Result = MessageResult<TripUpdatesFeedRequest>;
+
+    fn handle(&mut self, _: TripUpdatesFeedRequest, _: &mut Context<Self>) -> Self::Result {
+        let feeds = self
+            .operators
+            .values()
+            .filter_map(|state| Some((&state.operator, state.trip_updates.as_ref()?)))
+            .collect::<Vec<_>>();
+
+        MessageResult(republish::trip_updates(
+            &feeds,
+            Lobby::get_current_timestamp(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::time::Duration;
+
+    use futures::future::ready;
+    use tokio::time::delay_for;
+
+    use super::*;
+    use crate::config::LimitSettings;
+    use crate::geometry::Point;
+    use crate::gtfs::transit_realtime::{
+        FeedEntity, FeedMessage, Position, TripDescriptor, VehicleDescriptor, VehiclePosition,
+    };
+    use crate::gtfs::transit_static::{Route, Stop, StopTime, Trip};
+    use crate::gtfs::validation::ValidatedFeed;
+    use crate::store::{StoreFuture, TripQuery};
+
+    /// Static data without any trips, where looking up the trip "slow" takes a while.
+    struct SlowTrips;
+
+    impl StaticStore for SlowTrips {
+        fn ping(&self) -> StoreFuture<bool> {
+            Box::pin(ready(true))
+        }
+
+        fn has_static_data(&self) -> StoreFuture<bool> {
+            Box::pin(ready(true))
+        }
+
+        fn route_by_short_name(&self, _: &str) -> StoreFuture<Option<Route>> {
+            Box::pin(ready(None))
+        }
+
+        fn route(&self, _: &str) -> StoreFuture<Option<Route>> {
+            Box::pin(ready(None))
+        }
+
+        fn trip(&self, trip_id: &str) -> StoreFuture<Option<Trip>> {
+            let delay = match trip_id {
+                "slow" => Duration::from_millis(200),
+                _ => Duration::from_millis(0),
+            };
+
+            Box::pin(async move {
+                delay_for(delay).await;
+                None
+            })
+        }
+
+        fn trips(&self, _: TripQuery) -> StoreFuture<Option<Vec<Trip>>> {
+            Box::pin(ready(None))
+        }
+
+        fn shape(&self, _: &str) -> StoreFuture<Option<Vec<Point>>> {
+            Box::pin(ready(None))
+        }
+
+        fn stop_times(&self, _: &str) -> StoreFuture<Option<Vec<StopTime>>> {
+            Box::pin(ready(None))
+        }
+
+        fn stops(&self, _: &[String]) -> StoreFuture<Option<Vec<Stop>>> {
+            Box::pin(ready(None))
+        }
+    }
+
+    /// A feed with the vehicle 9031003 on `trip_id`.
+    fn feed_with_trip(trip_id: &str) -> ValidatedFeed {
+        let mut message = FeedMessage::default();
+        message.entity.push(FeedEntity {
+            id: Cow::Borrowed("1"),
+            vehicle: Some(VehiclePosition {
+                trip: Some(TripDescriptor {
+                    trip_id: Some(Cow::Borrowed(trip_id)),
+                    ..TripDescriptor::default()
+                }),
+                vehicle: Some(VehicleDescriptor {
+                    id: Some(Cow::Borrowed("9031003")),
+                    ..VehicleDescriptor::default()
+                }),
+                position: Some(Position {
+                    latitude: 59.85,
+                    longitude: 17.63,
+                    ..Position::default()
+                }),
+                ..VehiclePosition::default()
+            }),
+            ..FeedEntity::default()
+        });
+
+        ValidatedFeed::from_message(&message)
+    }
+
+    #[actix_rt::test]
+    async fn test_older_feeds_are_not_applied() {
+        let operators = vec![Operator::new("ul", "realtime", "static")];
+        let mut static_stores: HashMap<String, Arc<dyn StaticStore>> = HashMap::new();
+        static_stores.insert("ul".to_owned(), Arc::new(SlowTrips));
+
+        let broadcaster = Broadcaster::new(&LimitSettings::default()).start();
+        let vehicles = VehicleStore::new(&static_stores, &operators, broadcaster).start();
+
+        // The enrichment of the first feed finishes after that of the second one...
+        for trip_id in &["slow", "fast"] {
+            vehicles.do_send(ApplyFeed {
+                operator: "ul".to_owned(),
+                feed: feed_with_trip(trip_id),
+            });
+        }
+        delay_for(Duration::from_millis(400)).await;
+
+        // ...but the vehicles are still the ones from the second feed.
+        let vehicles = vehicles.send(VehiclesRequest { bbox: None }).await.unwrap();
+        assert_eq!(vehicles.len(), 1);
+        assert_eq!(vehicles[0].trip_id.as_deref(), Some("ul:fast"));
+    }
+}
@@ -0,0 +1,61 @@
+//! Structured logging with `tracing`.
+//!
+//! Every WebSocket connection has a span with the client's id, and every message from a client
+//! and every HTTP request gets a span with a request id of its own, so all output about a
+//! single rider's session can be found by filtering on `client_id`. Output is either human
+//! readable or JSON (one object per line), and the filter can be changed while the server is
+//! running.
+
+use actix_web::dev::ServiceRequest;
+use tracing::{info_span, Span};
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+use uuid::Uuid;
+
+use crate::config::{LogFormat, ServerSettings};
+
+/// Handle to change the log filter of the running server.
+#[derive(Clone)]
+pub struct LogHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogHandle {
+    /// Replaces the current filter, e.g. "info" or "info,bus_plus::lobby=debug".
+    pub fn set_filter(&self, filter: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(filter).map_err(|err| err.to_string())?;
+
+        self.0.reload(filter).map_err(|err| err.to_string())
+    }
+}
+
+/// Installs the global subscriber. Messages from crates that use `log` (such as actix) are
+/// forwarded to it as well.
+pub fn init(settings: &ServerSettings) -> LogHandle {
+    // The settings are validated before this is called, so the filter is always valid.
+    let filter = EnvFilter::try_new(&settings.log_level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let (human, json) = match settings.log_format {
+        LogFormat::Human => (Some(fmt::layer()), None),
+        LogFormat::Json => (None, Some(fmt::layer().json().flatten_event(true))),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(human)
+        .with(json)
+        .init();
+
+    LogHandle(handle)
+}
+
+/// Creates the span for a HTTP request.
+pub fn request_span(req: &ServiceRequest) -> Span {
+    info_span!(
+        "http_request",
+        request_id = %Uuid::new_v4(),
+        method = %req.method(),
+        path = %req.path(),
+    )
+}
@@ -1,51 +1,88 @@
 mod client;
+mod cluster;
 mod config;
 mod database;
+#[cfg(test)]
+mod e2e;
 mod endpoints;
+mod geometry;
 mod gtfs;
 mod lobby;
+mod logging;
 mod messages;
-mod protocol;
+mod metrics;
+mod reload;
+mod startup;
+mod store;
 mod util;
 mod ws;
 
+// The protocol is in the library, so that the load generator can use it as well.
+use bus_plus::protocol;
+
 use actix::Actor;
-use actix_web::{App, HttpServer};
+use tracing::error;
 
-use crate::config::{Config, CONFIG_FILE_PATH};
-use crate::database::init_db_connection;
-use crate::endpoints::ws_endpoint as ws_endpoint_route;
-use crate::lobby::Lobby;
+use crate::config::{CliArgs, Settings};
+use crate::reload::ConfigWatcher;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let mut config_handler = Config::new();
+    let args = CliArgs::from_env().unwrap_or_else(|reason| {
+        println!("{}\n\n{}", reason, CliArgs::USAGE);
+
+        std::process::exit(2);
+    });
 
-    // If the load somehow fails the program will panic since it cannot operate
-    // without the necessary data.
-    if let Err(reason) = config_handler.load_config(CONFIG_FILE_PATH) {
-        panic!("{}", reason);
-    };
+    if args.help {
+        println!("{}", CliArgs::USAGE);
 
-    // Get Database URI from config
-    let db_uri = config_handler.get_database_value("uri").unwrap();
+        return Ok(());
+    }
 
-    // Try to get a handle with a connection to the database, otherwise exit
-    let connection = init_db_connection(db_uri).await.unwrap_or_else(|reason| {
-        println!("Could not connect to database. Reason: {}", reason);
+    // The program cannot operate without valid settings, so every problem with them is
+    // printed and the program exits.
+    let settings = Settings::load(&args).unwrap_or_else(|reason| {
+        println!("{}", reason);
 
         std::process::exit(1);
     });
 
-    // Create the common/shared state.
-    let lobby = Lobby::new(connection).start();
+    // The log filter can be changed when the settings are reloaded.
+    let log_handle = logging::init(&settings.server);
+
+    // The operators are guaranteed to exist since the settings have been validated.
+    let operators = settings.operators().unwrap();
 
-    HttpServer::new(move || App::new().service(ws_endpoint_route).data(lobby.clone()))
-        // The "0.0.0.0" means that the server accepts requests from any host (127.0.0.1, 192.168.x.x, etc..)
-        .bind("0.0.0.0:8080")?
-        // By default, `run()` starts the server with the same amount of threads as logical CPU cores on the host
-        // machine. This can be configured with the method `workers()`, which sets the starting number of threads
-        // when the server is started.
-        .run()
+    // Without the database or the static data there is nothing to serve.
+    let (storage, cluster) = startup::connect(&settings, &operators)
         .await
+        .unwrap_or_else(|reason| {
+            error!("{}", reason);
+
+            std::process::exit(1);
+        });
+
+    let simulator = startup::simulator(&settings, &operators).unwrap_or_else(|reason| {
+        error!("{}", reason);
+
+        std::process::exit(1);
+    });
+
+    let (lobby, reload_recipients) =
+        startup::start_services(&settings, operators, storage, cluster);
+
+    // Apply changes to the config file without restarting the server.
+    ConfigWatcher::new(
+        args.config_path(),
+        args.overrides.clone(),
+        settings.clone(),
+        reload_recipients,
+        log_handle,
+    )
+    .start();
+
+    let (server, _) = startup::run_http_server(&settings, lobby, simulator)?;
+
+    server.await
 }
@@ -1,25 +1,44 @@
 //! Messages used for internal communication between different actors (Lobby and WebsocketClient for example).
 
+use std::collections::HashMap;
+
 use actix::prelude::{Message, Recipient};
+use chrono::NaiveDate;
 use uuid::Uuid;
 
-use crate::protocol::client_protocol::GeoPosition;
+use crate::config::Settings;
+use crate::gtfs::trafiklab::RealtimeFeed;
+use crate::gtfs::validation::ValidatedFeed;
+use crate::protocol::client_protocol::{GeoPosition, GeometryFormat, IdentifierKind};
+use crate::protocol::server_protocol::{
+    CrowdingForecast, ErrorOutput, FeedStatus, LineHealthOutput, LineOutput,
+    PassengerInformationOutput, PunctualityGrouping, PunctualityOutput, ReadinessOutput,
+    RouteInformationOutput, Vehicle,
+};
+use crate::util::BoundingBox;
 
 /// WebsocketClient responds to this to pipe it through to the actual client.
 #[derive(Debug, Message)]
 #[rtype(result = "()")]
 pub struct WsMessage(pub String);
 
-/// Lobby sends this to itself internally to echo out vehicle positions.
+/// WebsocketClient closes the connection with the given reason when it receives this.
 #[derive(Debug, Message)]
 #[rtype(result = "()")]
-pub struct EchoPositions;
+pub struct CloseConnection(pub String);
+
+/// ConfigWatcher sends this to the lobby and every RealtimeFetcher when the config file has
+/// been reloaded, containing the new (already validated) settings.
+#[derive(Debug, Clone, Message)]
+#[rtype(result = "()")]
+pub struct ReloadSettings(pub Settings);
 
 /// WebsocketClient sends this to connect to the lobby.
 #[derive(Debug, Message)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub addr: Recipient<WsMessage>,
+    pub close: Recipient<CloseConnection>,
     pub self_id: Uuid,
 }
 
@@ -44,6 +63,21 @@ pub struct PositionUpdate {
 pub struct RouteRequest {
     pub self_id: Uuid,
     pub identifier: String,
+
+    /// What kind of identifier `identifier` is, which is inferred if it is not given.
+    pub kind: Option<IdentifierKind>,
+    pub format: GeometryFormat,
+
+    /// The zoom level of the client's map, which the shape is simplified for.
+    pub zoom: Option<u8>,
+}
+
+/// WebsocketClient sends this to request the headways between the vehicles on a line.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct LineHealth {
+    pub self_id: Uuid,
+    pub line: String,
 }
 
 /// WebsocketClient sends this to reserve a seat on a bus.
@@ -54,6 +88,17 @@ pub struct PassengerInfo {
     pub descriptor_id: String,
 }
 
+/// The lobby sends this to the reservation manager once it has forecast how crowded a bus that
+/// a client asked about is at its next stop, to send the passenger information again with the
+/// forecast.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct PassengerInfoForecast {
+    pub self_id: Uuid,
+    pub descriptor_id: String,
+    pub forecast: CrowdingForecast,
+}
+
 /// WebsocketClient sends this to reserve a seat on a bus.
 #[derive(Debug, Message)]
 #[rtype(result = "()")]
@@ -68,3 +113,223 @@ pub struct ReserveSeat {
 pub struct UnreserveSeat {
     pub self_id: Uuid,
 }
+
+/// The REST API sends this to get the latest vehicles of every operator, optionally only the
+/// ones within a bounding box.
+#[derive(Debug, Message)]
+#[rtype(result = "Vec<Vehicle>")]
+pub struct VehiclesRequest {
+    pub bbox: Option<BoundingBox>,
+}
+
+/// The REST API sends this to get information about a line.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<LineOutput, ErrorOutput>")]
+pub struct LineRequest {
+    pub line: String,
+}
+
+/// The REST API sends this to get the headways between the vehicles on a line.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<LineHealthOutput, ErrorOutput>")]
+pub struct LineHealthRequest {
+    pub line: String,
+}
+
+/// The REST API sends this to get the on-time performance of arrivals between two service
+/// dates (inclusive). The line and the stop may be namespaced, and decide the operator if it is
+/// not given.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<PunctualityOutput, ErrorOutput>")]
+pub struct PunctualityRequest {
+    pub operator: Option<String>,
+    pub line: Option<String>,
+    pub stop_id: Option<String>,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub group_by: Option<PunctualityGrouping>,
+}
+
+/// The REST API sends this to get the shape of a route, identified by a line number or a
+/// trip id in the same way as `RouteRequest`.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<RouteInformationOutput, ErrorOutput>")]
+pub struct RouteShapeRequest {
+    pub identifier: String,
+    pub kind: Option<IdentifierKind>,
+    pub format: GeometryFormat,
+    pub zoom: Option<u8>,
+}
+
+/// The REST API sends this to get passenger information for a bus.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<PassengerInformationOutput, ErrorOutput>")]
+pub struct PassengerInfoRequest {
+    pub descriptor_id: String,
+}
+
+/// The GTFS Realtime endpoints send this to get the vehicles of every operator as a
+/// VehiclePositions feed.
+#[derive(Debug, Message)]
+#[rtype(result = "RealtimeFeed")]
+pub struct VehiclePositionsFeedRequest;
+
+/// The GTFS Realtime endpoints send this to get the trip updates of every operator as a
+/// TripUpdates feed.
+#[derive(Debug, Message)]
+#[rtype(result = "RealtimeFeed")]
+pub struct TripUpdatesFeedRequest;
+
+/// The readiness endpoint sends this to check whether the server is ready to serve clients.
+#[derive(Debug, Message)]
+#[rtype(result = "ReadinessOutput")]
+pub struct ReadinessRequest;
+
+// The messages below are sent between the actors that the lobby is made up of.
+
+/// The session registry sends this to the broadcaster when a client has connected, so that
+/// it starts receiving messages.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub addr: Recipient<WsMessage>,
+    pub close: Recipient<CloseConnection>,
+    pub client_id: Uuid,
+}
+
+/// The session registry sends this to the broadcaster when a client has disconnected.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub client_id: Uuid,
+}
+
+/// Sent to the broadcaster to send a message to a single client.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct SendToClient {
+    pub client_id: Uuid,
+    pub message: String,
+}
+
+/// Sent to the broadcaster to send a message to every connected client.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct SendToEveryone(pub String);
+
+/// The reservation manager sends this to the broadcaster when a client has requested
+/// information about a vehicle, so that the client gets updates about it.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct WatchVehicle {
+    pub client_id: Uuid,
+    pub descriptor_id: String,
+}
+
+/// Sent to the broadcaster to send a message to every client that last requested information
+/// about a vehicle.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct SendToWatchers {
+    pub descriptor_id: String,
+    pub message: String,
+}
+
+/// The vehicle store sends this to the broadcaster when the enriched vehicles have changed,
+/// containing the latest vehicles of every operator.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct VehiclesChanged(pub Vec<Vehicle>);
+
+/// The session registry sends this to the feed ingester when a client has connected, so that
+/// the client is told right away whether live data is available.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct SendFeedStatuses {
+    pub client_id: Uuid,
+}
+
+/// Requests the current feed status of every operator from the feed ingester.
+#[derive(Debug, Message)]
+#[rtype(result = "Vec<(String, FeedStatus)>")]
+pub struct FeedStatusesRequest;
+
+/// RealtimeFetcher sends this to the lobby to find out how many clients are connected, since
+/// there is no point in fetching data that nobody is going to see.
+#[derive(Debug, Message)]
+#[rtype(result = "usize")]
+pub struct ClientCountRequest;
+
+/// The feed ingester sends this to the vehicle store when an operator's feed has been
+/// fetched and validated.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct ApplyFeed {
+    pub operator: String,
+    pub feed: ValidatedFeed,
+}
+
+/// The feed ingester sends this to the vehicle store when an operator's TripUpdates feed has
+/// been fetched, to republish the trip updates in it.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct ApplyTripUpdates {
+    pub operator: String,
+    pub feed: RealtimeFeed,
+}
+
+/// The feed ingester sends this to the history recorder when one of an operator's feeds has
+/// been fetched, to record the arrivals in it.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct RecordArrivals {
+    pub operator: String,
+    pub feed: RealtimeFeed,
+}
+
+/// Requests the latest vehicles on a line from the vehicle store. `line` is not namespaced.
+#[derive(Debug, Message)]
+#[rtype(result = "Vec<Vehicle>")]
+pub struct LineVehiclesRequest {
+    pub operator: String,
+    pub line: String,
+}
+
+/// Requests the latest position of a vehicle from the vehicle store. `descriptor_id` is not
+/// namespaced.
+#[derive(Debug, Message)]
+#[rtype(result = "Option<Vehicle>")]
+pub struct VehicleRequest {
+    pub operator: String,
+    pub descriptor_id: String,
+}
+
+/// Asks the vehicle store to build a VehiclePositions feed, where `passenger_info` maps
+/// descriptor ids to passenger information.
+#[derive(Debug, Message)]
+#[rtype(result = "RealtimeFeed")]
+pub struct BuildVehiclePositionsFeed {
+    pub passenger_info: HashMap<String, PassengerInformationOutput>,
+}
+
+/// Requests the passenger information of a vehicle from the reservation manager, without
+/// creating it if there is none yet.
+#[derive(Debug, Message)]
+#[rtype(result = "PassengerInformationOutput")]
+pub struct VehiclePassengerInfoRequest {
+    pub descriptor_id: String,
+}
+
+/// Requests the passenger information of every vehicle that it is known for from the
+/// reservation manager.
+#[derive(Debug, Message)]
+#[rtype(result = "HashMap<String, PassengerInformationOutput>")]
+pub struct AllPassengerInfoRequest;
+
+/// The session registry sends this to the reservation manager when a client has
+/// disconnected.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct ClientDisconnected {
+    pub client_id: Uuid,
+}
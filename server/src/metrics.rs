@@ -0,0 +1,165 @@
+//! Prometheus metrics for the server.
+//!
+//! Every metric is registered in a registry of our own (instead of the default registry) so
+//! that only the server's own metrics are exposed by the "/metrics" endpoint.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+lazy_static! {
+    /// The registry that contains every metric below.
+    static ref REGISTRY: Registry = Registry::new();
+
+    /// The number of clients that currently are connected over WebSocket.
+    pub static ref CONNECTED_CLIENTS: IntGauge = register(IntGauge::new(
+        "busplus_connected_clients",
+        "Number of connected WebSocket clients."
+    ));
+
+    /// The number of messages received from clients, by `ClientInput` type.
+    pub static ref CLIENT_MESSAGES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "busplus_client_messages_total",
+            "Number of messages received from clients, by message type."
+        ),
+        &["type"]
+    ));
+
+    /// The number of messages to clients that were dropped, by reason ("mailbox_full" when
+    /// the client could not keep up, or "coalesced" when newer vehicle positions replaced
+    /// them).
+    pub static ref DROPPED_MESSAGES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "busplus_dropped_messages_total",
+            "Number of messages to clients that were dropped, by reason."
+        ),
+        &["reason"]
+    ));
+
+    /// The number of clients that were disconnected for being too slow.
+    pub static ref SLOW_CLIENT_DISCONNECTS: IntCounter = register(IntCounter::new(
+        "busplus_slow_client_disconnects_total",
+        "Number of clients that were disconnected because they could not keep up."
+    ));
+
+    /// How long requests to realtime APIs take, by operator and outcome.
+    pub static ref FEED_FETCH_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "busplus_feed_fetch_seconds",
+            "Time spent fetching realtime feeds, by operator and outcome."
+        )
+        .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+        &["operator", "outcome"]
+    ));
+
+    /// The number of failed requests to realtime APIs, by operator and reason.
+    pub static ref FEED_FETCH_FAILURES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "busplus_feed_fetch_failures_total",
+            "Number of failed realtime feed fetches, by operator and reason."
+        ),
+        &["operator", "reason"]
+    ));
+
+    /// The number of entities in realtime feeds that were skipped because they were
+    /// invalid, by operator and reason.
+    pub static ref INVALID_ENTITIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "busplus_invalid_entities_total",
+            "Number of invalid realtime feed entities that were skipped, by operator and reason."
+        ),
+        &["operator", "reason"]
+    ));
+
+    /// How long queries to the database take, by collection.
+    pub static ref DB_QUERY_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "busplus_db_query_seconds",
+            "Time spent on database queries, by collection."
+        )
+        .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+        &["collection"]
+    ));
+
+    /// The number of seats that currently are reserved.
+    pub static ref ACTIVE_RESERVATIONS: IntGauge = register(IntGauge::new(
+        "busplus_active_reservations",
+        "Number of seats that currently are reserved."
+    ));
+
+    /// The number of reservation requests, by action and outcome.
+    pub static ref RESERVATIONS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "busplus_reservations_total",
+            "Number of reservation requests, by action and outcome."
+        ),
+        &["action", "outcome"]
+    ));
+}
+
+/// Registers a metric in `REGISTRY`.
+fn register<M>(metric: prometheus::Result<M>) -> M
+where
+    M: prometheus::core::Collector + Clone + 'static,
+{
+    // The metrics are defined above with valid names and unique labels, so neither creating
+    // nor registering them can fail.
+    let metric = metric.expect("metric is valid");
+
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric is only registered once");
+
+    metric
+}
+
+/// Returns every metric in the Prometheus text format.
+pub fn gather() -> String {
+    // Metrics are registered when they are first used, but every metric should be exposed
+    // even if nothing has happened yet.
+    lazy_static::initialize(&CONNECTED_CLIENTS);
+    lazy_static::initialize(&CLIENT_MESSAGES);
+    lazy_static::initialize(&DROPPED_MESSAGES);
+    lazy_static::initialize(&SLOW_CLIENT_DISCONNECTS);
+    lazy_static::initialize(&FEED_FETCH_SECONDS);
+    lazy_static::initialize(&FEED_FETCH_FAILURES);
+    lazy_static::initialize(&INVALID_ENTITIES);
+    lazy_static::initialize(&DB_QUERY_SECONDS);
+    lazy_static::initialize(&ACTIVE_RESERVATIONS);
+    lazy_static::initialize(&RESERVATIONS);
+
+    let mut buffer = Vec::new();
+
+    // Encoding into a vector cannot fail.
+    let _ = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer);
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() {
+        CLIENT_MESSAGES.with_label_values(&["reserve-seat"]).inc();
+        FEED_FETCH_FAILURES
+            .with_label_values(&["ul", "timeout"])
+            .inc();
+        INVALID_ENTITIES
+            .with_label_values(&["ul", "missing_position"])
+            .inc();
+
+        let output = gather();
+
+        assert!(output.contains("busplus_client_messages_total{type=\"reserve-seat\"}"));
+        assert!(output.contains("busplus_feed_fetch_failures_total"));
+        assert!(output.contains(
+            "busplus_invalid_entities_total{operator=\"ul\",reason=\"missing_position\"}"
+        ));
+        assert!(output.contains("# TYPE busplus_connected_clients gauge"));
+    }
+}
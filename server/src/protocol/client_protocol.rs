@@ -15,7 +15,10 @@ pub enum ClientInput {
     GetLineInformation(LineInformation),
 
     #[serde(rename = "get-route-info")]
-    GetRouteInformation(Identifier),
+    GetRouteInformation(RouteInformationRequest),
+
+    #[serde(rename = "get-line-health")]
+    GetLineHealth(LineInformation),
 
     #[serde(rename = "geo-position-update")]
     GeoPositionUpdate(GeoPosition),
@@ -30,6 +33,21 @@ pub enum ClientInput {
     UnreserveSeat,
 }
 
+impl ClientInput {
+    /// Returns the "type" of the message, which is the same as its JSON key name.
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            ClientInput::GetLineInformation(_) => "get-line-info",
+            ClientInput::GetRouteInformation(_) => "get-route-info",
+            ClientInput::GetLineHealth(_) => "get-line-health",
+            ClientInput::GeoPositionUpdate(_) => "geo-position-update",
+            ClientInput::GetPassengerInformation(_) => "get-passenger-info",
+            ClientInput::ReserveSeat(_) => "reserve-seat",
+            ClientInput::UnreserveSeat => "unreserve-seat",
+        }
+    }
+}
+
 /// Contains a line number
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -37,12 +55,55 @@ pub struct LineInformation {
     pub line: String,
 }
 
-/// Contains an identifier. Typically used for cases where the identifier can have different meaning
-/// depending on the value
+/// Contains an identifier for a route and how its shape should be sent.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Identifier {
+pub struct RouteInformationRequest {
     pub id: String,
+
+    /// What kind of identifier `id` is. If left out, ids of up to three characters are line
+    /// numbers and longer numeric ids are trip ids.
+    pub kind: Option<IdentifierKind>,
+
+    /// The format of the shape, which is GeoJSON if left out.
+    #[serde(default)]
+    pub format: GeometryFormat,
+
+    /// The zoom level of the client's map. The shape is simplified to what is visible at this
+    /// zoom level, or sent in full if left out.
+    pub zoom: Option<u8>,
+}
+
+/// The kinds of identifiers that a route can be looked up by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentifierKind {
+    /// A line number as shown on the vehicles, e.g. "5" or "5X".
+    Line,
+
+    /// A route id from the static data.
+    RouteId,
+
+    /// A trip id from the static data.
+    TripId,
+
+    /// A shape id from the static data.
+    ShapeId,
+
+    /// The descriptor id of a vehicle, which gives the route of the trip it is currently on.
+    DescriptorId,
+}
+
+/// The formats that the shape of a route can be sent in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeometryFormat {
+    /// A GeoJSON "LineString".
+    #[default]
+    Geojson,
+
+    /// An encoded polyline (https://developers.google.com/maps/documentation/utilities/polylinealgorithm).
+    Polyline,
 }
 
 /// Position data from the client. Contains maximum distance and a position.
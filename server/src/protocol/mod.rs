@@ -2,3 +2,6 @@
 
 pub mod client_protocol;
 pub mod server_protocol;
+
+/// Separates the operator's name from an id in a namespaced id, e.g. "ul:9031003".
+pub const NAMESPACE_SEPARATOR: char = ':';
@@ -3,6 +3,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::gtfs::transit_realtime::Position;
+use crate::protocol::NAMESPACE_SEPARATOR;
 
 /// Defines possible errors that might occur on the server side.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -16,6 +17,7 @@ pub enum ErrorType {
     RouteInfo,
     Reserve,
     Unreserve,
+    PassengerInfo,
 }
 
 /// This is all possible output the server should be able to send to the
@@ -34,10 +36,16 @@ pub enum ServerOutput {
     VehiclePositions(VehiclePositionsOutput),
 
     #[serde(rename = "passenger-info")]
-    PassengerInformation(PassengerInformationOutput),
+    PassengerInformation(VehiclePassengerInformationOutput),
 
     #[serde(rename = "route-info")]
     RouteInformation(RouteInformationOutput),
+
+    #[serde(rename = "feed-status")]
+    FeedStatus(FeedStatusOutput),
+
+    #[serde(rename = "line-health")]
+    LineHealth(LineHealthOutput),
 }
 
 impl ServerOutput {
@@ -77,12 +85,145 @@ pub struct Vehicle {
     pub position: Position,
 }
 
+impl Vehicle {
+    /// Returns the trip id without its namespace. The trip ids of vehicles are always
+    /// namespaced with the operator that the vehicle belongs to.
+    pub fn plain_trip_id(&self) -> Option<&str> {
+        let trip_id = self.trip_id.as_deref()?;
+
+        Some(
+            trip_id
+                .split_once(NAMESPACE_SEPARATOR)
+                .map_or(trip_id, |(_, trip_id)| trip_id),
+        )
+    }
+}
+
 /// Represents passenger information for a bus.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PassengerInformationOutput {
     pub capacity: i32,
     pub passengers: i32,
+
+    /// How crowded the bus usually is at its next stop. Only given in answers to requests
+    /// for passenger information, and not in the updates that follow reservations.
+    #[serde(default)]
+    pub forecast: Option<CrowdingForecast>,
+}
+
+/// Passenger information for a bus together with its (namespaced) descriptor id, so that
+/// clients can tell which bus it is about.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VehiclePassengerInformationOutput {
+    pub descriptor_id: String,
+
+    #[serde(flatten)]
+    pub passenger_info: PassengerInformationOutput,
+}
+
+/// How crowded vehicles usually are at a stop, forecast from the occupancy of past arrivals
+/// at about the same time on the same kind of day.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrowdingForecast {
+    /// The stop, namespaced with the operator.
+    pub stop_id: String,
+
+    /// The kind of day and the scheduled time of day ("HH:MM") that the forecast is for.
+    pub day_type: DayType,
+    pub time: String,
+
+    /// The median occupancy of the past arrivals, in percent of the capacity.
+    pub occupancy: u32,
+
+    /// The number of past arrivals that the forecast is made from.
+    pub observations: u32,
+}
+
+/// The kinds of days that have different timetables and different crowding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DayType {
+    Weekday,
+    Saturday,
+    Sunday,
+}
+
+/// How reliable the realtime data currently is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedStatus {
+    /// Live data is received as expected.
+    Ok,
+
+    /// Live data is delayed or the external API is having problems.
+    Degraded,
+
+    /// No live data is available.
+    Stale,
+}
+
+/// Represents the health of the realtime feed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedStatusOutput {
+    pub timestamp: u64,
+
+    // The operator whose feed the status describes.
+    pub operator: String,
+
+    pub status: FeedStatus,
+
+    // POSIX timestamp of the last time data was successfully fetched.
+    pub last_update: Option<u64>,
+
+    // POSIX timestamp of when the latest data was created by the provider.
+    pub feed_timestamp: Option<u64>,
+
+    // How old (in seconds) the latest data is.
+    pub data_age: Option<u64>,
+
+    // The number of entities of each kind in the latest data.
+    pub entities: EntityCounts,
+
+    // The total number of invalid entities that have been skipped since the server started.
+    pub total_invalid_entities: u64,
+
+    pub consecutive_failures: u32,
+    pub message: Option<String>,
+}
+
+/// The number of entities of each kind in a realtime feed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntityCounts {
+    pub vehicles: usize,
+    pub trip_updates: usize,
+    pub alerts: usize,
+    pub deleted: usize,
+    pub invalid: usize,
+}
+
+/// Represents whether the server is ready to serve clients.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadinessOutput {
+    pub ready: bool,
+    // Whether the database responds.
+    pub database: bool,
+    pub operators: Vec<OperatorReadiness>,
+}
+
+/// Represents whether the data of an operator is available.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperatorReadiness {
+    pub operator: String,
+    pub feed_status: FeedStatus,
+    // Whether the operator's database contains static data.
+    pub static_data: bool,
 }
 
 /// Represent a list of lines.
@@ -90,41 +231,229 @@ pub struct PassengerInformationOutput {
 #[serde(rename_all = "camelCase")]
 pub struct RouteInformationOutput {
     pub timestamp: u64,
-    pub route: Vec<RouteNode>,
+
+    /// The shape that most trips use.
+    pub route: RouteGeometry,
+
+    /// Every shape of the route, grouped by direction and headsign.
+    pub directions: Vec<RouteDirection>,
 }
 
-/// Represent a route node.
+/// The trips of a route that go in the same direction with the same headsign.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct RouteNode {
-    pub lat: String,
-    pub lng: String,
-    pub sequence: i32,
+pub struct RouteDirection {
+    pub direction_id: Option<u8>,
+    pub headsign: Option<String>,
+
+    /// The share (0 to 1) of the route's trips that go in this direction.
+    pub share: f64,
+
+    /// The shapes of the trips in this direction, the most used first.
+    pub variants: Vec<RouteVariant>,
 }
 
-/// Represent a line.
+/// A shape that some of the trips of a route use.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Line {
-    pub timestamp: String,
+pub struct RouteVariant {
+    pub shape_id: String,
+
+    /// The number of trips that use the shape.
+    pub trips: u32,
+
+    /// The share (0 to 1) of the route's trips that use the shape.
+    pub share: f64,
+
+    pub route: RouteGeometry,
+
+    /// The stops along the shape, in the order they are passed.
+    pub stops: Vec<RouteStop>,
+}
+
+/// A stop along the shape of a route.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteStop {
+    pub stop_id: String,
+    pub name: String,
+    pub lat: f64,
+    pub lng: f64,
+    pub platform: Option<String>,
+
+    /// How far (in metres) along the shape the stop is, measured along the shape as it is
+    /// sent, so that the stop can be placed exactly on the drawn line.
+    pub distance: f64,
+
+    /// The scheduled times (like "08:15:00", which can be past "24:00:00" for trips that run
+    /// past midnight). Only given when the route is a single trip.
+    pub arrival_time: Option<String>,
+    pub departure_time: Option<String>,
+
+    /// How crowded the trip usually is at the stop. Only given when the route is a single
+    /// trip and the punctuality history is enabled.
+    pub forecast: Option<CrowdingForecast>,
+}
+
+/// The shape of a route, with points in the order they are driven.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum RouteGeometry {
+    /// A GeoJSON "LineString", where every coordinate is [longitude, latitude].
+    LineString { coordinates: Vec<[f64; 2]> },
+
+    /// The coordinates encoded with Google's encoded polyline algorithm.
+    EncodedPolyline { polyline: String },
+}
+
+/// Represents a line and the vehicles that currently drive on it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineOutput {
+    pub timestamp: u64,
+    // The line number, namespaced with the operator.
+    pub line: String,
+    // The GTFS route id, namespaced with the operator.
+    pub route_id: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub vehicles: Vec<Vehicle>,
+}
+
+/// Represents how evenly the vehicles on a line are spread out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineHealthOutput {
+    pub timestamp: u64,
+    // The line number, namespaced with the operator.
     pub line: String,
-    pub vehicles: u32,
-    pub stops: Vec<Stop>,
+    // The GTFS route id, namespaced with the operator.
+    pub route_id: String,
+
+    /// The vehicles on the line in each direction, and the headways between them.
+    pub directions: Vec<DirectionHealth>,
+
+    /// The number of vehicles on the line that could not be placed on the timetable of their
+    /// trip, e.g. because the trip is missing from the static data.
+    pub untracked: u32,
 }
 
-/// Represent a coordinate.
+/// The vehicles on a line that go in the same direction.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Coordinate {
-    pub lat: f32,
-    pub lng: f32,
+pub struct DirectionHealth {
+    pub direction_id: Option<u8>,
+
+    /// The headsign of the vehicle that has come the furthest.
+    pub headsign: Option<String>,
+
+    /// The descriptor ids of the vehicles, the one that has come the furthest first.
+    pub vehicles: Vec<String>,
+
+    /// The headway between every vehicle and the one behind it.
+    pub headways: Vec<Headway>,
+
+    /// The number of headways that are bunched and that are gaps.
+    pub bunched: u32,
+    pub gaps: u32,
 }
 
-// Represent a vehicle with an ID and a position.
+/// The headway between two consecutive vehicles in the same direction.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Stop {
-    pub id: String,
-    pub lines: Vec<String>,
-    pub position: Position,
+pub struct Headway {
+    /// The descriptor ids of the vehicle in front and of the one behind it.
+    pub leading: String,
+    pub following: String,
+
+    /// How long (in seconds) the following vehicle is scheduled to take to get to where the
+    /// leading vehicle is now.
+    pub actual: i64,
+
+    /// How long (in seconds) apart the trips of the vehicles are scheduled to depart.
+    pub scheduled: i64,
+
+    pub status: HeadwayStatus,
+}
+
+/// Whether a headway is about as long as scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadwayStatus {
+    Regular,
+
+    /// The vehicles are much closer than scheduled, usually because the leading vehicle is
+    /// late and picks up the passengers of both.
+    Bunched,
+
+    /// The vehicles are much further apart than scheduled.
+    Gap,
+}
+
+/// Represents the on-time performance of the arrivals that were observed between two dates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PunctualityOutput {
+    pub timestamp: u64,
+    pub operator: String,
+
+    /// The line and the stop that the arrivals are limited to, namespaced with the operator.
+    pub line: Option<String>,
+    pub stop_id: Option<String>,
+
+    /// The first and the last service date ("YYYY-MM-DD"), both included.
+    pub from: String,
+    pub to: String,
+
+    /// How early and how late (in seconds) an arrival can be and still be on time.
+    pub early_tolerance: u32,
+    pub late_tolerance: u32,
+
+    /// Every arrival that matches, and the same arrivals grouped by `group_by`.
+    pub total: Punctuality,
+    pub group_by: Option<PunctualityGrouping>,
+    pub groups: Vec<PunctualityGroup>,
+}
+
+/// How many arrivals were early, on time and late.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Punctuality {
+    pub arrivals: u32,
+    pub early: u32,
+    pub on_time: u32,
+    pub late: u32,
+
+    /// The share (0 to 1) of the arrivals that were on time, if there were any.
+    pub on_time_share: Option<f64>,
+
+    /// The average delay in seconds, where early arrivals count as negative delays.
+    pub average_delay: Option<f64>,
+}
+
+/// The on-time performance of the arrivals that belong to a group, e.g. an hour of the day.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PunctualityGroup {
+    pub key: String,
+
+    #[serde(flatten)]
+    pub punctuality: Punctuality,
+}
+
+/// What arrivals can be grouped by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PunctualityGrouping {
+    /// The namespaced line number.
+    Line,
+
+    /// The namespaced stop id.
+    Stop,
+
+    /// The hour of the day (0 to 23) that the arrival was scheduled at.
+    Hour,
+
+    /// The day of the week (e.g. "monday") that the arrival was scheduled at.
+    Weekday,
 }
@@ -0,0 +1,161 @@
+//! Reloads the settings while the server is running.
+//!
+//! The config file is checked for changes every few seconds, and on Unix systems a reload can
+//! also be triggered by sending SIGHUP to the process. New settings are validated before they
+//! are used, so a broken config file never replaces working settings. Changes that can be
+//! applied to the running server are sent to the lobby and the fetchers, and changes that
+//! require a restart are logged.
+
+use std::env;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use actix::prelude::{Actor, AsyncContext, Context, Recipient, StreamHandler};
+use tracing::{error, info, warn};
+
+use crate::config::Settings;
+use crate::logging::LogHandle;
+use crate::messages::ReloadSettings;
+
+/// How often the config file is checked for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watches the config file and forwards new settings to everyone that can apply them.
+pub struct ConfigWatcher {
+    /// Path to the config file.
+    path: String,
+
+    /// Overrides from the command line, which are applied on every reload.
+    overrides: Vec<String>,
+
+    /// The settings that are currently in use, which do not include changes that require a
+    /// restart.
+    current: Settings,
+
+    /// When the config file was last modified, as of the latest check.
+    last_modified: Option<SystemTime>,
+
+    /// Where new settings are sent.
+    recipients: Vec<Recipient<ReloadSettings>>,
+
+    /// Used to change the log filter.
+    log_handle: LogHandle,
+}
+
+impl ConfigWatcher {
+    pub fn new(
+        path: String,
+        overrides: Vec<String>,
+        current: Settings,
+        recipients: Vec<Recipient<ReloadSettings>>,
+        log_handle: LogHandle,
+    ) -> Self {
+        let last_modified = modified_time(&path);
+
+        ConfigWatcher {
+            path,
+            overrides,
+            current,
+            last_modified,
+            recipients,
+            log_handle,
+        }
+    }
+
+    /// Reloads the settings if the config file has been modified since the last check.
+    fn check_for_changes(&mut self) {
+        let modified = modified_time(&self.path);
+
+        if modified != self.last_modified {
+            self.last_modified = modified;
+            self.reload();
+        }
+    }
+
+    /// Loads and validates the settings and applies the ones that have changed.
+    fn reload(&mut self) {
+        let settings = match Settings::load_from(&self.path, env::vars(), &self.overrides) {
+            Ok(settings) => settings,
+            Err(reason) => {
+                error!(path = %self.path, "Ignoring changes to the config file. {}", reason);
+                return;
+            }
+        };
+
+        let changes = self.current.changes(&settings);
+
+        if changes.is_empty() {
+            return;
+        }
+
+        if !changes.reloadable.is_empty() {
+            info!(
+                path = %self.path,
+                changes = %changes.reloadable.join(", "),
+                "Reloaded settings."
+            );
+        }
+
+        if !changes.restart_required.is_empty() {
+            warn!(
+                path = %self.path,
+                changes = %changes.restart_required.join(", "),
+                "Some changes require a restart to take effect."
+            );
+        }
+
+        // Changes that require a restart are left out of what is applied, so no actor can
+        // apply them by accident, and they are compared against (and reported) again on the
+        // next reload.
+        self.current = self.current.with_reloaded(&settings, &changes);
+
+        if let Err(reason) = self.log_handle.set_filter(&self.current.server.log_level) {
+            error!("Could not change the log filter. Reason: {}", reason);
+        }
+
+        for recipient in &self.recipients {
+            let _ = recipient.do_send(ReloadSettings(self.current.clone()));
+        }
+    }
+}
+
+/// Returns when the file at `path` was last modified, or None if it cannot be read.
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+impl Actor for ConfigWatcher {
+    type Context = Context<Self>;
+
+    // This method is called when the watcher is started.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(CONFIG_POLL_INTERVAL, |act, _| act.check_for_changes());
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            match signal(SignalKind::hangup()) {
+                Ok(hangups) => {
+                    ctx.add_stream(hangups);
+                }
+                Err(err) => warn!("Could not listen for SIGHUP. Reason: {}", err),
+            }
+        }
+    }
+}
+
+/// Every item in the stream is a received SIGHUP.
+impl StreamHandler<()> for ConfigWatcher {
+    fn handle(&mut self, _: (), _: &mut Self::Context) {
+        info!(path = %self.path, "Received SIGHUP, reloading the config file.");
+
+        self.reload();
+    }
+
+    // The stream of signals never ends on its own, and the watcher should keep checking the
+    // file even if it does.
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
@@ -0,0 +1,179 @@
+//! Starts the parts of the server: the storage, the lobby, the realtime fetchers, the
+//! simulator and the HTTP server.
+//!
+//! `main` starts them from the settings in the config file, and the end-to-end tests (see
+//! `e2e`) start them the same way with settings of their own.
+
+use std::io;
+use std::net::SocketAddr;
+
+use actix::prelude::{Actor, Addr, Recipient};
+use actix_web::dev::{Server, Service};
+use actix_web::{App, HttpServer};
+use tracing::{info, Instrument};
+
+use crate::cluster::Cluster;
+use crate::config::{ClusterBackend, Settings};
+use crate::database::init_db_connection;
+use crate::endpoints::{
+    health_endpoint, line_endpoint, line_health_endpoint, metrics_endpoint, passengers_endpoint,
+    punctuality_endpoint, readiness_endpoint, route_shape_endpoint, simulated_feed_endpoint,
+    trip_updates_feed_endpoint, vehicle_positions_feed_endpoint, vehicles_endpoint, ws_endpoint,
+};
+use crate::gtfs::fetcher::RealtimeFetcher;
+use crate::gtfs::operator::Operator;
+use crate::gtfs::simulator::Simulator;
+use crate::lobby::Lobby;
+use crate::logging;
+use crate::messages::ReloadSettings;
+use crate::store::{MongoReservations, Storage};
+
+/// Connects to the database if a backend needs it, and sets up the storage and the cluster.
+pub async fn connect(
+    settings: &Settings,
+    operators: &[Operator],
+) -> Result<(Storage, Cluster), String> {
+    // Only the backends that keep their data in MongoDB need a connection to the database. The
+    // URI is guaranteed to exist when it is needed.
+    let connection = match settings.uses_database() {
+        true => {
+            let db_uri = settings.database.uri.clone().unwrap();
+
+            let connection = init_db_connection(&db_uri)
+                .await
+                .map_err(|reason| format!("Could not connect to database. Reason: {}", reason))?;
+
+            Some(connection)
+        }
+        false => None,
+    };
+
+    // Static data, reservations and the punctuality history.
+    let mut storage = Storage::from_settings(
+        &settings.storage,
+        &settings.history,
+        operators,
+        connection.as_ref(),
+    )
+    .map_err(|reason| format!("Could not set up storage. Reason: {}", reason))?;
+
+    // Shares reservations and realtime data with other instances of the server. Instances
+    // that share state keep their reservations in the cluster's database.
+    let cluster = Cluster::from_settings(&settings.cluster, connection.as_ref()).await;
+
+    if let (ClusterBackend::Mongodb, Some(connection)) = (settings.cluster.backend, &connection) {
+        let database = connection.database(&settings.cluster.database);
+        storage.reservations = Box::new(MongoReservations::new(&database));
+    }
+
+    Ok((storage, cluster))
+}
+
+/// Reads the timetables that the simulator needs if it is enabled.
+pub fn simulator(settings: &Settings, operators: &[Operator]) -> Result<Option<Simulator>, String> {
+    if !settings.simulator.enabled {
+        return Ok(None);
+    }
+
+    let simulator = Simulator::from_settings(&settings.simulator, operators)?;
+
+    let mut simulated = simulator.operators().collect::<Vec<_>>();
+    simulated.sort_unstable();
+    info!(operators = %simulated.join(", "), "Simulating vehicles.");
+
+    Ok(Some(simulator))
+}
+
+/// Starts the lobby and a realtime fetcher for every operator. Returns the lobby and every
+/// actor that should be told when the settings are reloaded.
+pub fn start_services(
+    settings: &Settings,
+    operators: Vec<Operator>,
+    storage: Storage,
+    cluster: Cluster,
+) -> (Addr<Lobby>, Vec<Recipient<ReloadSettings>>) {
+    // Create the common/shared state.
+    let lobby = Lobby::new(
+        storage,
+        operators.clone(),
+        settings.limits.clone(),
+        settings.history.clone(),
+        cluster.clone(),
+    )
+    .start();
+
+    // Start fetching realtime data for every operator in the background. Fetched data is
+    // published on the event bus, and the lobby on every instance echoes it out to all
+    // connected clients.
+    let mut reload_recipients = vec![lobby.clone().recipient()];
+
+    for operator in operators {
+        let fetcher = RealtimeFetcher::new(
+            operator,
+            settings,
+            cluster.clone(),
+            lobby.clone().recipient(),
+        )
+        .start();
+
+        reload_recipients.push(fetcher.recipient());
+    }
+
+    (lobby, reload_recipients)
+}
+
+/// Binds the HTTP server to the address in the settings and starts it. Returns the server,
+/// which resolves when it stops, and the addresses it listens on (the port is picked by the
+/// operating system if the address has port 0). The simulated feeds are only served if there
+/// is a simulator, and the punctuality of arrivals only if the history is enabled.
+pub fn run_http_server(
+    settings: &Settings,
+    lobby: Addr<Lobby>,
+    simulator: Option<Simulator>,
+) -> io::Result<(Server, Vec<SocketAddr>)> {
+    let app_settings = settings.clone();
+
+    let server = HttpServer::new(move || {
+        let app = App::new()
+            // Everything that is logged during a request belongs to the request's span.
+            .wrap_fn(|req, srv| {
+                let span = logging::request_span(&req);
+                srv.call(req).instrument(span)
+            })
+            .service(ws_endpoint)
+            .service(vehicles_endpoint)
+            .service(passengers_endpoint)
+            .service(line_endpoint)
+            .service(line_health_endpoint)
+            .service(route_shape_endpoint)
+            .service(vehicle_positions_feed_endpoint)
+            .service(trip_updates_feed_endpoint)
+            .service(health_endpoint)
+            .service(readiness_endpoint)
+            .service(metrics_endpoint)
+            .data(lobby.clone())
+            .data(app_settings.clone());
+
+        let app = match &simulator {
+            Some(simulator) => app.data(simulator.clone()).service(simulated_feed_endpoint),
+            None => app,
+        };
+
+        match app_settings.history.enabled {
+            true => app.service(punctuality_endpoint),
+            false => app,
+        }
+    })
+    .bind(&settings.server.bind_address)?;
+
+    // By default, `run()` starts the server with the same amount of threads as logical CPU cores
+    // on the host machine.
+    let server = match settings.server.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+
+    let addresses = server.addrs();
+
+    Ok((server.run(), addresses))
+}
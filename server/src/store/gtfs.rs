@@ -0,0 +1,209 @@
+//! Reads static data from a GTFS zip, such as the ones from Trafiklab's Static API.
+
+use std::fs::File;
+use std::io::{Read, Seek};
+
+use serde::de::DeserializeOwned;
+use tracing::warn;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::gtfs::transit_static::{Calendar, CalendarDates, Route, Shape, Stop, StopTime, Trip};
+
+/// The tables of a GTFS feed that the server uses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StaticTables {
+    pub routes: Vec<Route>,
+    pub trips: Vec<Trip>,
+    pub shapes: Vec<Shape>,
+    pub stop_times: Vec<StopTime>,
+    pub stops: Vec<Stop>,
+
+    /// The days that services run on, which only the simulator uses.
+    pub calendar: Vec<Calendar>,
+    pub calendar_dates: Vec<CalendarDates>,
+}
+
+impl StaticTables {
+    /// Reads the GTFS zip at `path`.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| err.to_string())?;
+
+        StaticTables::from_zip(file)
+    }
+
+    /// Reads a GTFS zip. Routes, trips and shapes are required, while stops, stop times and
+    /// calendars can be left out. Rows that cannot be parsed are skipped, but a required table
+    /// without any rows fails the import.
+    pub fn from_zip<R: Read + Seek>(reader: R) -> Result<Self, String> {
+        let mut archive = ZipArchive::new(reader).map_err(|err| err.to_string())?;
+
+        Ok(StaticTables {
+            routes: read_table(&mut archive, "routes.txt", true)?,
+            trips: read_table(&mut archive, "trips.txt", true)?,
+            shapes: read_table(&mut archive, "shapes.txt", true)?,
+            stop_times: read_table(&mut archive, "stop_times.txt", false)?,
+            stops: read_table(&mut archive, "stops.txt", false)?,
+            calendar: read_table(&mut archive, "calendar.txt", false)?,
+            calendar_dates: read_table(&mut archive, "calendar_dates.txt", false)?,
+        })
+    }
+}
+
+/// Reads every row of the file `name` in a GTFS zip. A file that is not `required` is read as
+/// an empty table if it is missing, while a file that is must have at least one row that can
+/// be parsed.
+fn read_table<T: DeserializeOwned, R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+    required: bool,
+) -> Result<Vec<T>, String> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) if !required => return Ok(Vec::new()),
+        Err(err) => return Err(format!("{}: {}", name, err)),
+    };
+
+    let mut reader = csv::Reader::from_reader(file);
+    let mut rows = Vec::new();
+    let mut skipped = 0;
+
+    for result in reader.deserialize() {
+        match result {
+            Ok(row) => rows.push(row),
+            Err(_) => skipped += 1,
+        }
+    }
+
+    if skipped > 0 {
+        warn!(
+            file = name,
+            skipped, "Skipped rows that could not be parsed."
+        );
+    }
+
+    if required && rows.is_empty() {
+        return Err(format!(
+            "{}: no rows could be read ({} could not be parsed)",
+            name, skipped
+        ));
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::{FileOptions, ZipWriter};
+
+    /// Creates a GTFS zip with the given files.
+    pub fn gtfs_zip(files: &[(&str, &str)]) -> Cursor<Vec<u8>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+
+        for (name, contents) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+
+        let mut zip = writer.finish().unwrap();
+        zip.set_position(0);
+        zip
+    }
+
+    /// A small feed for UL with line 5, which has a single trip.
+    pub fn line_5() -> Cursor<Vec<u8>> {
+        gtfs_zip(&[
+            (
+                "routes.txt",
+                "route_id,agency_id,route_short_name,route_long_name,route_type,route_desc\n\
+                 9011003000500000,1,5,Stenhagen - Gottsunda,700,Stadsbuss\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id,trip_headsign,direction_id,shape_id\n\
+                 9011003000500000,1,141010000123456789,Gottsunda,0,1\n",
+            ),
+            (
+                "shapes.txt",
+                "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence,shape_dist_traveled\n\
+                 1,59.859,17.638,2,111\n\
+                 1,59.858,17.638,1,0\n\
+                 1,not a number,17.638,3,\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign,\
+                 pickup_type,drop_off_type,shape_dist_traveled,timepoint\n\
+                 141010000123456789,08:02:00,08:02:00,9022003700021002,2,,0,0,,1\n\
+                 141010000123456789,08:00:00,08:00:00,9022003700021001,1,,0,0,,1\n",
+            ),
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station,platform_code\n\
+                 9022003700021001,Centralstationen,59.858,17.638,0,,A\n\
+                 9022003700021002,Stora torget,59.859,17.638,0,,\n",
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_from_zip() {
+        let tables = StaticTables::from_zip(line_5()).unwrap();
+
+        assert_eq!(tables.routes.len(), 1);
+        assert_eq!(tables.routes[0].route_short_name, "5");
+        assert_eq!(tables.trips[0].trip_headsign.as_deref(), Some("Gottsunda"));
+        assert_eq!(tables.shapes.len(), 3);
+        assert_eq!(tables.stop_times.len(), 2);
+        assert_eq!(tables.stops[0].platform_code.as_deref(), Some("A"));
+        assert_eq!(tables.stops[1].platform_code, None);
+
+        // Optional columns can be left empty or out.
+        assert_eq!(tables.stops[0].location_type, Some(0));
+        assert_eq!(tables.stop_times[0].timepoint, Some(1));
+
+        let required = [
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id,direction_id,shape_id\n1,1,1,0,1\n",
+            ),
+            (
+                "shapes.txt",
+                "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence\n1,59.8,17.6,1\n",
+            ),
+        ];
+        let routes = (
+            "routes.txt",
+            "route_id,agency_id,route_short_name,route_type\n1,1,5,700\n",
+        );
+        let stops = (
+            "stops.txt",
+            "stop_id,stop_name,stop_lat,stop_lon,location_type\n1,Centralstationen,59.8,17.6,\n",
+        );
+
+        let tables =
+            StaticTables::from_zip(gtfs_zip(&[routes, required[0], required[1], stops])).unwrap();
+        assert_eq!(tables.stops[0].location_type, None);
+        assert_eq!(tables.stops[0].parent_station, None);
+
+        // Stops are optional, but routes are not.
+        let tables = StaticTables::from_zip(gtfs_zip(&[routes, required[0], required[1]])).unwrap();
+        assert!(tables.stops.is_empty());
+
+        let error = StaticTables::from_zip(gtfs_zip(&required)).unwrap_err();
+        assert!(error.starts_with("routes.txt"), "{}", error);
+
+        // A required table must not end up empty, e.g. because none of its rows can be parsed.
+        let unparsable = ("routes.txt", "route_id,agency_id\n1,1\n");
+        let error =
+            StaticTables::from_zip(gtfs_zip(&[unparsable, required[0], required[1]])).unwrap_err();
+        assert_eq!(
+            error,
+            "routes.txt: no rows could be read (1 could not be parsed)"
+        );
+
+        assert!(StaticTables::from_zip(Cursor::new(b"not a zip".to_vec())).is_err());
+    }
+}
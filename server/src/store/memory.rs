@@ -0,0 +1,564 @@
+//! Stores that keep everything in memory.
+//!
+//! The static data is read from a GTFS zip when the server starts and is indexed by the ids
+//! that it is looked up by, so a query never has to look through a whole table.
+//!
+//! Reservations and observed arrivals only live as long as the server does.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDate;
+use uuid::Uuid;
+
+use crate::geometry::Point;
+use crate::gtfs::transit_static::{Route, Shape, Stop, StopTime, Trip};
+use crate::protocol::server_protocol::PassengerInformationOutput;
+use crate::store::{
+    shape_points, HistoryQuery, HistoryStore, ObservedArrival, ReservationStore, ReserveError,
+    StaticStore, StaticTables, StoreFuture, TripQuery,
+};
+
+/// Static data for an operator, read from a GTFS zip. Clones share the same data.
+#[derive(Clone)]
+pub struct MemoryStaticStore {
+    data: Arc<StaticData>,
+}
+
+/// The tables of a GTFS feed, indexed by id.
+struct StaticData {
+    routes: Vec<Route>,
+    trips: Vec<Trip>,
+
+    /// Maps trip ids, route ids and shape ids to the trips (as indices into `trips`) that
+    /// have them.
+    trips_by_id: HashMap<String, Vec<usize>>,
+    trips_by_route: HashMap<String, Vec<usize>>,
+    trips_by_shape: HashMap<String, Vec<usize>>,
+
+    /// Maps shape ids to their points, in the order they are driven.
+    shapes: HashMap<String, Vec<Point>>,
+
+    /// Maps trip ids to their stop times, in the order of their sequence.
+    stop_times: HashMap<String, Vec<StopTime>>,
+
+    stops: HashMap<String, Stop>,
+}
+
+impl MemoryStaticStore {
+    /// Reads the GTFS zip at `path`.
+    pub fn open(path: &str) -> Result<Self, String> {
+        Ok(MemoryStaticStore::new(StaticTables::open(path)?))
+    }
+
+    /// Reads a GTFS zip.
+    #[cfg(test)]
+    pub fn from_zip<R: std::io::Read + std::io::Seek>(reader: R) -> Result<Self, String> {
+        Ok(MemoryStaticStore::new(StaticTables::from_zip(reader)?))
+    }
+
+    /// Indexes the tables of a GTFS feed.
+    pub fn new(tables: StaticTables) -> Self {
+        let mut trips_by_id: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut trips_by_route: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut trips_by_shape: HashMap<String, Vec<usize>> = HashMap::new();
+
+        for (index, trip) in tables.trips.iter().enumerate() {
+            trips_by_id
+                .entry(trip.trip_id.clone())
+                .or_default()
+                .push(index);
+            trips_by_route
+                .entry(trip.route_id.clone())
+                .or_default()
+                .push(index);
+            trips_by_shape
+                .entry(trip.shape_id.clone())
+                .or_default()
+                .push(index);
+        }
+
+        let mut shape_rows: HashMap<String, Vec<Shape>> = HashMap::new();
+        for row in tables.shapes {
+            shape_rows
+                .entry(row.shape_id.clone())
+                .or_default()
+                .push(row);
+        }
+
+        let shapes = shape_rows
+            .into_iter()
+            .map(|(shape_id, rows)| (shape_id, shape_points(&rows)))
+            .collect();
+
+        let mut stop_times: HashMap<String, Vec<StopTime>> = HashMap::new();
+        for stop_time in tables.stop_times {
+            stop_times
+                .entry(stop_time.trip_id.clone())
+                .or_default()
+                .push(stop_time);
+        }
+
+        for trip_stop_times in stop_times.values_mut() {
+            trip_stop_times.sort_by_key(|stop_time| stop_time.stop_sequence);
+        }
+
+        let stops = tables
+            .stops
+            .into_iter()
+            .map(|stop| (stop.stop_id.clone(), stop))
+            .collect();
+
+        MemoryStaticStore {
+            data: Arc::new(StaticData {
+                routes: tables.routes,
+                trips: tables.trips,
+                trips_by_id,
+                trips_by_route,
+                trips_by_shape,
+                shapes,
+                stop_times,
+                stops,
+            }),
+        }
+    }
+}
+
+impl StaticData {
+    fn trips(&self, index: &HashMap<String, Vec<usize>>, id: &str) -> Vec<Trip> {
+        index
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|index| self.trips[*index].clone())
+            .collect()
+    }
+}
+
+/// Resolves to a value that is already known.
+fn ready<T: 'static>(value: T) -> StoreFuture<T> {
+    Box::pin(std::future::ready(value))
+}
+
+impl StaticStore for MemoryStaticStore {
+    fn ping(&self) -> StoreFuture<bool> {
+        ready(true)
+    }
+
+    fn has_static_data(&self) -> StoreFuture<bool> {
+        let data = &self.data;
+
+        ready(!data.routes.is_empty() && !data.trips.is_empty() && !data.shapes.is_empty())
+    }
+
+    fn route_by_short_name(&self, short_name: &str) -> StoreFuture<Option<Route>> {
+        let route = self
+            .data
+            .routes
+            .iter()
+            .find(|route| route.route_short_name == short_name);
+
+        ready(route.cloned())
+    }
+
+    fn route(&self, route_id: &str) -> StoreFuture<Option<Route>> {
+        let route = self
+            .data
+            .routes
+            .iter()
+            .find(|route| route.route_id == route_id);
+
+        ready(route.cloned())
+    }
+
+    fn trip(&self, trip_id: &str) -> StoreFuture<Option<Trip>> {
+        let data = &self.data;
+
+        ready(data.trips(&data.trips_by_id, trip_id).into_iter().next())
+    }
+
+    fn trips(&self, query: TripQuery) -> StoreFuture<Option<Vec<Trip>>> {
+        let data = &self.data;
+
+        let trips = match &query {
+            TripQuery::Route(route_id) => data.trips(&data.trips_by_route, route_id),
+            TripQuery::Trip(trip_id) => data.trips(&data.trips_by_id, trip_id),
+            TripQuery::Shape(shape_id) => data.trips(&data.trips_by_shape, shape_id),
+        };
+
+        ready(Some(trips))
+    }
+
+    fn shape(&self, shape_id: &str) -> StoreFuture<Option<Vec<Point>>> {
+        ready(Some(
+            self.data.shapes.get(shape_id).cloned().unwrap_or_default(),
+        ))
+    }
+
+    fn stop_times(&self, trip_id: &str) -> StoreFuture<Option<Vec<StopTime>>> {
+        ready(Some(
+            self.data
+                .stop_times
+                .get(trip_id)
+                .cloned()
+                .unwrap_or_default(),
+        ))
+    }
+
+    fn stops(&self, stop_ids: &[String]) -> StoreFuture<Option<Vec<Stop>>> {
+        let stops = stop_ids
+            .iter()
+            .filter_map(|stop_id| self.data.stops.get(stop_id).cloned())
+            .collect();
+
+        ready(Some(stops))
+    }
+}
+
+/// Passenger information and reservations that only live as long as the server. Clones share
+/// the same reservations.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReservations {
+    reservations: Arc<Mutex<Reservations>>,
+}
+
+#[derive(Debug, Default)]
+struct Reservations {
+    /// Maps vehicle descriptor ids to their passenger information.
+    passenger_info: HashMap<String, PassengerInformationOutput>,
+
+    /// Maps client IDs to the descriptor id of the bus on which they have reserved a seat.
+    reserved_seats: HashMap<Uuid, String>,
+}
+
+impl ReservationStore for MemoryReservations {
+    fn passenger_info(
+        &self,
+        descriptor_id: &str,
+    ) -> StoreFuture<Option<PassengerInformationOutput>> {
+        let reservations = self.reservations.lock().unwrap();
+
+        ready(reservations.passenger_info.get(descriptor_id).cloned())
+    }
+
+    fn all_passenger_info(&self) -> StoreFuture<HashMap<String, PassengerInformationOutput>> {
+        ready(self.reservations.lock().unwrap().passenger_info.clone())
+    }
+
+    fn create_passenger_info(
+        &self,
+        descriptor_id: &str,
+        passenger_info: PassengerInformationOutput,
+    ) -> StoreFuture<Option<PassengerInformationOutput>> {
+        let mut reservations = self.reservations.lock().unwrap();
+
+        let passenger_info = reservations
+            .passenger_info
+            .entry(descriptor_id.to_owned())
+            .or_insert(passenger_info);
+
+        ready(Some(passenger_info.clone()))
+    }
+
+    fn reserve_seat(
+        &self,
+        client_id: Uuid,
+        descriptor_id: &str,
+    ) -> StoreFuture<Result<(), ReserveError>> {
+        let mut reservations = self.reservations.lock().unwrap();
+
+        let result = match reservations.passenger_info.get_mut(descriptor_id) {
+            Some(passenger_info) if passenger_info.passengers < passenger_info.capacity => {
+                passenger_info.passengers += 1;
+                reservations
+                    .reserved_seats
+                    .insert(client_id, descriptor_id.to_owned());
+                Ok(())
+            }
+            Some(_) => Err(ReserveError::Full),
+            None => Err(ReserveError::UnknownVehicle),
+        };
+
+        ready(result)
+    }
+
+    fn unreserve_seat(&self, client_id: Uuid) -> StoreFuture<Option<String>> {
+        let mut reservations = self.reservations.lock().unwrap();
+
+        let descriptor_id = reservations.reserved_seats.remove(&client_id);
+
+        if let Some(descriptor_id) = &descriptor_id {
+            if let Some(passenger_info) = reservations.passenger_info.get_mut(descriptor_id) {
+                passenger_info.passengers -= 1;
+            }
+        }
+
+        ready(descriptor_id)
+    }
+
+    fn forget_reservation(&self, client_id: Uuid) -> StoreFuture<()> {
+        self.reservations
+            .lock()
+            .unwrap()
+            .reserved_seats
+            .remove(&client_id);
+
+        ready(())
+    }
+
+    fn reservation_count(&self) -> StoreFuture<usize> {
+        ready(self.reservations.lock().unwrap().reserved_seats.len())
+    }
+}
+
+/// Identifies an arrival of a trip at a stop: the operator, the trip id, the stop sequence and
+/// the service date.
+type ArrivalKey = (String, String, u32, NaiveDate);
+
+/// Observed arrivals that only live as long as the server. Clones share the same arrivals.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryHistory {
+    arrivals: Arc<Mutex<HashMap<ArrivalKey, ObservedArrival>>>,
+}
+
+impl HistoryStore for MemoryHistory {
+    fn record(&self, arrivals: Vec<ObservedArrival>) -> StoreFuture<bool> {
+        let mut stored = self.arrivals.lock().unwrap();
+
+        for arrival in arrivals {
+            let key = (
+                arrival.operator.clone(),
+                arrival.trip_id.clone(),
+                arrival.stop_sequence,
+                arrival.service_date,
+            );
+
+            match stored.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    let stored = entry.get_mut();
+                    stored.occupancy = stored.occupancy.or(arrival.occupancy);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(arrival);
+                }
+            }
+        }
+
+        ready(true)
+    }
+
+    fn arrivals(&self, query: HistoryQuery) -> StoreFuture<Option<Vec<ObservedArrival>>> {
+        let stored = self.arrivals.lock().unwrap();
+
+        let arrivals = stored
+            .values()
+            .filter(|arrival| query.matches(arrival))
+            .cloned()
+            .collect();
+
+        ready(Some(arrivals))
+    }
+
+    fn prune(&self, date: NaiveDate) -> StoreFuture<bool> {
+        let mut stored = self.arrivals.lock().unwrap();
+        stored.retain(|_, arrival| arrival.service_date >= date);
+
+        ready(true)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::store::gtfs::tests::line_5;
+
+    /// An arrival of UL's trip on line 5 at its second stop on 2021-05-10, which was
+    /// scheduled at 08:02 UTC.
+    pub fn arrival(stop_sequence: u32, observed: i64) -> ObservedArrival {
+        ObservedArrival {
+            operator: "ul".to_owned(),
+            line: "5".to_owned(),
+            trip_id: "141010000123456789".to_owned(),
+            stop_id: "9022003700021002".to_owned(),
+            stop_sequence,
+            service_date: NaiveDate::from_ymd_opt(2021, 5, 10).unwrap(),
+            scheduled: 1_620_633_720,
+            observed,
+            occupancy: None,
+        }
+    }
+
+    /// A query for every arrival of UL on line 5 between two days in May 2021.
+    pub fn may_query(from: u32, to: u32) -> HistoryQuery {
+        HistoryQuery {
+            operator: "ul".to_owned(),
+            line: Some("5".to_owned()),
+            stop_id: None,
+            from: NaiveDate::from_ymd_opt(2021, 5, from).unwrap(),
+            to: NaiveDate::from_ymd_opt(2021, 5, to).unwrap(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_memory_static_store() {
+        let store = MemoryStaticStore::from_zip(line_5()).unwrap();
+
+        assert!(store.has_static_data().await);
+
+        let route = store.route_by_short_name("5").await.unwrap();
+        assert_eq!(route.route_id, "9011003000500000");
+        assert!(store.route("9011003000500000").await.is_some());
+        assert!(store.route_by_short_name("6").await.is_none());
+
+        let trips = store.trips(TripQuery::Shape("1".to_owned())).await.unwrap();
+        assert_eq!(trips.len(), 1);
+        assert_eq!(
+            store.trip(&trips[0].trip_id).await.as_ref(),
+            Some(&trips[0])
+        );
+
+        // Nothing matches an unknown id, which is not the same as the store being unreadable.
+        assert_eq!(
+            store.trips(TripQuery::Route("0".to_owned())).await,
+            Some(Vec::new())
+        );
+
+        // Points are sorted and the ones that are not numbers are skipped.
+        let points = store.shape("1").await.unwrap();
+        assert_eq!(
+            points,
+            vec![
+                Point {
+                    lat: 59.858,
+                    lng: 17.638
+                },
+                Point {
+                    lat: 59.859,
+                    lng: 17.638
+                },
+            ]
+        );
+
+        let stop_times = store.stop_times(&trips[0].trip_id).await.unwrap();
+        assert_eq!(stop_times[0].stop_id, "9022003700021001");
+
+        let stops = store
+            .stops(&[stop_times[1].stop_id.clone(), "unknown".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops[0].stop_name, "Stora torget");
+    }
+
+    #[actix_rt::test]
+    async fn test_memory_reservations() {
+        let store = MemoryReservations::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let passenger_info = PassengerInformationOutput {
+            capacity: 12,
+            passengers: 10,
+            forecast: None,
+        };
+
+        // Only the first passenger information that is created for a vehicle is kept.
+        let created = store.create_passenger_info("ul:9031003", passenger_info.clone());
+        assert_eq!(created.await, Some(passenger_info.clone()));
+        let created = store.create_passenger_info(
+            "ul:9031003",
+            PassengerInformationOutput {
+                passengers: 0,
+                ..passenger_info
+            },
+        );
+        assert_eq!(created.await.unwrap().passengers, 10);
+        assert_eq!(store.all_passenger_info().await.len(), 1);
+
+        // A seat can be taken until the vehicle is full.
+        assert_eq!(store.reserve_seat(first, "ul:9031003").await, Ok(()));
+        assert_eq!(store.reserve_seat(second, "ul:9031003").await, Ok(()));
+        assert_eq!(
+            store.reserve_seat(Uuid::new_v4(), "ul:9031003").await,
+            Err(ReserveError::Full)
+        );
+        assert_eq!(
+            store.reserve_seat(first, "ul:0").await,
+            Err(ReserveError::UnknownVehicle)
+        );
+        assert_eq!(store.reservation_count().await, 2);
+
+        // Clones share the same reservations.
+        let clone = store.clone();
+        assert_eq!(
+            clone.unreserve_seat(first).await.as_deref(),
+            Some("ul:9031003")
+        );
+        assert_eq!(clone.unreserve_seat(first).await, None);
+        assert_eq!(
+            store.passenger_info("ul:9031003").await.unwrap().passengers,
+            11
+        );
+
+        // A forgotten reservation keeps its seat.
+        store.forget_reservation(second).await;
+        assert_eq!(store.reservation_count().await, 0);
+        assert_eq!(store.unreserve_seat(second).await, None);
+        assert_eq!(
+            store.passenger_info("ul:9031003").await.unwrap().passengers,
+            11
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_memory_history() {
+        let history = MemoryHistory::default();
+
+        // An arrival that is observed again keeps the time it was first observed at.
+        assert!(history.record(vec![arrival(2, 1_620_633_780)]).await);
+        assert!(
+            history
+                .record(vec![arrival(2, 1_620_633_840), arrival(3, 1_620_633_900)])
+                .await
+        );
+
+        let mut arrivals = history.arrivals(may_query(1, 10)).await.unwrap();
+        arrivals.sort_by_key(|arrival| arrival.stop_sequence);
+        assert_eq!(
+            arrivals,
+            vec![arrival(2, 1_620_633_780), arrival(3, 1_620_633_900)]
+        );
+        assert_eq!(arrivals[0].delay(), 60);
+
+        // An occupancy is added to an arrival that was observed without one.
+        let full = ObservedArrival {
+            occupancy: Some(80),
+            ..arrival(2, 1_620_633_840)
+        };
+        assert!(history.record(vec![full]).await);
+
+        let arrivals = history.arrivals(may_query(1, 10)).await.unwrap();
+        let stored = arrivals.iter().find(|arrival| arrival.stop_sequence == 2);
+        assert_eq!(
+            stored.map(|arrival| (arrival.observed, arrival.occupancy)),
+            Some((1_620_633_780, Some(80)))
+        );
+
+        assert!(history
+            .arrivals(may_query(11, 31))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut other_line = may_query(1, 31);
+        other_line.line = Some("6".to_owned());
+        assert!(history.arrivals(other_line).await.unwrap().is_empty());
+
+        assert!(
+            history
+                .prune(NaiveDate::from_ymd_opt(2021, 5, 11).unwrap())
+                .await
+        );
+        assert!(history.arrivals(may_query(1, 31)).await.unwrap().is_empty());
+    }
+}
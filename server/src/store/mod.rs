@@ -0,0 +1,364 @@
+//! Where static data and reservations are kept.
+//!
+//! The lobby never talks to a database directly. Static data (routes, trips, shapes and
+//! stops) is read through a `StaticStore` for every operator, and passenger information and
+//! seat reservations are kept in a `ReservationStore`.
+//!
+//! With the "mongodb" backend the static data is read from each operator's database. The
+//! "memory" backend loads it from a GTFS zip when the server starts instead, so the server can
+//! run without any external services. The "sqlite" backend imports the GTFS zips into a single
+//! file and keeps reservations there as well, so that they survive a restart.
+//!
+//! Instances that share state (see `cluster`) also share their reservations, which are then
+//! kept in the cluster's MongoDB database whatever the storage backend is.
+//!
+//! When the punctuality history is enabled, the arrivals that are observed in the realtime
+//! feeds are kept in a `HistoryStore` of the same backend. With the "mongodb" backend every
+//! instance records the same arrivals, which the store only keeps once. Arrivals also keep how
+//! full the vehicle was, which crowding forecasts are made from.
+
+mod gtfs;
+mod memory;
+mod mongo;
+mod sqlite;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::config::{HistorySettings, StorageBackend, StorageSettings};
+use crate::database::DbConnection;
+use crate::geometry::Point;
+use crate::gtfs::operator::Operator;
+use crate::gtfs::transit_static::{Route, Shape, Stop, StopTime, Trip};
+use crate::protocol::server_protocol::PassengerInformationOutput;
+
+pub use gtfs::StaticTables;
+pub use memory::{MemoryHistory, MemoryReservations, MemoryStaticStore};
+pub use mongo::{MongoHistory, MongoReservations};
+pub use sqlite::SqliteDatabase;
+
+#[cfg(test)]
+pub use gtfs::tests::{gtfs_zip, line_5};
+#[cfg(test)]
+pub use memory::tests::arrival;
+
+/// The result of a query for static data. The future does not borrow the store, so it can be
+/// awaited inside async blocks.
+pub type StoreFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+/// Which trips to look up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TripQuery {
+    /// Every trip of the route with this id.
+    Route(String),
+
+    /// The trip with this id.
+    Trip(String),
+
+    /// Every trip that uses the shape with this id.
+    Shape(String),
+}
+
+/// Static data for a single operator.
+///
+/// Queries that return lists resolve to `None` if the store could not be read, and to an
+/// empty list if nothing matched.
+pub trait StaticStore {
+    /// Resolves to true if the store can be read.
+    fn ping(&self) -> StoreFuture<bool>;
+
+    /// Resolves to true if the store contains routes, trips and shapes.
+    fn has_static_data(&self) -> StoreFuture<bool>;
+
+    /// Looks up a route by its line number, e.g. "5".
+    fn route_by_short_name(&self, short_name: &str) -> StoreFuture<Option<Route>>;
+
+    /// Looks up a route by its id.
+    fn route(&self, route_id: &str) -> StoreFuture<Option<Route>>;
+
+    /// Looks up a trip by its id.
+    fn trip(&self, trip_id: &str) -> StoreFuture<Option<Trip>>;
+
+    /// Looks up every trip that matches the query.
+    fn trips(&self, query: TripQuery) -> StoreFuture<Option<Vec<Trip>>>;
+
+    /// Looks up the points of a shape, in the order they are driven.
+    fn shape(&self, shape_id: &str) -> StoreFuture<Option<Vec<Point>>>;
+
+    /// Looks up the stop times of a trip, in the order of their sequence.
+    fn stop_times(&self, trip_id: &str) -> StoreFuture<Option<Vec<StopTime>>>;
+
+    /// Looks up every stop with one of the ids.
+    fn stops(&self, stop_ids: &[String]) -> StoreFuture<Option<Vec<Stop>>>;
+}
+
+/// Why a seat could not be reserved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReserveError {
+    /// Every seat on the vehicle is taken.
+    Full,
+
+    /// The vehicle has no passenger information.
+    UnknownVehicle,
+}
+
+/// Passenger information for every vehicle and the seats that clients have reserved.
+///
+/// Every change is a single atomic operation, so that several instances can share the same
+/// store without reserving a seat twice. A failed query is logged and treated as if nothing
+/// was found.
+pub trait ReservationStore {
+    /// Looks up the passenger information for a vehicle.
+    fn passenger_info(
+        &self,
+        descriptor_id: &str,
+    ) -> StoreFuture<Option<PassengerInformationOutput>>;
+
+    /// Looks up the passenger information for every vehicle, by descriptor id.
+    fn all_passenger_info(&self) -> StoreFuture<HashMap<String, PassengerInformationOutput>>;
+
+    /// Stores passenger information for a vehicle unless it already has some, and resolves to
+    /// the information that the vehicle has afterwards.
+    fn create_passenger_info(
+        &self,
+        descriptor_id: &str,
+        passenger_info: PassengerInformationOutput,
+    ) -> StoreFuture<Option<PassengerInformationOutput>>;
+
+    /// Takes a seat on a vehicle for a client, unless the vehicle is full.
+    fn reserve_seat(
+        &self,
+        client_id: Uuid,
+        descriptor_id: &str,
+    ) -> StoreFuture<Result<(), ReserveError>>;
+
+    /// Gives up a client's seat and resolves to the vehicle it was on.
+    fn unreserve_seat(&self, client_id: Uuid) -> StoreFuture<Option<String>>;
+
+    /// Forgets a client's reservation, but the seat stays taken.
+    fn forget_reservation(&self, client_id: Uuid) -> StoreFuture<()>;
+
+    /// Counts the seats that are reserved.
+    fn reservation_count(&self) -> StoreFuture<usize>;
+}
+
+/// An arrival of a trip at one of its stops that was observed in a realtime feed. Ids are
+/// not namespaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObservedArrival {
+    pub operator: String,
+
+    /// The line number of the trip's route, e.g. "5".
+    pub line: String,
+
+    pub trip_id: String,
+    pub stop_id: String,
+
+    /// The stop sequence of the stop time, which tells apart the visits of a trip that passes
+    /// the same stop twice.
+    pub stop_sequence: u32,
+
+    /// The day that the trip's timetable belongs to, which is the day before the arrival for
+    /// trips that run past midnight.
+    pub service_date: NaiveDate,
+
+    /// When the trip was scheduled to arrive and when it was observed to arrive, in seconds
+    /// since 1970-01-01 00:00:00 UTC.
+    pub scheduled: i64,
+    pub observed: i64,
+
+    /// How full the vehicle was (in percent of its capacity) when it arrived, if known.
+    pub occupancy: Option<u32>,
+}
+
+impl ObservedArrival {
+    /// Returns how late (in seconds) the trip arrived. Negative values are early.
+    pub fn delay(&self) -> i64 {
+        self.observed - self.scheduled
+    }
+}
+
+/// Which observed arrivals to look up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryQuery {
+    pub operator: String,
+
+    /// Only arrivals on the line with this line number, if given.
+    pub line: Option<String>,
+
+    /// Only arrivals at the stop with this id, if given.
+    pub stop_id: Option<String>,
+
+    /// The first and the last service date, both included.
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+impl HistoryQuery {
+    /// Returns true if the arrival matches the query.
+    pub fn matches(&self, arrival: &ObservedArrival) -> bool {
+        arrival.operator == self.operator
+            && self.line.as_ref().is_none_or(|line| &arrival.line == line)
+            && self
+                .stop_id
+                .as_ref()
+                .is_none_or(|stop_id| &arrival.stop_id == stop_id)
+            && (self.from..=self.to).contains(&arrival.service_date)
+    }
+}
+
+/// Arrivals that have been observed in the realtime feeds.
+pub trait HistoryStore {
+    /// Stores arrivals and resolves to false if they could not be stored. An arrival that has
+    /// already been stored (the same trip and stop sequence on the same service date) keeps
+    /// the time that it was first observed at, but gets the occupancy of the new one if it did
+    /// not have one.
+    fn record(&self, arrivals: Vec<ObservedArrival>) -> StoreFuture<bool>;
+
+    /// Looks up every arrival that matches the query.
+    fn arrivals(&self, query: HistoryQuery) -> StoreFuture<Option<Vec<ObservedArrival>>>;
+
+    /// Forgets every arrival with a service date before `date`, and resolves to false if they
+    /// could not be removed.
+    fn prune(&self, date: NaiveDate) -> StoreFuture<bool>;
+}
+
+/// The stores that the lobby uses.
+pub struct Storage {
+    /// Static data for every operator, by name.
+    pub static_stores: HashMap<String, Arc<dyn StaticStore>>,
+
+    pub reservations: Box<dyn ReservationStore>,
+
+    /// Observed arrivals, if the punctuality history is enabled.
+    pub history: Option<Arc<dyn HistoryStore>>,
+}
+
+impl Storage {
+    /// Sets up the backend that is chosen in the settings, with a history store if the
+    /// punctuality history is enabled. `db_connection` is only used (and must only be given)
+    /// by the "mongodb" backend.
+    ///
+    /// The "memory" backend reads every operator's GTFS zip and the "sqlite" backend imports
+    /// the ones that have changed, which fails if a file is missing or is not a GTFS feed.
+    pub fn from_settings(
+        settings: &StorageSettings,
+        history_settings: &HistorySettings,
+        operators: &[Operator],
+        db_connection: Option<&DbConnection>,
+    ) -> Result<Self, String> {
+        info!(backend = ?settings.backend, "Starting storage backend.");
+
+        let mut static_stores: HashMap<String, Arc<dyn StaticStore>> = HashMap::new();
+        let mut history: Option<Arc<dyn HistoryStore>> = None;
+
+        let reservations: Box<dyn ReservationStore> = match settings.backend {
+            StorageBackend::Mongodb => {
+                let db_connection =
+                    db_connection.expect("The mongodb backend needs a database connection");
+
+                for operator in operators {
+                    let store = db_connection.with_static_database(&operator.database);
+                    static_stores.insert(operator.name.clone(), Arc::new(store));
+                }
+
+                if history_settings.enabled {
+                    let database = db_connection.database(&history_settings.database);
+                    history = Some(Arc::new(MongoHistory::new(&database)));
+                }
+
+                Box::new(MemoryReservations::default())
+            }
+            StorageBackend::Memory => {
+                for operator in operators {
+                    // Validated settings have a GTFS zip for every operator.
+                    let path = operator.gtfs_path.as_deref().unwrap_or_default();
+
+                    info!(operator = %operator.name, %path, "Loading static data.");
+
+                    let store = MemoryStaticStore::open(path)
+                        .map_err(|reason| gtfs_error(operator, path, reason))?;
+                    static_stores.insert(operator.name.clone(), Arc::new(store));
+                }
+
+                if history_settings.enabled {
+                    history = Some(Arc::new(MemoryHistory::default()));
+                }
+
+                Box::new(MemoryReservations::default())
+            }
+            StorageBackend::Sqlite => {
+                let database = SqliteDatabase::open(&settings.sqlite_path).map_err(|reason| {
+                    format!(
+                        "Could not open '{}'. Reason: {}",
+                        settings.sqlite_path, reason
+                    )
+                })?;
+
+                for operator in operators {
+                    // Operators without a GTFS zip use the data that is already in the file.
+                    if let Some(path) = &operator.gtfs_path {
+                        database
+                            .import_gtfs(&operator.name, path)
+                            .map_err(|reason| gtfs_error(operator, path, reason))?;
+                    }
+
+                    let store = database.static_store(&operator.name);
+                    static_stores.insert(operator.name.clone(), Arc::new(store));
+                }
+
+                if history_settings.enabled {
+                    history = Some(Arc::new(database.history()));
+                }
+
+                Box::new(database.reservations()?)
+            }
+        };
+
+        Ok(Storage {
+            static_stores,
+            reservations,
+            history,
+        })
+    }
+}
+
+/// Creates an error about a GTFS zip that could not be read.
+fn gtfs_error(operator: &Operator, path: &str, reason: String) -> String {
+    format!(
+        "Could not load static data for operator '{}' from '{}'. Reason: {}",
+        operator.name, path, reason
+    )
+}
+
+/// Converts the rows of a shape to its points, in the order they are driven. Rows that are not
+/// numbers are skipped.
+pub fn shape_points(rows: &[Shape]) -> Vec<Point> {
+    let mut points = rows
+        .iter()
+        .filter_map(|row| {
+            // Everything is stored as strings, since that is how GTFS files are imported.
+            let parsed = (
+                row.shape_pt_sequence.parse::<i32>(),
+                row.shape_pt_lat.parse::<f64>(),
+                row.shape_pt_lon.parse::<f64>(),
+            );
+
+            match parsed {
+                (Ok(sequence), Ok(lat), Ok(lng)) => Some((sequence, Point { lat, lng })),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // The rows are not stored in any particular order.
+    points.sort_by_key(|(sequence, _)| *sequence);
+
+    points.into_iter().map(|(_, point)| point).collect()
+}
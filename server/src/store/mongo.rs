@@ -0,0 +1,540 @@
+//! Static data that is read from an operator's MongoDB database, and observed arrivals and
+//! reservations that are shared by every instance that uses the same database.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions};
+use mongodb::{Collection, Database};
+use tokio::stream::StreamExt;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::database::DbConnection;
+use crate::geometry::Point;
+use crate::gtfs::transit_static::{Route, Stop, StopTime, Trip};
+use crate::metrics::DB_QUERY_SECONDS;
+use crate::protocol::server_protocol::PassengerInformationOutput;
+use crate::store::{
+    HistoryQuery, HistoryStore, ObservedArrival, ReservationStore, ReserveError, StaticStore,
+    StoreFuture, TripQuery,
+};
+
+/// The collection that observed arrivals are kept in.
+const ARRIVALS_COLLECTION: &str = "arrivals";
+
+/// The collection with the passenger information of every vehicle, by descriptor id.
+const PASSENGER_INFO_COLLECTION: &str = "passenger_info";
+
+/// The collection with the descriptor id of the vehicle that every client has reserved a seat
+/// on, by client id.
+const RESERVATIONS_COLLECTION: &str = "reservations";
+
+/// The format of service dates in the arrivals collection, which orders them in the same way
+/// as the dates.
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+impl StaticStore for DbConnection {
+    fn ping(&self) -> StoreFuture<bool> {
+        let conn = self.clone();
+
+        Box::pin(async move { conn.ping().await })
+    }
+
+    fn has_static_data(&self) -> StoreFuture<bool> {
+        let conn = self.clone();
+
+        Box::pin(async move { conn.has_static_data().await })
+    }
+
+    fn route_by_short_name(&self, short_name: &str) -> StoreFuture<Option<Route>> {
+        let conn = self.clone();
+        let query = doc! {"route_short_name": short_name};
+
+        Box::pin(async move { conn.get_route(query).await })
+    }
+
+    fn route(&self, route_id: &str) -> StoreFuture<Option<Route>> {
+        let conn = self.clone();
+        let query = doc! {"route_id": route_id};
+
+        Box::pin(async move { conn.get_route(query).await })
+    }
+
+    fn trip(&self, trip_id: &str) -> StoreFuture<Option<Trip>> {
+        let conn = self.clone();
+        let query = doc! {"trip_id": trip_id};
+
+        Box::pin(async move { conn.get_trip(query).await })
+    }
+
+    fn trips(&self, query: TripQuery) -> StoreFuture<Option<Vec<Trip>>> {
+        let conn = self.clone();
+        let query = match query {
+            TripQuery::Route(route_id) => doc! {"route_id": route_id},
+            TripQuery::Trip(trip_id) => doc! {"trip_id": trip_id},
+            TripQuery::Shape(shape_id) => doc! {"shape_id": shape_id},
+        };
+
+        Box::pin(async move { conn.get_trips(query).await })
+    }
+
+    fn shape(&self, shape_id: &str) -> StoreFuture<Option<Vec<Point>>> {
+        let conn = self.clone();
+        let query = doc! {"shape_id": shape_id};
+
+        Box::pin(async move { conn.get_shapes(query).await })
+    }
+
+    fn stop_times(&self, trip_id: &str) -> StoreFuture<Option<Vec<StopTime>>> {
+        let conn = self.clone();
+        let query = doc! {"trip_id": trip_id};
+
+        Box::pin(async move { conn.get_stop_times(query).await })
+    }
+
+    fn stops(&self, stop_ids: &[String]) -> StoreFuture<Option<Vec<Stop>>> {
+        let conn = self.clone();
+        let query = doc! {"stop_id": {"$in": stop_ids}};
+
+        Box::pin(async move { conn.get_stops(query).await })
+    }
+}
+
+/// Observed arrivals in a MongoDB collection.
+pub struct MongoHistory {
+    arrivals: Collection,
+}
+
+impl MongoHistory {
+    pub fn new(database: &Database) -> Self {
+        MongoHistory {
+            arrivals: database.collection(ARRIVALS_COLLECTION),
+        }
+    }
+}
+
+impl HistoryStore for MongoHistory {
+    fn record(&self, arrivals: Vec<ObservedArrival>) -> StoreFuture<bool> {
+        let collection = self.arrivals.clone();
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&[ARRIVALS_COLLECTION])
+                .start_timer();
+
+            for arrival in &arrivals {
+                // Every instance records the same arrivals, so the id identifies the arrival
+                // and a document is only inserted by the first one.
+                let id = doc! {
+                    "operator": &arrival.operator,
+                    "trip_id": &arrival.trip_id,
+                    "stop_sequence": arrival.stop_sequence as i64,
+                    "service_date": arrival.service_date.format(DATE_FORMAT).to_string(),
+                };
+                let update = doc! {"$setOnInsert": encode(arrival)};
+                let options = UpdateOptions::builder().upsert(true).build();
+
+                if let Err(err) = collection
+                    .update_one(doc! {"_id": id.clone()}, update, options)
+                    .await
+                {
+                    warn!("Could not record an arrival. Reason: {}", err);
+                    return false;
+                }
+
+                // An arrival that was first observed without an occupancy gets this one.
+                if let Some(occupancy) = arrival.occupancy {
+                    let filter = doc! {"_id": id, "occupancy": null};
+                    let update = doc! {"$set": {"occupancy": occupancy as i64}};
+
+                    if let Err(err) = collection.update_one(filter, update, None).await {
+                        warn!("Could not record an occupancy. Reason: {}", err);
+                        return false;
+                    }
+                }
+            }
+
+            true
+        })
+    }
+
+    fn arrivals(&self, query: HistoryQuery) -> StoreFuture<Option<Vec<ObservedArrival>>> {
+        let collection = self.arrivals.clone();
+
+        let mut filter = doc! {
+            "operator": &query.operator,
+            "service_date": {
+                "$gte": query.from.format(DATE_FORMAT).to_string(),
+                "$lte": query.to.format(DATE_FORMAT).to_string(),
+            },
+        };
+
+        if let Some(line) = &query.line {
+            filter.insert("line", line);
+        }
+
+        if let Some(stop_id) = &query.stop_id {
+            filter.insert("stop_id", stop_id);
+        }
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&[ARRIVALS_COLLECTION])
+                .start_timer();
+
+            let mut cursor = match collection.find(filter, None).await {
+                Ok(cursor) => cursor,
+                Err(err) => {
+                    warn!("Could not look up arrivals. Reason: {}", err);
+                    return None;
+                }
+            };
+
+            let mut arrivals = Vec::new();
+
+            // Documents that cannot be parsed are skipped, like documents of static data.
+            while let Some(document) = cursor.next().await {
+                if let Some(arrival) = document.ok().as_ref().and_then(decode) {
+                    arrivals.push(arrival);
+                }
+            }
+
+            Some(arrivals)
+        })
+    }
+
+    fn prune(&self, date: NaiveDate) -> StoreFuture<bool> {
+        let collection = self.arrivals.clone();
+        let filter = doc! {"service_date": {"$lt": date.format(DATE_FORMAT).to_string()}};
+
+        Box::pin(async move {
+            match collection.delete_many(filter, None).await {
+                Ok(_) => true,
+                Err(err) => {
+                    warn!("Could not remove old arrivals. Reason: {}", err);
+                    false
+                }
+            }
+        })
+    }
+}
+
+/// Passenger information and reservations in MongoDB collections, which every instance that
+/// uses the same database shares. Seat counts are only changed with `$inc`, so two instances
+/// never take the same seat.
+pub struct MongoReservations {
+    passenger_info: Collection,
+    reservations: Collection,
+}
+
+impl MongoReservations {
+    pub fn new(database: &Database) -> Self {
+        MongoReservations {
+            passenger_info: database.collection(PASSENGER_INFO_COLLECTION),
+            reservations: database.collection(RESERVATIONS_COLLECTION),
+        }
+    }
+}
+
+impl ReservationStore for MongoReservations {
+    fn passenger_info(
+        &self,
+        descriptor_id: &str,
+    ) -> StoreFuture<Option<PassengerInformationOutput>> {
+        let collection = self.passenger_info.clone();
+        let filter = doc! {"_id": descriptor_id};
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&[PASSENGER_INFO_COLLECTION])
+                .start_timer();
+
+            match collection.find_one(filter, None).await {
+                Ok(document) => document.as_ref().and_then(decode_passenger_info),
+                Err(err) => {
+                    warn!("Could not look up passenger information. Reason: {}", err);
+                    None
+                }
+            }
+        })
+    }
+
+    fn all_passenger_info(&self) -> StoreFuture<HashMap<String, PassengerInformationOutput>> {
+        let collection = self.passenger_info.clone();
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&[PASSENGER_INFO_COLLECTION])
+                .start_timer();
+
+            let mut passenger_info = HashMap::new();
+
+            let mut cursor = match collection.find(None, None).await {
+                Ok(cursor) => cursor,
+                Err(err) => {
+                    warn!("Could not look up passenger information. Reason: {}", err);
+                    return passenger_info;
+                }
+            };
+
+            while let Some(Ok(document)) = cursor.next().await {
+                if let (Ok(descriptor_id), Some(info)) =
+                    (document.get_str("_id"), decode_passenger_info(&document))
+                {
+                    passenger_info.insert(descriptor_id.to_owned(), info);
+                }
+            }
+
+            passenger_info
+        })
+    }
+
+    fn create_passenger_info(
+        &self,
+        descriptor_id: &str,
+        passenger_info: PassengerInformationOutput,
+    ) -> StoreFuture<Option<PassengerInformationOutput>> {
+        let collection = self.passenger_info.clone();
+        let filter = doc! {"_id": descriptor_id};
+        let update = doc! {
+            "$setOnInsert": {
+                "passengers": passenger_info.passengers,
+                "capacity": passenger_info.capacity,
+            },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&[PASSENGER_INFO_COLLECTION])
+                .start_timer();
+
+            // When two instances insert the same vehicle at once, one of the upserts fails and
+            // the vehicle has the information that the other one inserted.
+            let document = match collection
+                .find_one_and_update(filter.clone(), update, options)
+                .await
+            {
+                Ok(document) => document,
+                Err(_) => collection
+                    .find_one(filter, None)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("Could not create passenger information. Reason: {}", err);
+                        None
+                    }),
+            };
+
+            document.as_ref().and_then(decode_passenger_info)
+        })
+    }
+
+    fn reserve_seat(
+        &self,
+        client_id: Uuid,
+        descriptor_id: &str,
+    ) -> StoreFuture<Result<(), ReserveError>> {
+        let passenger_info = self.passenger_info.clone();
+        let reservations = self.reservations.clone();
+        let descriptor_id = descriptor_id.to_owned();
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&[RESERVATIONS_COLLECTION])
+                .start_timer();
+
+            let filter = doc! {
+                "_id": &descriptor_id,
+                "$expr": {"$lt": ["$passengers", "$capacity"]},
+            };
+            let update = doc! {"$inc": {"passengers": 1}};
+
+            match passenger_info
+                .find_one_and_update(filter, update, None)
+                .await
+            {
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    // Either the vehicle is full or it has no passenger information.
+                    return match passenger_info
+                        .find_one(doc! {"_id": &descriptor_id}, None)
+                        .await
+                    {
+                        Ok(Some(_)) => Err(ReserveError::Full),
+                        Ok(None) => Err(ReserveError::UnknownVehicle),
+                        Err(err) => {
+                            warn!("Could not reserve a seat. Reason: {}", err);
+                            Err(ReserveError::UnknownVehicle)
+                        }
+                    };
+                }
+                Err(err) => {
+                    warn!("Could not reserve a seat. Reason: {}", err);
+                    return Err(ReserveError::UnknownVehicle);
+                }
+            }
+
+            let filter = doc! {"_id": client_id.to_string()};
+            let update = doc! {"$set": {"descriptor_id": &descriptor_id}};
+            let options = UpdateOptions::builder().upsert(true).build();
+
+            if let Err(err) = reservations.update_one(filter, update, options).await {
+                // The seat is taken, but the client cannot give it up.
+                warn!("Could not store a reservation. Reason: {}", err);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn unreserve_seat(&self, client_id: Uuid) -> StoreFuture<Option<String>> {
+        let passenger_info = self.passenger_info.clone();
+        let reservations = self.reservations.clone();
+        let filter = doc! {"_id": client_id.to_string()};
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&[RESERVATIONS_COLLECTION])
+                .start_timer();
+
+            // Only the instance that removes the reservation gives up the seat.
+            let descriptor_id = match reservations.find_one_and_delete(filter, None).await {
+                Ok(document) => document?.get_str("descriptor_id").ok()?.to_owned(),
+                Err(err) => {
+                    warn!("Could not remove a reservation. Reason: {}", err);
+                    return None;
+                }
+            };
+
+            let filter = doc! {"_id": &descriptor_id};
+            let update = doc! {"$inc": {"passengers": -1}};
+
+            if let Err(err) = passenger_info.update_one(filter, update, None).await {
+                warn!("Could not give up a seat. Reason: {}", err);
+            }
+
+            Some(descriptor_id)
+        })
+    }
+
+    fn forget_reservation(&self, client_id: Uuid) -> StoreFuture<()> {
+        let reservations = self.reservations.clone();
+        let filter = doc! {"_id": client_id.to_string()};
+
+        Box::pin(async move {
+            if let Err(err) = reservations.delete_one(filter, None).await {
+                warn!("Could not remove a reservation. Reason: {}", err);
+            }
+        })
+    }
+
+    fn reservation_count(&self) -> StoreFuture<usize> {
+        let reservations = self.reservations.clone();
+
+        Box::pin(async move {
+            reservations
+                .count_documents(None, None)
+                .await
+                .map(|count| count as usize)
+                .unwrap_or_else(|err| {
+                    warn!("Could not count reservations. Reason: {}", err);
+                    0
+                })
+        })
+    }
+}
+
+/// Converts a document in the passenger information collection to passenger information.
+fn decode_passenger_info(document: &Document) -> Option<PassengerInformationOutput> {
+    Some(PassengerInformationOutput {
+        passengers: document.get_i32("passengers").ok()?,
+        capacity: document.get_i32("capacity").ok()?,
+        forecast: None,
+    })
+}
+
+/// Converts an arrival to a document in the arrivals collection.
+fn encode(arrival: &ObservedArrival) -> Document {
+    doc! {
+        "operator": &arrival.operator,
+        "line": &arrival.line,
+        "trip_id": &arrival.trip_id,
+        "stop_id": &arrival.stop_id,
+        "stop_sequence": arrival.stop_sequence as i64,
+        "service_date": arrival.service_date.format(DATE_FORMAT).to_string(),
+        "scheduled": arrival.scheduled,
+        "observed": arrival.observed,
+        "occupancy": arrival
+            .occupancy
+            .map_or(Bson::Null, |occupancy| Bson::Int64(occupancy as i64)),
+    }
+}
+
+/// Converts a document in the arrivals collection to an arrival.
+fn decode(document: &Document) -> Option<ObservedArrival> {
+    let string = |key: &str| document.get_str(key).ok().map(str::to_owned);
+
+    Some(ObservedArrival {
+        operator: string("operator")?,
+        line: string("line")?,
+        trip_id: string("trip_id")?,
+        stop_id: string("stop_id")?,
+        stop_sequence: document.get_i64("stop_sequence").ok()? as u32,
+        service_date: NaiveDate::parse_from_str(
+            document.get_str("service_date").ok()?,
+            DATE_FORMAT,
+        )
+        .ok()?,
+        scheduled: document.get_i64("scheduled").ok()?,
+        observed: document.get_i64("observed").ok()?,
+        occupancy: document
+            .get_i64("occupancy")
+            .ok()
+            .map(|occupancy| occupancy as u32),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::tests::arrival;
+
+    #[test]
+    fn test_passenger_info_documents() {
+        let document = doc! {"_id": "ul:9031003", "passengers": 10, "capacity": 30};
+        assert_eq!(
+            decode_passenger_info(&document),
+            Some(PassengerInformationOutput {
+                passengers: 10,
+                capacity: 30,
+                forecast: None,
+            })
+        );
+
+        assert_eq!(decode_passenger_info(&doc! {"_id": "ul:9031003"}), None);
+    }
+
+    #[test]
+    fn test_arrival_documents() {
+        let arrival = arrival(2, 1_620_633_780);
+        let document = encode(&arrival);
+
+        assert_eq!(document.get_str("service_date"), Ok("2021-05-10"));
+        assert_eq!(decode(&document), Some(arrival.clone()));
+
+        let full = ObservedArrival {
+            occupancy: Some(80),
+            ..arrival
+        };
+        assert_eq!(decode(&encode(&full)), Some(full));
+
+        let mut document = document;
+        document.insert("service_date", "10 May");
+        assert_eq!(decode(&document), None);
+    }
+}
@@ -0,0 +1,1190 @@
+//! Static data, reservations and observed arrivals in a single SQLite file.
+//!
+//! Every operator's static data is kept in the same tables, with the operator's name in every
+//! row. It is imported from the operator's GTFS zip when the server starts, unless the same
+//! zip has already been imported.
+//!
+//! The schema is created and upgraded by the migrations below. SQLite's `user_version` holds
+//! the number of migrations that have been applied to a file, so a migration must never be
+//! changed once it has been released. Changes are added as new migrations at the end.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix_web::web;
+use chrono::NaiveDate;
+use rusqlite::{params, Connection, OptionalExtension, Row, NO_PARAMS};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::geometry::Point;
+use crate::gtfs::transit_static::{Route, Shape, Stop, StopTime, Trip};
+use crate::metrics::DB_QUERY_SECONDS;
+use crate::protocol::server_protocol::PassengerInformationOutput;
+use crate::store::{
+    shape_points, HistoryQuery, HistoryStore, ObservedArrival, ReservationStore, ReserveError,
+    StaticStore, StaticTables, StoreFuture, TripQuery,
+};
+
+/// How long a query waits for another connection to the same file to finish writing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Migrations of the schema, in the order they are applied.
+const MIGRATIONS: &[&str] = &[
+    // The tables from GTFS have the same columns as the files they are imported from.
+    "
+    CREATE TABLE routes (
+        operator TEXT NOT NULL,
+        route_id TEXT NOT NULL,
+        agency_id TEXT NOT NULL,
+        route_short_name TEXT NOT NULL,
+        route_long_name TEXT,
+        route_type TEXT NOT NULL,
+        route_desc TEXT,
+        PRIMARY KEY (operator, route_id)
+    );
+    CREATE INDEX routes_by_short_name ON routes (operator, route_short_name);
+
+    CREATE TABLE trips (
+        operator TEXT NOT NULL,
+        route_id TEXT NOT NULL,
+        service_id TEXT NOT NULL,
+        trip_id TEXT NOT NULL,
+        trip_headsign TEXT,
+        direction_id TEXT NOT NULL,
+        shape_id TEXT NOT NULL,
+        PRIMARY KEY (operator, trip_id)
+    );
+    CREATE INDEX trips_by_route ON trips (operator, route_id);
+    CREATE INDEX trips_by_shape ON trips (operator, shape_id);
+
+    CREATE TABLE shapes (
+        operator TEXT NOT NULL,
+        shape_id TEXT NOT NULL,
+        shape_pt_lat TEXT NOT NULL,
+        shape_pt_lon TEXT NOT NULL,
+        shape_pt_sequence TEXT NOT NULL,
+        shape_dist_traveled TEXT
+    );
+    CREATE INDEX shapes_by_id ON shapes (operator, shape_id);
+
+    CREATE TABLE stop_times (
+        operator TEXT NOT NULL,
+        trip_id TEXT NOT NULL,
+        arrival_time TEXT NOT NULL,
+        departure_time TEXT NOT NULL,
+        stop_id TEXT NOT NULL,
+        stop_sequence INTEGER NOT NULL,
+        stop_headsign TEXT NOT NULL,
+        pickup_type INTEGER NOT NULL,
+        drop_off_type INTEGER NOT NULL,
+        shape_dist_traveled REAL,
+        timepoint INTEGER NOT NULL
+    );
+    CREATE INDEX stop_times_by_trip ON stop_times (operator, trip_id);
+
+    CREATE TABLE stops (
+        operator TEXT NOT NULL,
+        stop_id TEXT NOT NULL,
+        stop_name TEXT NOT NULL,
+        stop_lat TEXT NOT NULL,
+        stop_lon TEXT NOT NULL,
+        location_type INTEGER NOT NULL,
+        parent_station TEXT,
+        platform_code TEXT,
+        PRIMARY KEY (operator, stop_id)
+    );
+
+    -- The GTFS zip that each operator's static data was imported from.
+    CREATE TABLE imports (
+        operator TEXT PRIMARY KEY,
+        source TEXT NOT NULL
+    );
+
+    CREATE TABLE passenger_info (
+        descriptor_id TEXT PRIMARY KEY,
+        capacity INTEGER NOT NULL,
+        passengers INTEGER NOT NULL
+    );
+
+    CREATE TABLE reservations (
+        client_id TEXT PRIMARY KEY,
+        descriptor_id TEXT NOT NULL
+    );
+    ",
+    // Arrivals that have been observed in the realtime feeds. Service dates are stored as
+    // "YYYY-MM-DD", so that they are ordered in the same way as the dates.
+    "
+    CREATE TABLE arrivals (
+        operator TEXT NOT NULL,
+        line TEXT NOT NULL,
+        trip_id TEXT NOT NULL,
+        stop_id TEXT NOT NULL,
+        stop_sequence INTEGER NOT NULL,
+        service_date TEXT NOT NULL,
+        scheduled INTEGER NOT NULL,
+        observed INTEGER NOT NULL,
+        PRIMARY KEY (operator, trip_id, stop_sequence, service_date)
+    );
+    CREATE INDEX arrivals_by_line ON arrivals (operator, line, service_date);
+    CREATE INDEX arrivals_by_stop ON arrivals (operator, stop_id, service_date);
+    ",
+    // How full (in percent) the vehicle was when it arrived, if known.
+    "
+    ALTER TABLE arrivals ADD COLUMN occupancy INTEGER;
+    ",
+    // Columns that are optional in GTFS can be empty. SQLite cannot drop a NOT NULL
+    // constraint, so the tables are copied into new ones without it.
+    "
+    CREATE TABLE stop_times_optional (
+        operator TEXT NOT NULL,
+        trip_id TEXT NOT NULL,
+        arrival_time TEXT NOT NULL,
+        departure_time TEXT NOT NULL,
+        stop_id TEXT NOT NULL,
+        stop_sequence INTEGER NOT NULL,
+        stop_headsign TEXT NOT NULL,
+        pickup_type INTEGER,
+        drop_off_type INTEGER,
+        shape_dist_traveled REAL,
+        timepoint INTEGER
+    );
+    INSERT INTO stop_times_optional SELECT * FROM stop_times;
+    DROP TABLE stop_times;
+    ALTER TABLE stop_times_optional RENAME TO stop_times;
+    CREATE INDEX stop_times_by_trip ON stop_times (operator, trip_id);
+
+    CREATE TABLE stops_optional (
+        operator TEXT NOT NULL,
+        stop_id TEXT NOT NULL,
+        stop_name TEXT NOT NULL,
+        stop_lat TEXT NOT NULL,
+        stop_lon TEXT NOT NULL,
+        location_type INTEGER,
+        parent_station TEXT,
+        platform_code TEXT,
+        PRIMARY KEY (operator, stop_id)
+    );
+    INSERT INTO stops_optional SELECT * FROM stops;
+    DROP TABLE stops;
+    ALTER TABLE stops_optional RENAME TO stops;
+    ",
+];
+
+/// The format of service dates in the arrivals table.
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// The tables that hold static data.
+const STATIC_TABLES: &[&str] = &["routes", "trips", "shapes", "stop_times", "stops"];
+
+/// A SQLite file with static data for every operator, reservations and observed arrivals.
+/// Clones share the same connection.
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteDatabase {
+    /// Opens (or creates) the file at `path` and migrates it to the latest schema.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let mut conn = Connection::open(path).map_err(|err| err.to_string())?;
+
+        conn.busy_timeout(BUSY_TIMEOUT)
+            .map_err(|err| err.to_string())?;
+        migrate(&mut conn)?;
+
+        Ok(SqliteDatabase {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Imports the GTFS zip at `path` as the static data of `operator`, unless it has already
+    /// been imported. The zip counts as the same if it has the same path, size and
+    /// modification time. Returns true if it was imported.
+    pub fn import_gtfs(&self, operator: &str, path: &str) -> Result<bool, String> {
+        let metadata = fs::metadata(path).map_err(|err| err.to_string())?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or_default();
+
+        let source = format!("{} ({} bytes, modified {})", path, metadata.len(), modified);
+
+        let imported = self
+            .with(|conn| {
+                conn.query_row(
+                    "SELECT source FROM imports WHERE operator = ?",
+                    params![operator],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .map_err(|err| err.to_string())?;
+
+        if imported.as_deref() == Some(source.as_str()) {
+            return Ok(false);
+        }
+
+        let tables = StaticTables::open(path)?;
+        self.import(operator, &tables, &source)
+            .map_err(|err| err.to_string())?;
+
+        info!(
+            %operator,
+            routes = tables.routes.len(),
+            trips = tables.trips.len(),
+            "Imported static data into the SQLite database."
+        );
+
+        Ok(true)
+    }
+
+    /// Replaces the static data of `operator` with `tables`, which came from `source`.
+    pub fn import(
+        &self,
+        operator: &str,
+        tables: &StaticTables,
+        source: &str,
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for table in STATIC_TABLES {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE operator = ?", table),
+                params![operator],
+            )?;
+        }
+
+        {
+            let mut insert =
+                tx.prepare("INSERT OR REPLACE INTO routes VALUES (?, ?, ?, ?, ?, ?, ?)")?;
+            for route in &tables.routes {
+                insert.execute(params![
+                    operator,
+                    route.route_id,
+                    route.agency_id,
+                    route.route_short_name,
+                    route.route_long_name,
+                    route.route_type,
+                    route.route_desc,
+                ])?;
+            }
+
+            let mut insert =
+                tx.prepare("INSERT OR REPLACE INTO trips VALUES (?, ?, ?, ?, ?, ?, ?)")?;
+            for trip in &tables.trips {
+                insert.execute(params![
+                    operator,
+                    trip.route_id,
+                    trip.service_id,
+                    trip.trip_id,
+                    trip.trip_headsign,
+                    trip.direction_id,
+                    trip.shape_id,
+                ])?;
+            }
+
+            let mut insert = tx.prepare("INSERT INTO shapes VALUES (?, ?, ?, ?, ?, ?)")?;
+            for shape in &tables.shapes {
+                insert.execute(params![
+                    operator,
+                    shape.shape_id,
+                    shape.shape_pt_lat,
+                    shape.shape_pt_lon,
+                    shape.shape_pt_sequence,
+                    shape.shape_dist_traveled,
+                ])?;
+            }
+
+            let mut insert =
+                tx.prepare("INSERT INTO stop_times VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+            for stop_time in &tables.stop_times {
+                insert.execute(params![
+                    operator,
+                    stop_time.trip_id,
+                    stop_time.arrival_time,
+                    stop_time.departure_time,
+                    stop_time.stop_id,
+                    stop_time.stop_sequence,
+                    stop_time.stop_headsign,
+                    stop_time.pickup_type,
+                    stop_time.drop_off_type,
+                    stop_time.shape_dist_traveled,
+                    stop_time.timepoint,
+                ])?;
+            }
+
+            let mut insert =
+                tx.prepare("INSERT OR REPLACE INTO stops VALUES (?, ?, ?, ?, ?, ?, ?, ?)")?;
+            for stop in &tables.stops {
+                insert.execute(params![
+                    operator,
+                    stop.stop_id,
+                    stop.stop_name,
+                    stop.stop_lat,
+                    stop.stop_lon,
+                    stop.location_type,
+                    stop.parent_station,
+                    stop.platform_code,
+                ])?;
+            }
+        }
+
+        tx.execute(
+            "INSERT OR REPLACE INTO imports VALUES (?, ?)",
+            params![operator, source],
+        )?;
+
+        tx.commit()
+    }
+
+    /// Returns the static data of `operator`.
+    pub fn static_store(&self, operator: &str) -> SqliteStaticStore {
+        SqliteStaticStore {
+            database: self.clone(),
+            operator: operator.to_owned(),
+        }
+    }
+
+    /// Returns the reservations in the file. Reservations that were made before the server
+    /// started are forgotten, since their clients have been disconnected, but the seats stay
+    /// taken.
+    pub fn reservations(&self) -> Result<SqliteReservations, String> {
+        self.with(|conn| conn.execute("DELETE FROM reservations", NO_PARAMS))
+            .map_err(|err| err.to_string())?;
+
+        Ok(SqliteReservations {
+            database: self.clone(),
+        })
+    }
+
+    /// Returns the observed arrivals in the file.
+    pub fn history(&self) -> SqliteHistory {
+        SqliteHistory {
+            database: self.clone(),
+        }
+    }
+
+    /// Runs `query` on a thread pool, since SQLite blocks while it reads the file. The future
+    /// resolves to `None` if the query fails.
+    fn query<T, F>(&self, table: &'static str, query: F) -> StoreFuture<Option<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let database = self.clone();
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS.with_label_values(&[table]).start_timer();
+
+            match web::block(move || database.with(query)).await {
+                Ok(result) => Some(result),
+                Err(err) => {
+                    warn!(
+                        table,
+                        "Could not query the SQLite database. Reason: {}", err
+                    );
+                    None
+                }
+            }
+        })
+    }
+
+    /// Runs `f` with the connection locked.
+    fn with<T>(&self, f: impl FnOnce(&Connection) -> T) -> T {
+        let conn = self.conn.lock().unwrap();
+
+        f(&conn)
+    }
+}
+
+/// Applies every migration that has not been applied to the file yet.
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version = conn
+        .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get::<_, i64>(0))
+        .map_err(|err| err.to_string())? as usize;
+
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "The file has schema version {}, but this server only knows about version {}",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let migrated = conn.transaction().and_then(|tx| {
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", &((index + 1) as i64))?;
+            tx.commit()
+        });
+
+        migrated.map_err(|err| format!("Migration {} failed: {}", index + 1, err))?;
+
+        info!(version = index + 1, "Migrated the SQLite database.");
+    }
+
+    Ok(())
+}
+
+/// Static data for a single operator in a SQLite file.
+///
+/// SQLite blocks while it reads the file, so queries are run on a thread pool instead of the
+/// actor that awaits them.
+pub struct SqliteStaticStore {
+    database: SqliteDatabase,
+    operator: String,
+}
+
+impl SqliteStaticStore {
+    /// Runs `query` with the connection and the name of the operator. The future resolves to
+    /// `None` if the query fails.
+    fn query<T, F>(&self, table: &'static str, query: F) -> StoreFuture<Option<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection, &str) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let operator = self.operator.clone();
+
+        self.database
+            .query(table, move |conn| query(conn, &operator))
+    }
+
+    /// Looks up every row of `table` where `column` is `value`, ordered by `order_by`.
+    fn select_all<T, F>(
+        &self,
+        table: &'static str,
+        column: &'static str,
+        value: &str,
+        order_by: &'static str,
+        from_row: F,
+    ) -> StoreFuture<Option<Vec<T>>>
+    where
+        T: Send + 'static,
+        F: Fn(&Row) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let value = value.to_owned();
+
+        self.query(table, move |conn, operator| {
+            let mut statement = conn.prepare(&format!(
+                "SELECT * FROM {} WHERE operator = ? AND {} = ? ORDER BY {}",
+                table, column, order_by
+            ))?;
+
+            let rows = statement.query_map(params![operator, value], from_row)?;
+            rows.collect()
+        })
+    }
+
+    /// Looks up the first row of `table` where `column` is `value`.
+    fn select_one<T, F>(
+        &self,
+        table: &'static str,
+        column: &'static str,
+        value: &str,
+        from_row: F,
+    ) -> StoreFuture<Option<T>>
+    where
+        T: Send + 'static,
+        F: Fn(&Row) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let found = self.select_all(table, column, value, "rowid", from_row);
+
+        Box::pin(async move { found.await.and_then(|rows| rows.into_iter().next()) })
+    }
+}
+
+impl StaticStore for SqliteStaticStore {
+    fn ping(&self) -> StoreFuture<bool> {
+        let result = self.query("ping", |conn, _| {
+            conn.query_row("SELECT 1", NO_PARAMS, |row| row.get::<_, i64>(0))
+        });
+
+        Box::pin(async move { result.await.is_some() })
+    }
+
+    fn has_static_data(&self) -> StoreFuture<bool> {
+        let result = self.query("static_data", |conn, operator| {
+            let mut found = true;
+
+            for table in &["routes", "trips", "shapes"] {
+                found &= conn
+                    .query_row(
+                        &format!("SELECT 1 FROM {} WHERE operator = ? LIMIT 1", table),
+                        params![operator],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some();
+            }
+
+            Ok(found)
+        });
+
+        Box::pin(async move { result.await.unwrap_or(false) })
+    }
+
+    fn route_by_short_name(&self, short_name: &str) -> StoreFuture<Option<Route>> {
+        self.select_one("routes", "route_short_name", short_name, route_from_row)
+    }
+
+    fn route(&self, route_id: &str) -> StoreFuture<Option<Route>> {
+        self.select_one("routes", "route_id", route_id, route_from_row)
+    }
+
+    fn trip(&self, trip_id: &str) -> StoreFuture<Option<Trip>> {
+        self.select_one("trips", "trip_id", trip_id, trip_from_row)
+    }
+
+    fn trips(&self, query: TripQuery) -> StoreFuture<Option<Vec<Trip>>> {
+        let (column, value) = match &query {
+            TripQuery::Route(route_id) => ("route_id", route_id),
+            TripQuery::Trip(trip_id) => ("trip_id", trip_id),
+            TripQuery::Shape(shape_id) => ("shape_id", shape_id),
+        };
+
+        self.select_all("trips", column, value, "trip_id", trip_from_row)
+    }
+
+    fn shape(&self, shape_id: &str) -> StoreFuture<Option<Vec<Point>>> {
+        let rows = self.select_all("shapes", "shape_id", shape_id, "rowid", shape_from_row);
+
+        Box::pin(async move { rows.await.map(|rows| shape_points(&rows)) })
+    }
+
+    fn stop_times(&self, trip_id: &str) -> StoreFuture<Option<Vec<StopTime>>> {
+        self.select_all(
+            "stop_times",
+            "trip_id",
+            trip_id,
+            "stop_sequence",
+            stop_time_from_row,
+        )
+    }
+
+    fn stops(&self, stop_ids: &[String]) -> StoreFuture<Option<Vec<Stop>>> {
+        let stop_ids = stop_ids.to_vec();
+
+        self.query("stops", move |conn, operator| {
+            let placeholders = vec!["?"; stop_ids.len()].join(", ");
+            let mut statement = conn.prepare(&format!(
+                "SELECT * FROM stops WHERE operator = ? AND stop_id IN ({})",
+                placeholders
+            ))?;
+
+            let values = std::iter::once(operator.to_owned()).chain(stop_ids);
+            let rows = statement.query_map(values, stop_from_row)?;
+            rows.collect()
+        })
+    }
+}
+
+fn route_from_row(row: &Row) -> rusqlite::Result<Route> {
+    Ok(Route {
+        route_id: row.get("route_id")?,
+        agency_id: row.get("agency_id")?,
+        route_short_name: row.get("route_short_name")?,
+        route_long_name: row.get("route_long_name")?,
+        route_type: row.get("route_type")?,
+        route_desc: row.get("route_desc")?,
+    })
+}
+
+fn trip_from_row(row: &Row) -> rusqlite::Result<Trip> {
+    Ok(Trip {
+        route_id: row.get("route_id")?,
+        service_id: row.get("service_id")?,
+        trip_id: row.get("trip_id")?,
+        trip_headsign: row.get("trip_headsign")?,
+        direction_id: row.get("direction_id")?,
+        shape_id: row.get("shape_id")?,
+    })
+}
+
+fn shape_from_row(row: &Row) -> rusqlite::Result<Shape> {
+    Ok(Shape {
+        shape_id: row.get("shape_id")?,
+        shape_pt_lat: row.get("shape_pt_lat")?,
+        shape_pt_lon: row.get("shape_pt_lon")?,
+        shape_pt_sequence: row.get("shape_pt_sequence")?,
+        shape_dist_traveled: row.get("shape_dist_traveled")?,
+    })
+}
+
+fn stop_time_from_row(row: &Row) -> rusqlite::Result<StopTime> {
+    Ok(StopTime {
+        trip_id: row.get("trip_id")?,
+        arrival_time: row.get("arrival_time")?,
+        departure_time: row.get("departure_time")?,
+        stop_id: row.get("stop_id")?,
+        stop_sequence: row.get("stop_sequence")?,
+        stop_headsign: row.get("stop_headsign")?,
+        pickup_type: row.get("pickup_type")?,
+        drop_off_type: row.get("drop_off_type")?,
+        shape_dist_traveled: row.get("shape_dist_traveled")?,
+        timepoint: row.get("timepoint")?,
+    })
+}
+
+fn stop_from_row(row: &Row) -> rusqlite::Result<Stop> {
+    Ok(Stop {
+        stop_id: row.get("stop_id")?,
+        stop_name: row.get("stop_name")?,
+        stop_lat: row.get("stop_lat")?,
+        stop_lon: row.get("stop_lon")?,
+        location_type: row.get("location_type")?,
+        parent_station: row.get("parent_station")?,
+        platform_code: row.get("platform_code")?,
+    })
+}
+
+/// Passenger information and reservations in a SQLite file, so that seat counts survive a
+/// restart.
+///
+/// The connection is shared with the static data and the history, so a long import can hold
+/// it for a while. Queries are run on a thread pool, like the other queries, so that the
+/// reservation manager never blocks while it waits for the connection.
+pub struct SqliteReservations {
+    database: SqliteDatabase,
+}
+
+impl SqliteReservations {
+    /// Runs `query` on a thread pool, and logs the error and resolves to the default value if
+    /// it fails.
+    fn logged<T, F>(&self, query: F) -> StoreFuture<T>
+    where
+        T: Default + Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            let _timer = DB_QUERY_SECONDS
+                .with_label_values(&["reservations"])
+                .start_timer();
+
+            web::block(move || database.with(query))
+                .await
+                .unwrap_or_else(|err| {
+                    error!(
+                        "Could not query reservations in the SQLite database. Reason: {}",
+                        err
+                    );
+                    T::default()
+                })
+        })
+    }
+}
+
+impl ReservationStore for SqliteReservations {
+    fn passenger_info(
+        &self,
+        descriptor_id: &str,
+    ) -> StoreFuture<Option<PassengerInformationOutput>> {
+        let descriptor_id = descriptor_id.to_owned();
+
+        self.logged(move |conn| select_passenger_info(conn, &descriptor_id))
+    }
+
+    fn all_passenger_info(&self) -> StoreFuture<HashMap<String, PassengerInformationOutput>> {
+        self.logged(|conn| {
+            let mut statement =
+                conn.prepare("SELECT descriptor_id, capacity, passengers FROM passenger_info")?;
+
+            let rows = statement.query_map(NO_PARAMS, |row| {
+                let passenger_info = PassengerInformationOutput {
+                    capacity: row.get(1)?,
+                    passengers: row.get(2)?,
+                    forecast: None,
+                };
+
+                Ok((row.get(0)?, passenger_info))
+            })?;
+
+            rows.collect()
+        })
+    }
+
+    fn create_passenger_info(
+        &self,
+        descriptor_id: &str,
+        passenger_info: PassengerInformationOutput,
+    ) -> StoreFuture<Option<PassengerInformationOutput>> {
+        let descriptor_id = descriptor_id.to_owned();
+
+        self.logged(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO passenger_info VALUES (?, ?, ?)",
+                params![
+                    descriptor_id,
+                    passenger_info.capacity,
+                    passenger_info.passengers
+                ],
+            )?;
+
+            select_passenger_info(conn, &descriptor_id)
+        })
+    }
+
+    fn reserve_seat(
+        &self,
+        client_id: Uuid,
+        descriptor_id: &str,
+    ) -> StoreFuture<Result<(), ReserveError>> {
+        let descriptor_id = descriptor_id.to_owned();
+
+        // Resolves to `None` for vehicles without passenger information.
+        let result = self.logged(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+
+            let taken = tx.execute(
+                "UPDATE passenger_info SET passengers = passengers + 1
+                WHERE descriptor_id = ? AND passengers < capacity",
+                params![descriptor_id],
+            )?;
+
+            if taken == 0 {
+                let full =
+                    select_passenger_info(&tx, &descriptor_id)?.map(|_| Err(ReserveError::Full));
+                return Ok(full);
+            }
+
+            tx.execute(
+                "INSERT OR REPLACE INTO reservations VALUES (?, ?)",
+                params![client_id.to_string(), descriptor_id],
+            )?;
+
+            tx.commit()?;
+            Ok(Some(Ok(())))
+        });
+
+        Box::pin(async move { result.await.unwrap_or(Err(ReserveError::UnknownVehicle)) })
+    }
+
+    fn unreserve_seat(&self, client_id: Uuid) -> StoreFuture<Option<String>> {
+        let client_id = client_id.to_string();
+
+        self.logged(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+
+            let descriptor_id = tx
+                .query_row(
+                    "SELECT descriptor_id FROM reservations WHERE client_id = ?",
+                    params![client_id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+
+            if let Some(descriptor_id) = &descriptor_id {
+                tx.execute(
+                    "DELETE FROM reservations WHERE client_id = ?",
+                    params![client_id],
+                )?;
+                tx.execute(
+                    "UPDATE passenger_info SET passengers = passengers - 1 WHERE descriptor_id = ?",
+                    params![descriptor_id],
+                )?;
+            }
+
+            tx.commit()?;
+            Ok(descriptor_id)
+        })
+    }
+
+    fn forget_reservation(&self, client_id: Uuid) -> StoreFuture<()> {
+        self.logged(move |conn| {
+            conn.execute(
+                "DELETE FROM reservations WHERE client_id = ?",
+                params![client_id.to_string()],
+            )
+            .map(|_| ())
+        })
+    }
+
+    fn reservation_count(&self) -> StoreFuture<usize> {
+        self.logged(|conn| {
+            conn.query_row("SELECT COUNT(*) FROM reservations", NO_PARAMS, |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|count| count as usize)
+        })
+    }
+}
+
+/// Looks up the passenger information for a vehicle.
+fn select_passenger_info(
+    conn: &Connection,
+    descriptor_id: &str,
+) -> rusqlite::Result<Option<PassengerInformationOutput>> {
+    conn.query_row(
+        "SELECT capacity, passengers FROM passenger_info WHERE descriptor_id = ?",
+        params![descriptor_id],
+        |row| {
+            Ok(PassengerInformationOutput {
+                capacity: row.get(0)?,
+                passengers: row.get(1)?,
+                forecast: None,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Observed arrivals in a SQLite file. Like static data, they are read and written on a thread
+/// pool.
+pub struct SqliteHistory {
+    database: SqliteDatabase,
+}
+
+impl HistoryStore for SqliteHistory {
+    fn record(&self, arrivals: Vec<ObservedArrival>) -> StoreFuture<bool> {
+        let recorded = self.database.query("arrivals", move |conn| {
+            let tx = conn.unchecked_transaction()?;
+
+            {
+                let mut insert = tx.prepare(
+                    "INSERT INTO arrivals VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                     ON CONFLICT (operator, trip_id, stop_sequence, service_date)
+                     DO UPDATE SET occupancy = COALESCE(occupancy, excluded.occupancy)",
+                )?;
+
+                for arrival in &arrivals {
+                    insert.execute(params![
+                        arrival.operator,
+                        arrival.line,
+                        arrival.trip_id,
+                        arrival.stop_id,
+                        arrival.stop_sequence,
+                        arrival.service_date.format(DATE_FORMAT).to_string(),
+                        arrival.scheduled,
+                        arrival.observed,
+                        arrival.occupancy,
+                    ])?;
+                }
+            }
+
+            tx.commit()
+        });
+
+        Box::pin(async move { recorded.await.is_some() })
+    }
+
+    fn arrivals(&self, query: HistoryQuery) -> StoreFuture<Option<Vec<ObservedArrival>>> {
+        self.database.query("arrivals", move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT * FROM arrivals WHERE operator = ?1
+                 AND (?2 IS NULL OR line = ?2)
+                 AND (?3 IS NULL OR stop_id = ?3)
+                 AND service_date BETWEEN ?4 AND ?5
+                 ORDER BY service_date, scheduled, stop_sequence",
+            )?;
+
+            let rows = statement.query_map(
+                params![
+                    query.operator,
+                    query.line,
+                    query.stop_id,
+                    query.from.format(DATE_FORMAT).to_string(),
+                    query.to.format(DATE_FORMAT).to_string(),
+                ],
+                arrival_from_row,
+            )?;
+
+            rows.collect()
+        })
+    }
+
+    fn prune(&self, date: NaiveDate) -> StoreFuture<bool> {
+        let date = date.format(DATE_FORMAT).to_string();
+
+        let pruned = self.database.query("arrivals", move |conn| {
+            conn.execute("DELETE FROM arrivals WHERE service_date < ?", params![date])
+        });
+
+        Box::pin(async move { pruned.await.is_some() })
+    }
+}
+
+fn arrival_from_row(row: &Row) -> rusqlite::Result<ObservedArrival> {
+    let service_date = row.get::<_, String>("service_date")?;
+
+    Ok(ObservedArrival {
+        operator: row.get("operator")?,
+        line: row.get("line")?,
+        trip_id: row.get("trip_id")?,
+        stop_id: row.get("stop_id")?,
+        stop_sequence: row.get("stop_sequence")?,
+        // Only valid dates are stored, but a file that has been edited by hand may have others.
+        service_date: NaiveDate::parse_from_str(&service_date, DATE_FORMAT).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
+        })?,
+        scheduled: row.get("scheduled")?,
+        observed: row.get("observed")?,
+        occupancy: row.get("occupancy")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::gtfs::tests::line_5;
+    use crate::store::memory::tests::{arrival, may_query};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_migrations() {
+        let dir = TempDir::new("sqlite_test_dir").unwrap();
+        let path = dir.path().join("busplus.sqlite3");
+        let path = path.to_str().unwrap();
+
+        SqliteDatabase::open(path).unwrap();
+
+        // Opening a migrated file again does not migrate it again.
+        let database = SqliteDatabase::open(path).unwrap();
+        let version: i64 = database
+            .with(|conn| conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0)))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        // A GTFS zip is only imported again when it has changed.
+        let zip_path = dir.path().join("ul.zip");
+        fs::write(&zip_path, line_5().into_inner()).unwrap();
+        let zip_path = zip_path.to_str().unwrap();
+
+        assert_eq!(database.import_gtfs("ul", zip_path), Ok(true));
+        assert_eq!(database.import_gtfs("ul", zip_path), Ok(false));
+        assert!(database.import_gtfs("ul", "does_not_exist.zip").is_err());
+
+        // Static data that was imported before the optional columns could be empty is kept.
+        let mut old = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..3] {
+            old.execute_batch(migration).unwrap();
+        }
+        old.execute_batch(
+            "PRAGMA user_version = 3;
+             INSERT INTO stops VALUES ('ul', '1', 'Centralstationen', '59.8', '17.6', 0, NULL, NULL);",
+        )
+        .unwrap();
+        migrate(&mut old).unwrap();
+        let location_type: Option<i32> = old
+            .query_row("SELECT location_type FROM stops", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(location_type, Some(0));
+
+        // A file from a newer server is not touched.
+        database
+            .with(|conn| conn.pragma_update(None, "user_version", &100))
+            .unwrap();
+        assert!(SqliteDatabase::open(path).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite_static_store() {
+        let database = SqliteDatabase::open(":memory:").unwrap();
+        let tables = StaticTables::from_zip(line_5()).unwrap();
+
+        // Importing twice replaces the first import.
+        database.import("ul", &tables, "line_5.zip").unwrap();
+        database.import("ul", &tables, "line_5.zip").unwrap();
+
+        let store = database.static_store("ul");
+        assert!(store.ping().await);
+        assert!(store.has_static_data().await);
+        assert!(!database.static_store("sl").has_static_data().await);
+
+        let route = store.route_by_short_name("5").await.unwrap();
+        assert_eq!(route, tables.routes[0]);
+        assert_eq!(store.route(&route.route_id).await, Some(route.clone()));
+
+        let trips = store
+            .trips(TripQuery::Route(route.route_id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(trips, tables.trips);
+        assert_eq!(store.trip(&trips[0].trip_id).await, Some(trips[0].clone()));
+
+        // Only the operator's own data is found.
+        assert_eq!(
+            database
+                .static_store("sl")
+                .trips(TripQuery::Route(route.route_id))
+                .await,
+            Some(Vec::new())
+        );
+
+        let points = store.shape(&trips[0].shape_id).await.unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].lat, 59.858);
+
+        let stop_times = store.stop_times(&trips[0].trip_id).await.unwrap();
+        assert_eq!(stop_times[0].stop_sequence, 1);
+        assert_eq!(stop_times[1].stop_sequence, 2);
+
+        let stop_ids = stop_times
+            .iter()
+            .map(|stop_time| stop_time.stop_id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(store.stops(&stop_ids).await.unwrap().len(), 2);
+
+        // Columns that are optional in GTFS can be empty.
+        let mut tables = tables;
+        tables.stops[0].location_type = None;
+        database.import("ul", &tables, "line_5.zip").unwrap();
+
+        let stops = store.stops(&stop_ids[..1]).await.unwrap();
+        assert_eq!(stops[0].location_type, None);
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite_reservations() {
+        let database = SqliteDatabase::open(":memory:").unwrap();
+        let store = database.reservations().unwrap();
+        let client_id = Uuid::new_v4();
+
+        let passenger_info = PassengerInformationOutput {
+            capacity: 12,
+            passengers: 10,
+            forecast: None,
+        };
+        let created = store.create_passenger_info("ul:9031003", passenger_info.clone());
+        assert_eq!(created.await, Some(passenger_info.clone()));
+
+        // Only the first passenger information that is created for a vehicle is kept.
+        let created = store.create_passenger_info(
+            "ul:9031003",
+            PassengerInformationOutput {
+                passengers: 0,
+                ..passenger_info.clone()
+            },
+        );
+        assert_eq!(created.await, Some(passenger_info));
+        assert_eq!(store.passenger_info("ul:0").await, None);
+        assert_eq!(store.all_passenger_info().await.len(), 1);
+
+        assert_eq!(store.reserve_seat(client_id, "ul:9031003").await, Ok(()));
+        assert_eq!(
+            store.reserve_seat(Uuid::new_v4(), "ul:9031003").await,
+            Ok(())
+        );
+        assert_eq!(
+            store.reserve_seat(Uuid::new_v4(), "ul:9031003").await,
+            Err(ReserveError::Full)
+        );
+        assert_eq!(
+            store.reserve_seat(client_id, "ul:0").await,
+            Err(ReserveError::UnknownVehicle)
+        );
+        assert_eq!(store.reservation_count().await, 2);
+
+        // The seats stay taken when the server restarts, but the reservations are forgotten.
+        let store = database.reservations().unwrap();
+        assert_eq!(store.reservation_count().await, 0);
+        assert_eq!(
+            store.passenger_info("ul:9031003").await.unwrap().passengers,
+            12
+        );
+
+        assert_eq!(store.unreserve_seat(client_id).await, None);
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite_unreserve_seat() {
+        let database = SqliteDatabase::open(":memory:").unwrap();
+        let store = database.reservations().unwrap();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let passenger_info = PassengerInformationOutput {
+            capacity: 30,
+            passengers: 10,
+            forecast: None,
+        };
+        store
+            .create_passenger_info("ul:9031003", passenger_info)
+            .await;
+        store.reserve_seat(first, "ul:9031003").await.unwrap();
+        store.reserve_seat(second, "ul:9031003").await.unwrap();
+
+        assert_eq!(
+            store.unreserve_seat(first).await.as_deref(),
+            Some("ul:9031003")
+        );
+        assert_eq!(store.unreserve_seat(first).await, None);
+        assert_eq!(
+            store.passenger_info("ul:9031003").await.unwrap().passengers,
+            11
+        );
+
+        // A forgotten reservation keeps its seat.
+        store.forget_reservation(second).await;
+        assert_eq!(store.reservation_count().await, 0);
+        assert_eq!(
+            store.passenger_info("ul:9031003").await.unwrap().passengers,
+            11
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite_history() {
+        let database = SqliteDatabase::open(":memory:").unwrap();
+        let history = database.history();
+
+        // An arrival that is observed again keeps the time it was first observed at, and
+        // gets an occupancy if it did not have one.
+        let full = |mut arrival: ObservedArrival, occupancy| {
+            arrival.occupancy = Some(occupancy);
+            arrival
+        };
+
+        assert!(history.record(vec![arrival(2, 1_620_633_780)]).await);
+        assert!(
+            history
+                .record(vec![
+                    full(arrival(2, 1_620_633_840), 80),
+                    full(arrival(3, 1_620_633_900), 60),
+                ])
+                .await
+        );
+        assert!(
+            history
+                .record(vec![full(arrival(3, 1_620_633_900), 90)])
+                .await
+        );
+
+        let arrivals = history.arrivals(may_query(10, 10)).await.unwrap();
+        assert_eq!(
+            arrivals,
+            vec![
+                full(arrival(2, 1_620_633_780), 80),
+                full(arrival(3, 1_620_633_900), 60),
+            ]
+        );
+
+        assert!(history
+            .arrivals(may_query(11, 31))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut at_stop = may_query(1, 31);
+        at_stop.line = None;
+        at_stop.stop_id = Some("9022003700021002".to_owned());
+        assert_eq!(history.arrivals(at_stop.clone()).await.unwrap().len(), 2);
+
+        at_stop.stop_id = Some("9022003700021001".to_owned());
+        assert!(history.arrivals(at_stop).await.unwrap().is_empty());
+
+        assert!(
+            history
+                .prune(NaiveDate::from_ymd_opt(2021, 5, 11).unwrap())
+                .await
+        );
+        assert!(history.arrivals(may_query(1, 31)).await.unwrap().is_empty());
+    }
+}
@@ -1,7 +1,9 @@
 //! Utilities. Functions that do not belong anywhere else.
 
+use chrono::NaiveDate;
 use geoutils::Location;
 
+use crate::gtfs::transit_realtime::Position;
 use crate::protocol::client_protocol::GeoPosition;
 use crate::protocol::server_protocol::Vehicle;
 
@@ -24,3 +26,105 @@ pub fn filter_vehicle_position(client_geo: &GeoPosition, vhc: &Vehicle) -> bool
 pub fn only_numbers(input: &str) -> bool {
     input.chars().all(|c| c.is_numeric())
 }
+
+/// The format of dates in GTFS files and feeds.
+pub const GTFS_DATE_FORMAT: &str = "%Y%m%d";
+
+/// Parses a date in a GTFS file or feed ("YYYYMMDD").
+pub fn parse_gtfs_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date.trim(), GTFS_DATE_FORMAT).ok()
+}
+
+/// Parses a time in a GTFS file ("HH:MM:SS") to seconds after midnight. The hours can be 24
+/// or more for trips that run past midnight.
+pub fn parse_gtfs_time(time: &str) -> Option<i64> {
+    let mut parts = time.trim().split(':').map(|part| part.parse::<i64>().ok());
+
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) => {
+            Some(hours * 3600 + minutes * 60 + seconds)
+        }
+        _ => None,
+    }
+}
+
+/// A rectangular area given as "west,south,east,north" (longitudes and latitudes), which is
+/// the same order as the "bbox" member in GeoJSON.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub west: f32,
+    pub south: f32,
+    pub east: f32,
+    pub north: f32,
+}
+
+impl BoundingBox {
+    /// Parses a bounding box from a comma separated string like "17.5,59.8,17.8,59.9".
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let values = input
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| format!("'{}' contains a value that is not a number", input))?;
+
+        let (west, south, east, north) = match values.as_slice() {
+            [west, south, east, north] => (*west, *south, *east, *north),
+            _ => return Err(format!("'{}' must contain exactly four values", input)),
+        };
+
+        if !(-180.0..=180.0).contains(&west) || !(-180.0..=180.0).contains(&east) {
+            return Err(format!("'{}' contains an invalid longitude", input));
+        }
+
+        if !(-90.0..=90.0).contains(&south) || !(-90.0..=90.0).contains(&north) || south > north {
+            return Err(format!("'{}' contains an invalid latitude", input));
+        }
+
+        Ok(BoundingBox {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+
+    /// Returns true if the position is inside the bounding box. A bounding box where west is
+    /// greater than east crosses the antimeridian.
+    pub fn contains(&self, position: &Position) -> bool {
+        let within_longitude = if self.west <= self.east {
+            (self.west..=self.east).contains(&position.longitude)
+        } else {
+            position.longitude >= self.west || position.longitude <= self.east
+        };
+
+        within_longitude && (self.south..=self.north).contains(&position.latitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounding_box() {
+        let bbox = BoundingBox::parse("17.5,59.8,17.8,59.9").unwrap();
+
+        let inside = Position {
+            latitude: 59.85,
+            longitude: 17.63,
+            ..Position::default()
+        };
+        let outside = Position {
+            latitude: 59.85,
+            longitude: 18.0,
+            ..Position::default()
+        };
+
+        assert!(bbox.contains(&inside));
+        assert!(!bbox.contains(&outside));
+
+        assert!(BoundingBox::parse("17.5,59.8,17.8").is_err());
+        assert!(BoundingBox::parse("17.5,59.8,17.8,north").is_err());
+        assert!(BoundingBox::parse("17.5,59.9,17.8,59.8").is_err());
+    }
+}
@@ -1,25 +1,22 @@
 //! Everything related to managing a WebSocket connection.
 
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use actix::prelude::*;
-use actix_web_actors::ws;
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use tracing::{debug, debug_span, field, info, info_span, trace, Span};
 use uuid::Uuid;
 
+use crate::config::HeartbeatSettings;
 use crate::lobby::Lobby;
 use crate::messages::{
-    Connect, Disconnect, PassengerInfo, PositionUpdate, ReserveSeat, RouteRequest, UnreserveSeat,
-    WsMessage,
+    CloseConnection, Connect, Disconnect, LineHealth, PassengerInfo, PositionUpdate, ReserveSeat,
+    RouteRequest, UnreserveSeat, WsMessage,
 };
+use crate::metrics::CLIENT_MESSAGES;
 use crate::protocol::client_protocol::ClientInput;
 use crate::protocol::server_protocol::{ErrorType, ServerOutput};
 
-/// How often heartbeat pings are sent.
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-
-/// How long before lack of client response causes a timeout.
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-
 /// Represents a client connected to the server via an open Websocket connection.
 pub struct WebsocketClient {
     /// Address to communicate with the lobby actor.
@@ -30,24 +27,40 @@ pub struct WebsocketClient {
 
     /// Timestamp for the latest received message from the client (heartbeat).
     hb: Instant,
+
+    /// How often pings are sent and how long the client has to respond to them.
+    heartbeat: HeartbeatSettings,
+
+    /// How many messages can wait to be sent to the client.
+    mailbox_capacity: usize,
+
+    /// Span for everything that happens during the connection.
+    span: Span,
 }
 
 impl WebsocketClient {
-    pub fn new(lobby: Addr<Lobby>) -> Self {
+    pub fn new(lobby: Addr<Lobby>, heartbeat: HeartbeatSettings, mailbox_capacity: usize) -> Self {
+        let id = Uuid::new_v4();
+
         WebsocketClient {
             lobby_addr: lobby,
-            id: Uuid::new_v4(),
+            id,
             hb: Instant::now(),
+            heartbeat,
+            mailbox_capacity,
+            span: info_span!("ws_session", client_id = %id),
         }
     }
 
     /// Starts an interval which runs a function that checks if we've gotten a
-    /// response from the user/any ping sent during the `CLIENT_TIMEMOUT` duration.
+    /// response from the user/any ping sent during the client timeout.
     pub fn hb(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        ctx.run_interval(self.heartbeat.interval(), |act, ctx| {
             // Check if the duration since we've gotten a response from the client
-            // is larger than our allowed timeout time (CLIENT_TIMEOUT).
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            // is larger than our allowed timeout time.
+            if Instant::now().duration_since(act.hb) > act.heartbeat.client_timeout() {
+                info!(parent: &act.span, "Client timed out.");
+
                 // Send a disconnect message to the lobby before closing the connection.
                 act.lobby_addr.do_send(Disconnect { self_id: act.id });
 
@@ -72,9 +85,14 @@ impl Actor for WebsocketClient {
         // Start the heartbet interval. This is really important.
         self.hb(ctx);
 
+        // The mailbox only fills up when the connection cannot keep up, since the actor only
+        // reads it when there is room to write to the connection.
+        ctx.set_mailbox_capacity(self.mailbox_capacity);
+
         // Send connect message to the lobby.
         self.lobby_addr.do_send(Connect {
             addr: ctx.address().recipient(),
+            close: ctx.address().recipient(),
             self_id: self.id,
         });
     }
@@ -91,15 +109,10 @@ impl Actor for WebsocketClient {
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketClient {
     // This method handles any incoming message by any client.
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        // Prints what kind of message is received to the terminal if it's not a ping or pong.
-        // Uncomment these lines to debug messages.
-        /*
-        match msg {
-            Ok(ws::Message::Pong(_)) => (),
-            Ok(ws::Message::Ping(_)) => (),
-            _ => println!("WS: {:?}", msg),
-        }
-        */
+        let span = self.span.clone();
+        let _session = span.enter();
+
+        trace!(?msg, "Received WebSocket frame.");
 
         // Figure out what kind of message we've received.
         match msg {
@@ -134,11 +147,27 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketClient {
             }
             Ok(ws::Message::Nop) => (),
             Ok(ws::Message::Text(text)) => {
+                // Every message gets an id of its own so that everything it causes can be
+                // told apart from other messages from the same client.
+                let message_span = debug_span!(
+                    "client_message",
+                    request_id = %Uuid::new_v4(),
+                    message_type = field::Empty,
+                );
+                let _message = message_span.enter();
+
                 // Try to parse the text received as a JSON representation of a ClientInput object.
                 let parse_result = serde_json::from_str::<ClientInput>(&text);
 
                 // Check if the parsing was sucessful.
                 if let Ok(parsed_input) = parse_result {
+                    message_span.record("message_type", parsed_input.message_type());
+                    debug!("Received message.");
+
+                    CLIENT_MESSAGES
+                        .with_label_values(&[parsed_input.message_type()])
+                        .inc();
+
                     // If it was successful, pattern match on what type of input was received.
                     match parsed_input {
                         // TODO: Handle these.
@@ -147,6 +176,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketClient {
                             self.lobby_addr.do_send(RouteRequest {
                                 self_id: self.id,
                                 identifier: inp.id,
+                                kind: inp.kind,
+                                format: inp.format,
+                                zoom: inp.zoom,
+                            });
+                        }
+                        ClientInput::GetLineHealth(inp) => {
+                            self.lobby_addr.do_send(LineHealth {
+                                self_id: self.id,
+                                line: inp.line,
                             });
                         }
                         ClientInput::GeoPositionUpdate(inp) => {
@@ -173,6 +211,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketClient {
                         }
                     }
                 } else {
+                    CLIENT_MESSAGES.with_label_values(&["unknown"]).inc();
+
+                    debug!(%text, "Received unsupported message.");
+
                     // If the message sent by the client is not parseable as JSON, an error message
                     // is sent back to the user.
                     ctx.text(ServerOutput::error_message(
@@ -198,3 +240,17 @@ impl Handler<WsMessage> for WebsocketClient {
         ctx.text(msg.0);
     }
 }
+
+impl Handler<CloseConnection> for WebsocketClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseConnection, ctx: &mut Self::Context) {
+        info!(parent: &self.span, reason = %msg.0, "Closing the connection.");
+
+        ctx.close(Some(CloseReason {
+            code: CloseCode::Again,
+            description: Some(msg.0),
+        }));
+        ctx.stop();
+    }
+}